@@ -24,9 +24,10 @@ use argon2::{
 };
 use auth::authenticate_server::{Authenticate, AuthenticateServer};
 use auth::{
-    LoginRequest, LoginResponse, LoginSuccess, PublicKeyRequest,
-    PublicKeyResponse, RefreshRequest, RefreshResponse, RegisterRequest,
-    RegisterResponse, RegisterSuccess,
+    LoginRequest, LoginResponse, LoginSuccess, LookupUsersRequest,
+    LookupUsersResponse, PublicKeyRequest, PublicKeyResponse, RefreshRequest,
+    RefreshResponse, RegisterRequest, RegisterResponse, RegisterSuccess,
+    UserHandle,
 };
 use blake3::Hasher;
 use chrono::{Duration, Utc};
@@ -59,6 +60,7 @@ use std::{
 use tokio::task;
 use tonic::{Code, async_trait};
 use tonic::{Request, Response as TonicResponse, Status, transport::Server};
+use tracing::error;
 use tracing_subscriber::fmt::format;
 use uuid::{Timestamp, Uuid, timestamp};
 use validator::Validate;
@@ -66,6 +68,10 @@ use validator::Validate;
 pub mod auth {
     tonic::include_proto!("authentication");
 }
+//Lookups come from message mentions, which are capped well below this
+const MAX_LOOKUP_USERNAMES: usize = 64;
+///Metadata entry other services put their `INTERNAL_SERVICE_TOKEN` in
+pub const SERVICE_TOKEN_HEADER: &str = "x-service-token";
 pub(crate) struct Authenticator<U, K>
 where
     U: UserRepo + Send + Sync,
@@ -77,6 +83,9 @@ where
     asymmetric_kp: AsymmetricKeyPair<V4>,
     pepper: String,
     claims_config: ClaimsConfig,
+    //Hash of the token internal-only calls have to carry, they are all
+    // refused without one
+    service_token: Option<blake3::Hash>,
 }
 
 #[async_trait]
@@ -293,6 +302,13 @@ impl Authenticate for Authenticator<PostgresUserRepo, PostgresKeyRepo> {
             return Err(Status::unauthenticated("not a valid PID"));
         }
     }
+
+    async fn lookup_users(
+        &self,
+        request: Request<LookupUsersRequest>,
+    ) -> Result<TonicResponse<LookupUsersResponse>, Status> {
+        self.lookup_users(request).await
+    }
 }
 //
 impl Authenticator<PostgresUserRepo, PostgresKeyRepo> {
@@ -312,6 +328,10 @@ impl Authenticator<PostgresUserRepo, PostgresKeyRepo> {
             asymmetric_kp: AsymmetricKeyPair::generate().unwrap(),
             pepper: super_secret_key,
             claims_config: ClaimsConfig::new(),
+            service_token: var("INTERNAL_SERVICE_TOKEN")
+                .ok()
+                .filter(|token| !token.is_empty())
+                .map(|token| blake3::hash(token.as_bytes())),
         }
     }
     //
@@ -344,6 +364,51 @@ impl Authenticator<PostgresUserRepo, PostgresKeyRepo> {
             _ => Err(Status::unauthenticated("Could not authenticate")),
         }
     }
+    async fn lookup_users(
+        &self,
+        request: Request<LookupUsersRequest>,
+    ) -> Result<TonicResponse<LookupUsersResponse>, Status> {
+        //Lets anyone enumerate accounts, only other services may ask
+        self.verify_service(&request)?;
+        let usernames = request.into_inner().usernames;
+        if usernames.len() > MAX_LOOKUP_USERNAMES {
+            return Err(Status::invalid_argument("too many usernames"));
+        }
+        let users = self
+            .user_repo
+            .get_users_from_usernames(&usernames)
+            .await
+            .map_err(|err| {
+            error!("could not look up users: {err}");
+            Status::internal("could not look up users")
+        })?;
+        let users = users
+            .into_iter()
+            .map(|user| UserHandle {
+                user_id: user.user_id.hyphenated().to_string(),
+                username: user.username,
+            })
+            .collect();
+        Ok(TonicResponse::new(LookupUsersResponse { users }))
+    }
+    ///Checks the caller is another service. Comparing blake3 hashes takes
+    /// the same time however much of the token matches.
+    fn verify_service<T>(&self, request: &Request<T>) -> Result<(), Status> {
+        let Some(expected) = self.service_token else {
+            return Err(Status::permission_denied(
+                "internal calls are not enabled",
+            ));
+        };
+        let presented = request
+            .metadata()
+            .get(SERVICE_TOKEN_HEADER)
+            .and_then(|token| token.to_str().ok())
+            .ok_or_else(|| Status::unauthenticated("service token missing"))?;
+        if blake3::hash(presented.as_bytes()) != expected {
+            return Err(Status::unauthenticated("invalid service token"));
+        }
+        Ok(())
+    }
     async fn verify_password(
         password: String,
         hash_str: String,
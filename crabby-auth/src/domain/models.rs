@@ -58,6 +58,11 @@ pub struct RegisterResponseData {
     pub user_id: Uuid,
     pub username: String,
 }
+// Public part of a user, safe to hand to other services
+pub struct UserHandleRow {
+    pub user_id: Uuid,
+    pub username: String,
+}
 #[derive(FromRow, Debug)]
 pub struct UserRow {
     pub user_id: Uuid,
@@ -108,9 +113,9 @@ impl ConvertToken for RefreshTokenWithMetadata {
         RefreshTokenRow {
             token_hash,
             user_id: self.user_id,
-            token_jti: self.jti.clone(),
-            issued_at: self.issued_at.clone(),
-            expires_at: self.expires_at.clone(),
+            token_jti: self.jti,
+            issued_at: self.issued_at,
+            expires_at: self.expires_at,
         }
     }
 }
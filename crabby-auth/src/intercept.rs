@@ -1,4 +1,3 @@
-use pasetors::{Local, token::UntrustedToken, version4::V4};
 use tonic::{Request, Status};
 
 pub fn intercept(mut req: Request<()>) -> Result<Request<()>, Status> {
@@ -7,8 +7,7 @@ use std::{sync::LazyLock, time::Duration};
 
 use argon2::{Algorithm, Argon2, Params, Version};
 use authenticate::auth::authenticate_server::AuthenticateServer;
-use dotenvy::{dotenv, var};
-use sqlx::PgPool;
+use dotenvy::var;
 use sqlx::postgres::PgPoolOptions;
 use tonic::transport::Server;
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
@@ -56,7 +55,8 @@ mod tests {
     use once_cell::sync::Lazy;
 
     use crate::authenticate::auth::{
-        LoginRequest, RefreshRequest, RegisterRequest, authenticate_client::AuthenticateClient,
+        LoginRequest, LookupUsersRequest, RefreshRequest, RegisterRequest,
+        authenticate_client::AuthenticateClient,
     };
 
     use eyre::Result;
@@ -214,4 +214,39 @@ mod tests {
 
         Ok(())
     }
+
+    #[tokio::test]
+    async fn test_lookup_users_needs_service_token() -> Result<()> {
+        let mut client = get_client().await?;
+        let reg = next_register_test();
+        client
+            .register(tonic::Request::new(reg.register_to_request()))
+            .await?;
+        let lookup = || LookupUsersRequest {
+            usernames: vec![reg.username.clone()],
+        };
+
+        let res = client.lookup_users(tonic::Request::new(lookup())).await;
+        assert!(res.is_err(), "lookup without a token should fail: {res:?}");
+
+        let mut req = tonic::Request::new(lookup());
+        req.metadata_mut().insert(
+            crate::authenticate::SERVICE_TOKEN_HEADER,
+            "not-the-token".parse().expect("valid metadata value"),
+        );
+        let res = client.lookup_users(req).await;
+        assert!(res.is_err(), "lookup with a wrong token should fail: {res:?}");
+
+        //The server's own token, when the tests are given it too
+        if let Ok(token) = dotenvy::var("INTERNAL_SERVICE_TOKEN") {
+            let mut req = tonic::Request::new(lookup());
+            req.metadata_mut().insert(
+                crate::authenticate::SERVICE_TOKEN_HEADER,
+                token.parse().expect("valid metadata value"),
+            );
+            let users = client.lookup_users(req).await?.into_inner().users;
+            assert_eq!(users.len(), 1);
+        }
+        Ok(())
+    }
 }
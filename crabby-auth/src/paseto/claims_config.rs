@@ -1,11 +1,14 @@
-use dotenvy::{dotenv, var};
+use dotenvy::var;
 use pasetors::claims::ClaimsValidationRules;
 pub struct ClaimsConfig {
+    //Access tokens are verified by the services they are sent to
+    #[allow(dead_code)]
     access: ClaimsValidationRules,
     refresh: ClaimsValidationRules,
 }
 
 impl ClaimsConfig {
+    #[allow(dead_code)]
     pub fn access(&self) -> &ClaimsValidationRules {
         &self.access
     }
@@ -2,13 +2,11 @@ use std::sync::Arc;
 
 use crabby_core::tokens::KeyRetrieval;
 use eyre::Result;
-use hmac::{Hmac, Mac};
 use pasetors::{
     keys::{AsymmetricPublicKey, SymmetricKey},
     paserk::{FormatAsPaserk, Id},
     version4::V4,
 };
-use sha2::Sha256;
 use sqlx::{PgPool, prelude::FromRow, query, query_as};
 use uuid::Uuid;
 
@@ -32,7 +30,7 @@ impl KeyRetrieval<AsymmetricPublicKey<V4>> for PostgresKeyRepo {
         .fetch_one(&mut *tx)
         .await?;
         let key = AsymmetricPublicKey::from(bytes.public_paserk.as_slice());
-        tx.commit().await;
+        tx.commit().await?;
         Ok(key?)
     }
 }
@@ -50,12 +48,14 @@ impl KeyRetrieval<SymmetricKey<V4>> for PostgresKeyRepo {
         )
         .fetch_one(&mut *tx)
         .await?;
-        let key = SymmetricKey::from(&bytes.local_wrap_paserk.as_slice());
-        tx.commit().await;
+        let key = SymmetricKey::from(bytes.local_wrap_paserk.as_slice());
+        tx.commit().await?;
         Ok(key?)
     }
 }
 
+//Only used with concrete repos, so nothing needs the futures to be Send
+#[allow(async_fn_in_trait)]
 pub trait PasetoKeyRepo {
     async fn store_public_key(&self, key: AsymmetricPublicKey<V4>) -> Result<()>;
     async fn fetch_public_key(&self, kid: String) -> Result<AsymmetricPublicKey<V4>>;
@@ -77,7 +77,7 @@ impl PasetoKeyRepo for PostgresKeyRepo {
         let id = Id::from(&key);
 
         let mut string_id = String::new();
-        id.fmt(&mut string_id);
+        id.fmt(&mut string_id)?;
         let key_bytes = key.as_bytes();
         let _ = query!(
             "INSERT INTO validation.paseto_public_key (kid, public_paserk) VALUES ($1,$2) RETURNING kid ",
@@ -93,10 +93,10 @@ impl PasetoKeyRepo for PostgresKeyRepo {
     }
     //Lots of cleaning up TODO:
     async fn store_local_key(&self, key: SymmetricKey<V4>) -> Result<()> {
-        let mut tx = self.conn.begin().await?;
+        let tx = self.conn.begin().await?;
         let id = Id::from(&key);
         let mut string_id = String::new();
-        id.fmt(&mut string_id);
+        id.fmt(&mut string_id)?;
         let key_bytes = key.as_bytes();
         query!(
             "INSERT INTO validation.paseto_local_wrap_key (kid, local_wrap_paserk) VALUES ($1,$2) RETURNING kid ",
@@ -113,7 +113,7 @@ impl PasetoKeyRepo for PostgresKeyRepo {
     }
 
     async fn store_refresh_info(&self, token: &RefreshTokenRow) -> Result<()> {
-        let mut tx = self.conn.begin().await?;
+        let tx = self.conn.begin().await?;
         let _ = query!(
             "INSERT INTO validation.refresh_token(user_id, token_jti, token_hash, issued_at, expires_at) VALUES ($1,$2, $3, $4, $5) ",
             token.user_id,&token.token_jti, token.token_hash.as_slice(), token.issued_at, token.expires_at
@@ -1,5 +1,5 @@
 use ::core::time::Duration;
-use chrono::{DateTime, TimeDelta, Utc};
+use chrono::Utc;
 use eyre::Result;
 use pasetors::{
     claims::Claims,
@@ -62,7 +62,7 @@ pub(crate) fn refresh(
     //unwrap is safe here as it's unlikely that this program will be running when time reaches
     //out of range
     let mut claims = Claims::new().unwrap();
-    claims.set_expires_in(&delta);
+    let _ = claims.set_expires_in(&delta);
     let _ = claims.issuer("crabby-auth");
     let _ = claims.audience("crabby-auth");
     let _ = claims.subject(id);
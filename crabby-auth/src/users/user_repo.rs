@@ -1,17 +1,20 @@
 use std::sync::Arc;
 
-use crate::domain::models::{RegisterRequestData, RegisterResponseData};
+use crate::domain::models::{RegisterRequestData, RegisterResponseData, UserHandleRow};
 use eyre::Result;
 use sqlx::{PgPool, query_as};
 use uuid::Uuid;
 
 use crate::domain::models::UserRow;
 
+//Only used with concrete repos, so nothing needs the futures to be Send
+#[allow(async_fn_in_trait)]
 pub trait UserRepo {
     // add code here
     async fn register_user(&self, user: RegisterRequestData) -> Result<RegisterResponseData>;
     async fn get_user_from_id(&self, id: Uuid) -> Result<UserRow>;
     async fn get_user_from_username(&self, username: &str) -> Result<UserRow>;
+    async fn get_users_from_usernames(&self, usernames: &[String]) -> Result<Vec<UserHandleRow>>;
 }
 
 pub struct PostgresUserRepo {
@@ -32,7 +35,6 @@ impl UserRepo for PostgresUserRepo {
     }
 
     async fn get_user_from_id(&self, id: Uuid) -> Result<UserRow> {
-        let mut tx = self.conn.begin().await?;
         let user = query_as!(
             UserRow,
             "SELECT * from validation.auth_user where user_id = ($1)",
@@ -53,4 +55,15 @@ impl UserRepo for PostgresUserRepo {
         .await?;
         Ok(user)
     }
+
+    async fn get_users_from_usernames(&self, usernames: &[String]) -> Result<Vec<UserHandleRow>> {
+        let users = query_as!(
+            UserHandleRow,
+            "SELECT user_id, username::text AS \"username!\" from validation.auth_user where username = ANY($1::text[]::citext[])",
+            usernames
+        )
+        .fetch_all(&*self.conn)
+        .await?;
+        Ok(users)
+    }
 }
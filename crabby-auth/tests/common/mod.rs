@@ -1,5 +1,5 @@
 use once_cell::sync::OnceCell;
-use sqlx::{AssertSqlSafe, Connection, Executor, PgConnection, PgPool, SqlSafeStr, SqlStr};
+use sqlx::{AssertSqlSafe, Connection, PgConnection, PgPool, SqlSafeStr};
 use std::time::Duration;
 use url::Url;
 use uuid::Uuid;
@@ -47,14 +47,10 @@ impl TestDb {
         let test_url = test_url.to_string();
 
         // Small retry loop in case Postgres needs a moment to accept the new DB.
-        let mut last_err: Option<eyre::Report> = None;
         let pool = loop {
             match PgPool::connect(&test_url).await {
                 Ok(p) => break p,
-                Err(e) => {
-                    last_err = Some(eyre::Report::new(e));
-                    tokio::time::sleep(Duration::from_millis(150)).await;
-                }
+                Err(_) => tokio::time::sleep(Duration::from_millis(150)).await,
             }
         };
 
@@ -20,7 +20,7 @@ async fn store_and_fetch_public_and_local_keys_by_kid() -> eyre::Result<()> {
     let kid_pub = {
         let id = Id::from(&kp.public);
         let mut s = String::new();
-        id.fmt(&mut s);
+        id.fmt(&mut s).unwrap();
         s
     };
 
@@ -33,7 +33,7 @@ async fn store_and_fetch_public_and_local_keys_by_kid() -> eyre::Result<()> {
     let kid_local = {
         let id = Id::from(&sk);
         let mut s = String::new();
-        id.fmt(&mut s);
+        id.fmt(&mut s).unwrap();
         s
     };
     repo.store_local_key(sk.clone()).await?;
@@ -19,7 +19,6 @@ use std::{
 };
 
 use eyre::Result;
-use fake::{Fake, faker::internet::en::Username};
 use once_cell::sync::Lazy;
 use tonic::{Request, transport::Channel};
 
@@ -151,7 +150,6 @@ where
 
     let mut handles = Vec::with_capacity(c);
     for _ in 0..c {
-        let end = end;
         let make_call = make_call.clone(); // 👈 per-worker clone
         let ok = Arc::clone(&ok);
         let err = Arc::clone(&err);
@@ -1,5 +1,20 @@
 // generated by `sqlx migrate build-script`
+use tonic_prost_build::configure;
+
 fn main() {
     // trigger recompilation when a new migration is added
     println!("cargo:rerun-if-changed=migrations");
+    println!("cargo:rerun-if-changed=../proto/auth.proto");
+    println!("cargo:rerun-if-changed=../proto/groups.proto");
+
+    configure()
+        .build_server(false)
+        .build_client(true)
+        .compile_protos(&["../proto/auth.proto"], &["../proto"])
+        .expect("failed to compile auth.proto");
+    configure()
+        .build_server(false)
+        .build_client(true)
+        .compile_protos(&["../proto/groups.proto"], &["../proto"])
+        .expect("failed to compile groups.proto");
 }
@@ -0,0 +1,3 @@
+-- Add down migration script here
+DROP TABLE IF EXISTS chat_message;
+DROP TYPE IF EXISTS destination_type;
@@ -0,0 +1,22 @@
+-- Add up migration script here
+CREATE TYPE destination_type AS ENUM ('individual', 'group');
+
+-- message_id is the snowflake handed out by the engine, the time a
+-- message was accepted is encoded in it so it is not stored separately
+CREATE TABLE chat_message(
+    message_id          BIGINT NOT NULL,
+    sender_id           UUID NOT NULL,
+    dest_type           destination_type NOT NULL,
+    dest_id             UUID NOT NULL,
+    contents            TEXT NOT NULL,
+    client_sent_at      TEXT,
+    PRIMARY KEY (message_id)
+);
+
+CREATE INDEX chat_message_destination
+    ON chat_message (dest_type, dest_id, message_id DESC);
+
+-- direct message history is looked up from both sides of a conversation
+CREATE INDEX chat_message_direct
+    ON chat_message (sender_id, dest_id, message_id DESC)
+    WHERE dest_type = 'individual';
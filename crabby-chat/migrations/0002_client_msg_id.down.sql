@@ -0,0 +1,3 @@
+-- Add down migration script here
+DROP INDEX IF EXISTS chat_message_client_msg_id;
+ALTER TABLE chat_message DROP COLUMN IF EXISTS client_msg_id;
@@ -0,0 +1,7 @@
+-- Add up migration script here
+ALTER TABLE chat_message ADD COLUMN client_msg_id TEXT;
+
+-- a sender never gets two messages stored under the same idempotency key
+CREATE UNIQUE INDEX chat_message_client_msg_id
+    ON chat_message (sender_id, client_msg_id)
+    WHERE client_msg_id IS NOT NULL;
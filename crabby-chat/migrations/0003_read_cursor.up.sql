@@ -0,0 +1,10 @@
+-- Add up migration script here
+-- how far each user has read each conversation, a direct conversation is
+-- keyed by the other side so both users have their own cursor
+CREATE TABLE read_cursor(
+    user_id             UUID NOT NULL,
+    dest_type           destination_type NOT NULL,
+    dest_id             UUID NOT NULL,
+    up_to               BIGINT NOT NULL,
+    PRIMARY KEY (user_id, dest_type, dest_id)
+);
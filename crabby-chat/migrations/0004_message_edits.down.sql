@@ -0,0 +1,6 @@
+-- Add down migration script here
+DROP TABLE IF EXISTS chat_message_revision;
+
+ALTER TABLE chat_message
+    DROP COLUMN IF EXISTS deleted_id,
+    DROP COLUMN IF EXISTS edited_id;
@@ -0,0 +1,16 @@
+-- Add up migration script here
+-- edits and deletes are stamped with a snowflake of their own, the time
+-- they happened is encoded in it. A deleted message keeps its row with
+-- the contents cleared so clients can tell it apart from a missing one.
+ALTER TABLE chat_message
+    ADD COLUMN edited_id BIGINT,
+    ADD COLUMN deleted_id BIGINT;
+
+-- every version an edit replaced, written_id is the message_id or the
+-- edited_id the version was written under
+CREATE TABLE chat_message_revision(
+    message_id          BIGINT NOT NULL REFERENCES chat_message ON DELETE CASCADE,
+    written_id          BIGINT NOT NULL,
+    contents            TEXT NOT NULL,
+    PRIMARY KEY (message_id, written_id)
+);
@@ -0,0 +1,10 @@
+-- Add up migration script here
+-- one row per user and emoji they reacted to a message with, reacted_at
+-- only orders the emojis of a message by when they were first used
+CREATE TABLE message_reaction(
+    message_id          BIGINT NOT NULL REFERENCES chat_message ON DELETE CASCADE,
+    user_id             UUID NOT NULL,
+    emoji               TEXT NOT NULL,
+    reacted_at          TIMESTAMPTZ NOT NULL DEFAULT now(),
+    PRIMARY KEY (message_id, user_id, emoji)
+);
@@ -0,0 +1,6 @@
+-- Add down migration script here
+DROP INDEX IF EXISTS chat_message_thread;
+
+ALTER TABLE chat_message
+    DROP COLUMN IF EXISTS thread_root,
+    DROP COLUMN IF EXISTS reply_to;
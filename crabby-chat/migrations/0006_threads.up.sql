@@ -0,0 +1,10 @@
+-- Add up migration script here
+-- thread_root is the message a thread was started under, replies keep
+-- their conversation in dest_type and dest_id like any other message
+ALTER TABLE chat_message
+    ADD COLUMN reply_to BIGINT,
+    ADD COLUMN thread_root BIGINT;
+
+CREATE INDEX chat_message_thread
+    ON chat_message (thread_root, message_id DESC)
+    WHERE thread_root IS NOT NULL;
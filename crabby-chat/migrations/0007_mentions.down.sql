@@ -0,0 +1,4 @@
+-- Add down migration script here
+ALTER TABLE chat_message
+    DROP COLUMN IF EXISTS mentions_group,
+    DROP COLUMN IF EXISTS mentions;
@@ -0,0 +1,6 @@
+-- Add up migration script here
+-- Users mentioned in a message, a group-wide mention is kept apart in
+-- `mentions_group`
+ALTER TABLE chat_message
+    ADD COLUMN mentions UUID[] NOT NULL DEFAULT '{}',
+    ADD COLUMN mentions_group BOOLEAN NOT NULL DEFAULT false;
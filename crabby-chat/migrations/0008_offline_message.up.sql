@@ -0,0 +1,9 @@
+-- Add up migration script here
+-- messages waiting for users who were not connected anywhere when they
+-- were sent, drained when the user connects again
+CREATE TABLE offline_message(
+    user_id             UUID NOT NULL,
+    message_id          BIGINT NOT NULL REFERENCES chat_message ON DELETE CASCADE,
+    queued_at           TIMESTAMPTZ NOT NULL DEFAULT now(),
+    PRIMARY KEY (user_id, message_id)
+);
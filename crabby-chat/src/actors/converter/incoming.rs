@@ -1,3 +1,4 @@
+use crabby_specs::ws::incoming::CrabbyWsFromClient;
 use eyre::Result;
 
 ///This trait is used to convert incoming messages from inbound types to a domain specific type
@@ -6,3 +7,19 @@ pub trait Decode<I> {
     type Error;
     fn decode(item: I) -> Result<Self::Output>;
 }
+
+///Bytes of data a frame carries, `None` for control frames. Rate limits
+/// are charged for data frames only.
+pub trait DataLen {
+    fn data_len(&self) -> Option<usize>;
+}
+
+///What an inbound frame means to the connection that read it
+#[derive(Debug)]
+pub enum InboundFrame {
+    Message(CrabbyWsFromClient),
+    Ping,
+    Pong,
+    ///The client is closing the connection
+    Close,
+}
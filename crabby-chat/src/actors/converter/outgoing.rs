@@ -1,5 +1,8 @@
-use axum::{body::Bytes, extract::ws::Message};
-use crabby_specs::ws::outgoing::CrabbyWsFromServer;
+use axum::{
+    body::Bytes,
+    extract::ws::{CloseFrame, Message},
+};
+use crabby_specs::ws::outgoing::{CrabbyWsFromServer, ServerFrame};
 use eyre::Result;
 
 pub trait Encode<I> {
@@ -18,6 +21,36 @@ impl Encode<CrabbyWsFromServer> for ServerToTransport {
         Ok(bytes)
     }
 }
+impl Encode<ServerFrame> for ServerToTransport {
+    type Output = Message;
+
+    fn encode(item: ServerFrame) -> Result<Self::Output> {
+        let serialized = serde_json::to_vec(&item)?;
+        Ok(Message::Binary(Bytes::from(serialized)))
+    }
+}
+///Connection level frames the server sends on its own, outside the
+/// sequenced message stream
+#[derive(Debug, Clone)]
+pub enum ControlFrame {
+    Ping,
+    Close { code: u16, reason: String },
+}
+impl Encode<ControlFrame> for ServerToTransport {
+    type Output = Message;
+
+    fn encode(item: ControlFrame) -> Result<Self::Output> {
+        Ok(match item {
+            ControlFrame::Ping => Message::Ping(Bytes::new()),
+            ControlFrame::Close { code, reason } => {
+                Message::Close(Some(CloseFrame {
+                    code,
+                    reason: reason.into(),
+                }))
+            }
+        })
+    }
+}
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -31,6 +64,11 @@ mod tests {
             dest: Destination::Individual { id: Uuid::nil() },
             timestamp: "2026-01-01T00:00:00Z".to_string(),
             contents: "hello world".to_string(),
+            client_sent_at: None,
+            reply_to: None,
+            thread_root: None,
+            mentions: Vec::new(),
+            mentions_group: false,
         }
     }
 
@@ -64,6 +102,7 @@ mod tests {
                 assert_eq!(message_id, 42);
                 assert_eq!(contents, "hello world");
             }
+            other => panic!("Expected ChatMessage, got {:?}", other),
         }
     }
 
@@ -76,6 +115,11 @@ mod tests {
             dest: Destination::Individual { id: dest_id },
             timestamp: String::new(),
             contents: String::new(),
+            client_sent_at: None,
+            reply_to: None,
+            thread_root: None,
+            mentions: Vec::new(),
+            mentions_group: false,
         };
         let encoded = ServerToTransport::encode(msg).unwrap();
         let bytes = match encoded {
@@ -89,6 +133,7 @@ mod tests {
                 Destination::Individual { id } => assert_eq!(id, dest_id),
                 _ => panic!("Expected Individual destination"),
             },
+            other => panic!("Expected ChatMessage, got {:?}", other),
         }
     }
 
@@ -101,6 +146,11 @@ mod tests {
             dest: Destination::Group { id: group_id },
             timestamp: String::new(),
             contents: String::new(),
+            client_sent_at: None,
+            reply_to: None,
+            thread_root: None,
+            mentions: Vec::new(),
+            mentions_group: false,
         };
         let encoded = ServerToTransport::encode(msg).unwrap();
         let bytes = match encoded {
@@ -114,6 +164,7 @@ mod tests {
                 Destination::Group { id } => assert_eq!(id, group_id),
                 _ => panic!("Expected Group destination"),
             },
+            other => panic!("Expected ChatMessage, got {:?}", other),
         }
     }
 
@@ -125,6 +176,11 @@ mod tests {
             dest: Destination::Individual { id: Uuid::nil() },
             timestamp: String::new(),
             contents: String::new(),
+            client_sent_at: None,
+            reply_to: None,
+            thread_root: None,
+            mentions: Vec::new(),
+            mentions_group: false,
         };
         let result = ServerToTransport::encode(msg);
         assert!(result.is_ok());
@@ -138,6 +194,11 @@ mod tests {
             dest: Destination::Individual { id: Uuid::nil() },
             timestamp: String::new(),
             contents: "🦀 héllo wörld 你好".to_string(),
+            client_sent_at: None,
+            reply_to: None,
+            thread_root: None,
+            mentions: Vec::new(),
+            mentions_group: false,
         };
         let encoded = ServerToTransport::encode(msg).unwrap();
         let bytes = match encoded {
@@ -150,6 +211,53 @@ mod tests {
             CrabbyWsFromServer::ChatMessage { contents, .. } => {
                 assert_eq!(contents, "🦀 héllo wörld 你好");
             }
+            other => panic!("Expected ChatMessage, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn encode_frame_flattens_message_next_to_seq() {
+        let frame = ServerFrame {
+            seq: Some(3),
+            message: sample_server_message(),
+        };
+        let bytes = match ServerToTransport::encode(frame).unwrap() {
+            Message::Binary(b) => b,
+            _ => panic!("Expected Binary"),
+        };
+        let value: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(value["seq"], 3);
+        assert_eq!(value["type"], "ChatMessage");
+        assert_eq!(value["message_id"], 42);
+    }
+
+    #[test]
+    fn encode_frame_without_seq_omits_it() {
+        let frame = ServerFrame {
+            seq: None,
+            message: sample_server_message(),
+        };
+        let bytes = match ServerToTransport::encode(frame).unwrap() {
+            Message::Binary(b) => b,
+            _ => panic!("Expected Binary"),
+        };
+        let value: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+        assert!(value.get("seq").is_none());
+    }
+
+    #[test]
+    fn close_frame_carries_reason() {
+        let encoded = ServerToTransport::encode(ControlFrame::Close {
+            code: 1001,
+            reason: "heartbeat timeout".to_string(),
+        })
+        .unwrap();
+        match encoded {
+            Message::Close(Some(frame)) => {
+                assert_eq!(frame.code, 1001);
+                assert_eq!(frame.reason.as_str(), "heartbeat timeout");
+            }
+            other => panic!("Expected Close message, got {:?}", other),
         }
     }
 }
@@ -1,22 +1,155 @@
 use crate::{
-    id::{GenerateId, IdGenerator},
-    messages::internal::{UserConnected, UserDisconnected},
+    cluster::Cluster,
+    config::{OfflineQueueConfig, PresenceConfig},
+    groups::ResolveMembers,
+    id::{GenerateId, IdGenerator, timestamp_of},
+    mentions::{self, MAX_MENTIONS},
+    messages::internal::{
+        ClientMessage, ConnectionDiagnostics, ParkSession, PresenceTick,
+        RemoteDelivery, RemotePresence, ResumeSession, TypingExpired,
+        UserConnected, UserDisconnected,
+    },
+    presence::{Audience, AudienceCache, FORGET_AFTER, PresenceBoard},
+    replay::ReplayBuffer,
+    sessions::{ConnectionStats, Session, SessionRegistry},
+    store::{
+        MAX_HISTORY_PAGE, MessageRepo, MessageStore, SaveOutcome, StoredMessage,
+    },
+    typing::{Expiry, TYPING_TIMEOUT, TypingTracker},
+    users::ResolveUsers,
 };
-use crabby_specs::ws::{
-    incoming::CrabbyWsFromClient, outgoing::CrabbyWsFromServer,
+use crabby_specs::{
+    nats::channel::PresenceEvent,
+    ws::{
+        common::{Destination, TypingState},
+        incoming::CrabbyWsFromClient,
+        outgoing::{
+            CrabbyWsFromServer, ErrorCode, HistoricalMessage, UserPresence,
+        },
+    },
 };
-use hashbrown::HashMap;
+use futures::StreamExt;
+use hashbrown::{HashMap, HashSet};
+use jiff::Timestamp;
 use kameo::{
     Actor,
-    actor::{ActorRef, Recipient},
+    actor::{ActorRef, WeakActorRef},
     error::Infallible,
     prelude::Message,
 };
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::task::AbortHandle;
+use tracing::{error, info, warn};
 use uuid::Uuid;
 
+///How long the frames of a dropped connection are kept for the client to
+/// resume its session
+pub const RESUME_WINDOW: Duration = Duration::from_secs(120);
+
+///Longest `client_msg_id` accepted, in bytes
+pub const MAX_CLIENT_MSG_ID_LEN: usize = 64;
+
+///Most users a single `QueryPresence` is answered for
+pub const MAX_PRESENCE_QUERY: usize = 256;
+
+///Longest `emoji` accepted in a reaction, in bytes. Enough for a
+/// sequence of joined emojis, not for a message smuggled in as one.
+pub const MAX_EMOJI_LEN: usize = 32;
+
+fn send_ack(client_msg_id: String, message_id: u64) -> CrabbyWsFromServer {
+    CrabbyWsFromServer::SendAck {
+        client_msg_id,
+        message_id,
+        timestamp: timestamp_of(message_id).to_string(),
+    }
+}
+
+///Typing indicators are only of use while they are current, they are
+/// never kept for a parked session
+fn is_ephemeral(message: &CrabbyWsFromServer) -> bool {
+    matches!(message, CrabbyWsFromServer::UserTyping { .. })
+}
+
+///Has the engine check on a typing indicator once `after` has passed
+fn expire_typing_after(
+    engine: WeakActorRef<EngineActor>,
+    user_id: Uuid,
+    dest: Destination,
+    after: Duration,
+) {
+    tokio::spawn(async move {
+        tokio::time::sleep(after).await;
+        if let Some(engine) = engine.upgrade() {
+            let _ = engine.tell(TypingExpired { user_id, dest }).await;
+        }
+    });
+}
+
+///Has the engine send a `PresenceTick` to itself every `interval` for as
+/// long as it runs
+fn tick_presence(engine: WeakActorRef<EngineActor>, interval: Duration) {
+    tokio::spawn(async move {
+        let mut ticks = tokio::time::interval(interval);
+        loop {
+            ticks.tick().await;
+            let Some(engine) = engine.upgrade() else {
+                break;
+            };
+            let _ = engine.tell(PresenceTick).await;
+        }
+    });
+}
+
+///Where in its conversation a message goes
+#[derive(Debug, Clone, Copy, Default)]
+struct Placement {
+    reply_to: Option<u64>,
+    thread_root: Option<u64>,
+}
+
+///A message as the client sent it, before it is checked and stamped
+struct Draft {
+    client_msg_id: String,
+    dest: Destination,
+    contents: String,
+    client_sent_at: Option<String>,
+    placement: Placement,
+    mentions: Vec<Uuid>,
+    mentions_group: bool,
+}
+
+///A session whose connection dropped. Messages for its user keep being
+/// sequenced into the buffer until it is resumed or expires.
+struct ParkedSession {
+    user_id: Uuid,
+    buffer: ReplayBuffer,
+    parked_at: Instant,
+    //Next seq at the time the buffer was handed to a resuming connection,
+    // frames from there on are sent once it is attached
+    handed_out: Option<u64>,
+}
+
 pub struct EngineActor {
-    map: HashMap<Uuid, Recipient<CrabbyWsFromServer>>,
+    sessions: SessionRegistry,
+    parked: HashMap<Uuid, ParkedSession>,
     id_gen: IdGenerator,
+    groups: Arc<dyn ResolveMembers>,
+    users: Arc<dyn ResolveUsers>,
+    store: MessageStore,
+    cluster: Cluster,
+    //Cluster subscriptions of the users with a live or parked session on
+    // this node
+    subscriptions: HashMap<Uuid, AbortHandle>,
+    presence: PresenceBoard,
+    audiences: AudienceCache,
+    //Users asked about with `QueryPresence`, and the users who asked
+    watchers: HashMap<Uuid, HashSet<Uuid>>,
+    typing: TypingTracker,
+    offline: OfflineQueueConfig,
+    heartbeat: PresenceConfig,
 }
 impl Actor for EngineActor {
     type Args = Self;
@@ -25,79 +158,3206 @@ impl Actor for EngineActor {
 
     async fn on_start(
         args: Self::Args,
-        _actor_ref: ActorRef<Self>,
+        actor_ref: ActorRef<Self>,
     ) -> Result<Self, Self::Error> {
+        args.follow_presence(actor_ref.downgrade()).await;
+        tick_presence(actor_ref.downgrade(), args.heartbeat.interval);
         Ok(args)
     }
 }
 impl EngineActor {
     pub fn new(
-        map: HashMap<Uuid, Recipient<CrabbyWsFromServer>>,
+        sessions: SessionRegistry,
         id_gen: IdGenerator,
+        groups: Arc<dyn ResolveMembers>,
+        users: Arc<dyn ResolveUsers>,
+        store: MessageStore,
+        cluster: Cluster,
     ) -> EngineActor {
-        Self { map, id_gen }
+        Self {
+            sessions,
+            parked: HashMap::new(),
+            id_gen,
+            groups,
+            users,
+            store,
+            presence: PresenceBoard::new(cluster.node_id()),
+            cluster,
+            subscriptions: HashMap::new(),
+            audiences: AudienceCache::default(),
+            watchers: HashMap::new(),
+            typing: TypingTracker::default(),
+            offline: OfflineQueueConfig::default(),
+            heartbeat: PresenceConfig::default(),
+        }
+    }
+    pub fn with_offline_queue(mut self, offline: OfflineQueueConfig) -> Self {
+        self.offline = offline;
+        self
+    }
+    pub fn with_presence_heartbeat(
+        mut self,
+        heartbeat: PresenceConfig,
+    ) -> Self {
+        self.heartbeat = heartbeat;
+        self
+    }
+    ///Stamps a client message with the authenticated sender and an id
+    /// from which the server timestamp is derived, so the two always
+    /// agree on ordering.
+    async fn stamp(&self, sender: Uuid, draft: Draft) -> StoredMessage {
+        StoredMessage {
+            message_id: self.id_gen.id().await,
+            sender_id: sender,
+            client_msg_id: Some(draft.client_msg_id),
+            dest: draft.dest,
+            contents: draft.contents,
+            client_sent_at: draft.client_sent_at,
+            edited_id: None,
+            deleted_id: None,
+            reply_to: draft.placement.reply_to,
+            thread_root: draft.placement.thread_root,
+            mentions: draft.mentions,
+            mentions_group: draft.mentions_group,
+        }
+    }
+    ///Stores and delivers a message sent by `sender`, returning the
+    /// `SendAck` for the sending connection
+    async fn accept(
+        &mut self,
+        sender: Uuid,
+        mut draft: Draft,
+    ) -> Result<CrabbyWsFromServer, ErrorCode> {
+        let client_msg_id = draft.client_msg_id.clone();
+        if client_msg_id.is_empty()
+            || client_msg_id.len() > MAX_CLIENT_MSG_ID_LEN
+        {
+            return Err(ErrorCode::InvalidClientMsgId);
+        }
+        //Recipients are resolved before anything is stored, a message
+        // nobody is allowed to receive is never kept
+        let users = self.recipients(sender, &draft.dest).await?;
+        draft.placement =
+            self.place(sender, &draft.dest, draft.placement).await?;
+        (draft.mentions, draft.mentions_group) =
+            self.mentions(sender, &draft, &users).await;
+        let dest = draft.dest.clone();
+        let thread_root = draft.placement.thread_root;
+        let message = self.stamp(sender, draft).await;
+        //A message is only delivered once it has been stored, so
+        // history never misses anything a client has seen
+        match self.store.save(&message).await {
+            Ok(SaveOutcome::Stored) => {
+                //The conversations of the sender, and of the other side of
+                // a direct one, may have just grown
+                self.audiences.invalidate(sender);
+                if let Destination::Individual { id } = dest {
+                    self.audiences.invalidate(id);
+                }
+                let ack = send_ack(client_msg_id, message.message_id);
+                let (message_id, mentions, group_wide) = (
+                    message.message_id,
+                    message.mentions.clone(),
+                    message.mentions_group,
+                );
+                self.deliver(users.clone(), message.into()).await;
+                if let Some(root) = thread_root {
+                    self.thread_replied(root, dest.clone(), sender, &users)
+                        .await;
+                }
+                self.mentioned(
+                    message_id, dest, sender, &mentions, group_wide, &users,
+                )
+                .await;
+                Ok(ack)
+            }
+            //A retransmit is acked with the id of the original and not
+            // delivered again
+            Ok(SaveOutcome::Duplicate { message_id }) => {
+                Ok(send_ack(client_msg_id, message_id))
+            }
+            Err(err) => {
+                error!("could not store message {}: {err}", message.message_id);
+                Err(ErrorCode::Unavailable)
+            }
+        }
     }
-    async fn make_outbound_message(
+    ///Works out who a message mentions, by `@username` in its contents or
+    /// picked by the client. Only users in the conversation other than
+    /// the sender are kept, `@group` in a group comes back as the flag.
+    /// A message is never held back for its mentions, if usernames cannot
+    /// be looked up they go unnoticed.
+    async fn mentions(
         &self,
-        message: CrabbyWsFromClient,
-    ) -> CrabbyWsFromServer {
-        let id = self.id_gen.id().await;
+        sender: Uuid,
+        draft: &Draft,
+        users: &HashSet<Uuid>,
+    ) -> (Vec<Uuid>, bool) {
+        let written = mentions::parse(&draft.contents);
+        let mut mentioned: Vec<Uuid> =
+            draft.mentions.iter().copied().take(MAX_MENTIONS).collect();
+        if !written.usernames.is_empty() {
+            match self.users.by_username(&written.usernames).await {
+                Ok(found) => mentioned.extend(found),
+                Err(err) => warn!("could not look up mentioned users: {err}"),
+            }
+        }
+        let mut kept = Vec::new();
+        for user in mentioned {
+            if user != sender && users.contains(&user) && !kept.contains(&user)
+            {
+                kept.push(user);
+            }
+        }
+        let group_wide =
+            written.group && matches!(draft.dest, Destination::Group { .. });
+        (kept, group_wide)
+    }
+    ///Sends `Mentioned` to everyone a message mentions, on top of the
+    /// message itself, so clients can alert them in conversations they
+    /// otherwise keep quiet
+    async fn mentioned(
+        &mut self,
+        message_id: u64,
+        dest: Destination,
+        sender: Uuid,
+        mentions: &[Uuid],
+        group_wide: bool,
+        users: &HashSet<Uuid>,
+    ) {
+        let (by_name, by_group): (HashSet<Uuid>, HashSet<Uuid>) = users
+            .iter()
+            .copied()
+            .filter(|user| *user != sender)
+            .filter(|user| group_wide || mentions.contains(user))
+            .partition(|user| mentions.contains(user));
+        for (notified, group_wide) in [(by_name, false), (by_group, true)] {
+            if notified.is_empty() {
+                continue;
+            }
+            let mentioned = CrabbyWsFromServer::Mentioned {
+                message_id,
+                dest: dest.clone(),
+                user_id: sender,
+                group_wide,
+            };
+            self.deliver(notified, mentioned).await;
+        }
+    }
+    ///Checks where in the conversation a message goes. Whatever it
+    /// answers has to be part of the same conversation, and of the named
+    /// thread if there is one. Answering a message outside of any thread
+    /// starts a thread under it.
+    async fn place(
+        &self,
+        sender: Uuid,
+        dest: &Destination,
+        asked: Placement,
+    ) -> Result<Placement, ErrorCode> {
+        let mut placed = asked;
+        if let Some(root) = asked.thread_root {
+            let root = self.message_in(sender, dest, root).await?;
+            if root.thread_root.is_some() {
+                return Err(ErrorCode::InvalidThread);
+            }
+        }
+        if let Some(reply_to) = asked.reply_to {
+            let answered = self.message_in(sender, dest, reply_to).await?;
+            let thread = answered.thread_root.unwrap_or(answered.message_id);
+            if asked.thread_root.is_some_and(|root| root != thread) {
+                return Err(ErrorCode::InvalidThread);
+            }
+            placed.thread_root = Some(thread);
+        }
+        Ok(placed)
+    }
+    ///Looks up a message of the conversation `sender` has with `dest`
+    async fn message_in(
+        &self,
+        sender: Uuid,
+        dest: &Destination,
+        message_id: u64,
+    ) -> Result<StoredMessage, ErrorCode> {
+        let message = match self.store.find(message_id).await {
+            Ok(Some(message)) => message,
+            Ok(None) => return Err(ErrorCode::InvalidThread),
+            Err(err) => {
+                warn!("could not load message {message_id}: {err}");
+                return Err(ErrorCode::Unavailable);
+            }
+        };
+        let same_conversation = match (dest, &message.dest) {
+            (Destination::Group { id }, Destination::Group { id: to }) => {
+                id == to
+            }
+            (
+                Destination::Individual { id },
+                Destination::Individual { id: to },
+            ) => {
+                (message.sender_id == sender && to == id)
+                    || (message.sender_id == *id && *to == sender)
+            }
+            _ => false,
+        };
+        if !same_conversation {
+            return Err(ErrorCode::InvalidThread);
+        }
+        Ok(message)
+    }
+    ///Tells everyone taking part in a thread, other than whoever just
+    /// replied, how the thread stands now. Only those still in the
+    /// conversation hear about it.
+    async fn thread_replied(
+        &mut self,
+        root: u64,
+        dest: Destination,
+        sender: Uuid,
+        users: &HashSet<Uuid>,
+    ) {
+        let thread = match self.store.threads(&[root]).await {
+            Ok(mut threads) => threads.remove(&root),
+            Err(err) => {
+                warn!("could not load thread {root}: {err}");
+                None
+            }
+        };
+        let Some(thread) = thread else {
+            return;
+        };
+        let participants = match self.store.thread_participants(root).await {
+            Ok(participants) => participants,
+            Err(err) => {
+                warn!("could not load participants of thread {root}: {err}");
+                return;
+            }
+        };
+        let notified = participants
+            .into_iter()
+            .filter(|user| *user != sender && users.contains(user))
+            .collect();
+        let updated = CrabbyWsFromServer::ThreadUpdated {
+            dest,
+            thread_root: root,
+            thread,
+        };
+        self.deliver(notified, updated).await;
+    }
+    ///Works out which users a message addressed to `dest` has to reach.
+    /// Direct messages go to the addressee and are echoed back to the
+    /// sender so their other sessions stay in sync, group messages go
+    /// to every member of the group and can only be sent by a member.
+    async fn recipients(
+        &self,
+        sender: Uuid,
+        dest: &Destination,
+    ) -> Result<HashSet<Uuid>, ErrorCode> {
+        match dest {
+            Destination::Individual { id } => Ok(HashSet::from([*id, sender])),
+            Destination::Group { id } => {
+                let members = self.groups.members(id).await.map_err(|err| {
+                    warn!("could not resolve members of group {id}: {err}");
+                    ErrorCode::Unavailable
+                })?;
+                if !members.contains(&sender) {
+                    return Err(ErrorCode::NotAMember);
+                }
+                Ok(members.into_iter().collect())
+            }
+        }
+    }
+    async fn deliver(
+        &mut self,
+        users: HashSet<Uuid>,
+        message: CrabbyWsFromServer,
+    ) {
+        //Chat messages wait in the store for users without a live session
+        // here or on a node that was heard from lately. A user connecting
+        // elsewhere meanwhile may get one twice, a user on a node that just
+        // went down may get one from the queue they already saw.
+        if let CrabbyWsFromServer::ChatMessage { message_id, .. } = &message {
+            let now = Instant::now();
+            let max_quiet = self.heartbeat.expire_after();
+            let offline: Vec<_> = users
+                .iter()
+                .copied()
+                .filter(|user| {
+                    !self.sessions.is_online(user)
+                        && !self
+                            .presence
+                            .is_held_elsewhere(*user, now, max_quiet)
+                })
+                .collect();
+            if !offline.is_empty()
+                && let Err(err) = self
+                    .store
+                    .enqueue(&offline, *message_id, self.offline)
+                    .await
+            {
+                warn!("could not queue message {message_id}: {err}");
+            }
+        }
+        //Other nodes deliver to the sessions they hold, this one skips the
+        // deliveries it published itself. Nothing is published for users
+        // only this node holds, a session parked elsewhere catches up on
+        // chat messages from the offline queue.
+        let remote: Vec<_> = users
+            .iter()
+            .copied()
+            .filter(|user| self.presence.is_remote(*user))
+            .collect();
+        for user in users {
+            self.deliver_local(user, message.clone());
+        }
+        if !remote.is_empty() {
+            self.cluster.publish(remote, message);
+        }
+    }
+    ///Hands a message to the sessions of a user held by this node. Pushing
+    /// never waits, a slow client only holds up itself.
+    fn deliver_local(&mut self, user_id: Uuid, message: CrabbyWsFromServer) {
+        if !is_ephemeral(&message) {
+            for parked in self
+                .parked
+                .values_mut()
+                .filter(|parked| parked.user_id == user_id)
+            {
+                parked.buffer.push(message.clone());
+            }
+        }
+        for queue in self.sessions.sessions(&user_id) {
+            queue.push(message.clone());
+        }
+    }
+    fn expire_parked(&mut self) {
+        self.parked
+            .retain(|_, parked| parked.parked_at.elapsed() < RESUME_WINDOW);
+    }
+    ///Starts following the user's deliveries from other nodes, unless
+    /// this node already does
+    async fn follow(&mut self, user_id: Uuid, engine: WeakActorRef<Self>) {
+        if self.subscriptions.contains_key(&user_id) {
+            return;
+        }
+        let mut deliveries = match self.cluster.subscribe(user_id).await {
+            Ok(deliveries) => deliveries,
+            Err(err) => {
+                warn!("could not subscribe to deliveries for {user_id}: {err}");
+                return;
+            }
+        };
+        let task = tokio::spawn(async move {
+            while let Some(delivery) = deliveries.next().await {
+                let delivery = match delivery {
+                    Ok(delivery) => delivery,
+                    Err(err) => {
+                        warn!("undecodable delivery for {user_id}: {err}");
+                        continue;
+                    }
+                };
+                let Some(engine) = engine.upgrade() else {
+                    break;
+                };
+                let _ = engine.tell(RemoteDelivery { user_id, delivery }).await;
+            }
+        });
+        self.subscriptions.insert(user_id, task.abort_handle());
+    }
+    ///Drops the subscriptions of users without a live or parked session
+    fn unfollow_idle(&mut self) {
+        let Self {
+            subscriptions,
+            sessions,
+            parked,
+            watchers,
+            ..
+        } = self;
+        subscriptions.retain(|user_id, task| {
+            let wanted = sessions.is_online(user_id)
+                || parked.values().any(|parked| parked.user_id == *user_id);
+            if !wanted {
+                task.abort();
+            }
+            wanted
+        });
+        watchers.retain(|_, watching| {
+            watching.retain(|user_id| sessions.is_online(user_id));
+            !watching.is_empty()
+        });
+    }
+    ///Follows the presence news of every node, and asks the nodes that
+    /// were already running to announce the users they hold
+    async fn follow_presence(&self, engine: WeakActorRef<Self>) {
+        let mut events = match self.cluster.presence().await {
+            Ok(events) => events,
+            Err(err) => {
+                warn!("could not subscribe to presence: {err}");
+                return;
+            }
+        };
+        tokio::spawn(async move {
+            while let Some(event) = events.next().await {
+                let event = match event {
+                    Ok(event) => event,
+                    Err(err) => {
+                        warn!("undecodable presence event: {err}");
+                        continue;
+                    }
+                };
+                let Some(engine) = engine.upgrade() else {
+                    break;
+                };
+                let _ = engine.tell(RemotePresence { event }).await;
+            }
+        });
+        if let Err(err) = self.cluster.request_presence().await {
+            warn!("could not request presence from other nodes: {err}");
+        }
+    }
+    ///Tells the other nodes how this node sees the user
+    async fn announce(&self, user_id: Uuid) {
+        let (status, custom_text) = self.presence.chosen(user_id);
+        let connected = self.sessions.is_online(&user_id);
+        if let Err(err) = self
+            .cluster
+            .announce_presence(user_id, connected, status, custom_text)
+            .await
+        {
+            warn!("could not announce presence of {user_id}: {err}");
+        }
+    }
+    ///Users who see the presence of `user_id`: everyone they share a
+    /// direct conversation or a group with, and everyone who asked
+    async fn presence_audience(&mut self, user_id: Uuid) -> HashSet<Uuid> {
+        let mut audience =
+            self.watchers.get(&user_id).cloned().unwrap_or_default();
+        audience.extend(self.shared_audience(user_id).await);
+        audience.remove(&user_id);
+        audience
+    }
+    ///Everyone the user shares a direct conversation or a group with,
+    /// from the cache while the membership of its groups has not moved
+    async fn shared_audience(&mut self, user_id: Uuid) -> HashSet<Uuid> {
+        let now = Instant::now();
+        if let Some(cached) = self.audiences.get(user_id, now) {
+            let mut current = true;
+            for (group, ver) in &cached.groups {
+                match self.groups.version(group).await {
+                    Ok(current) if current == *ver => {}
+                    _ => {
+                        current = false;
+                        break;
+                    }
+                }
+            }
+            if current {
+                return cached.users.clone();
+            }
+        }
+        let mut audience = Audience::default();
+        //Only a complete answer is cached
+        let mut complete = true;
+        match self.store.conversations(user_id).await {
+            Ok(conversations) => {
+                for dest in conversations {
+                    match dest {
+                        Destination::Individual { id } => {
+                            audience.users.insert(id);
+                        }
+                        Destination::Group { id } => {
+                            match self.members_at(&id).await {
+                                Ok((ver, members)) => {
+                                    audience.users.extend(members);
+                                    audience.groups.push((id, ver));
+                                }
+                                Err(err) => {
+                                    complete = false;
+                                    warn!(
+                                        "could not resolve members of group \
+                                         {id}: {err}"
+                                    )
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+            Err(err) => {
+                complete = false;
+                warn!("could not load conversations of {user_id}: {err}")
+            }
+        }
+        let users = audience.users.clone();
+        if complete {
+            self.audiences.insert(user_id, audience, now);
+        }
+        users
+    }
+    ///Members of the group and the membership version they are current
+    /// as of. The version is read first, members that moved on in between
+    /// only make the next version check miss.
+    async fn members_at(
+        &self,
+        group_id: &Uuid,
+    ) -> eyre::Result<(Option<u64>, Vec<Uuid>)> {
+        let ver = self.groups.version(group_id).await?;
+        Ok((ver, self.groups.members(group_id).await?))
+    }
+    ///Sends a presence change to the interested users connected here.
+    /// Presence is not worth replaying, parked sessions are skipped.
+    async fn presence_changed(&mut self, presence: UserPresence) {
+        let audience = self.presence_audience(presence.user_id).await;
+        let message = CrabbyWsFromServer::from(presence);
+        for user in audience {
+            for queue in self.sessions.sessions(&user) {
+                queue.push(message.clone());
+            }
+        }
+    }
+    ///The user's last live session on this node went away
+    async fn left(&mut self, user_id: Uuid) {
+        if self.sessions.is_online(&user_id) {
+            return;
+        }
+        if let Some(presence) = self.presence.disconnected(user_id) {
+            self.presence_changed(presence).await;
+        }
+        self.announce(user_id).await;
+    }
+    ///Tells everyone else in the conversation that the user started or
+    /// stopped typing. Typing somewhere the user may not send to goes
+    /// nowhere.
+    async fn typing_changed(
+        &mut self,
+        user_id: Uuid,
+        dest: Destination,
+        state: TypingState,
+    ) {
+        let Ok(mut users) = self.recipients(user_id, &dest).await else {
+            return;
+        };
+        users.remove(&user_id);
+        let message = CrabbyWsFromServer::UserTyping {
+            user_id,
+            dest,
+            state,
+        };
+        self.deliver(users, message).await;
+    }
+    ///Moves the reader's cursor and tells everyone in the conversation
+    /// about it, the reader's other sessions included
+    async fn mark_read(
+        &mut self,
+        reader: Uuid,
+        dest: Destination,
+        up_to: u64,
+    ) -> Result<(), ErrorCode> {
+        //Same rules as sending, nobody marks a group they are not in
+        let users = self.recipients(reader, &dest).await?;
+        match self.store.mark_read(reader, &dest, up_to).await {
+            Ok(true) => {}
+            Ok(false) => return Ok(()),
+            Err(err) => {
+                warn!("could not store read cursor of {reader}: {err}");
+                return Err(ErrorCode::Unavailable);
+            }
+        }
+        let receipt = CrabbyWsFromServer::ReadReceipt {
+            user_id: reader,
+            dest,
+            up_to_message_id: up_to,
+        };
+        self.deliver(users, receipt).await;
+        Ok(())
+    }
+    ///Looks up a message that was not deleted, along with everyone in
+    /// its conversation. `user_id` has to be one of them.
+    async fn conversation_of(
+        &self,
+        user_id: Uuid,
+        message_id: u64,
+    ) -> Result<(StoredMessage, HashSet<Uuid>), ErrorCode> {
+        let message = match self.store.find(message_id).await {
+            Ok(Some(message)) => message,
+            Ok(None) => return Err(ErrorCode::NotFound),
+            Err(err) => {
+                warn!("could not load message {message_id}: {err}");
+                return Err(ErrorCode::Unavailable);
+            }
+        };
+        if message.deleted_id.is_some() {
+            return Err(ErrorCode::Deleted);
+        }
+        let users = match message.dest {
+            Destination::Individual { id } => {
+                HashSet::from([message.sender_id, id])
+            }
+            //Whoever left a group no longer has a say in it
+            Destination::Group { .. } => self
+                .recipients(user_id, &message.dest)
+                .await
+                .map_err(|code| match code {
+                    ErrorCode::NotAMember => ErrorCode::Forbidden,
+                    code => code,
+                })?,
+        };
+        if !users.contains(&user_id) {
+            return Err(ErrorCode::Forbidden);
+        }
+        Ok((message, users))
+    }
+    ///Looks up a message `user_id` wants to edit or delete and checks
+    /// they may. Senders can change their own messages, admins of a group
+    /// the messages of everyone in it.
+    async fn changeable(
+        &self,
+        user_id: Uuid,
+        message_id: u64,
+    ) -> Result<(StoredMessage, HashSet<Uuid>), ErrorCode> {
+        let (message, users) =
+            self.conversation_of(user_id, message_id).await?;
+        let allowed = match message.dest {
+            _ if message.sender_id == user_id => true,
+            Destination::Individual { .. } => false,
+            Destination::Group { id } => {
+                self.groups.is_admin(&id, &user_id).await.map_err(|err| {
+                    warn!("could not check role of {user_id} in {id}: {err}");
+                    ErrorCode::Unavailable
+                })?
+            }
+        };
+        if !allowed {
+            return Err(ErrorCode::Forbidden);
+        }
+        Ok((message, users))
+    }
+    ///Replaces the contents of a message and shows the new version to
+    /// everyone in the conversation, the editor included
+    async fn edit(
+        &mut self,
+        user_id: Uuid,
+        message_id: u64,
+        contents: String,
+    ) -> Result<(), ErrorCode> {
+        let (message, users) = self.changeable(user_id, message_id).await?;
+        let edited_id = self.id_gen.id().await;
+        let revisions =
+            match self.store.edit(message_id, &contents, edited_id).await {
+                Ok(Some(revisions)) => revisions,
+                //Deleted since it was looked up
+                Ok(None) => return Err(ErrorCode::Deleted),
+                Err(err) => {
+                    error!("could not store edit of {message_id}: {err}");
+                    return Err(ErrorCode::Unavailable);
+                }
+            };
+        let edited = CrabbyWsFromServer::MessageEdited {
+            message_id,
+            dest: message.dest,
+            user_id,
+            contents,
+            edited_at: timestamp_of(edited_id).to_string(),
+            revisions,
+        };
+        self.deliver(users, edited).await;
+        Ok(())
+    }
+    ///Leaves a tombstone in place of a message and tells everyone in the
+    /// conversation so they can drop it from what they keep
+    async fn delete(
+        &mut self,
+        user_id: Uuid,
+        message_id: u64,
+    ) -> Result<(), ErrorCode> {
+        let (message, users) = self.changeable(user_id, message_id).await?;
+        let deleted_id = self.id_gen.id().await;
+        match self.store.delete(message_id, deleted_id).await {
+            Ok(true) => {}
+            Ok(false) => return Err(ErrorCode::Deleted),
+            Err(err) => {
+                error!("could not store delete of {message_id}: {err}");
+                return Err(ErrorCode::Unavailable);
+            }
+        }
+        let deleted = CrabbyWsFromServer::MessageDeleted {
+            message_id,
+            dest: message.dest,
+            user_id,
+            deleted_at: timestamp_of(deleted_id).to_string(),
+        };
+        self.deliver(users, deleted).await;
+        Ok(())
+    }
+    ///Adds or takes back a reaction and sends the new count for the emoji
+    /// to everyone in the conversation
+    async fn react(
+        &mut self,
+        user_id: Uuid,
+        message_id: u64,
+        emoji: String,
+        added: bool,
+    ) -> Result<(), ErrorCode> {
+        if emoji.is_empty() || emoji.len() > MAX_EMOJI_LEN {
+            return Err(ErrorCode::InvalidEmoji);
+        }
+        let (message, users) =
+            self.conversation_of(user_id, message_id).await?;
+        let changed = if added {
+            self.store.react(message_id, user_id, &emoji).await
+        } else {
+            self.store.unreact(message_id, user_id, &emoji).await
+        };
+        let count = match changed {
+            Ok(Some(count)) => count,
+            //Reacting twice or taking back a reaction that is not there
+            // changes nothing
+            Ok(None) => return Ok(()),
+            Err(err) => {
+                error!("could not store reaction to {message_id}: {err}");
+                return Err(ErrorCode::Unavailable);
+            }
+        };
+        let changed = CrabbyWsFromServer::ReactionChanged {
+            message_id,
+            dest: message.dest,
+            user_id,
+            emoji,
+            added,
+            count,
+        };
+        self.deliver(users, changed).await;
+        Ok(())
+    }
+    ///Anyone can read their own direct messages, group history is only
+    /// available to members of the group
+    async fn can_read(&self, user: Uuid, dest: &Destination) -> bool {
+        match dest {
+            Destination::Individual { .. } => true,
+            Destination::Group { id } => self
+                .groups
+                .members(id)
+                .await
+                .map(|members| members.contains(&user))
+                .unwrap_or(false),
+        }
+    }
+    ///Replies in a thread are visible to those who can see the message
+    /// that started it
+    async fn can_read_thread(&self, viewer: Uuid, root: u64) -> bool {
+        match self.store.find(root).await {
+            Ok(Some(root)) => match root.dest {
+                Destination::Individual { id } => {
+                    viewer == id || viewer == root.sender_id
+                }
+                Destination::Group { .. } => {
+                    self.can_read(viewer, &root.dest).await
+                }
+            },
+            Ok(None) => false,
+            Err(err) => {
+                warn!("could not load thread {root}: {err}");
+                false
+            }
+        }
+    }
+    async fn history(
+        &self,
+        viewer: Uuid,
+        dest: Destination,
+        before: Option<u64>,
+        limit: u32,
+    ) -> Result<CrabbyWsFromServer, ErrorCode> {
+        let limit = limit.clamp(1, MAX_HISTORY_PAGE);
+        let mut messages = Vec::new();
+        if self.can_read(viewer, &dest).await {
+            //One extra message tells us whether there is an older page
+            messages = self
+                .store
+                .history(viewer, &dest, before.unwrap_or(u64::MAX), limit + 1)
+                .await
+                .map_err(|err| {
+                    warn!("could not load history for {viewer}: {err}");
+                    ErrorCode::Unavailable
+                })?;
+        }
+        let (messages, next_before) = self.page(viewer, messages, limit).await;
+        Ok(CrabbyWsFromServer::History {
+            dest,
+            messages,
+            next_before,
+        })
+    }
+    async fn thread(
+        &self,
+        viewer: Uuid,
+        thread_root: u64,
+        before: Option<u64>,
+        limit: u32,
+    ) -> Result<CrabbyWsFromServer, ErrorCode> {
+        let limit = limit.clamp(1, MAX_HISTORY_PAGE);
+        let mut messages = Vec::new();
+        if self.can_read_thread(viewer, thread_root).await {
+            messages = self
+                .store
+                .thread(thread_root, before.unwrap_or(u64::MAX), limit + 1)
+                .await
+                .map_err(|err| {
+                    warn!("could not load thread {thread_root}: {err}");
+                    ErrorCode::Unavailable
+                })?;
+        }
+        let (messages, next_before) = self.page(viewer, messages, limit).await;
+        Ok(CrabbyWsFromServer::Thread {
+            thread_root,
+            messages,
+            next_before,
+        })
+    }
+    ///Turns messages fetched newest first, one more than `limit`, into a
+    /// page for the client along with the `before` of the next one
+    async fn page(
+        &self,
+        viewer: Uuid,
+        mut messages: Vec<StoredMessage>,
+        limit: u32,
+    ) -> (Vec<HistoricalMessage>, Option<u64>) {
+        let next_before = if messages.len() > limit as usize {
+            messages.truncate(limit as usize);
+            messages.last().map(|m| m.message_id)
+        } else {
+            None
+        };
+        messages.reverse();
+        let ids: Vec<_> = messages.iter().map(|m| m.message_id).collect();
+        //A page without reactions or threads beats no page at all
+        let mut reactions = self
+            .store
+            .reactions(viewer, &ids)
+            .await
+            .unwrap_or_else(|err| {
+                warn!("could not load reactions for {viewer}: {err}");
+                HashMap::new()
+            });
+        let mut threads =
+            self.store.threads(&ids).await.unwrap_or_else(|err| {
+                warn!("could not load threads for {viewer}: {err}");
+                HashMap::new()
+            });
+        let messages = messages
+            .into_iter()
+            .map(|m| {
+                let reactions =
+                    reactions.remove(&m.message_id).unwrap_or_default();
+                let thread = threads.remove(&m.message_id);
+                m.into_historical(reactions, thread)
+            })
+            .collect();
+        (messages, next_before)
+    }
+}
+impl Message<ClientMessage> for EngineActor {
+    type Reply = ();
+
+    async fn handle(
+        &mut self,
+        msg: ClientMessage,
+        ctx: &mut kameo::prelude::Context<Self, Self::Reply>,
+    ) -> Self::Reply {
+        let ClientMessage {
+            user_id,
+            message,
+            reply_to,
+        } = msg;
+        //Errors point back at the message that caused them
+        let correlation = Some(message.correlation());
         match message {
             CrabbyWsFromClient::UserMessage {
-                user_id,
+                client_msg_id,
                 dest,
-                timestamp,
                 contents,
-            } => CrabbyWsFromServer::ChatMessage {
-                message_id: id,
-                user_id,
+                client_sent_at,
+                reply_to: answered,
+                thread_root,
+                mentions,
+            } => {
+                let draft = Draft {
+                    client_msg_id,
+                    dest,
+                    contents,
+                    client_sent_at,
+                    placement: Placement {
+                        reply_to: answered,
+                        thread_root,
+                    },
+                    mentions,
+                    mentions_group: false,
+                };
+                let reply =
+                    self.accept(user_id, draft).await.unwrap_or_else(|code| {
+                        CrabbyWsFromServer::error(
+                            code,
+                            "the message was not accepted",
+                            correlation,
+                        )
+                    });
+                reply_to.push(reply);
+            }
+            CrabbyWsFromClient::FetchHistory {
                 dest,
-                timestamp,
+                before,
+                limit,
+            } => {
+                let page = self
+                    .history(user_id, dest, before, limit)
+                    .await
+                    .unwrap_or_else(|code| {
+                        CrabbyWsFromServer::error(
+                            code,
+                            "no page of history could be loaded",
+                            correlation,
+                        )
+                    });
+                reply_to.push(page);
+            }
+            CrabbyWsFromClient::FetchThread {
+                thread_root,
+                before,
+                limit,
+            } => {
+                let page = self
+                    .thread(user_id, thread_root, before, limit)
+                    .await
+                    .unwrap_or_else(|code| {
+                        CrabbyWsFromServer::error(
+                            code,
+                            "no page of the thread could be loaded",
+                            correlation,
+                        )
+                    });
+                reply_to.push(page);
+            }
+            //Handshakes and acks are settled by the connection that
+            // received them
+            CrabbyWsFromClient::Hello { .. }
+            | CrabbyWsFromClient::Ack { .. } => (),
+            CrabbyWsFromClient::SetPresence {
+                status,
+                custom_text,
+            } => {
+                if let Some(presence) =
+                    self.presence.set(user_id, status, custom_text)
+                {
+                    self.presence_changed(presence).await;
+                }
+                self.announce(user_id).await;
+            }
+            CrabbyWsFromClient::QueryPresence { mut user_ids } => {
+                user_ids.truncate(MAX_PRESENCE_QUERY);
+                //Only users the asker would be told about anyway can be
+                // asked about, the rest are left out
+                let shared = self.shared_audience(user_id).await;
+                user_ids.retain(|id| shared.contains(id));
+                for id in &user_ids {
+                    self.watchers.entry(*id).or_default().insert(user_id);
+                }
+                let users = user_ids
+                    .into_iter()
+                    .map(|id| self.presence.get(id))
+                    .collect();
+                reply_to.push(CrabbyWsFromServer::Presence { users });
+            }
+            CrabbyWsFromClient::Typing { dest, state } => {
+                let now = Instant::now();
+                let forward = match state {
+                    TypingState::Started => {
+                        let new = !self.typing.is_showing(user_id, &dest);
+                        if new {
+                            expire_typing_after(
+                                ctx.actor_ref().downgrade(),
+                                user_id,
+                                dest.clone(),
+                                TYPING_TIMEOUT,
+                            );
+                        }
+                        self.typing.started(user_id, dest.clone(), now)
+                    }
+                    TypingState::Stopped => {
+                        self.typing.stopped(user_id, dest.clone())
+                    }
+                };
+                if forward {
+                    self.typing_changed(user_id, dest, state).await;
+                }
+            }
+            CrabbyWsFromClient::MarkRead {
+                dest,
+                up_to_message_id,
+            } => {
+                if let Err(code) =
+                    self.mark_read(user_id, dest, up_to_message_id).await
+                {
+                    reply_to.push(CrabbyWsFromServer::error(
+                        code,
+                        "the read cursor was not moved",
+                        correlation,
+                    ));
+                }
+            }
+            CrabbyWsFromClient::EditMessage {
+                message_id,
                 contents,
+            } => {
+                if let Err(code) =
+                    self.edit(user_id, message_id, contents).await
+                {
+                    reply_to.push(CrabbyWsFromServer::error(
+                        code,
+                        "the message was not edited",
+                        correlation,
+                    ));
+                }
+            }
+            CrabbyWsFromClient::DeleteMessage { message_id } => {
+                if let Err(code) = self.delete(user_id, message_id).await {
+                    reply_to.push(CrabbyWsFromServer::error(
+                        code,
+                        "the message was not deleted",
+                        correlation,
+                    ));
+                }
+            }
+            CrabbyWsFromClient::React { message_id, emoji } => {
+                if let Err(code) =
+                    self.react(user_id, message_id, emoji, true).await
+                {
+                    reply_to.push(CrabbyWsFromServer::error(
+                        code,
+                        "the reaction was not added",
+                        correlation,
+                    ));
+                }
+            }
+            CrabbyWsFromClient::Unreact { message_id, emoji } => {
+                if let Err(code) =
+                    self.react(user_id, message_id, emoji, false).await
+                {
+                    reply_to.push(CrabbyWsFromServer::error(
+                        code,
+                        "the reaction was not taken back",
+                        correlation,
+                    ));
+                }
+            }
+        }
+    }
+}
+impl Message<UserDisconnected> for EngineActor {
+    type Reply = ();
+
+    async fn handle(
+        &mut self,
+        msg: UserDisconnected,
+        _ctx: &mut kameo::prelude::Context<Self, Self::Reply>,
+    ) -> Self::Reply {
+        if self.sessions.remove(msg.user_id, msg.session_id) {
+            self.left(msg.user_id).await;
+        }
+        self.unfollow_idle();
+    }
+}
+impl Message<UserConnected> for EngineActor {
+    type Reply = ();
+
+    async fn handle(
+        &mut self,
+        msg: UserConnected,
+        ctx: &mut kameo::prelude::Context<Self, Self::Reply>,
+    ) -> Self::Reply {
+        let first = !self.sessions.is_online(&msg.user_id);
+        let queue = msg.queue.clone();
+        self.sessions.insert(
+            msg.user_id,
+            msg.session_id,
+            Session {
+                queue: msg.queue,
+                liveness: msg.liveness,
             },
+        );
+        //A resumed session is only let go of now that its connection is
+        // registered, what reached it after its buffer was handed out goes
+        // first
+        let parked = if msg.resumed {
+            self.parked
+                .remove(&msg.session_id)
+                .filter(|parked| parked.user_id == msg.user_id)
+        } else {
+            None
+        };
+        let mut replayed = HashSet::new();
+        if let Some(parked) = parked {
+            let handed_out = parked.handed_out.unwrap_or(u64::MAX);
+            for frame in parked.buffer.pending() {
+                if let CrabbyWsFromServer::ChatMessage { message_id, .. } =
+                    &frame.message
+                {
+                    replayed.insert(*message_id);
+                }
+                if frame.seq.is_some_and(|seq| seq >= handed_out) {
+                    queue.push(frame.message.clone());
+                }
+            }
+        }
+        //What waited for the user goes out ahead of anything live, less
+        // what a resumed session was replayed already
+        let queued =
+            match self.store.drain(msg.user_id, self.offline.max_age).await {
+                Ok(queued) => queued
+                    .into_iter()
+                    .filter(|message| !replayed.contains(&message.message_id))
+                    .collect(),
+                Err(err) => {
+                    warn!("could not drain queue of {}: {err}", msg.user_id);
+                    Vec::new()
+                }
+            };
+        let count = queued.len() as u64;
+        for message in queued {
+            queue.push(message.into());
+        }
+        queue.push(CrabbyWsFromServer::CatchUpComplete { queued: count });
+        //Users who never wrote or read anything get no counts
+        match self.store.unread(msg.user_id).await {
+            Ok(conversations) if !conversations.is_empty() => {
+                queue.push(CrabbyWsFromServer::UnreadCounts { conversations })
+            }
+            Ok(_) => {}
+            Err(err) => {
+                warn!(
+                    "could not count unread messages of {}: {err}",
+                    msg.user_id
+                )
+            }
+        }
+        self.follow(msg.user_id, ctx.actor_ref().downgrade()).await;
+        if first {
+            if let Some(presence) = self.presence.connected(msg.user_id) {
+                self.presence_changed(presence).await;
+            }
+            self.announce(msg.user_id).await;
         }
     }
 }
-impl Message<CrabbyWsFromClient> for EngineActor {
+impl Message<RemoteDelivery> for EngineActor {
     type Reply = ();
 
     async fn handle(
         &mut self,
-        msg: CrabbyWsFromClient,
+        msg: RemoteDelivery,
         _ctx: &mut kameo::prelude::Context<Self, Self::Reply>,
     ) -> Self::Reply {
-        // if let Some(outgoing) = self.map.get(&msg.to) {
-        //     let _ = outgoing.tell(msg).await;
-        // }
-        let recipients: Vec<_> = self.map.values().cloned().collect();
-        let msg_with_id = self.make_outbound_message(msg).await;
-        for recipient in recipients {
-            let _ = recipient.tell(msg_with_id.clone()).await;
+        if self.cluster.is_own(&msg.delivery) {
+            return;
         }
+        self.deliver_local(msg.user_id, msg.delivery.message);
     }
 }
-impl Message<UserDisconnected> for EngineActor {
+impl Message<RemotePresence> for EngineActor {
     type Reply = ();
 
     async fn handle(
         &mut self,
-        msg: UserDisconnected,
+        msg: RemotePresence,
         _ctx: &mut kameo::prelude::Context<Self, Self::Reply>,
     ) -> Self::Reply {
-        match msg {
-            UserDisconnected(id) => self.map.remove(&id),
-        };
+        if msg.event.origin() == self.cluster.node_id() {
+            return;
+        }
+        let unknown =
+            self.presence.heard_from(msg.event.origin(), Instant::now());
+        match msg.event {
+            PresenceEvent::Update {
+                origin,
+                user_id,
+                connected,
+                status,
+                custom_text,
+            } => {
+                if let Some(presence) = self.presence.apply(
+                    &origin,
+                    user_id,
+                    connected,
+                    status,
+                    custom_text,
+                ) {
+                    self.presence_changed(presence).await;
+                }
+            }
+            PresenceEvent::Sync { .. } => {
+                let users: Vec<_> = self.sessions.users().copied().collect();
+                for user_id in users {
+                    self.announce(user_id).await;
+                }
+            }
+            //A node that was given up on, or that holds no sessions and so
+            // never announced any, only shows itself through heartbeats.
+            // Every node announces again so what it holds is known.
+            PresenceEvent::Heartbeat { .. } if unknown => {
+                if let Err(err) = self.cluster.request_presence().await {
+                    warn!("could not request presence from other nodes: {err}");
+                }
+            }
+            PresenceEvent::Heartbeat { .. } => {}
+        }
     }
 }
-impl Message<UserConnected> for EngineActor {
+impl Message<PresenceTick> for EngineActor {
     type Reply = ();
 
     async fn handle(
         &mut self,
-        msg: UserConnected,
+        _msg: PresenceTick,
         _ctx: &mut kameo::prelude::Context<Self, Self::Reply>,
     ) -> Self::Reply {
-        self.map.insert(msg.0, msg.1);
+        if let Err(err) = self.cluster.heartbeat().await {
+            warn!("could not send presence heartbeat: {err}");
+        }
+        if let Some(stale_before) =
+            Instant::now().checked_sub(self.heartbeat.expire_after())
+        {
+            for presence in self.presence.expire(stale_before) {
+                self.presence_changed(presence).await;
+            }
+        }
+        self.presence.forget(Timestamp::now() - FORGET_AFTER);
+        self.audiences.expire(Instant::now());
     }
 }
+impl Message<TypingExpired> for EngineActor {
+    type Reply = ();
 
+    async fn handle(
+        &mut self,
+        msg: TypingExpired,
+        ctx: &mut kameo::prelude::Context<Self, Self::Reply>,
+    ) -> Self::Reply {
+        let TypingExpired { user_id, dest } = msg;
+        match self.typing.expire(user_id, dest.clone(), Instant::now()) {
+            Expiry::Expired => {
+                self.typing_changed(user_id, dest, TypingState::Stopped)
+                    .await;
+            }
+            Expiry::Remaining(after) => expire_typing_after(
+                ctx.actor_ref().downgrade(),
+                user_id,
+                dest,
+                after,
+            ),
+            Expiry::Gone => (),
+        }
+    }
+}
+impl Message<ConnectionDiagnostics> for EngineActor {
+    type Reply = Vec<ConnectionStats>;
+
+    async fn handle(
+        &mut self,
+        _msg: ConnectionDiagnostics,
+        _ctx: &mut kameo::prelude::Context<Self, Self::Reply>,
+    ) -> Self::Reply {
+        self.sessions.stats()
+    }
+}
+impl Message<ParkSession> for EngineActor {
+    type Reply = ();
+
+    async fn handle(
+        &mut self,
+        msg: ParkSession,
+        _ctx: &mut kameo::prelude::Context<Self, Self::Reply>,
+    ) -> Self::Reply {
+        let removed = self.sessions.remove(msg.user_id, msg.session_id);
+        if removed {
+            self.left(msg.user_id).await;
+        }
+        self.expire_parked();
+        self.parked.insert(
+            msg.session_id,
+            ParkedSession {
+                user_id: msg.user_id,
+                buffer: msg.buffer,
+                parked_at: Instant::now(),
+                handed_out: None,
+            },
+        );
+        self.unfollow_idle();
+    }
+}
+impl Message<ResumeSession> for EngineActor {
+    type Reply = Option<ReplayBuffer>;
+
+    async fn handle(
+        &mut self,
+        msg: ResumeSession,
+        _ctx: &mut kameo::prelude::Context<Self, Self::Reply>,
+    ) -> Self::Reply {
+        self.expire_parked();
+        //A session is resumed by one connection at most
+        let parked = self.parked.get_mut(&msg.session_id).filter(|parked| {
+            parked.user_id == msg.user_id && parked.handed_out.is_none()
+        })?;
+        //Frames the client never saw were dropped, replaying the rest would
+        // leave a gap it cannot see. A fresh session has it catch up.
+        if !parked.buffer.covers(msg.last_seq) {
+            info!(
+                "session {} of {} lost frames after {}, not resuming",
+                msg.session_id, msg.user_id, msg.last_seq
+            );
+            self.parked.remove(&msg.session_id);
+            return None;
+        }
+        //It stays parked until the new connection is attached, so nothing
+        // sent in between is lost
+        parked.handed_out = Some(parked.buffer.next_seq());
+        Some(parked.buffer.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crabby_specs::ws::{common::PresenceStatus, outgoing::UnreadCount};
+    use crabby_transport::memory::InMemoryTransport;
+    use kameo::actor::Spawn;
+    use tokio::sync::mpsc::{UnboundedReceiver, unbounded_channel};
+
+    use super::*;
+    use crate::{
+        cluster::{DeliveryBus, TransportBus},
+        config::{PresenceConfig, QueueConfig, SlowConsumerPolicy},
+        groups::InMemoryGroups,
+        id::{NoOpIdGeneratorImpl, SequentialIdGenerator},
+        liveness::Liveness,
+        queue::OutboundQueue,
+        store::InMemoryMessageRepo,
+        users::InMemoryUsers,
+    };
+
+    fn spawn_engine(groups: InMemoryGroups) -> ActorRef<EngineActor> {
+        spawn_engine_with(groups, InMemoryMessageRepo::default())
+    }
+
+    fn spawn_engine_with(
+        groups: InMemoryGroups,
+        store: impl MessageRepo,
+    ) -> ActorRef<EngineActor> {
+        spawn_node("node", groups, store, InMemoryTransport::default())
+    }
+
+    ///An engine that knows the usernames in `users`
+    fn spawn_engine_with_users(
+        groups: InMemoryGroups,
+        users: InMemoryUsers,
+    ) -> ActorRef<EngineActor> {
+        EngineActor::spawn(EngineActor::new(
+            SessionRegistry::default(),
+            IdGenerator::new(NoOpIdGeneratorImpl),
+            Arc::new(groups),
+            Arc::new(users),
+            MessageStore::new(InMemoryMessageRepo::default()),
+            Cluster::new(
+                "node",
+                TransportBus::new(InMemoryTransport::default()),
+            ),
+        ))
+    }
+
+    ///An engine that is one node of the cluster carried by `transport`
+    fn spawn_node(
+        node_id: &str,
+        groups: InMemoryGroups,
+        store: impl MessageRepo,
+        transport: InMemoryTransport,
+    ) -> ActorRef<EngineActor> {
+        EngineActor::spawn(EngineActor::new(
+            SessionRegistry::default(),
+            IdGenerator::new(NoOpIdGeneratorImpl),
+            Arc::new(groups),
+            Arc::new(InMemoryUsers::default()),
+            MessageStore::new(store),
+            Cluster::new(node_id, TransportBus::new(transport)),
+        ))
+    }
+
+    ///A node of the cluster carried by `transport` that sends presence
+    /// heartbeats every 20ms and gives up on nodes after two missed ones
+    fn spawn_watchful_node(
+        node_id: &str,
+        store: impl MessageRepo,
+        transport: InMemoryTransport,
+    ) -> ActorRef<EngineActor> {
+        EngineActor::spawn(
+            EngineActor::new(
+                SessionRegistry::default(),
+                IdGenerator::new(NoOpIdGeneratorImpl),
+                Arc::new(InMemoryGroups::default()),
+                Arc::new(InMemoryUsers::default()),
+                MessageStore::new(store),
+                Cluster::new(node_id, TransportBus::new(transport)),
+            )
+            .with_presence_heartbeat(PresenceConfig {
+                interval: Duration::from_millis(20),
+                max_missed: 2,
+            }),
+        )
+    }
+
+    /// Stands in for an `OutgoingMessageActor`, everything the engine
+    /// queues for the connection ends up in the channel
+    fn collector() -> (OutboundQueue, UnboundedReceiver<CrabbyWsFromServer>) {
+        let (tx, rx) = unbounded_channel();
+        let queue = OutboundQueue::new(QueueConfig::default());
+        let pump = queue.clone();
+        tokio::spawn(async move {
+            while let Some(msg) = pump.pop().await {
+                let _ = tx.send(msg);
+            }
+        });
+        (queue, rx)
+    }
+
+    async fn connect(
+        engine: &ActorRef<EngineActor>,
+        user: Uuid,
+    ) -> UnboundedReceiver<CrabbyWsFromServer> {
+        catch_up(engine, user).await.0
+    }
+
+    ///Connects `user` and returns what was queued for them ahead of
+    /// `CatchUpComplete`
+    async fn catch_up(
+        engine: &ActorRef<EngineActor>,
+        user: Uuid,
+    ) -> (
+        UnboundedReceiver<CrabbyWsFromServer>,
+        Vec<CrabbyWsFromServer>,
+    ) {
+        let (queue, mut rx) = collector();
+        engine
+            .ask(UserConnected {
+                user_id: user,
+                session_id: Uuid::now_v7(),
+                queue,
+                liveness: Liveness::new(),
+                resumed: false,
+            })
+            .await
+            .unwrap();
+        let mut queued = Vec::new();
+        loop {
+            match next(&mut rx).await {
+                Some(CrabbyWsFromServer::CatchUpComplete { .. }) => {
+                    return (rx, queued);
+                }
+                Some(message) => queued.push(message),
+                None => panic!("{user} never caught up"),
+            }
+        }
+    }
+
+    fn client_message(
+        user_id: Uuid,
+        message: CrabbyWsFromClient,
+    ) -> ClientMessage {
+        let (reply_to, _) = collector();
+        ClientMessage {
+            user_id,
+            message,
+            reply_to,
+        }
+    }
+
+    fn fetch_history(
+        dest: Destination,
+        before: Option<u64>,
+        limit: u32,
+    ) -> CrabbyWsFromClient {
+        CrabbyWsFromClient::FetchHistory {
+            dest,
+            before,
+            limit,
+        }
+    }
+
+    ///Sends `message` as `user_id` and returns the reply meant for the
+    /// sending connection
+    async fn reply_to(
+        engine: &ActorRef<EngineActor>,
+        user_id: Uuid,
+        message: CrabbyWsFromClient,
+    ) -> CrabbyWsFromServer {
+        let (reply_to, mut rx) = collector();
+        engine
+            .ask(ClientMessage {
+                user_id,
+                message,
+                reply_to,
+            })
+            .await
+            .unwrap();
+        next(&mut rx).await.expect("expected a reply")
+    }
+
+    async fn history_page(
+        engine: &ActorRef<EngineActor>,
+        user_id: Uuid,
+        message: CrabbyWsFromClient,
+    ) -> (Vec<u64>, Option<u64>) {
+        match reply_to(engine, user_id, message).await {
+            CrabbyWsFromServer::History {
+                messages,
+                next_before,
+                ..
+            } => (messages.iter().map(|m| m.message_id).collect(), next_before),
+            other => panic!("expected a history page, got {other:?}"),
+        }
+    }
+
+    ///A send with a fresh `client_msg_id`, so it is never taken for a
+    /// retransmit
+    fn user_message(dest: Destination) -> CrabbyWsFromClient {
+        CrabbyWsFromClient::UserMessage {
+            client_msg_id: Uuid::now_v7().to_string(),
+            dest,
+            contents: "hello".to_string(),
+            client_sent_at: None,
+            reply_to: None,
+            thread_root: None,
+            mentions: Vec::new(),
+        }
+    }
+
+    async fn received(rx: &mut UnboundedReceiver<CrabbyWsFromServer>) -> bool {
+        next(rx).await.is_some()
+    }
+
+    async fn next(
+        rx: &mut UnboundedReceiver<CrabbyWsFromServer>,
+    ) -> Option<CrabbyWsFromServer> {
+        tokio::time::timeout(Duration::from_millis(100), rx.recv())
+            .await
+            .ok()
+            .flatten()
+    }
+
+    #[tokio::test]
+    async fn direct_message_reaches_only_addressee_and_sender() {
+        let engine = spawn_engine(InMemoryGroups::default());
+        let (alice, bob, carol) =
+            (Uuid::from_u128(1), Uuid::from_u128(2), Uuid::from_u128(3));
+        let mut alice_rx = connect(&engine, alice).await;
+        let mut bob_rx = connect(&engine, bob).await;
+        let mut carol_rx = connect(&engine, carol).await;
+
+        engine
+            .ask(client_message(
+                alice,
+                user_message(Destination::Individual { id: bob }),
+            ))
+            .await
+            .unwrap();
+
+        assert!(received(&mut bob_rx).await);
+        assert!(received(&mut alice_rx).await);
+        assert!(!received(&mut carol_rx).await);
+    }
+
+    #[tokio::test]
+    async fn group_message_reaches_only_members() {
+        let (alice, bob, carol) =
+            (Uuid::from_u128(1), Uuid::from_u128(2), Uuid::from_u128(3));
+        let group = Uuid::from_u128(100);
+        let mut groups = HashMap::new();
+        groups.insert(group, vec![alice, bob]);
+        let engine = spawn_engine(InMemoryGroups::new(groups));
+        let mut alice_rx = connect(&engine, alice).await;
+        let mut bob_rx = connect(&engine, bob).await;
+        let mut carol_rx = connect(&engine, carol).await;
+
+        engine
+            .ask(client_message(
+                alice,
+                user_message(Destination::Group { id: group }),
+            ))
+            .await
+            .unwrap();
+
+        assert!(received(&mut alice_rx).await);
+        assert!(received(&mut bob_rx).await);
+        assert!(!received(&mut carol_rx).await);
+    }
+
+    #[tokio::test]
+    async fn non_member_cannot_send_to_group() {
+        let (alice, bob, carol) =
+            (Uuid::from_u128(1), Uuid::from_u128(2), Uuid::from_u128(3));
+        let group = Uuid::from_u128(100);
+        let mut groups = HashMap::new();
+        groups.insert(group, vec![alice, bob]);
+        let engine = spawn_engine(InMemoryGroups::new(groups));
+        let mut alice_rx = connect(&engine, alice).await;
+
+        let reply = reply_to(
+            &engine,
+            carol,
+            user_message(Destination::Group { id: group }),
+        )
+        .await;
+
+        assert!(matches!(
+            reply,
+            CrabbyWsFromServer::Error {
+                code: ErrorCode::NotAMember,
+                ..
+            }
+        ));
+        assert!(!received(&mut alice_rx).await);
+    }
+
+    #[tokio::test]
+    async fn direct_message_to_offline_user_is_not_broadcast() {
+        let engine = spawn_engine(InMemoryGroups::default());
+        let (alice, carol) = (Uuid::from_u128(1), Uuid::from_u128(3));
+        let mut carol_rx = connect(&engine, carol).await;
+
+        engine
+            .ask(client_message(
+                alice,
+                user_message(Destination::Individual {
+                    id: Uuid::from_u128(2),
+                }),
+            ))
+            .await
+            .unwrap();
+
+        assert!(!received(&mut carol_rx).await);
+    }
+
+    #[tokio::test]
+    async fn sender_and_timestamp_are_stamped_by_server() {
+        let engine = spawn_engine(InMemoryGroups::default());
+        let (alice, bob) = (Uuid::from_u128(1), Uuid::from_u128(2));
+        let mut bob_rx = connect(&engine, bob).await;
+
+        engine
+            .ask(client_message(
+                alice,
+                CrabbyWsFromClient::UserMessage {
+                    client_msg_id: "c1".to_string(),
+                    dest: Destination::Individual { id: bob },
+                    contents: "hello".to_string(),
+                    client_sent_at: Some("1999-12-31T23:59:59Z".to_string()),
+                    reply_to: None,
+                    thread_root: None,
+                    mentions: Vec::new(),
+                },
+            ))
+            .await
+            .unwrap();
+
+        match next(&mut bob_rx).await {
+            Some(CrabbyWsFromServer::ChatMessage {
+                message_id,
+                user_id,
+                timestamp,
+                client_sent_at,
+                ..
+            }) => {
+                assert_eq!(user_id, alice);
+                assert_eq!(timestamp, timestamp_of(message_id).to_string());
+                assert_eq!(
+                    client_sent_at.as_deref(),
+                    Some("1999-12-31T23:59:59Z")
+                );
+            }
+            other => panic!("expected a chat message, got {other:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn sent_messages_show_up_in_history() {
+        let engine = spawn_engine(InMemoryGroups::default());
+        let (alice, bob) = (Uuid::from_u128(1), Uuid::from_u128(2));
+
+        engine
+            .ask(client_message(
+                alice,
+                user_message(Destination::Individual { id: bob }),
+            ))
+            .await
+            .unwrap();
+
+        let (ids, next_before) = history_page(
+            &engine,
+            bob,
+            fetch_history(Destination::Individual { id: alice }, None, 10),
+        )
+        .await;
+        assert_eq!(ids, vec![1]);
+        assert_eq!(next_before, None);
+    }
+
+    #[tokio::test]
+    async fn history_is_paged_oldest_first() {
+        let (alice, group) = (Uuid::from_u128(1), Uuid::from_u128(100));
+        let store = InMemoryMessageRepo::default();
+        for message_id in 1..=3 {
+            store
+                .save(&StoredMessage {
+                    message_id,
+                    sender_id: alice,
+                    client_msg_id: None,
+                    dest: Destination::Group { id: group },
+                    contents: String::new(),
+                    client_sent_at: None,
+                    edited_id: None,
+                    deleted_id: None,
+                    reply_to: None,
+                    thread_root: None,
+                    mentions: Vec::new(),
+                    mentions_group: false,
+                })
+                .await
+                .unwrap();
+        }
+        let mut groups = HashMap::new();
+        groups.insert(group, vec![alice]);
+        let engine = spawn_engine_with(InMemoryGroups::new(groups), store);
+        let dest = Destination::Group { id: group };
+
+        let (ids, next_before) =
+            history_page(&engine, alice, fetch_history(dest.clone(), None, 2))
+                .await;
+        assert_eq!(ids, vec![2, 3]);
+        assert_eq!(next_before, Some(2));
+
+        let (ids, next_before) =
+            history_page(&engine, alice, fetch_history(dest, next_before, 2))
+                .await;
+        assert_eq!(ids, vec![1]);
+        assert_eq!(next_before, None);
+    }
+
+    #[tokio::test]
+    async fn group_history_is_hidden_from_non_members() {
+        let (alice, carol, group) =
+            (Uuid::from_u128(1), Uuid::from_u128(3), Uuid::from_u128(100));
+        let mut groups = HashMap::new();
+        groups.insert(group, vec![alice]);
+        let engine = spawn_engine(InMemoryGroups::new(groups));
+        let dest = Destination::Group { id: group };
+
+        engine
+            .ask(client_message(alice, user_message(dest.clone())))
+            .await
+            .unwrap();
+
+        let (ids, _) =
+            history_page(&engine, carol, fetch_history(dest, None, 10)).await;
+        assert!(ids.is_empty());
+    }
+
+    #[tokio::test]
+    async fn parked_session_buffers_until_resumed() {
+        let engine = spawn_engine(InMemoryGroups::default());
+        let (alice, bob) = (Uuid::from_u128(1), Uuid::from_u128(2));
+        let session_id = Uuid::from_u128(50);
+        engine
+            .ask(ParkSession {
+                user_id: bob,
+                session_id,
+                buffer: ReplayBuffer::default(),
+            })
+            .await
+            .unwrap();
+
+        engine
+            .ask(client_message(
+                alice,
+                user_message(Destination::Individual { id: bob }),
+            ))
+            .await
+            .unwrap();
+
+        //Only the owner of the session may resume it
+        let stolen = engine
+            .ask(ResumeSession {
+                user_id: alice,
+                session_id,
+                last_seq: 0,
+            })
+            .await
+            .unwrap();
+        assert!(stolen.is_none());
+
+        let buffer = engine
+            .ask(ResumeSession {
+                user_id: bob,
+                session_id,
+                last_seq: 0,
+            })
+            .await
+            .unwrap()
+            .expect("session should still be parked");
+        assert_eq!(buffer.len(), 1);
+
+        let again = engine
+            .ask(ResumeSession {
+                user_id: bob,
+                session_id,
+                last_seq: 0,
+            })
+            .await
+            .unwrap();
+        assert!(again.is_none());
+    }
+
+    #[tokio::test]
+    async fn messages_sent_while_resuming_are_not_lost() {
+        //The two messages have to be told apart by id
+        let engine = EngineActor::spawn(EngineActor::new(
+            SessionRegistry::default(),
+            IdGenerator::new(SequentialIdGenerator::default()),
+            Arc::new(InMemoryGroups::default()),
+            Arc::new(InMemoryUsers::default()),
+            MessageStore::new(InMemoryMessageRepo::default()),
+            Cluster::new(
+                "node",
+                TransportBus::new(InMemoryTransport::default()),
+            ),
+        ));
+        let (alice, bob) = (Uuid::from_u128(1), Uuid::from_u128(2));
+        let session_id = Uuid::from_u128(50);
+        engine
+            .ask(ParkSession {
+                user_id: bob,
+                session_id,
+                buffer: ReplayBuffer::default(),
+            })
+            .await
+            .unwrap();
+        let to_bob = || {
+            client_message(
+                alice,
+                user_message(Destination::Individual { id: bob }),
+            )
+        };
+        engine.ask(to_bob()).await.unwrap();
+        let buffer = engine
+            .ask(ResumeSession {
+                user_id: bob,
+                session_id,
+                last_seq: 0,
+            })
+            .await
+            .unwrap()
+            .expect("session should still be parked");
+        assert_eq!(buffer.len(), 1);
+
+        //Sent after the buffer was handed out, before the connection is
+        // attached
+        engine.ask(to_bob()).await.unwrap();
+        let (queue, mut rx) = collector();
+        engine
+            .ask(UserConnected {
+                user_id: bob,
+                session_id,
+                queue,
+                liveness: Liveness::new(),
+                resumed: true,
+            })
+            .await
+            .unwrap();
+
+        //Only the message that was missed, the replayed one is not
+        // drained from the offline queue a second time
+        assert!(matches!(
+            next(&mut rx).await,
+            Some(CrabbyWsFromServer::ChatMessage { message_id: 2, .. })
+        ));
+        assert!(matches!(
+            next(&mut rx).await,
+            Some(CrabbyWsFromServer::CatchUpComplete { queued: 0 })
+        ));
+    }
+
+    #[tokio::test]
+    async fn session_that_lost_frames_is_not_resumed() {
+        let engine = spawn_engine(InMemoryGroups::default());
+        let (alice, bob) = (Uuid::from_u128(1), Uuid::from_u128(2));
+        let session_id = Uuid::from_u128(50);
+        engine
+            .ask(ParkSession {
+                user_id: bob,
+                session_id,
+                buffer: ReplayBuffer::new(1),
+            })
+            .await
+            .unwrap();
+        for _ in 0..2 {
+            engine
+                .ask(client_message(
+                    alice,
+                    user_message(Destination::Individual { id: bob }),
+                ))
+                .await
+                .unwrap();
+        }
+
+        //Frame 1 was dropped before the client saw it
+        let resumed = engine
+            .ask(ResumeSession {
+                user_id: bob,
+                session_id,
+                last_seq: 0,
+            })
+            .await
+            .unwrap();
+        assert!(resumed.is_none());
+        //Nor is it kept around for another try
+        let again = engine
+            .ask(ResumeSession {
+                user_id: bob,
+                session_id,
+                last_seq: 1,
+            })
+            .await
+            .unwrap();
+        assert!(again.is_none());
+    }
+
+    #[tokio::test]
+    async fn stale_disconnect_keeps_newer_session() {
+        let engine = spawn_engine(InMemoryGroups::default());
+        let (alice, bob) = (Uuid::from_u128(1), Uuid::from_u128(2));
+        let (old, _old_rx) = collector();
+        let old_session = Uuid::from_u128(50);
+        engine
+            .ask(UserConnected {
+                user_id: bob,
+                session_id: old_session,
+                queue: old,
+                liveness: Liveness::new(),
+                resumed: false,
+            })
+            .await
+            .unwrap();
+        let mut bob_rx = connect(&engine, bob).await;
+
+        engine
+            .ask(UserDisconnected {
+                user_id: bob,
+                session_id: old_session,
+            })
+            .await
+            .unwrap();
+        engine
+            .ask(client_message(
+                alice,
+                user_message(Destination::Individual { id: bob }),
+            ))
+            .await
+            .unwrap();
+
+        assert!(received(&mut bob_rx).await);
+    }
+
+    #[tokio::test]
+    async fn every_device_of_a_user_gets_the_message() {
+        let engine = spawn_engine(InMemoryGroups::default());
+        let (alice, bob) = (Uuid::from_u128(1), Uuid::from_u128(2));
+        let mut phone_rx = connect(&engine, bob).await;
+        let (desktop, mut desktop_rx) = collector();
+        let desktop_session = Uuid::from_u128(50);
+        engine
+            .ask(UserConnected {
+                user_id: bob,
+                session_id: desktop_session,
+                queue: desktop,
+                liveness: Liveness::new(),
+                resumed: false,
+            })
+            .await
+            .unwrap();
+        assert!(matches!(
+            next(&mut desktop_rx).await,
+            Some(CrabbyWsFromServer::CatchUpComplete { queued: 0 })
+        ));
+
+        engine
+            .ask(client_message(
+                alice,
+                user_message(Destination::Individual { id: bob }),
+            ))
+            .await
+            .unwrap();
+        assert!(received(&mut phone_rx).await);
+        assert!(received(&mut desktop_rx).await);
+
+        //Closing the desktop leaves the phone connected
+        engine
+            .ask(UserDisconnected {
+                user_id: bob,
+                session_id: desktop_session,
+            })
+            .await
+            .unwrap();
+        engine
+            .ask(client_message(
+                alice,
+                user_message(Destination::Individual { id: bob }),
+            ))
+            .await
+            .unwrap();
+        assert!(received(&mut phone_rx).await);
+        assert!(!received(&mut desktop_rx).await);
+    }
+
+    #[tokio::test]
+    async fn diagnostics_list_every_live_session() {
+        let engine = spawn_engine(InMemoryGroups::default());
+        let bob = Uuid::from_u128(2);
+        let _phone_rx = connect(&engine, bob).await;
+        let _desktop_rx = connect(&engine, bob).await;
+
+        let stats = engine.ask(ConnectionDiagnostics).await.unwrap();
+        assert_eq!(stats.len(), 2);
+        assert!(stats.iter().all(|s| s.user_id == bob && s.rtt_ms.is_none()));
+    }
+
+    #[tokio::test]
+    async fn slow_session_does_not_hold_up_others() {
+        let engine = spawn_engine(InMemoryGroups::default());
+        let (alice, bob) = (Uuid::from_u128(1), Uuid::from_u128(2));
+        //Nothing ever drains this one
+        let stalled = OutboundQueue::new(QueueConfig {
+            capacity: 2,
+            policy: SlowConsumerPolicy::DropOldest,
+        });
+        engine
+            .ask(UserConnected {
+                user_id: bob,
+                session_id: Uuid::from_u128(50),
+                queue: stalled.clone(),
+                liveness: Liveness::new(),
+                resumed: false,
+            })
+            .await
+            .unwrap();
+        let mut phone_rx = connect(&engine, bob).await;
+
+        for _ in 0..5 {
+            engine
+                .ask(client_message(
+                    alice,
+                    user_message(Destination::Individual { id: bob }),
+                ))
+                .await
+                .unwrap();
+            assert!(received(&mut phone_rx).await);
+        }
+        //The catch-up marker went first
+        assert_eq!(stalled.depth(), 2);
+        assert_eq!(stalled.dropped(), 4);
+    }
+
+    #[tokio::test]
+    async fn messages_reach_users_connected_to_another_node() {
+        let transport = InMemoryTransport::default();
+        let (alice, bob) = (Uuid::from_u128(1), Uuid::from_u128(2));
+        let node_a = spawn_node(
+            "a",
+            InMemoryGroups::default(),
+            InMemoryMessageRepo::default(),
+            transport.clone(),
+        );
+        let node_b = spawn_node(
+            "b",
+            InMemoryGroups::default(),
+            InMemoryMessageRepo::default(),
+            transport,
+        );
+        let mut alice_rx = connect(&node_a, alice).await;
+        let mut alice_web_rx = connect(&node_b, alice).await;
+        let mut bob_rx = connect(&node_b, bob).await;
+
+        node_a
+            .ask(client_message(
+                alice,
+                user_message(Destination::Individual { id: bob }),
+            ))
+            .await
+            .unwrap();
+
+        assert!(received(&mut bob_rx).await);
+        assert!(received(&mut alice_web_rx).await);
+        //Node a delivered to alice itself and skips its own publish
+        assert!(received(&mut alice_rx).await);
+        assert!(!received(&mut alice_rx).await);
+        assert!(!received(&mut bob_rx).await);
+    }
+
+    #[tokio::test]
+    async fn nothing_is_published_for_users_only_this_node_holds() {
+        let transport = InMemoryTransport::default();
+        let (alice, bob) = (Uuid::from_u128(1), Uuid::from_u128(2));
+        let node_a = spawn_node(
+            "a",
+            InMemoryGroups::default(),
+            InMemoryMessageRepo::default(),
+            transport.clone(),
+        );
+        let mut published =
+            TransportBus::new(transport).subscribe(bob).await.unwrap();
+        let _alice_rx = connect(&node_a, alice).await;
+        let mut bob_rx = connect(&node_a, bob).await;
+
+        node_a
+            .ask(client_message(
+                alice,
+                user_message(Destination::Individual { id: bob }),
+            ))
+            .await
+            .unwrap();
+
+        assert!(received(&mut bob_rx).await);
+        let publish =
+            tokio::time::timeout(Duration::from_millis(100), published.next())
+                .await;
+        assert!(publish.is_err());
+    }
+
+    fn presence_of(
+        message: Option<CrabbyWsFromServer>,
+    ) -> (Uuid, PresenceStatus) {
+        match message {
+            Some(CrabbyWsFromServer::PresenceChanged {
+                user_id,
+                status,
+                ..
+            }) => (user_id, status),
+            other => panic!("expected a presence change, got {other:?}"),
+        }
+    }
+
+    ///The next presence change queued within a second, skipping anything
+    /// else
+    async fn next_presence_change(
+        rx: &mut UnboundedReceiver<CrabbyWsFromServer>,
+    ) -> Option<CrabbyWsFromServer> {
+        tokio::time::timeout(Duration::from_secs(1), async {
+            loop {
+                match rx.recv().await {
+                    Some(
+                        message @ CrabbyWsFromServer::PresenceChanged { .. },
+                    ) => break Some(message),
+                    Some(_) => continue,
+                    None => break None,
+                }
+            }
+        })
+        .await
+        .ok()
+        .flatten()
+    }
+
+    #[tokio::test]
+    async fn users_of_a_node_that_went_quiet_go_offline() {
+        let transport = InMemoryTransport::default();
+        let (alice, bob) = (Uuid::from_u128(1), Uuid::from_u128(2));
+        let node_a = spawn_watchful_node(
+            "a",
+            InMemoryMessageRepo::default(),
+            transport.clone(),
+        );
+        let node_b =
+            spawn_watchful_node("b", InMemoryMessageRepo::default(), transport);
+        let _bob_rx = connect(&node_b, bob).await;
+        let mut alice_rx = connect(&node_a, alice).await;
+        //Writing to bob makes alice one of the users who see his presence
+        node_a
+            .ask(client_message(
+                alice,
+                user_message(Destination::Individual { id: bob }),
+            ))
+            .await
+            .unwrap();
+
+        node_b.kill();
+        node_b.wait_for_shutdown().await;
+
+        assert_eq!(
+            presence_of(next_presence_change(&mut alice_rx).await),
+            (bob, PresenceStatus::Offline)
+        );
+    }
+
+    fn query_presence(user_ids: Vec<Uuid>) -> CrabbyWsFromClient {
+        CrabbyWsFromClient::QueryPresence { user_ids }
+    }
+
+    #[tokio::test]
+    async fn conversation_partners_see_presence_changes() {
+        let engine = spawn_engine(InMemoryGroups::default());
+        let (alice, bob) = (Uuid::from_u128(1), Uuid::from_u128(2));
+        let mut bob_rx = connect(&engine, bob).await;
+        engine
+            .ask(client_message(
+                bob,
+                user_message(Destination::Individual { id: alice }),
+            ))
+            .await
+            .unwrap();
+        assert!(received(&mut bob_rx).await);
+
+        let (queue, _alice_rx) = collector();
+        let alice_session = Uuid::from_u128(50);
+        engine
+            .ask(UserConnected {
+                user_id: alice,
+                session_id: alice_session,
+                queue,
+                liveness: Liveness::new(),
+                resumed: false,
+            })
+            .await
+            .unwrap();
+        assert_eq!(
+            presence_of(next(&mut bob_rx).await),
+            (alice, PresenceStatus::Online)
+        );
+
+        engine
+            .ask(client_message(
+                alice,
+                CrabbyWsFromClient::SetPresence {
+                    status: PresenceStatus::Away,
+                    custom_text: None,
+                },
+            ))
+            .await
+            .unwrap();
+        assert_eq!(
+            presence_of(next(&mut bob_rx).await),
+            (alice, PresenceStatus::Away)
+        );
+
+        engine
+            .ask(UserDisconnected {
+                user_id: alice,
+                session_id: alice_session,
+            })
+            .await
+            .unwrap();
+        assert_eq!(
+            presence_of(next(&mut bob_rx).await),
+            (alice, PresenceStatus::Offline)
+        );
+    }
+
+    fn set_presence(status: PresenceStatus) -> CrabbyWsFromClient {
+        CrabbyWsFromClient::SetPresence {
+            status,
+            custom_text: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn a_new_conversation_joins_the_presence_audience() {
+        let engine = spawn_engine(InMemoryGroups::default());
+        let (alice, carol) = (Uuid::from_u128(1), Uuid::from_u128(3));
+        let _alice_rx = connect(&engine, alice).await;
+        let mut carol_rx = connect(&engine, carol).await;
+
+        engine
+            .ask(client_message(alice, set_presence(PresenceStatus::Away)))
+            .await
+            .unwrap();
+        assert!(!received(&mut carol_rx).await);
+
+        //Alice's audience was worked out without carol, writing to her
+        // has it worked out again
+        engine
+            .ask(client_message(
+                carol,
+                user_message(Destination::Individual { id: alice }),
+            ))
+            .await
+            .unwrap();
+        engine
+            .ask(client_message(alice, set_presence(PresenceStatus::Online)))
+            .await
+            .unwrap();
+        assert_eq!(
+            presence_of(next_presence_change(&mut carol_rx).await),
+            (alice, PresenceStatus::Online)
+        );
+    }
+
+    #[tokio::test]
+    async fn queried_users_are_watched() {
+        let engine = spawn_engine(InMemoryGroups::default());
+        let (alice, carol) = (Uuid::from_u128(1), Uuid::from_u128(3));
+        let mut carol_rx = connect(&engine, carol).await;
+        engine
+            .ask(client_message(
+                carol,
+                user_message(Destination::Individual { id: alice }),
+            ))
+            .await
+            .unwrap();
+        assert!(received(&mut carol_rx).await);
+
+        match reply_to(&engine, carol, query_presence(vec![alice])).await {
+            CrabbyWsFromServer::Presence { users } => {
+                assert_eq!(users.len(), 1);
+                assert_eq!(users[0].status, PresenceStatus::Offline);
+            }
+            other => panic!("expected presence, got {other:?}"),
+        }
+
+        let _alice_rx = connect(&engine, alice).await;
+        assert_eq!(
+            presence_of(next(&mut carol_rx).await),
+            (alice, PresenceStatus::Online)
+        );
+    }
+
+    #[tokio::test]
+    async fn presence_is_shared_between_nodes() {
+        let transport = InMemoryTransport::default();
+        let (alice, bob) = (Uuid::from_u128(1), Uuid::from_u128(2));
+        let node_a = spawn_node(
+            "a",
+            InMemoryGroups::default(),
+            InMemoryMessageRepo::default(),
+            transport.clone(),
+        );
+        let node_b = spawn_node(
+            "b",
+            InMemoryGroups::default(),
+            InMemoryMessageRepo::default(),
+            transport,
+        );
+        let mut bob_rx = connect(&node_b, bob).await;
+        node_b
+            .ask(client_message(
+                bob,
+                user_message(Destination::Individual { id: alice }),
+            ))
+            .await
+            .unwrap();
+        assert!(received(&mut bob_rx).await);
+        reply_to(&node_b, bob, query_presence(vec![alice])).await;
+
+        let _alice_rx = connect(&node_a, alice).await;
+        assert_eq!(
+            presence_of(next(&mut bob_rx).await),
+            (alice, PresenceStatus::Online)
+        );
+        match reply_to(&node_b, bob, query_presence(vec![alice])).await {
+            CrabbyWsFromServer::Presence { users } => {
+                assert_eq!(users[0].status, PresenceStatus::Online);
+            }
+            other => panic!("expected presence, got {other:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn only_users_in_a_shared_conversation_can_be_queried() {
+        let (alice, bob, carol) =
+            (Uuid::from_u128(1), Uuid::from_u128(2), Uuid::from_u128(3));
+        let group = Uuid::from_u128(100);
+        let mut groups = HashMap::new();
+        groups.insert(group, vec![alice, bob]);
+        let engine = spawn_engine(InMemoryGroups::new(groups));
+        let mut alice_rx = connect(&engine, alice).await;
+        let _bob_rx = connect(&engine, bob).await;
+        let mut carol_rx = connect(&engine, carol).await;
+        engine
+            .ask(client_message(
+                alice,
+                user_message(Destination::Group { id: group }),
+            ))
+            .await
+            .unwrap();
+        assert!(received(&mut alice_rx).await);
+
+        match reply_to(&engine, alice, query_presence(vec![bob, carol])).await {
+            CrabbyWsFromServer::Presence { users } => {
+                let asked: Vec<_> = users.iter().map(|u| u.user_id).collect();
+                assert_eq!(asked, vec![bob]);
+            }
+            other => panic!("expected presence, got {other:?}"),
+        }
+        //Carol shares nothing with alice, asking does not make her a
+        // watcher either
+        match reply_to(&engine, carol, query_presence(vec![alice])).await {
+            CrabbyWsFromServer::Presence { users } => assert!(users.is_empty()),
+            other => panic!("expected presence, got {other:?}"),
+        }
+        engine
+            .ask(client_message(alice, set_presence(PresenceStatus::Away)))
+            .await
+            .unwrap();
+        assert!(next_presence_change(&mut carol_rx).await.is_none());
+    }
+
+    fn typing(dest: Destination, state: TypingState) -> CrabbyWsFromClient {
+        CrabbyWsFromClient::Typing { dest, state }
+    }
+
+    #[tokio::test]
+    async fn typing_reaches_the_others_in_the_group_at_a_limited_rate() {
+        let (alice, bob, carol) =
+            (Uuid::from_u128(1), Uuid::from_u128(2), Uuid::from_u128(3));
+        let group = Uuid::from_u128(100);
+        let mut groups = HashMap::new();
+        groups.insert(group, vec![alice, bob]);
+        let engine = spawn_engine(InMemoryGroups::new(groups));
+        let mut alice_rx = connect(&engine, alice).await;
+        let mut bob_rx = connect(&engine, bob).await;
+        let mut carol_rx = connect(&engine, carol).await;
+        let dest = Destination::Group { id: group };
+
+        for _ in 0..3 {
+            engine
+                .ask(client_message(
+                    alice,
+                    typing(dest.clone(), TypingState::Started),
+                ))
+                .await
+                .unwrap();
+        }
+        assert!(matches!(
+            next(&mut bob_rx).await,
+            Some(CrabbyWsFromServer::UserTyping {
+                user_id,
+                state: TypingState::Started,
+                ..
+            }) if user_id == alice
+        ));
+        assert!(!received(&mut bob_rx).await);
+
+        engine
+            .ask(client_message(alice, typing(dest, TypingState::Stopped)))
+            .await
+            .unwrap();
+        assert!(matches!(
+            next(&mut bob_rx).await,
+            Some(CrabbyWsFromServer::UserTyping {
+                state: TypingState::Stopped,
+                ..
+            })
+        ));
+        assert!(!received(&mut alice_rx).await);
+        assert!(!received(&mut carol_rx).await);
+    }
+
+    fn unread_of(
+        message: Option<CrabbyWsFromServer>,
+    ) -> Vec<(u64, Option<u64>)> {
+        match message {
+            Some(CrabbyWsFromServer::UnreadCounts { conversations }) => {
+                conversations
+                    .iter()
+                    .map(|count| (count.unread, count.read_up_to))
+                    .collect()
+            }
+            other => panic!("expected unread counts, got {other:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn marking_read_sends_receipts_and_moves_unread_counts() {
+        let engine = spawn_engine(InMemoryGroups::default());
+        let (alice, bob) = (Uuid::from_u128(1), Uuid::from_u128(2));
+        let mut alice_rx = connect(&engine, alice).await;
+        engine
+            .ask(client_message(
+                alice,
+                user_message(Destination::Individual { id: bob }),
+            ))
+            .await
+            .unwrap();
+        assert!(received(&mut alice_rx).await);
+
+        let mut bob_rx = connect(&engine, bob).await;
+        assert_eq!(unread_of(next(&mut bob_rx).await), vec![(1, None)]);
+        assert_eq!(
+            presence_of(next(&mut alice_rx).await),
+            (bob, PresenceStatus::Online)
+        );
+
+        engine
+            .ask(client_message(
+                bob,
+                CrabbyWsFromClient::MarkRead {
+                    dest: Destination::Individual { id: alice },
+                    up_to_message_id: 1,
+                },
+            ))
+            .await
+            .unwrap();
+        for rx in [&mut alice_rx, &mut bob_rx] {
+            assert!(matches!(
+                next(rx).await,
+                Some(CrabbyWsFromServer::ReadReceipt {
+                    user_id,
+                    up_to_message_id: 1,
+                    ..
+                }) if user_id == bob
+            ));
+        }
+
+        let mut phone_rx = connect(&engine, bob).await;
+        assert_eq!(unread_of(next(&mut phone_rx).await), vec![(0, Some(1))]);
+    }
+
+    #[tokio::test]
+    async fn marking_a_group_one_is_not_in_is_an_error() {
+        let (alice, carol, group) =
+            (Uuid::from_u128(1), Uuid::from_u128(3), Uuid::from_u128(100));
+        let mut groups = HashMap::new();
+        groups.insert(group, vec![alice]);
+        let engine = spawn_engine(InMemoryGroups::new(groups));
+
+        let mark = CrabbyWsFromClient::MarkRead {
+            dest: Destination::Group { id: group },
+            up_to_message_id: 1,
+        };
+        match reply_to(&engine, carol, mark).await {
+            CrabbyWsFromServer::Error {
+                code,
+                retryable,
+                correlation: Some(correlation),
+                ..
+            } => {
+                assert_eq!(code, ErrorCode::NotAMember);
+                assert!(!retryable);
+                assert_eq!(correlation.request, "MarkRead");
+            }
+            other => panic!("expected an error, got {other:?}"),
+        }
+    }
+
+    fn direct_message(client_msg_id: &str, to: Uuid) -> CrabbyWsFromClient {
+        CrabbyWsFromClient::UserMessage {
+            client_msg_id: client_msg_id.to_string(),
+            dest: Destination::Individual { id: to },
+            contents: "hello".to_string(),
+            client_sent_at: None,
+            reply_to: None,
+            thread_root: None,
+            mentions: Vec::new(),
+        }
+    }
+
+    fn edit_message(message_id: u64, contents: &str) -> CrabbyWsFromClient {
+        CrabbyWsFromClient::EditMessage {
+            message_id,
+            contents: contents.to_string(),
+        }
+    }
+
+    fn error_code(reply: CrabbyWsFromServer) -> ErrorCode {
+        match reply {
+            CrabbyWsFromServer::Error { code, .. } => code,
+            other => panic!("expected an error, got {other:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn only_the_sender_edits_a_direct_message() {
+        let engine = spawn_engine(InMemoryGroups::default());
+        let (alice, bob) = (Uuid::from_u128(1), Uuid::from_u128(2));
+        let mut bob_rx = connect(&engine, bob).await;
+        reply_to(&engine, alice, direct_message("c1", bob)).await;
+        assert!(received(&mut bob_rx).await);
+
+        let reply = reply_to(&engine, bob, edit_message(1, "mine now")).await;
+        assert_eq!(error_code(reply), ErrorCode::Forbidden);
+        let reply = reply_to(&engine, alice, edit_message(7, "hi")).await;
+        assert_eq!(error_code(reply), ErrorCode::NotFound);
+
+        engine
+            .ask(client_message(alice, edit_message(1, "hello again")))
+            .await
+            .unwrap();
+        match next(&mut bob_rx).await {
+            Some(CrabbyWsFromServer::MessageEdited {
+                user_id,
+                contents,
+                revisions,
+                ..
+            }) => {
+                assert_eq!(user_id, alice);
+                assert_eq!(contents, "hello again");
+                assert_eq!(revisions.len(), 1);
+                assert_eq!(revisions[0].contents, "hello");
+            }
+            other => panic!("expected an edit, got {other:?}"),
+        }
+
+        let dest = Destination::Individual { id: alice };
+        match reply_to(&engine, bob, fetch_history(dest, None, 10)).await {
+            CrabbyWsFromServer::History { messages, .. } => {
+                assert_eq!(messages[0].contents, "hello again");
+                assert!(messages[0].edited_at.is_some());
+            }
+            other => panic!("expected a history page, got {other:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn group_admin_deletes_and_a_tombstone_stays() {
+        let (alice, bob, carol) =
+            (Uuid::from_u128(1), Uuid::from_u128(2), Uuid::from_u128(3));
+        let group = Uuid::from_u128(100);
+        let mut groups = HashMap::new();
+        groups.insert(group, vec![alice, bob, carol]);
+        let engine =
+            spawn_engine(InMemoryGroups::new(groups).with_admin(group, carol));
+        let dest = Destination::Group { id: group };
+        let mut alice_rx = connect(&engine, alice).await;
+        engine
+            .ask(client_message(alice, user_message(dest.clone())))
+            .await
+            .unwrap();
+        assert!(received(&mut alice_rx).await);
+
+        let delete = CrabbyWsFromClient::DeleteMessage { message_id: 1 };
+        let reply = reply_to(&engine, bob, delete.clone()).await;
+        assert_eq!(error_code(reply), ErrorCode::Forbidden);
+
+        engine.ask(client_message(carol, delete)).await.unwrap();
+        assert!(matches!(
+            next(&mut alice_rx).await,
+            Some(CrabbyWsFromServer::MessageDeleted {
+                message_id: 1,
+                user_id,
+                ..
+            }) if user_id == carol
+        ));
+        let reply = reply_to(&engine, alice, edit_message(1, "too late")).await;
+        assert_eq!(error_code(reply), ErrorCode::Deleted);
+
+        match reply_to(&engine, bob, fetch_history(dest, None, 10)).await {
+            CrabbyWsFromServer::History { messages, .. } => {
+                assert!(messages[0].contents.is_empty());
+                assert!(messages[0].deleted_at.is_some());
+            }
+            other => panic!("expected a history page, got {other:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn reactions_fan_out_as_counts_and_show_in_history() {
+        let engine = spawn_engine(InMemoryGroups::default());
+        let (alice, bob, carol) =
+            (Uuid::from_u128(1), Uuid::from_u128(2), Uuid::from_u128(3));
+        let mut alice_rx = connect(&engine, alice).await;
+        reply_to(&engine, alice, direct_message("c1", bob)).await;
+        assert!(received(&mut alice_rx).await);
+
+        let react = |emoji: &str| CrabbyWsFromClient::React {
+            message_id: 1,
+            emoji: emoji.to_string(),
+        };
+        let reply = reply_to(&engine, carol, react("👍")).await;
+        assert_eq!(error_code(reply), ErrorCode::Forbidden);
+        let reply = reply_to(&engine, bob, react("")).await;
+        assert_eq!(error_code(reply), ErrorCode::InvalidEmoji);
+
+        for _ in 0..2 {
+            engine.ask(client_message(bob, react("👍"))).await.unwrap();
+        }
+        assert!(matches!(
+            next(&mut alice_rx).await,
+            Some(CrabbyWsFromServer::ReactionChanged {
+                user_id,
+                added: true,
+                count: 1,
+                ..
+            }) if user_id == bob
+        ));
+        assert!(!received(&mut alice_rx).await);
+
+        let dest = Destination::Individual { id: bob };
+        match reply_to(&engine, alice, fetch_history(dest, None, 10)).await {
+            CrabbyWsFromServer::History { messages, .. } => {
+                let reaction = &messages[0].reactions[0];
+                assert_eq!((reaction.count, reaction.reacted), (1, false));
+            }
+            other => panic!("expected a history page, got {other:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn replies_notify_the_thread_and_stay_out_of_history() {
+        let (alice, bob, carol) =
+            (Uuid::from_u128(1), Uuid::from_u128(2), Uuid::from_u128(3));
+        let group = Uuid::from_u128(100);
+        let dest = Destination::Group { id: group };
+        let store = InMemoryMessageRepo::default();
+        store
+            .save(&StoredMessage {
+                message_id: 50,
+                sender_id: alice,
+                client_msg_id: None,
+                dest: dest.clone(),
+                contents: "root".to_string(),
+                client_sent_at: None,
+                edited_id: None,
+                deleted_id: None,
+                reply_to: None,
+                thread_root: None,
+                mentions: Vec::new(),
+                mentions_group: false,
+            })
+            .await
+            .unwrap();
+        let mut groups = HashMap::new();
+        groups.insert(group, vec![alice, bob, carol]);
+        let engine = spawn_engine_with(InMemoryGroups::new(groups), store);
+        let mut alice_rx = connect(&engine, alice).await;
+        assert!(matches!(
+            next(&mut alice_rx).await,
+            Some(CrabbyWsFromServer::UnreadCounts { .. })
+        ));
+        let mut carol_rx = connect(&engine, carol).await;
+
+        let reply = |answered: u64| CrabbyWsFromClient::UserMessage {
+            client_msg_id: Uuid::now_v7().to_string(),
+            dest: dest.clone(),
+            contents: "reply".to_string(),
+            client_sent_at: None,
+            reply_to: Some(answered),
+            thread_root: None,
+            mentions: Vec::new(),
+        };
+        assert!(matches!(
+            reply_to(&engine, bob, reply(999)).await,
+            CrabbyWsFromServer::Error {
+                code: ErrorCode::InvalidThread,
+                ..
+            }
+        ));
+        reply_to(&engine, bob, reply(50)).await;
+
+        assert!(matches!(
+            next(&mut carol_rx).await,
+            Some(CrabbyWsFromServer::ChatMessage {
+                thread_root: Some(50),
+                ..
+            })
+        ));
+        assert!(!received(&mut carol_rx).await);
+        assert!(received(&mut alice_rx).await);
+        match next(&mut alice_rx).await {
+            Some(CrabbyWsFromServer::ThreadUpdated { thread, .. }) => {
+                assert_eq!(thread.reply_count, 1);
+                assert_eq!(thread.last_reply_by, bob);
+            }
+            other => panic!("expected a thread update, got {other:?}"),
+        }
+
+        let (ids, _) =
+            history_page(&engine, carol, fetch_history(dest, None, 10)).await;
+        assert_eq!(ids, vec![50]);
+        let fetch_thread = CrabbyWsFromClient::FetchThread {
+            thread_root: 50,
+            before: None,
+            limit: 10,
+        };
+        match reply_to(&engine, carol, fetch_thread).await {
+            CrabbyWsFromServer::Thread { messages, .. } => {
+                assert_eq!(messages.len(), 1);
+                assert_eq!(messages[0].reply_to, Some(50));
+            }
+            other => panic!("expected a thread page, got {other:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn mentioned_users_are_told_apart_from_the_message() {
+        let (alice, bob, carol, dave) = (
+            Uuid::from_u128(1),
+            Uuid::from_u128(2),
+            Uuid::from_u128(3),
+            Uuid::from_u128(4),
+        );
+        let group = Uuid::from_u128(100);
+        let mut groups = HashMap::new();
+        groups.insert(group, vec![alice, bob, carol]);
+        //dave is a known user outside of the group and is never told
+        let users = InMemoryUsers::default()
+            .with_user("bobby", bob)
+            .with_user("davey", dave);
+        let engine =
+            spawn_engine_with_users(InMemoryGroups::new(groups), users);
+        let mut bob_rx = connect(&engine, bob).await;
+        let mut carol_rx = connect(&engine, carol).await;
+        let mut dave_rx = connect(&engine, dave).await;
+
+        let dest = Destination::Group { id: group };
+        let message = CrabbyWsFromClient::UserMessage {
+            client_msg_id: Uuid::now_v7().to_string(),
+            dest: dest.clone(),
+            contents: "@Bobby and @davey, look".to_string(),
+            client_sent_at: None,
+            reply_to: None,
+            thread_root: None,
+            mentions: vec![carol],
+        };
+        reply_to(&engine, alice, message).await;
+        for (rx, user) in [(&mut bob_rx, bob), (&mut carol_rx, carol)] {
+            match next(rx).await {
+                Some(CrabbyWsFromServer::ChatMessage { mentions, .. }) => {
+                    assert_eq!(mentions, vec![carol, bob]);
+                }
+                other => panic!("expected the message, got {other:?}"),
+            }
+            assert!(
+                matches!(
+                    next(rx).await,
+                    Some(CrabbyWsFromServer::Mentioned {
+                        group_wide: false,
+                        ..
+                    })
+                ),
+                "{user} was not told about the mention"
+            );
+        }
+        assert!(!received(&mut dave_rx).await);
+
+        let everyone = CrabbyWsFromClient::UserMessage {
+            client_msg_id: Uuid::now_v7().to_string(),
+            dest,
+            contents: "@group lunch?".to_string(),
+            client_sent_at: None,
+            reply_to: None,
+            thread_root: None,
+            mentions: Vec::new(),
+        };
+        reply_to(&engine, alice, everyone).await;
+        //The group is not one of the users mentioned
+        assert!(matches!(
+            next(&mut carol_rx).await,
+            Some(CrabbyWsFromServer::ChatMessage {
+                mentions,
+                mentions_group: true,
+                ..
+            }) if mentions.is_empty()
+        ));
+        assert!(matches!(
+            next(&mut carol_rx).await,
+            Some(CrabbyWsFromServer::Mentioned {
+                group_wide: true,
+                ..
+            })
+        ));
+    }
+
+    #[tokio::test]
+    async fn offline_users_catch_up_before_live_traffic() {
+        let engine = spawn_engine(InMemoryGroups::default());
+        let (alice, bob) = (Uuid::from_u128(1), Uuid::from_u128(2));
+        let _alice_rx = connect(&engine, alice).await;
+        reply_to(
+            &engine,
+            alice,
+            user_message(Destination::Individual { id: bob }),
+        )
+        .await;
+
+        let (mut bob_rx, queued) = catch_up(&engine, bob).await;
+        assert!(matches!(
+            queued.as_slice(),
+            [CrabbyWsFromServer::ChatMessage { user_id, .. }] if *user_id == alice
+        ));
+        reply_to(
+            &engine,
+            alice,
+            user_message(Destination::Individual { id: bob }),
+        )
+        .await;
+        assert!(matches!(
+            next(&mut bob_rx).await,
+            Some(CrabbyWsFromServer::UnreadCounts { .. })
+        ));
+        assert!(matches!(
+            next(&mut bob_rx).await,
+            Some(CrabbyWsFromServer::ChatMessage { .. })
+        ));
+
+        //Messages to a connected user are not queued for later
+        let (_, queued) = catch_up(&engine, bob).await;
+        assert!(queued.is_empty());
+    }
+
+    #[tokio::test]
+    async fn users_of_a_node_that_went_down_are_queued_for() {
+        let transport = InMemoryTransport::default();
+        let (alice, bob) = (Uuid::from_u128(1), Uuid::from_u128(2));
+        let node_a = spawn_watchful_node(
+            "a",
+            InMemoryMessageRepo::default(),
+            transport.clone(),
+        );
+        let node_b =
+            spawn_watchful_node("b", InMemoryMessageRepo::default(), transport);
+        let mut bob_rx = connect(&node_b, bob).await;
+        let _alice_rx = connect(&node_a, alice).await;
+        //Reaches bob on node b, so it is not queued
+        reply_to(
+            &node_a,
+            alice,
+            user_message(Destination::Individual { id: bob }),
+        )
+        .await;
+        assert!(received(&mut bob_rx).await);
+
+        node_b.kill();
+        node_b.wait_for_shutdown().await;
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        reply_to(
+            &node_a,
+            alice,
+            user_message(Destination::Individual { id: bob }),
+        )
+        .await;
+
+        let (_, queued) = catch_up(&node_a, bob).await;
+        assert!(matches!(
+            queued.as_slice(),
+            [CrabbyWsFromServer::ChatMessage { user_id, .. }] if *user_id == alice
+        ));
+    }
+
+    #[tokio::test]
+    async fn sender_is_acked_with_assigned_id() {
+        let engine = spawn_engine(InMemoryGroups::default());
+        let (alice, bob) = (Uuid::from_u128(1), Uuid::from_u128(2));
+
+        match reply_to(&engine, alice, direct_message("c1", bob)).await {
+            CrabbyWsFromServer::SendAck {
+                client_msg_id,
+                message_id,
+                timestamp,
+            } => {
+                assert_eq!(client_msg_id, "c1");
+                assert_eq!(message_id, 1);
+                assert_eq!(timestamp, timestamp_of(1).to_string());
+            }
+            other => panic!("expected an ack, got {other:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn retransmit_is_acked_but_delivered_once() {
+        let engine = spawn_engine(InMemoryGroups::default());
+        let (alice, bob) = (Uuid::from_u128(1), Uuid::from_u128(2));
+        let mut bob_rx = connect(&engine, bob).await;
+
+        let first = reply_to(&engine, alice, direct_message("c1", bob)).await;
+        let again = reply_to(&engine, alice, direct_message("c1", bob)).await;
+
+        assert!(received(&mut bob_rx).await);
+        assert!(!received(&mut bob_rx).await);
+        match (first, again) {
+            (
+                CrabbyWsFromServer::SendAck { message_id: a, .. },
+                CrabbyWsFromServer::SendAck { message_id: b, .. },
+            ) => assert_eq!(a, b),
+            other => panic!("expected two acks, got {other:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn same_client_msg_id_from_other_user_is_not_a_retransmit() {
+        let engine = spawn_engine(InMemoryGroups::default());
+        let (alice, bob, carol) =
+            (Uuid::from_u128(1), Uuid::from_u128(2), Uuid::from_u128(3));
+        let mut carol_rx = connect(&engine, carol).await;
+
+        reply_to(&engine, alice, direct_message("c1", carol)).await;
+        reply_to(&engine, bob, direct_message("c1", carol)).await;
+
+        assert!(received(&mut carol_rx).await);
+        assert!(received(&mut carol_rx).await);
+    }
+
+    #[tokio::test]
+    async fn empty_client_msg_id_is_rejected() {
+        let engine = spawn_engine(InMemoryGroups::default());
+        let (alice, bob) = (Uuid::from_u128(1), Uuid::from_u128(2));
+        let mut bob_rx = connect(&engine, bob).await;
+
+        let reply = reply_to(&engine, alice, direct_message("", bob)).await;
+
+        assert!(matches!(
+            reply,
+            CrabbyWsFromServer::Error {
+                code: ErrorCode::InvalidClientMsgId,
+                ..
+            }
+        ));
+        assert!(!received(&mut bob_rx).await);
+    }
+
+    /// Store that is always down
+    struct UnavailableStore;
+    #[async_trait::async_trait]
+    impl MessageRepo for UnavailableStore {
+        async fn save(
+            &self,
+            _message: &StoredMessage,
+        ) -> eyre::Result<SaveOutcome> {
+            Err(eyre::eyre!("database is down"))
+        }
+        async fn history(
+            &self,
+            _viewer: Uuid,
+            _dest: &Destination,
+            _before: u64,
+            _limit: u32,
+        ) -> eyre::Result<Vec<StoredMessage>> {
+            Err(eyre::eyre!("database is down"))
+        }
+        async fn thread(
+            &self,
+            _root: u64,
+            _before: u64,
+            _limit: u32,
+        ) -> eyre::Result<Vec<StoredMessage>> {
+            Err(eyre::eyre!("database is down"))
+        }
+        async fn threads(
+            &self,
+            _roots: &[u64],
+        ) -> eyre::Result<HashMap<u64, crabby_specs::ws::outgoing::ThreadSummary>>
+        {
+            Err(eyre::eyre!("database is down"))
+        }
+        async fn thread_participants(
+            &self,
+            _root: u64,
+        ) -> eyre::Result<Vec<Uuid>> {
+            Err(eyre::eyre!("database is down"))
+        }
+        async fn conversations(
+            &self,
+            _user: Uuid,
+        ) -> eyre::Result<Vec<Destination>> {
+            Err(eyre::eyre!("database is down"))
+        }
+        async fn mark_read(
+            &self,
+            _user: Uuid,
+            _dest: &Destination,
+            _up_to: u64,
+        ) -> eyre::Result<bool> {
+            Err(eyre::eyre!("database is down"))
+        }
+        async fn unread(&self, _user: Uuid) -> eyre::Result<Vec<UnreadCount>> {
+            Err(eyre::eyre!("database is down"))
+        }
+        async fn find(
+            &self,
+            _message_id: u64,
+        ) -> eyre::Result<Option<StoredMessage>> {
+            Err(eyre::eyre!("database is down"))
+        }
+        async fn edit(
+            &self,
+            _message_id: u64,
+            _contents: &str,
+            _edited_id: u64,
+        ) -> eyre::Result<
+            Option<Vec<crabby_specs::ws::outgoing::MessageRevision>>,
+        > {
+            Err(eyre::eyre!("database is down"))
+        }
+        async fn delete(
+            &self,
+            _message_id: u64,
+            _deleted_id: u64,
+        ) -> eyre::Result<bool> {
+            Err(eyre::eyre!("database is down"))
+        }
+        async fn react(
+            &self,
+            _message_id: u64,
+            _user: Uuid,
+            _emoji: &str,
+        ) -> eyre::Result<Option<u64>> {
+            Err(eyre::eyre!("database is down"))
+        }
+        async fn unreact(
+            &self,
+            _message_id: u64,
+            _user: Uuid,
+            _emoji: &str,
+        ) -> eyre::Result<Option<u64>> {
+            Err(eyre::eyre!("database is down"))
+        }
+        async fn reactions(
+            &self,
+            _viewer: Uuid,
+            _message_ids: &[u64],
+        ) -> eyre::Result<
+            HashMap<u64, Vec<crabby_specs::ws::outgoing::ReactionSummary>>,
+        > {
+            Err(eyre::eyre!("database is down"))
+        }
+        async fn enqueue(
+            &self,
+            _users: &[Uuid],
+            _message_id: u64,
+            _limits: OfflineQueueConfig,
+        ) -> eyre::Result<()> {
+            Err(eyre::eyre!("database is down"))
+        }
+        async fn drain(
+            &self,
+            _user: Uuid,
+            _max_age: Duration,
+        ) -> eyre::Result<Vec<StoredMessage>> {
+            Err(eyre::eyre!("database is down"))
+        }
+    }
+
+    #[tokio::test]
+    async fn unstored_message_is_rejected_and_not_delivered() {
+        let engine =
+            spawn_engine_with(InMemoryGroups::default(), UnavailableStore);
+        let (alice, bob) = (Uuid::from_u128(1), Uuid::from_u128(2));
+        let mut bob_rx = connect(&engine, bob).await;
+
+        let reply = reply_to(&engine, alice, direct_message("c1", bob)).await;
+
+        match reply {
+            CrabbyWsFromServer::Error {
+                code,
+                correlation: Some(correlation),
+                ..
+            } => {
+                assert_eq!(code, ErrorCode::Unavailable);
+                assert_eq!(correlation.request, "UserMessage");
+                assert_eq!(correlation.id.as_deref(), Some("c1"));
+            }
+            other => panic!("expected an error, got {other:?}"),
+        }
+        assert!(!received(&mut bob_rx).await);
+    }
+}
@@ -1,14 +1,32 @@
 use axum::extract::ws::Message as WsMessage;
-use crabby_specs::ws::incoming::CrabbyWsFromClient;
+use crabby_specs::ws::{
+    incoming::CrabbyWsFromClient,
+    outgoing::{CrabbyWsFromServer, ErrorCode},
+};
 use futures::Stream;
 use kameo::{
-    Actor, actor::ActorRef, error::Infallible, message::StreamMessage,
+    Actor,
+    actor::{ActorId, ActorRef, Recipient, WeakActorRef},
+    error::{ActorStopReason, Infallible, SendError},
+    message::StreamMessage,
     prelude::Message,
 };
-use std::{marker::PhantomData, pin::Pin};
+use std::{marker::PhantomData, ops::ControlFlow, pin::Pin, time::Instant};
+use tracing::warn;
 use uuid::Uuid;
 
-use crate::actors::{converter::incoming::Decode, engine::EngineActor};
+use crate::{
+    actors::{
+        converter::incoming::{DataLen, Decode, InboundFrame},
+        engine::EngineActor,
+    },
+    config::FrameLimits,
+    liveness::Liveness,
+    messages::internal::{ClientMessage, ConnectionControl},
+    queue::OutboundQueue,
+    ratelimit::{ConnectionLimiter, Verdict},
+    validate::{self, Rejection},
+};
 //Because axum's Websocket stream returns Result<Item,Error> I need to filter_map to get a stream
 //of only Items
 pub type IncomingWebsocketActor = IncomingMessageActor<
@@ -22,6 +40,12 @@ where
 {
     engine: ActorRef<EngineActor>,
     user_id: Uuid,
+    //Outbound queue of the same connection
+    reply_to: OutboundQueue,
+    control: Recipient<ConnectionControl>,
+    liveness: Liveness,
+    limits: ConnectionLimiter,
+    frame_limits: FrameLimits,
     me: Option<ActorRef<Self>>,
     _stream: PhantomData<S>,
     _stream_item: PhantomData<I>,
@@ -32,10 +56,23 @@ where
     S: Stream<Item = I> + Send + 'static,
     I: Send + Sync + 'static,
 {
-    pub fn new(engine: ActorRef<EngineActor>, user_id: Uuid) -> Self {
+    pub fn new(
+        engine: ActorRef<EngineActor>,
+        user_id: Uuid,
+        reply_to: OutboundQueue,
+        control: Recipient<ConnectionControl>,
+        liveness: Liveness,
+        limits: ConnectionLimiter,
+        frame_limits: FrameLimits,
+    ) -> Self {
         Self {
             engine,
             user_id,
+            reply_to,
+            control,
+            liveness,
+            limits,
+            frame_limits,
             me: None,
             _stream: PhantomData,
             _stream_item: PhantomData,
@@ -44,6 +81,91 @@ where
     fn actor_ref(&mut self, handle: ActorRef<Self>) {
         self.me = Some(handle);
     }
+    async fn on_message(&mut self, mut msg: CrabbyWsFromClient) {
+        if let Err(rejection) = validate::message(&mut msg, &self.frame_limits)
+        {
+            return self.reject(rejection, Some(&msg));
+        }
+        match msg {
+            CrabbyWsFromClient::Hello { .. } => self.reject(
+                Rejection::new(
+                    ErrorCode::UnexpectedHello,
+                    "the handshake is already done",
+                ),
+                Some(&msg),
+            ),
+            CrabbyWsFromClient::Ack { up_to } => {
+                let _ =
+                    self.control.tell(ConnectionControl::Acked(up_to)).await;
+            }
+            msg => {
+                let correlation = msg.correlation();
+                let sent = self
+                    .engine
+                    .tell(ClientMessage {
+                        user_id: self.user_id,
+                        message: msg,
+                        reply_to: self.reply_to.clone(),
+                    })
+                    .await;
+                if sent.is_err() {
+                    warn!("engine did not take a message of {}", self.user_id);
+                    self.reply_to.push(CrabbyWsFromServer::error(
+                        ErrorCode::Internal,
+                        "the message was not handled",
+                        Some(correlation),
+                    ));
+                }
+            }
+        }
+    }
+    ///Tells the client a frame of theirs went nowhere
+    fn reject(&self, rejection: Rejection, cause: Option<&CrabbyWsFromClient>) {
+        self.reply_to.push(rejection.into_event(cause));
+    }
+    ///Charges a data frame to the rate limits. A frame over the limit is
+    /// dropped and the client told when to try again, or the connection
+    /// is closed if it keeps at it.
+    async fn admit(&mut self, len: usize) -> bool {
+        match self.limits.check(len, Instant::now()) {
+            Verdict::Allowed => true,
+            Verdict::Limited(retry_after) => {
+                //Rounded up, trying again any sooner would be refused
+                let retry_after_ms = retry_after.as_micros().div_ceil(1000);
+                self.reply_to.push(CrabbyWsFromServer::rate_limited(
+                    retry_after_ms as u64,
+                ));
+                false
+            }
+            Verdict::Disconnect => {
+                warn!("user {} kept going over the rate limit", self.user_id);
+                let _ = self.control.tell(ConnectionControl::RateLimited).await;
+                false
+            }
+        }
+    }
+    ///The client said goodbye, so the session ends here instead of being
+    /// kept around for a resume
+    async fn close(&mut self) {
+        let _ = self.control.tell(ConnectionControl::Close).await;
+        self.stop().await;
+    }
+    //The linked outgoing actor stops along with this one and tells the
+    // engine what became of the session
+    async fn stop(&mut self) {
+        if let Some(me) = self.me.take()
+            && let Err(err) = me.stop_gracefully().await
+        {
+            match err {
+                //Already on its way out
+                SendError::ActorNotRunning(_) | SendError::ActorStopped => (),
+                err => warn!(
+                    "could not stop incoming actor of user {}: {err:?}",
+                    self.user_id
+                ),
+            }
+        }
+    }
 }
 impl<I, S> Actor for IncomingMessageActor<I, S>
 where
@@ -61,13 +183,27 @@ where
         args.actor_ref(actor_ref);
         std::result::Result::Ok(args)
     }
+
+    async fn on_link_died(
+        &mut self,
+        _actor_ref: WeakActorRef<Self>,
+        id: ActorId,
+        reason: ActorStopReason,
+    ) -> Result<ControlFlow<ActorStopReason>, Self::Error> {
+        //The outgoing side lost the socket, nothing read from it could be
+        // answered anymore
+        Ok(ControlFlow::Break(ActorStopReason::LinkDied {
+            id,
+            reason: Box::new(reason),
+        }))
+    }
 }
 impl<I, S> Decode<WsMessage> for IncomingMessageActor<I, S>
 where
     S: Stream<Item = I> + Send + 'static,
     I: Send + Sync + 'static,
 {
-    type Output = CrabbyWsFromClient;
+    type Output = InboundFrame;
 
     type Error = Infallible;
 
@@ -76,15 +212,33 @@ where
     ) -> eyre::Result<<IncomingMessageActor<I, S> as Decode<WsMessage>>::Output>
     {
         match item {
-            WsMessage::Text(_) => unimplemented!(),
+            WsMessage::Text(text) => {
+                let message: CrabbyWsFromClient =
+                    serde_json::from_str(text.as_str())?;
+                eyre::Ok(InboundFrame::Message(message))
+            }
             WsMessage::Binary(bytes) => {
                 let message: CrabbyWsFromClient =
                     serde_json::from_slice(bytes.as_ref())?;
-                eyre::Ok(message)
+                eyre::Ok(InboundFrame::Message(message))
+            }
+            //axum answers pings on its own, they only tell us the client
+            // is still there
+            WsMessage::Ping(_bytes) => eyre::Ok(InboundFrame::Ping),
+            WsMessage::Pong(_bytes) => eyre::Ok(InboundFrame::Pong),
+            WsMessage::Close(_close_frame) => eyre::Ok(InboundFrame::Close),
+        }
+    }
+}
+
+impl DataLen for WsMessage {
+    fn data_len(&self) -> Option<usize> {
+        match self {
+            WsMessage::Text(text) => Some(text.len()),
+            WsMessage::Binary(bytes) => Some(bytes.len()),
+            WsMessage::Ping(_) | WsMessage::Pong(_) | WsMessage::Close(_) => {
+                None
             }
-            WsMessage::Ping(_bytes) => unimplemented!(),
-            WsMessage::Pong(_bytes) => unimplemented!(),
-            WsMessage::Close(_close_frame) => unimplemented!(),
         }
     }
 }
@@ -93,7 +247,8 @@ impl<I, S> Message<StreamMessage<I, (), ()>> for IncomingMessageActor<I, S>
 where
     S: Stream<Item = I> + Send + 'static,
     I: Send + Sync + 'static,
-    Self: Decode<I, Output = CrabbyWsFromClient> + Send,
+    Self: Decode<I, Output = InboundFrame> + Send,
+    I: DataLen,
 {
     type Reply = ();
 
@@ -104,27 +259,41 @@ where
     ) -> Self::Reply {
         match msg {
             StreamMessage::Next(msg) => {
-                let decoded = Self::decode(msg);
-                if let std::result::Result::Ok(msg) = decoded {
-                    println!("{:?}", msg);
-                    let _ = self.engine.tell(msg).await;
+                if let Some(len) = msg.data_len() {
+                    if !self.admit(len).await {
+                        return;
+                    }
+                    //axum refuses larger messages already, this holds for
+                    // any other transport as well
+                    let max = self.frame_limits.max_frame_bytes;
+                    if len > max {
+                        return self.reject(
+                            Rejection::new(
+                                ErrorCode::FrameTooLarge,
+                                format!(
+                                    "frame is {len} bytes, the limit is {max}"
+                                ),
+                            ),
+                            None,
+                        );
+                    }
                 }
-            }
-            StreamMessage::Started(_) => (),
-            //TODO: check to make sure implementation is secure
-            StreamMessage::Finished(_) => {
-                if let Some(r) = self.me.take()
-                    && let Err(err) = r.stop_gracefully().await
-                {
-                    match err {
-                        kameo::error::SendError::ActorNotRunning(_) => todo!(),
-                        kameo::error::SendError::ActorStopped => todo!(),
-                        kameo::error::SendError::MailboxFull(_) => todo!(),
-                        kameo::error::SendError::HandlerError(_) => todo!(),
-                        kameo::error::SendError::Timeout(_) => todo!(),
+                match Self::decode(msg) {
+                    Ok(InboundFrame::Message(msg)) => {
+                        self.liveness.saw_frame();
+                        self.on_message(msg).await;
                     }
+                    Ok(InboundFrame::Ping) => self.liveness.saw_frame(),
+                    Ok(InboundFrame::Pong) => self.liveness.saw_pong(),
+                    Ok(InboundFrame::Close) => self.close().await,
+                    Err(err) => self.reject(
+                        Rejection::new(ErrorCode::Malformed, err.to_string()),
+                        None,
+                    ),
                 }
             }
+            StreamMessage::Started(_) => (),
+            StreamMessage::Finished(_) => self.stop().await,
         }
     }
 }
@@ -140,29 +309,42 @@ mod tests {
         WsMessage::Binary(Bytes::from(json))
     }
 
+    fn message(frame: InboundFrame) -> CrabbyWsFromClient {
+        match frame {
+            InboundFrame::Message(msg) => msg,
+            other => panic!("Expected a client message, got {:?}", other),
+        }
+    }
+
     #[test]
     fn decode_valid_binary_user_message() {
-        let user_id = Uuid::from_u128(1);
         let dest_id = Uuid::from_u128(2);
         let original = CrabbyWsFromClient::UserMessage {
-            user_id,
+            client_msg_id: "c1".to_string(),
             dest: Destination::Individual { id: dest_id },
-            timestamp: "2026-03-01T12:00:00Z".to_string(),
             contents: "test message".to_string(),
+            client_sent_at: Some("2026-03-01T12:00:00Z".to_string()),
+            reply_to: None,
+            thread_root: None,
+            mentions: Vec::new(),
         };
         let ws_msg = make_binary_ws_message(&original);
         let decoded =
             <IncomingWebsocketActor as Decode<WsMessage>>::decode(ws_msg);
         assert!(decoded.is_ok());
-        match decoded.unwrap() {
+        match message(decoded.unwrap()) {
             CrabbyWsFromClient::UserMessage {
-                user_id: uid,
                 contents,
+                client_sent_at,
                 ..
             } => {
-                assert_eq!(uid, user_id);
                 assert_eq!(contents, "test message");
+                assert_eq!(
+                    client_sent_at.as_deref(),
+                    Some("2026-03-01T12:00:00Z")
+                );
             }
+            other => panic!("Expected UserMessage, got {:?}", other),
         }
     }
 
@@ -174,6 +356,24 @@ mod tests {
         assert!(decoded.is_err());
     }
 
+    #[test]
+    fn decode_ignores_client_supplied_identity() {
+        //Older clients still send their own user id and timestamp, those
+        // are dropped rather than trusted
+        let ws_msg = WsMessage::Binary(Bytes::from_static(
+            br#"{"type":"UserMessage","client_msg_id":"c1","user_id":"00000000-0000-0000-0000-000000000009","timestamp":"2026-03-01T12:00:00Z","dest":{"type":"Individual","id":"00000000-0000-0000-0000-000000000002"},"contents":"hi"}"#,
+        ));
+        let decoded =
+            <IncomingWebsocketActor as Decode<WsMessage>>::decode(ws_msg)
+                .unwrap();
+        match message(decoded) {
+            CrabbyWsFromClient::UserMessage { client_sent_at, .. } => {
+                assert_eq!(client_sent_at, None)
+            }
+            other => panic!("Expected UserMessage, got {:?}", other),
+        }
+    }
+
     #[test]
     fn decode_empty_binary_returns_error() {
         let ws_msg = WsMessage::Binary(Bytes::from_static(b""));
@@ -193,70 +393,138 @@ mod tests {
     }
 
     #[test]
-    #[should_panic(expected = "not implemented")]
-    fn decode_text_message_panics() {
+    fn decode_text_message_as_json() {
+        let ws_msg = WsMessage::Text(r#"{"type":"Ack","up_to":3}"#.into());
+        let decoded =
+            <IncomingWebsocketActor as Decode<WsMessage>>::decode(ws_msg)
+                .unwrap();
+        assert!(matches!(
+            decoded,
+            InboundFrame::Message(CrabbyWsFromClient::Ack { up_to: 3 })
+        ));
+    }
+
+    #[test]
+    fn decode_invalid_text_returns_error() {
         let ws_msg = WsMessage::Text("some text".into());
-        let _ = <IncomingWebsocketActor as Decode<WsMessage>>::decode(ws_msg);
+        let decoded =
+            <IncomingWebsocketActor as Decode<WsMessage>>::decode(ws_msg);
+        assert!(decoded.is_err());
     }
 
     #[test]
-    #[should_panic(expected = "not implemented")]
-    fn decode_ping_panics() {
+    fn decode_ping() {
         let ws_msg = WsMessage::Ping(Bytes::from_static(b"ping"));
-        let _ = <IncomingWebsocketActor as Decode<WsMessage>>::decode(ws_msg);
+        let decoded =
+            <IncomingWebsocketActor as Decode<WsMessage>>::decode(ws_msg)
+                .unwrap();
+        assert!(matches!(decoded, InboundFrame::Ping));
     }
 
     #[test]
-    #[should_panic(expected = "not implemented")]
-    fn decode_pong_panics() {
+    fn decode_pong() {
         let ws_msg = WsMessage::Pong(Bytes::from_static(b"pong"));
-        let _ = <IncomingWebsocketActor as Decode<WsMessage>>::decode(ws_msg);
+        let decoded =
+            <IncomingWebsocketActor as Decode<WsMessage>>::decode(ws_msg)
+                .unwrap();
+        assert!(matches!(decoded, InboundFrame::Pong));
     }
 
     #[test]
-    #[should_panic(expected = "not implemented")]
-    fn decode_close_panics() {
+    fn decode_close() {
         let ws_msg = WsMessage::Close(None);
-        let _ = <IncomingWebsocketActor as Decode<WsMessage>>::decode(ws_msg);
+        let decoded =
+            <IncomingWebsocketActor as Decode<WsMessage>>::decode(ws_msg)
+                .unwrap();
+        assert!(matches!(decoded, InboundFrame::Close));
     }
 
     #[test]
     fn decode_preserves_group_destination() {
         let group_id = Uuid::from_u128(999);
         let original = CrabbyWsFromClient::UserMessage {
-            user_id: Uuid::nil(),
+            client_msg_id: "c1".to_string(),
             dest: Destination::Group { id: group_id },
-            timestamp: String::new(),
             contents: String::new(),
+            client_sent_at: None,
+            reply_to: None,
+            thread_root: None,
+            mentions: Vec::new(),
         };
         let ws_msg = make_binary_ws_message(&original);
         let decoded =
             <IncomingWebsocketActor as Decode<WsMessage>>::decode(ws_msg)
                 .unwrap();
-        match decoded {
+        match message(decoded) {
             CrabbyWsFromClient::UserMessage { dest, .. } => match dest {
                 Destination::Group { id } => assert_eq!(id, group_id),
                 _ => panic!("Expected Group destination"),
             },
+            other => panic!("Expected UserMessage, got {:?}", other),
         }
     }
 
+    #[test]
+    fn decode_fetch_history_without_cursor() {
+        let ws_msg = WsMessage::Binary(Bytes::from_static(
+            br#"{"type":"FetchHistory","dest":{"type":"Group","id":"00000000-0000-0000-0000-000000000009"},"limit":50}"#,
+        ));
+        let decoded =
+            <IncomingWebsocketActor as Decode<WsMessage>>::decode(ws_msg)
+                .unwrap();
+        match message(decoded) {
+            CrabbyWsFromClient::FetchHistory { before, limit, .. } => {
+                assert_eq!(before, None);
+                assert_eq!(limit, 50);
+            }
+            other => panic!("Expected FetchHistory, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn decode_user_message_without_client_msg_id_returns_error() {
+        let ws_msg = WsMessage::Binary(Bytes::from_static(
+            br#"{"type":"UserMessage","dest":{"type":"Group","id":"00000000-0000-0000-0000-000000000009"},"contents":"hi"}"#,
+        ));
+        let decoded =
+            <IncomingWebsocketActor as Decode<WsMessage>>::decode(ws_msg);
+        assert!(decoded.is_err());
+    }
+
+    #[test]
+    fn decode_ack() {
+        let ws_msg = WsMessage::Binary(Bytes::from_static(
+            br#"{"type":"Ack","up_to":7}"#,
+        ));
+        let decoded =
+            <IncomingWebsocketActor as Decode<WsMessage>>::decode(ws_msg)
+                .unwrap();
+        assert!(matches!(
+            decoded,
+            InboundFrame::Message(CrabbyWsFromClient::Ack { up_to: 7 })
+        ));
+    }
+
     #[test]
     fn decode_unicode_contents() {
         let original = CrabbyWsFromClient::UserMessage {
-            user_id: Uuid::nil(),
+            client_msg_id: "c1".to_string(),
             dest: Destination::Individual { id: Uuid::nil() },
-            timestamp: String::new(),
             contents: "🦀 crabs are chatty 日本語".to_string(),
+            client_sent_at: None,
+            reply_to: None,
+            thread_root: None,
+            mentions: Vec::new(),
         };
         let ws_msg = make_binary_ws_message(&original);
         let decoded =
             <IncomingWebsocketActor as Decode<WsMessage>>::decode(ws_msg)
                 .unwrap();
-        match decoded {
+        match message(decoded) {
             CrabbyWsFromClient::UserMessage { contents, .. } => {
                 assert_eq!(contents, "🦀 crabs are chatty 日本語");
             }
+            other => panic!("Expected UserMessage, got {:?}", other),
         }
     }
 }
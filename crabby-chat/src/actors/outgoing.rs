@@ -1,27 +1,70 @@
 use crate::{
     actors::{
-        converter::outgoing::{Encode, ServerToTransport},
+        converter::outgoing::{ControlFrame, Encode, ServerToTransport},
         engine::EngineActor,
     },
-    messages::internal::UserConnected,
+    config::HeartbeatConfig,
+    handshake::Negotiated,
+    liveness::Liveness,
+    messages::internal::{
+        ConnectionControl, Heartbeat, ParkSession, UserConnected,
+        UserDisconnected,
+    },
+    queue::OutboundQueue,
+    replay::ReplayBuffer,
 };
-use axum::{extract::ws::Message as WsMessage, extract::ws::WebSocket};
-use crabby_specs::ws::outgoing::CrabbyWsFromServer;
+use axum::extract::ws::{Message as WsMessage, WebSocket, close_code};
+use crabby_specs::ws::outgoing::{CrabbyWsFromServer, ServerFrame};
+use eyre::eyre;
 use futures::{Sink, SinkExt, stream::SplitSink};
-use kameo::{Actor, actor::ActorRef, error::Infallible, prelude::Message};
-use std::marker::PhantomData;
+use kameo::{
+    Actor,
+    actor::{ActorId, ActorRef, WeakActorRef},
+    error::{ActorStopReason, Infallible},
+    prelude::Message,
+};
+use std::{marker::PhantomData, ops::ControlFlow};
+use tracing::warn;
 use uuid::Uuid;
+
+///Close reason sent to a client that stopped answering pings
+pub const HEARTBEAT_TIMEOUT: &str = "heartbeat timeout";
+///Close code sent to a client that fell too far behind its outbound queue
+pub const SLOW_CONSUMER_CODE: u16 = 4008;
+pub const SLOW_CONSUMER: &str = "slow consumer";
+///Close code sent to a client that kept sending over its rate limit
+pub const RATE_LIMITED_CODE: u16 = 4029;
+pub const RATE_LIMITED: &str = "rate limited";
+
 pub struct OutgoingMessageActor<S, I, C>
 where
     S: Sink<I> + Send + Sync + 'static,
     I: Send + Sync + 'static,
-    C: Encode<CrabbyWsFromServer, Output = I> + Send + Sync + 'static,
+    C: Encode<ServerFrame, Output = I>
+        + Encode<ControlFrame, Output = I>
+        + Send
+        + Sync
+        + 'static,
 {
     sink: S,
     _phantom: PhantomData<I>,
     engine: ActorRef<EngineActor>,
     converter: C,
     user_id: Uuid,
+    session_id: Uuid,
+    buffer: ReplayBuffer,
+    //Last sequence number the client saw before reconnecting, set when
+    // an existing session is resumed
+    resumed_from: Option<u64>,
+    //Set when the client closed the session itself, nothing is kept for
+    // it to resume
+    closed: bool,
+    //Everything the engine sends this connection comes through here
+    queue: OutboundQueue,
+    liveness: Liveness,
+    heartbeat: HeartbeatConfig,
+    //What the client settled on in its `Hello`, answered with `Welcome`
+    negotiated: Option<Negotiated>,
 }
 pub type OutgoingWebsocketActor = OutgoingMessageActor<
     SplitSink<WebSocket, WsMessage>,
@@ -40,54 +83,529 @@ impl
         sink: SplitSink<WebSocket, WsMessage>,
         engine_ref: ActorRef<EngineActor>,
         user_id: Uuid,
+        session_id: Uuid,
+        queue: OutboundQueue,
+        liveness: Liveness,
+        heartbeat: HeartbeatConfig,
     ) -> Self {
         Self {
             sink,
             engine: engine_ref,
             converter: ServerToTransport,
             user_id,
+            session_id,
+            buffer: ReplayBuffer::default(),
+            resumed_from: None,
+            closed: false,
+            queue,
+            liveness,
+            heartbeat,
+            negotiated: None,
             _phantom: PhantomData,
         }
     }
 }
+impl<S, I, C> OutgoingMessageActor<S, I, C>
+where
+    S: SinkExt<I> + Send + Sync + 'static + futures::Sink<I> + Unpin,
+    I: Send + Sync + 'static,
+    C: Encode<ServerFrame, Output = I>
+        + Encode<ControlFrame, Output = I>
+        + Send
+        + Sync,
+{
+    ///Continues a parked session, every frame after `last_seq` is
+    /// replayed once the actor starts
+    pub fn resume(mut self, buffer: ReplayBuffer, last_seq: u64) -> Self {
+        self.buffer = buffer;
+        self.resumed_from = Some(last_seq);
+        self
+    }
+    ///Answers the client's handshake before anything else is sent
+    pub fn welcome(mut self, negotiated: Negotiated) -> Self {
+        self.negotiated = Some(negotiated);
+        self
+    }
+    async fn write(&mut self, frame: ServerFrame) -> eyre::Result<()> {
+        let encoded = <C as Encode<ServerFrame>>::encode(frame)?;
+        self.send(encoded).await
+    }
+    async fn write_control(&mut self, frame: ControlFrame) -> eyre::Result<()> {
+        let encoded = <C as Encode<ControlFrame>>::encode(frame)?;
+        self.send(encoded).await
+    }
+    async fn send(&mut self, item: I) -> eyre::Result<()> {
+        self.sink
+            .send(item)
+            .await
+            .map_err(|_| eyre!("websocket sink closed"))
+    }
+}
 
 impl<S, I, C> Actor for OutgoingMessageActor<S, I, C>
 where
     S: SinkExt<I> + Send + Sync + 'static + futures::Sink<I> + Unpin,
     I: Send + Sync + 'static,
-    C: Encode<CrabbyWsFromServer, Output = I> + Send + Sync,
+    C: Encode<ServerFrame, Output = I>
+        + Encode<ControlFrame, Output = I>
+        + Send
+        + Sync,
 {
     type Args = Self;
 
     type Error = Infallible;
 
     async fn on_start(
-        args: Self::Args,
+        mut args: Self::Args,
         actor_ref: kameo::prelude::ActorRef<Self>,
     ) -> Result<Self, Self::Error> {
+        if let Some(negotiated) = args.negotiated.take() {
+            let heartbeat_interval = args.heartbeat.interval.as_millis() as u64;
+            let welcome = ServerFrame {
+                seq: None,
+                message: CrabbyWsFromServer::Welcome {
+                    session_id: args.session_id,
+                    server_version: env!("CARGO_PKG_VERSION").to_string(),
+                    negotiated_features: negotiated.features,
+                    heartbeat_interval,
+                },
+            };
+            let _ = args.write(welcome).await;
+        }
+        let started = ServerFrame {
+            seq: None,
+            message: CrabbyWsFromServer::SessionStarted {
+                session_id: args.session_id,
+                resumed: args.resumed_from.is_some(),
+            },
+        };
+        let _ = args.write(started).await;
+        //Replay before registering so resent frames go out ahead of any
+        // new traffic
+        if let Some(last_seq) = args.resumed_from {
+            args.buffer.ack(last_seq);
+            let pending: Vec<_> = args.buffer.pending().cloned().collect();
+            for frame in pending {
+                if args.write(frame).await.is_err() {
+                    break;
+                }
+            }
+        }
         let _ = args
             .engine
-            .tell(UserConnected(args.user_id, actor_ref.clone().recipient()))
+            .tell(UserConnected {
+                user_id: args.user_id,
+                session_id: args.session_id,
+                queue: args.queue.clone(),
+                liveness: args.liveness.clone(),
+                resumed: args.resumed_from.is_some(),
+            })
             .await;
+        //Only the pump waits on a slow socket, the engine just queues
+        let queue = args.queue.clone();
+        let weak = actor_ref.downgrade();
+        tokio::spawn(async move {
+            while let Some(msg) = queue.pop().await {
+                let Some(actor_ref) = weak.upgrade() else {
+                    return;
+                };
+                if actor_ref.ask(msg).await.is_err() {
+                    return;
+                }
+            }
+            if queue.overflowed()
+                && let Some(actor_ref) = weak.upgrade()
+            {
+                let _ = actor_ref.tell(ConnectionControl::Overflowed).await;
+            }
+        });
+        //The ticker only holds a weak reference, it ends with the actor
+        let weak = actor_ref.downgrade();
+        let interval = args.heartbeat.interval;
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            //The first tick completes right away
+            ticker.tick().await;
+            loop {
+                ticker.tick().await;
+                let Some(actor_ref) = weak.upgrade() else {
+                    break;
+                };
+                if actor_ref.tell(Heartbeat).await.is_err() {
+                    break;
+                }
+            }
+        });
         Ok(args)
     }
+
+    async fn on_stop(
+        &mut self,
+        _actor_ref: WeakActorRef<Self>,
+        _reason: ActorStopReason,
+    ) -> Result<(), Self::Error> {
+        let backlog = self.queue.close();
+        //What came in after the queue overflowed is gone, a resumed
+        // session would not show the gap, so the client starts afresh
+        let lost = self.queue.overflowed() && self.queue.dropped() > 0;
+        if self.closed || lost {
+            let _ = self
+                .engine
+                .tell(UserDisconnected {
+                    user_id: self.user_id,
+                    session_id: self.session_id,
+                })
+                .await;
+            return Ok(());
+        }
+        //Whatever the client had not been sent yet is replayed on resume
+        for msg in backlog {
+            self.buffer.push(msg);
+        }
+        let _ = self
+            .engine
+            .tell(ParkSession {
+                user_id: self.user_id,
+                session_id: self.session_id,
+                buffer: std::mem::take(&mut self.buffer),
+            })
+            .await;
+        Ok(())
+    }
+
+    //Linked to the incoming actor of the same connection, neither half is
+    // any use without the other
+    async fn on_link_died(
+        &mut self,
+        _actor_ref: WeakActorRef<Self>,
+        id: ActorId,
+        reason: ActorStopReason,
+    ) -> Result<ControlFlow<ActorStopReason>, Self::Error> {
+        Ok(ControlFlow::Break(ActorStopReason::LinkDied {
+            id,
+            reason: Box::new(reason),
+        }))
+    }
 }
 
 impl<S, I, C> Message<CrabbyWsFromServer> for OutgoingMessageActor<S, I, C>
 where
     S: SinkExt<I> + Send + Sync + 'static + futures::Sink<I> + Unpin,
     I: Send + Sync + 'static,
-    C: Encode<CrabbyWsFromServer, Output = I> + Send + Sync,
+    C: Encode<ServerFrame, Output = I>
+        + Encode<ControlFrame, Output = I>
+        + Send
+        + Sync,
 {
     type Reply = ();
 
     async fn handle(
         &mut self,
         msg: CrabbyWsFromServer,
-        _ctx: &mut kameo::prelude::Context<Self, Self::Reply>,
+        ctx: &mut kameo::prelude::Context<Self, Self::Reply>,
+    ) -> Self::Reply {
+        //The frame stays buffered whether or not the write goes through
+        let frame = self.buffer.push(msg);
+        if let Err(err) = self.write(frame).await {
+            warn!("session {} lost its connection: {err}", self.session_id);
+            let _ = ctx.actor_ref().stop_gracefully().await;
+        }
+    }
+}
+impl<S, I, C> Message<ConnectionControl> for OutgoingMessageActor<S, I, C>
+where
+    S: SinkExt<I> + Send + Sync + 'static + futures::Sink<I> + Unpin,
+    I: Send + Sync + 'static,
+    C: Encode<ServerFrame, Output = I>
+        + Encode<ControlFrame, Output = I>
+        + Send
+        + Sync,
+{
+    type Reply = ();
+
+    async fn handle(
+        &mut self,
+        msg: ConnectionControl,
+        ctx: &mut kameo::prelude::Context<Self, Self::Reply>,
     ) -> Self::Reply {
-        if let Ok(encoded) = C::encode(msg) {
-            let _ = self.sink.send(encoded).await;
+        match msg {
+            ConnectionControl::Acked(up_to) => self.buffer.ack(up_to),
+            ConnectionControl::Close => {
+                self.closed = true;
+                let _ = ctx.actor_ref().stop_gracefully().await;
+            }
+            ConnectionControl::Overflowed => {
+                warn!(
+                    "session {} has {} queued messages, closing it",
+                    self.session_id,
+                    self.queue.depth()
+                );
+                let _ = self
+                    .write_control(ControlFrame::Close {
+                        code: SLOW_CONSUMER_CODE,
+                        reason: SLOW_CONSUMER.to_string(),
+                    })
+                    .await;
+                let _ = ctx.actor_ref().stop_gracefully().await;
+            }
+            ConnectionControl::RateLimited => {
+                //Not kept for a resume, the client would only pick up
+                // where it was cut off
+                self.closed = true;
+                let _ = self
+                    .write_control(ControlFrame::Close {
+                        code: RATE_LIMITED_CODE,
+                        reason: RATE_LIMITED.to_string(),
+                    })
+                    .await;
+                let _ = ctx.actor_ref().stop_gracefully().await;
+            }
         }
     }
 }
+
+impl<S, I, C> Message<Heartbeat> for OutgoingMessageActor<S, I, C>
+where
+    S: SinkExt<I> + Send + Sync + 'static + futures::Sink<I> + Unpin,
+    I: Send + Sync + 'static,
+    C: Encode<ServerFrame, Output = I>
+        + Encode<ControlFrame, Output = I>
+        + Send
+        + Sync,
+{
+    type Reply = ();
+
+    async fn handle(
+        &mut self,
+        _msg: Heartbeat,
+        ctx: &mut kameo::prelude::Context<Self, Self::Reply>,
+    ) -> Self::Reply {
+        let missed = self.liveness.ping_sent();
+        if missed >= self.heartbeat.max_missed {
+            warn!(
+                "session {} missed {missed} heartbeats, closing it",
+                self.session_id
+            );
+            //The session is parked on stop so the client can still resume
+            let _ = self
+                .write_control(ControlFrame::Close {
+                    code: close_code::AWAY,
+                    reason: HEARTBEAT_TIMEOUT.to_string(),
+                })
+                .await;
+            let _ = ctx.actor_ref().stop_gracefully().await;
+        } else if self.write_control(ControlFrame::Ping).await.is_err() {
+            let _ = ctx.actor_ref().stop_gracefully().await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crabby_specs::ws::common::Destination;
+    use crabby_transport::memory::InMemoryTransport;
+    use futures::{
+        StreamExt,
+        channel::mpsc::{UnboundedReceiver, UnboundedSender, unbounded},
+    };
+    use kameo::actor::Spawn;
+    use std::{sync::Arc, time::Duration};
+
+    use super::*;
+    use crate::{
+        cluster::{Cluster, TransportBus},
+        config::{QueueConfig, SlowConsumerPolicy},
+        groups::InMemoryGroups,
+        id::{IdGenerator, NoOpIdGeneratorImpl},
+        sessions::SessionRegistry,
+        store::{InMemoryMessageRepo, MessageStore},
+        users::InMemoryUsers,
+    };
+
+    type TestOutgoing = OutgoingMessageActor<
+        UnboundedSender<WsMessage>,
+        WsMessage,
+        ServerToTransport,
+    >;
+
+    fn spawn_engine() -> ActorRef<EngineActor> {
+        EngineActor::spawn(EngineActor::new(
+            SessionRegistry::default(),
+            IdGenerator::new(NoOpIdGeneratorImpl),
+            Arc::new(InMemoryGroups::default()),
+            Arc::new(InMemoryUsers::default()),
+            MessageStore::new(InMemoryMessageRepo::default()),
+            Cluster::new(
+                "node",
+                TransportBus::new(InMemoryTransport::default()),
+            ),
+        ))
+    }
+
+    fn outgoing(
+        engine: &ActorRef<EngineActor>,
+    ) -> (TestOutgoing, UnboundedReceiver<WsMessage>) {
+        let (sink, rx) = unbounded();
+        let actor = OutgoingMessageActor {
+            sink,
+            _phantom: PhantomData,
+            engine: engine.clone(),
+            converter: ServerToTransport,
+            user_id: Uuid::from_u128(1),
+            session_id: Uuid::from_u128(50),
+            buffer: ReplayBuffer::default(),
+            resumed_from: None,
+            closed: false,
+            queue: OutboundQueue::new(QueueConfig::default()),
+            liveness: Liveness::new(),
+            heartbeat: HeartbeatConfig::default(),
+            negotiated: None,
+        };
+        (actor, rx)
+    }
+
+    fn message(id: u64) -> CrabbyWsFromServer {
+        CrabbyWsFromServer::ChatMessage {
+            message_id: id,
+            user_id: Uuid::nil(),
+            dest: Destination::Individual { id: Uuid::nil() },
+            timestamp: String::new(),
+            contents: String::new(),
+            client_sent_at: None,
+            reply_to: None,
+            thread_root: None,
+            mentions: Vec::new(),
+            mentions_group: false,
+        }
+    }
+
+    async fn next_frame(rx: &mut UnboundedReceiver<WsMessage>) -> ServerFrame {
+        match rx.next().await {
+            Some(WsMessage::Binary(bytes)) => {
+                serde_json::from_slice(&bytes).unwrap()
+            }
+            other => panic!("expected a binary frame, got {other:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn frames_are_sequenced_after_session_started() {
+        let engine = spawn_engine();
+        let (actor, mut rx) = outgoing(&engine);
+        let actor_ref = TestOutgoing::spawn(actor);
+
+        let started = next_frame(&mut rx).await;
+        assert_eq!(started.seq, None);
+        assert!(matches!(
+            started.message,
+            CrabbyWsFromServer::SessionStarted { resumed: false, .. }
+        ));
+        actor_ref.ask(message(7)).await.unwrap();
+        actor_ref.ask(message(8)).await.unwrap();
+        assert_eq!(next_frame(&mut rx).await.seq, Some(1));
+        assert_eq!(next_frame(&mut rx).await.seq, Some(2));
+    }
+
+    #[tokio::test]
+    async fn welcome_goes_out_ahead_of_session_started() {
+        let engine = spawn_engine();
+        let (actor, mut rx) = outgoing(&engine);
+        let negotiated = Negotiated {
+            protocol_version: 1,
+            client_name: "test".to_string(),
+            features: vec!["resume".to_string()],
+        };
+        let _actor_ref = TestOutgoing::spawn(actor.welcome(negotiated));
+
+        let welcome = next_frame(&mut rx).await;
+        assert_eq!(welcome.seq, None);
+        match welcome.message {
+            CrabbyWsFromServer::Welcome {
+                session_id,
+                negotiated_features,
+                heartbeat_interval,
+                ..
+            } => {
+                assert_eq!(session_id, Uuid::from_u128(50));
+                assert_eq!(negotiated_features, vec!["resume"]);
+                assert_eq!(heartbeat_interval, 30_000);
+            }
+            other => panic!("expected a welcome, got {other:?}"),
+        }
+        assert!(matches!(
+            next_frame(&mut rx).await.message,
+            CrabbyWsFromServer::SessionStarted { .. }
+        ));
+    }
+
+    #[tokio::test]
+    async fn resume_replays_only_frames_after_last_seen() {
+        let engine = spawn_engine();
+        let mut buffer = ReplayBuffer::default();
+        for id in 0..3 {
+            buffer.push(message(id));
+        }
+        let (actor, mut rx) = outgoing(&engine);
+        let _actor_ref = TestOutgoing::spawn(actor.resume(buffer, 1));
+
+        let started = next_frame(&mut rx).await;
+        assert!(matches!(
+            started.message,
+            CrabbyWsFromServer::SessionStarted { resumed: true, .. }
+        ));
+        assert_eq!(next_frame(&mut rx).await.seq, Some(2));
+        assert_eq!(next_frame(&mut rx).await.seq, Some(3));
+    }
+
+    #[tokio::test]
+    async fn missed_heartbeats_close_the_connection() {
+        let engine = spawn_engine();
+        let (mut actor, mut rx) = outgoing(&engine);
+        actor.heartbeat = HeartbeatConfig {
+            interval: Duration::from_millis(10),
+            max_missed: 2,
+        };
+        let _actor_ref = TestOutgoing::spawn(actor);
+
+        let mut pings = 0;
+        let reason = loop {
+            let frame = tokio::time::timeout(Duration::from_secs(1), rx.next())
+                .await
+                .expect("connection should be closed");
+            match frame {
+                Some(WsMessage::Ping(_)) => pings += 1,
+                Some(WsMessage::Binary(_)) => (),
+                Some(WsMessage::Close(Some(frame))) => break frame.reason,
+                other => panic!("unexpected frame {other:?}"),
+            }
+        };
+        assert_eq!(pings, 2);
+        assert_eq!(reason.as_str(), HEARTBEAT_TIMEOUT);
+    }
+
+    #[tokio::test]
+    async fn overflowing_queue_closes_as_slow_consumer() {
+        let engine = spawn_engine();
+        let (mut actor, mut rx) = outgoing(&engine);
+        let queue = OutboundQueue::new(QueueConfig {
+            capacity: 1,
+            policy: SlowConsumerPolicy::Disconnect,
+        });
+        actor.queue = queue.clone();
+        queue.push(message(1));
+        queue.push(message(2));
+        let _actor_ref = TestOutgoing::spawn(actor);
+
+        let code = loop {
+            let frame = tokio::time::timeout(Duration::from_secs(1), rx.next())
+                .await
+                .expect("connection should be closed");
+            match frame {
+                Some(WsMessage::Binary(_)) => (),
+                Some(WsMessage::Close(Some(frame))) => break frame.code,
+                other => panic!("unexpected frame {other:?}"),
+            }
+        };
+        assert_eq!(code, SLOW_CONSUMER_CODE);
+    }
+}
@@ -0,0 +1,389 @@
+use std::{
+    str::FromStr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use axum::{
+    extract::{FromRef, FromRequestParts},
+    http::{HeaderMap, header::AUTHORIZATION, request::Parts},
+};
+use crabby_core::tokens::{KeyRetrieval, VerifyToken};
+use eyre::{Result, eyre};
+use hashbrown::HashMap;
+use pasetors::{
+    Public,
+    claims::ClaimsValidationRules,
+    footer::Footer,
+    keys::AsymmetricPublicKey,
+    paserk::Id,
+    public,
+    token::{TrustedToken, UntrustedToken},
+    version4::V4,
+};
+use tokio::sync::{Mutex, RwLock};
+use tonic::{
+    Code,
+    transport::{Channel, Endpoint},
+};
+use tracing::warn;
+use uuid::Uuid;
+
+use crate::error::AuthError;
+
+pub mod proto {
+    tonic::include_proto!("authentication");
+}
+use proto::{PublicKeyRequest, authenticate_client::AuthenticateClient};
+
+///How long a key id crabby-auth did not have a key for is refused
+/// without asking again
+const UNKNOWN_KEY_TTL: Duration = Duration::from_secs(60);
+///Least time between fetches once one came back without a key, so
+/// tokens with made up key ids cannot flood crabby-auth
+const MIN_REFETCH_INTERVAL: Duration = Duration::from_secs(1);
+
+///Fetches crabby-auth's public keys over gRPC, keyed by the PASERK id
+/// found in the token footer. Keys are cached once they have been
+/// fetched and checked against the id they were requested with.
+pub struct AuthServiceKeys {
+    client: AuthenticateClient<Channel>,
+    //Keys are stored as raw bytes and rebuilt on retrieval
+    cache: RwLock<HashMap<String, Vec<u8>>>,
+    misses: Mutex<KeyMisses>,
+}
+impl AuthServiceKeys {
+    ///The connection is lazy so the chat service can start before
+    /// crabby-auth is reachable
+    pub fn new(addr: String) -> Result<Self> {
+        let channel = Endpoint::from_shared(addr)?.connect_lazy();
+        Ok(Self {
+            client: AuthenticateClient::new(channel),
+            cache: RwLock::new(HashMap::new()),
+            misses: Mutex::new(KeyMisses::default()),
+        })
+    }
+    ///Asks crabby-auth for the key `kid` names
+    async fn fetch(
+        &self,
+        kid: &str,
+        id: &Id,
+    ) -> Result<AsymmetricPublicKey<V4>> {
+        let response = self
+            .client
+            .clone()
+            .public_key(PublicKeyRequest {
+                req: kid.to_string(),
+            })
+            .await?
+            .into_inner();
+        let key =
+            AsymmetricPublicKey::<V4>::try_from(response.paserk.as_str())?;
+        //Never trust a key that does not hash to the id we asked for
+        if Id::from(&key) != *id {
+            return Err(eyre!("public key does not match key id {kid}"));
+        }
+        Ok(key)
+    }
+}
+impl KeyRetrieval<AsymmetricPublicKey<V4>> for AuthServiceKeys {
+    async fn get_key(&self, kid: &str) -> Result<AsymmetricPublicKey<V4>> {
+        if let Some(bytes) = self.cache.read().await.get(kid) {
+            return Ok(AsymmetricPublicKey::from(bytes.as_slice())?);
+        }
+        //Something that is not a key id at all is never worth a fetch
+        let id = Id::try_from(kid)?;
+        self.misses.lock().await.check(kid, Instant::now())?;
+        let key = match self.fetch(kid, &id).await {
+            Ok(key) => key,
+            Err(err) => {
+                //Only an answer from crabby-auth says the key does not
+                // exist, an outage just holds back the next fetch
+                let unreachable = err
+                    .downcast_ref::<tonic::Status>()
+                    .is_some_and(|status| status.code() == Code::Unavailable);
+                self.misses.lock().await.record(
+                    kid,
+                    !unreachable,
+                    Instant::now(),
+                );
+                return Err(err);
+            }
+        };
+        self.cache
+            .write()
+            .await
+            .insert(kid.to_string(), key.as_bytes().to_vec());
+        Ok(key)
+    }
+}
+
+///Key ids that recently came back without a usable key
+#[derive(Default)]
+struct KeyMisses {
+    unknown: HashMap<String, Instant>,
+    last: Option<Instant>,
+}
+impl KeyMisses {
+    ///Refuses a fetch of `kid` while it is known to have no key, or too
+    /// soon after the last fetch that missed
+    fn check(&self, kid: &str, now: Instant) -> Result<()> {
+        if self
+            .unknown
+            .get(kid)
+            .is_some_and(|&at| now.duration_since(at) < UNKNOWN_KEY_TTL)
+        {
+            return Err(eyre!("no public key for key id {kid}"));
+        }
+        if self
+            .last
+            .is_some_and(|at| now.duration_since(at) < MIN_REFETCH_INTERVAL)
+        {
+            return Err(eyre!("not fetching key id {kid} yet"));
+        }
+        Ok(())
+    }
+    ///Notes a fetch of `kid` that missed, remembering the id itself only
+    /// if crabby-auth said it has no such key
+    fn record(&mut self, kid: &str, unknown: bool, now: Instant) {
+        self.last = Some(now);
+        if unknown {
+            self.unknown
+                .retain(|_, at| now.duration_since(*at) < UNKNOWN_KEY_TTL);
+            self.unknown.insert(kid.to_string(), now);
+        }
+    }
+}
+
+///Verifies v4.public bearer tokens issued by crabby-auth
+pub struct TokenVerifier<R>
+where
+    R: KeyRetrieval<AsymmetricPublicKey<V4>>,
+{
+    keys: R,
+    rules: ClaimsValidationRules,
+}
+impl<R> TokenVerifier<R>
+where
+    R: KeyRetrieval<AsymmetricPublicKey<V4>>,
+{
+    pub fn new(keys: R, issuer: &str, audience: &str) -> Self {
+        let mut rules = ClaimsValidationRules::new();
+        rules.validate_issuer_with(issuer);
+        rules.validate_audience_with(audience);
+        Self { keys, rules }
+    }
+    ///Verifies the token and returns the user it was issued to
+    pub async fn authenticate(&self, token: String) -> Result<Uuid> {
+        let trusted = self.verify(token).await?;
+        subject(&trusted)
+    }
+}
+impl<R> VerifyToken<AsymmetricPublicKey<V4>> for TokenVerifier<R>
+where
+    R: KeyRetrieval<AsymmetricPublicKey<V4>>,
+{
+    type Storage = R;
+
+    async fn verify(&self, token: String) -> Result<TrustedToken> {
+        let untrusted = UntrustedToken::<Public, V4>::try_from(token.as_str())?;
+        let mut footer = Footer::new();
+        footer.parse_bytes(untrusted.untrusted_footer())?;
+        let kid = footer
+            .get_claim("kid")
+            .and_then(|kid| kid.as_str())
+            .ok_or_else(|| eyre!("token footer has no key id"))?;
+        let key = self.keys.get_key(kid).await?;
+        let trusted =
+            public::verify(&key, &untrusted, &self.rules, Some(&footer), None)?;
+        Ok(trusted)
+    }
+}
+
+fn subject(token: &TrustedToken) -> Result<Uuid> {
+    let sub = token
+        .payload_claims()
+        .and_then(|claims| claims.get_claim("sub"))
+        .and_then(|sub| sub.as_str())
+        .ok_or_else(|| eyre!("token has no subject"))?;
+    Ok(Uuid::from_str(sub)?)
+}
+
+fn bearer_token(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(AUTHORIZATION)?
+        .to_str()
+        .ok()?
+        .strip_prefix("Bearer ")
+}
+
+pub type Verifier = Arc<TokenVerifier<AuthServiceKeys>>;
+
+///The user a request was authenticated as, taken from the `sub` claim
+/// of its bearer token
+#[derive(Debug, Clone, Copy)]
+pub struct AuthenticatedUser(pub Uuid);
+
+impl<S> FromRequestParts<S> for AuthenticatedUser
+where
+    Verifier: FromRef<S>,
+    S: Send + Sync,
+{
+    type Rejection = AuthError;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &S,
+    ) -> Result<Self, Self::Rejection> {
+        let token = bearer_token(&parts.headers)
+            .ok_or(AuthError::MissingToken)?
+            .to_string();
+        let verifier = Verifier::from_ref(state);
+        verifier
+            .authenticate(token)
+            .await
+            .map(AuthenticatedUser)
+            .map_err(|err| {
+                warn!("rejected bearer token: {err}");
+                AuthError::InvalidToken
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::HeaderValue;
+    use pasetors::{
+        claims::Claims,
+        keys::{AsymmetricKeyPair, Generate},
+    };
+
+    use super::*;
+
+    const ISSUER: &str = "crabby-auth";
+    const AUDIENCE: &str = "crabby-gateway";
+
+    /// Key store that only knows about a single key pair
+    struct StaticKeys {
+        kid: String,
+        key: AsymmetricPublicKey<V4>,
+    }
+    impl KeyRetrieval<AsymmetricPublicKey<V4>> for StaticKeys {
+        async fn get_key(&self, kid: &str) -> Result<AsymmetricPublicKey<V4>> {
+            if kid != self.kid {
+                return Err(eyre!("unknown key id"));
+            }
+            Ok(AsymmetricPublicKey::from(self.key.as_bytes())?)
+        }
+    }
+
+    fn kid_of(kp: &AsymmetricKeyPair<V4>) -> String {
+        let mut kid = String::new();
+        pasetors::paserk::FormatAsPaserk::fmt(&Id::from(&kp.public), &mut kid)
+            .unwrap();
+        kid
+    }
+
+    fn verifier(kp: &AsymmetricKeyPair<V4>) -> TokenVerifier<StaticKeys> {
+        let keys = StaticKeys {
+            kid: kid_of(kp),
+            key: AsymmetricPublicKey::from(kp.public.as_bytes()).unwrap(),
+        };
+        TokenVerifier::new(keys, ISSUER, AUDIENCE)
+    }
+
+    fn sign(kp: &AsymmetricKeyPair<V4>, claims: &Claims) -> String {
+        let mut footer = Footer::new();
+        footer.key_id(&Id::from(&kp.public));
+        public::sign(&kp.secret, claims, Some(&footer), None).unwrap()
+    }
+
+    fn claims(sub: &str, audience: &str) -> Claims {
+        let mut claims = Claims::new().unwrap();
+        claims.issuer(ISSUER).unwrap();
+        claims.audience(audience).unwrap();
+        claims.subject(sub).unwrap();
+        claims
+    }
+
+    #[tokio::test]
+    async fn valid_token_yields_subject() {
+        let kp = AsymmetricKeyPair::<V4>::generate().unwrap();
+        let user = Uuid::from_u128(42);
+        let token = sign(&kp, &claims(&user.to_string(), AUDIENCE));
+        let authenticated = verifier(&kp).authenticate(token).await.unwrap();
+        assert_eq!(authenticated, user);
+    }
+
+    #[tokio::test]
+    async fn wrong_audience_is_rejected() {
+        let kp = AsymmetricKeyPair::<V4>::generate().unwrap();
+        let token = sign(
+            &kp,
+            &claims(&Uuid::from_u128(42).to_string(), "crabby-auth"),
+        );
+        assert!(verifier(&kp).authenticate(token).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn expired_token_is_rejected() {
+        let kp = AsymmetricKeyPair::<V4>::generate().unwrap();
+        let mut claims = claims(&Uuid::from_u128(42).to_string(), AUDIENCE);
+        claims.issued_at("2020-01-01T00:00:00+00:00").unwrap();
+        claims.not_before("2020-01-01T00:00:00+00:00").unwrap();
+        claims.expiration("2020-01-01T00:15:00+00:00").unwrap();
+        let token = sign(&kp, &claims);
+        assert!(verifier(&kp).authenticate(token).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn token_signed_by_other_key_is_rejected() {
+        let kp = AsymmetricKeyPair::<V4>::generate().unwrap();
+        let other = AsymmetricKeyPair::<V4>::generate().unwrap();
+        let token =
+            sign(&other, &claims(&Uuid::from_u128(42).to_string(), AUDIENCE));
+        assert!(verifier(&kp).authenticate(token).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn non_uuid_subject_is_rejected() {
+        let kp = AsymmetricKeyPair::<V4>::generate().unwrap();
+        let token = sign(&kp, &claims("not-a-uuid", AUDIENCE));
+        assert!(verifier(&kp).authenticate(token).await.is_err());
+    }
+
+    #[test]
+    fn unknown_key_id_is_not_fetched_again_until_it_expires() {
+        let mut misses = KeyMisses::default();
+        let start = Instant::now();
+        misses.record("k4.pid.a", true, start);
+        let later = start + MIN_REFETCH_INTERVAL;
+        assert!(misses.check("k4.pid.a", later).is_err());
+        assert!(misses.check("k4.pid.b", later).is_ok());
+        assert!(misses.check("k4.pid.a", start + UNKNOWN_KEY_TTL).is_ok());
+    }
+
+    #[test]
+    fn fetches_are_held_back_after_a_miss() {
+        let mut misses = KeyMisses::default();
+        let start = Instant::now();
+        misses.record("k4.pid.a", false, start);
+        assert!(misses.check("k4.pid.b", start).is_err());
+        //An outage does not mark the key id itself as unknown
+        assert!(
+            misses
+                .check("k4.pid.a", start + MIN_REFETCH_INTERVAL)
+                .is_ok()
+        );
+    }
+
+    #[test]
+    fn bearer_token_requires_bearer_scheme() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(bearer_token(&headers), None);
+        headers.insert(AUTHORIZATION, HeaderValue::from_static("Basic abc"));
+        assert_eq!(bearer_token(&headers), None);
+        headers.insert(AUTHORIZATION, HeaderValue::from_static("Bearer abc"));
+        assert_eq!(bearer_token(&headers), Some("abc"));
+    }
+}
@@ -18,12 +18,18 @@ use tokio::{
 };
 use tracing::info;
 use uuid::{NoContext, Timestamp as UuidTimestamp, Uuid};
+///Version of the chat protocol this client speaks
+const PROTOCOL_VERSION: u32 = 1;
 #[tokio::main]
 async fn main() {
     let io = tokio::io::stdin();
     let mut reader = BufReader::new(io);
+    //Bearer token obtained from crabby-auth's Register or Refresh RPC
+    let token = std::env::var("CRABBY_TOKEN")
+        .expect("Set CRABBY_TOKEN to a bearer token");
     let connection = Client::default()
         .get("http://127.0.0.1:6969/ws")
+        .bearer_auth(token)
         .upgrade()
         .send()
         .await
@@ -33,11 +39,20 @@ async fn main() {
         .await
         .expect("could not upgrade websocket");
     let (mut sink, mut stream) = websocket.split();
+    //The server closes the connection unless it hears this first
+    let hello = CrabbyWsFromClient::Hello {
+        protocol_version: PROTOCOL_VERSION,
+        client_name: format!("crabby-client/{}", env!("CARGO_PKG_VERSION")),
+        capabilities: Vec::new(),
+    };
+    let hello = Bytes::from(serde_json::to_vec(&hello).unwrap());
+    sink.send(Message::Binary(hello))
+        .await
+        .expect("could not say hello");
 
     let _send = tokio::spawn(async move { incoming_messages(stream).await });
-    let id = id();
     let _recv =
-        tokio::spawn(async move { outgoing_message(sink, reader, &id).await });
+        tokio::spawn(async move { outgoing_message(sink, reader).await });
 
     select! {
         _ = _send =>return,
@@ -68,7 +83,6 @@ async fn incoming_messages(mut income: SplitStream<WebSocket>) {
 async fn outgoing_message(
     mut sink: SplitSink<WebSocket, Message>,
     mut reader: BufReader<Stdin>,
-    user_id: &Uuid,
 ) {
     let mut buf = String::new();
     while let Ok(read) = reader.read_line(&mut buf).await {
@@ -76,7 +90,7 @@ async fn outgoing_message(
             return;
         }
         println!("inside outgoing");
-        let message = message_from_str(user_id, buf);
+        let message = message_from_str(buf);
         let serialized = serde_json::to_vec_pretty(&message).unwrap();
         let bytes = Bytes::from(serialized);
         let blah = sink.send(Message::Binary(bytes)).await;
@@ -84,13 +98,16 @@ async fn outgoing_message(
     }
 }
 
-fn message_from_str(user_id: &Uuid, message: String) -> CrabbyWsFromClient {
+fn message_from_str(message: String) -> CrabbyWsFromClient {
     println!("{:?}", message);
     CrabbyWsFromClient::UserMessage {
-        user_id: *user_id,
+        client_msg_id: id().to_string(),
         dest: crabby_specs::ws::common::Destination::Individual { id: id() },
-        timestamp: Timestamp::now().to_string(),
         contents: message,
+        client_sent_at: Some(Timestamp::now().to_string()),
+        reply_to: None,
+        thread_root: None,
+        mentions: Vec::new(),
     }
 }
 
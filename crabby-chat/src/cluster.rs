@@ -0,0 +1,206 @@
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use crabby_specs::{
+    nats::channel::{
+        Delivery, PresenceEvent, PresenceUpdates, UserMessageDelivery,
+    },
+    ws::{common::PresenceStatus, outgoing::CrabbyWsFromServer},
+};
+use crabby_transport::{
+    publisher::Publisher, subscriber::Subscriber, transport::Transport,
+};
+use eyre::Result;
+use futures::{Stream, StreamExt, future::join_all, stream::BoxStream};
+use tokio::sync::mpsc::{
+    UnboundedReceiver, UnboundedSender, unbounded_channel,
+};
+use tracing::warn;
+use uuid::Uuid;
+
+///Carries deliveries between chat nodes on the `users.delivery.{user_id}`
+/// subjects, and presence on `presence.updates`.
+#[async_trait]
+pub trait DeliveryBus: Send + Sync + 'static {
+    async fn publish(&self, user_id: Uuid, delivery: Delivery) -> Result<()>;
+    ///Every delivery published for the user from now on, whichever node
+    /// published it. Dropping the stream ends the subscription.
+    async fn subscribe(
+        &self,
+        user_id: Uuid,
+    ) -> Result<BoxStream<'static, Result<Delivery>>>;
+    async fn announce(&self, event: PresenceEvent) -> Result<()>;
+    ///Every presence event published from now on, this node's included
+    async fn presence(
+        &self,
+    ) -> Result<BoxStream<'static, Result<PresenceEvent>>>;
+}
+
+///A `DeliveryBus` over any transport that can carry `UserMessageDelivery`
+/// and `PresenceUpdates`
+pub struct TransportBus<T> {
+    transport: T,
+}
+impl<T> TransportBus<T> {
+    pub fn new(transport: T) -> Self {
+        Self { transport }
+    }
+}
+#[async_trait]
+impl<T> DeliveryBus for TransportBus<T>
+where
+    T: Transport<UserMessageDelivery> + Transport<PresenceUpdates>,
+    <T as Transport<UserMessageDelivery>>::Publisher:
+        Publisher<UserMessageDelivery>,
+    <T as Transport<UserMessageDelivery>>::Subscriber:
+        Subscriber<UserMessageDelivery>,
+    <<T as Transport<UserMessageDelivery>>::Subscriber as Subscriber<
+        UserMessageDelivery,
+    >>::Stream: Stream<Item = Result<Delivery>> + Send + 'static,
+    <T as Transport<PresenceUpdates>>::Publisher: Publisher<PresenceUpdates>,
+    <T as Transport<PresenceUpdates>>::Subscriber: Subscriber<PresenceUpdates>,
+    <<T as Transport<PresenceUpdates>>::Subscriber as Subscriber<
+        PresenceUpdates,
+    >>::Stream: Stream<Item = Result<PresenceEvent>> + Send + 'static,
+{
+    async fn publish(&self, user_id: Uuid, delivery: Delivery) -> Result<()> {
+        let channel = UserMessageDelivery::new(&user_id.to_string());
+        let publisher = Transport::<UserMessageDelivery>::publisher(
+            &self.transport,
+            &channel,
+        )?;
+        publisher.publish(delivery).await
+    }
+
+    async fn subscribe(
+        &self,
+        user_id: Uuid,
+    ) -> Result<BoxStream<'static, Result<Delivery>>> {
+        let channel = UserMessageDelivery::new(&user_id.to_string());
+        let subscriber =
+            Transport::<UserMessageDelivery>::subscriber(&self.transport)?;
+        let stream = subscriber.subscribe(channel).await?;
+        Ok(stream.boxed())
+    }
+
+    async fn announce(&self, event: PresenceEvent) -> Result<()> {
+        let publisher = Transport::<PresenceUpdates>::publisher(
+            &self.transport,
+            &PresenceUpdates,
+        )?;
+        publisher.publish(event).await
+    }
+
+    async fn presence(
+        &self,
+    ) -> Result<BoxStream<'static, Result<PresenceEvent>>> {
+        let subscriber =
+            Transport::<PresenceUpdates>::subscriber(&self.transport)?;
+        let stream = subscriber.subscribe(PresenceUpdates).await?;
+        Ok(stream.boxed())
+    }
+}
+
+///This node's place in the chat cluster, deliveries it publishes carry
+/// its id so it can recognise them when they come back
+#[derive(Clone)]
+pub struct Cluster {
+    node_id: String,
+    bus: Arc<dyn DeliveryBus>,
+    outbox: UnboundedSender<(Vec<Uuid>, Delivery)>,
+}
+impl Cluster {
+    pub fn new(node_id: impl Into<String>, bus: impl DeliveryBus) -> Self {
+        let bus: Arc<dyn DeliveryBus> = Arc::new(bus);
+        let (outbox, pending) = unbounded_channel();
+        tokio::spawn(publish_pending(bus.clone(), pending));
+        Self {
+            node_id: node_id.into(),
+            bus,
+            outbox,
+        }
+    }
+    pub fn node_id(&self) -> &str {
+        &self.node_id
+    }
+    ///Publishes the message for each of the users without waiting on the
+    /// bus. Messages go out in the order they were handed over.
+    pub fn publish(&self, users: Vec<Uuid>, message: CrabbyWsFromServer) {
+        let delivery = Delivery {
+            origin: self.node_id.clone(),
+            message,
+        };
+        if self.outbox.send((users, delivery)).is_err() {
+            warn!("delivery publisher is gone, dropping a delivery");
+        }
+    }
+    pub async fn subscribe(
+        &self,
+        user_id: Uuid,
+    ) -> Result<BoxStream<'static, Result<Delivery>>> {
+        self.bus.subscribe(user_id).await
+    }
+    pub fn is_own(&self, delivery: &Delivery) -> bool {
+        delivery.origin == self.node_id
+    }
+    ///Tells the other nodes how this node sees the user
+    pub async fn announce_presence(
+        &self,
+        user_id: Uuid,
+        connected: bool,
+        status: PresenceStatus,
+        custom_text: Option<String>,
+    ) -> Result<()> {
+        self.bus
+            .announce(PresenceEvent::Update {
+                origin: self.node_id.clone(),
+                user_id,
+                connected,
+                status,
+                custom_text,
+            })
+            .await
+    }
+    ///Asks the other nodes to announce every user they hold a session of
+    pub async fn request_presence(&self) -> Result<()> {
+        self.bus
+            .announce(PresenceEvent::Sync {
+                origin: self.node_id.clone(),
+            })
+            .await
+    }
+    ///Tells the other nodes this one is still alive
+    pub async fn heartbeat(&self) -> Result<()> {
+        self.bus
+            .announce(PresenceEvent::Heartbeat {
+                origin: self.node_id.clone(),
+            })
+            .await
+    }
+    pub async fn presence(
+        &self,
+    ) -> Result<BoxStream<'static, Result<PresenceEvent>>> {
+        self.bus.presence().await
+    }
+}
+
+///Publishes what `Cluster::publish` was handed, one message at a time
+/// and to all of its users at once
+async fn publish_pending(
+    bus: Arc<dyn DeliveryBus>,
+    mut pending: UnboundedReceiver<(Vec<Uuid>, Delivery)>,
+) {
+    while let Some((users, delivery)) = pending.recv().await {
+        let published = join_all(
+            users
+                .iter()
+                .map(|user| bus.publish(*user, delivery.clone())),
+        )
+        .await;
+        for (user, result) in users.iter().zip(published) {
+            if let Err(err) = result {
+                warn!("could not publish delivery for {user}: {err}");
+            }
+        }
+    }
+}
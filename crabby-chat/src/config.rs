@@ -0,0 +1,339 @@
+use std::{str::FromStr, time::Duration};
+
+use eyre::eyre;
+
+///How often the server pings a connection and how many unanswered pings
+/// it tolerates before giving up on it
+#[derive(Debug, Clone, Copy)]
+pub struct HeartbeatConfig {
+    pub interval: Duration,
+    pub max_missed: u32,
+}
+impl Default for HeartbeatConfig {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(30),
+            max_missed: 3,
+        }
+    }
+}
+impl HeartbeatConfig {
+    ///Reads `HEARTBEAT_INTERVAL_SECS` and `HEARTBEAT_MAX_MISSED`, anything
+    /// missing or unparsable keeps its default
+    pub fn from_env() -> Self {
+        let default = Self::default();
+        let interval = std::env::var("HEARTBEAT_INTERVAL_SECS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .filter(|secs| *secs > 0)
+            .map(Duration::from_secs)
+            .unwrap_or(default.interval);
+        let max_missed = std::env::var("HEARTBEAT_MAX_MISSED")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .filter(|missed| *missed > 0)
+            .unwrap_or(default.max_missed);
+        Self {
+            interval,
+            max_missed,
+        }
+    }
+}
+
+///How often this node tells the others it is alive, and how many of
+/// those a node may miss before the sessions it held count as gone
+#[derive(Debug, Clone, Copy)]
+pub struct PresenceConfig {
+    pub interval: Duration,
+    pub max_missed: u32,
+}
+impl Default for PresenceConfig {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(10),
+            max_missed: 3,
+        }
+    }
+}
+impl PresenceConfig {
+    ///Reads `PRESENCE_HEARTBEAT_SECS` and `PRESENCE_MAX_MISSED`, anything
+    /// missing or unparsable keeps its default
+    pub fn from_env() -> Self {
+        let default = Self::default();
+        let interval = std::env::var("PRESENCE_HEARTBEAT_SECS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .filter(|secs| *secs > 0)
+            .map(Duration::from_secs)
+            .unwrap_or(default.interval);
+        let max_missed = std::env::var("PRESENCE_MAX_MISSED")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .filter(|missed| *missed > 0)
+            .unwrap_or(default.max_missed);
+        Self {
+            interval,
+            max_missed,
+        }
+    }
+    ///How long a node may stay quiet before it is taken to be gone
+    pub fn expire_after(&self) -> Duration {
+        self.interval * self.max_missed
+    }
+}
+
+///What a connection's outbound queue does once the client stops keeping
+/// up with it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SlowConsumerPolicy {
+    ///Throw away the oldest queued message
+    DropOldest,
+    ///Replace a queued typing, presence, reaction count or read cursor
+    /// event with the newer one for the same key. Without one, throw away
+    /// the oldest chat message, which the client can fetch again from
+    /// history, and keep the replies it is waiting on.
+    Coalesce,
+    ///Close the connection with a slow consumer close code once the queue
+    /// is full, the backlog is kept with the parked session
+    Disconnect,
+}
+impl FromStr for SlowConsumerPolicy {
+    type Err = eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "drop_oldest" => Ok(Self::DropOldest),
+            "coalesce" => Ok(Self::Coalesce),
+            "disconnect" => Ok(Self::Disconnect),
+            other => Err(eyre!("unknown slow consumer policy {other}")),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct QueueConfig {
+    ///Messages queued for a connection before the policy kicks in
+    pub capacity: usize,
+    pub policy: SlowConsumerPolicy,
+}
+impl Default for QueueConfig {
+    fn default() -> Self {
+        Self {
+            capacity: 256,
+            policy: SlowConsumerPolicy::Disconnect,
+        }
+    }
+}
+impl QueueConfig {
+    ///Reads `OUTBOUND_QUEUE_CAPACITY` and `SLOW_CONSUMER_POLICY`, anything
+    /// missing or unparsable keeps its default
+    pub fn from_env() -> Self {
+        let default = Self::default();
+        let capacity = std::env::var("OUTBOUND_QUEUE_CAPACITY")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .filter(|capacity| *capacity > 0)
+            .unwrap_or(default.capacity);
+        let policy = std::env::var("SLOW_CONSUMER_POLICY")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(default.policy);
+        Self { capacity, policy }
+    }
+}
+
+///How long resolved group members and admin checks are used before
+/// the group's membership version is checked again
+#[derive(Debug, Clone, Copy)]
+pub struct MembershipCacheConfig {
+    pub ttl: Duration,
+}
+impl Default for MembershipCacheConfig {
+    fn default() -> Self {
+        Self {
+            ttl: Duration::from_secs(5),
+        }
+    }
+}
+impl MembershipCacheConfig {
+    ///Reads `GROUP_CACHE_TTL_SECS`, anything missing or unparsable keeps
+    /// the default. Zero checks the version on every lookup.
+    pub fn from_env() -> Self {
+        let ttl = std::env::var("GROUP_CACHE_TTL_SECS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .map(Duration::from_secs)
+            .unwrap_or(Self::default().ttl);
+        Self { ttl }
+    }
+}
+
+///What is kept for users while they are not connected anywhere
+#[derive(Debug, Clone, Copy)]
+pub struct OfflineQueueConfig {
+    ///Messages kept per user, the oldest are dropped first
+    pub max_messages: usize,
+    ///Messages queued for longer are dropped, they are still in history
+    pub max_age: Duration,
+}
+impl Default for OfflineQueueConfig {
+    fn default() -> Self {
+        Self {
+            max_messages: 128,
+            max_age: Duration::from_secs(7 * 24 * 60 * 60),
+        }
+    }
+}
+impl OfflineQueueConfig {
+    ///Reads `OFFLINE_QUEUE_MAX_MESSAGES` and `OFFLINE_QUEUE_MAX_AGE_SECS`,
+    /// anything missing or unparsable keeps its default. At most half of
+    /// the outbound queue is used, so catching up never overflows it.
+    pub fn from_env(queue: &QueueConfig) -> Self {
+        let default = Self::default();
+        let max_messages = std::env::var("OFFLINE_QUEUE_MAX_MESSAGES")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(default.max_messages)
+            .min(queue.capacity / 2);
+        let max_age = std::env::var("OFFLINE_QUEUE_MAX_AGE_SECS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .filter(|secs| *secs > 0)
+            .map(Duration::from_secs)
+            .unwrap_or(default.max_age);
+        Self {
+            max_messages,
+            max_age,
+        }
+    }
+}
+
+///Largest frames and messages clients may send
+#[derive(Debug, Clone, Copy)]
+pub struct FrameLimits {
+    ///Bytes of a whole WebSocket message, however it was fragmented
+    pub max_frame_bytes: usize,
+    ///Bytes of the contents of a chat message once normalised
+    pub max_contents_bytes: usize,
+}
+impl Default for FrameLimits {
+    fn default() -> Self {
+        Self {
+            max_frame_bytes: 16 * 1024,
+            max_contents_bytes: 4096,
+        }
+    }
+}
+impl FrameLimits {
+    ///Reads `MAX_FRAME_BYTES` and `MAX_MESSAGE_BYTES`, anything missing,
+    /// unparsable or zero keeps its default
+    pub fn from_env() -> Self {
+        let default = Self::default();
+        let max_frame_bytes = std::env::var("MAX_FRAME_BYTES")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .filter(|bytes| *bytes > 0)
+            .unwrap_or(default.max_frame_bytes);
+        let max_contents_bytes = std::env::var("MAX_MESSAGE_BYTES")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .filter(|bytes| *bytes > 0)
+            .unwrap_or(default.max_contents_bytes);
+        Self {
+            max_frame_bytes,
+            max_contents_bytes,
+        }
+    }
+}
+
+///Token bucket rates of one scope, a connection or a user. Each bucket
+/// holds one second worth of tokens, so that is also the largest burst.
+#[derive(Debug, Clone, Copy)]
+pub struct RateLimit {
+    pub messages_per_sec: u32,
+    pub bytes_per_sec: u32,
+}
+
+///Limits on what clients may send, per connection and per user across
+/// all of their connections to this node
+#[derive(Debug, Clone, Copy)]
+pub struct RateLimitConfig {
+    pub connection: RateLimit,
+    pub user: RateLimit,
+    ///Frames in a row that may go over the limit before the connection
+    /// is closed
+    pub max_strikes: u32,
+}
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        Self {
+            connection: RateLimit {
+                messages_per_sec: 10,
+                bytes_per_sec: 64 * 1024,
+            },
+            user: RateLimit {
+                messages_per_sec: 20,
+                bytes_per_sec: 128 * 1024,
+            },
+            max_strikes: 10,
+        }
+    }
+}
+impl RateLimitConfig {
+    ///Reads `RATE_LIMIT_CONNECTION_MESSAGES_PER_SEC`,
+    /// `RATE_LIMIT_CONNECTION_BYTES_PER_SEC`,
+    /// `RATE_LIMIT_USER_MESSAGES_PER_SEC`, `RATE_LIMIT_USER_BYTES_PER_SEC`
+    /// and `RATE_LIMIT_MAX_STRIKES`, anything missing, unparsable or zero
+    /// keeps its default
+    pub fn from_env() -> Self {
+        let default = Self::default();
+        let var = |name: &str, default: u32| {
+            std::env::var(name)
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .filter(|value| *value > 0)
+                .unwrap_or(default)
+        };
+        Self {
+            connection: RateLimit {
+                messages_per_sec: var(
+                    "RATE_LIMIT_CONNECTION_MESSAGES_PER_SEC",
+                    default.connection.messages_per_sec,
+                ),
+                bytes_per_sec: var(
+                    "RATE_LIMIT_CONNECTION_BYTES_PER_SEC",
+                    default.connection.bytes_per_sec,
+                ),
+            },
+            user: RateLimit {
+                messages_per_sec: var(
+                    "RATE_LIMIT_USER_MESSAGES_PER_SEC",
+                    default.user.messages_per_sec,
+                ),
+                bytes_per_sec: var(
+                    "RATE_LIMIT_USER_BYTES_PER_SEC",
+                    default.user.bytes_per_sec,
+                ),
+            },
+            max_strikes: var("RATE_LIMIT_MAX_STRIKES", default.max_strikes),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn policy_parses_from_snake_case() {
+        assert_eq!(
+            "drop_oldest".parse::<SlowConsumerPolicy>().unwrap(),
+            SlowConsumerPolicy::DropOldest
+        );
+        assert_eq!(
+            "coalesce".parse::<SlowConsumerPolicy>().unwrap(),
+            SlowConsumerPolicy::Coalesce
+        );
+        assert!("DropOldest".parse::<SlowConsumerPolicy>().is_err());
+    }
+}
@@ -1,9 +1,29 @@
+use axum::{http::StatusCode, response::IntoResponse};
 use thiserror::Error;
 
 #[derive(Debug, Error)]
-pub(crate) enum ChatError {
+pub enum ChatError {
     #[error("User was never connected")]
     UserNotConnected,
     #[error("Send channel has been replaced")]
     UserSinkReplaced,
 }
+
+#[derive(Debug, Error)]
+pub enum AuthError {
+    #[error("missing bearer token")]
+    MissingToken,
+    #[error("invalid bearer token")]
+    InvalidToken,
+}
+
+impl IntoResponse for AuthError {
+    fn into_response(self) -> axum::response::Response {
+        match self {
+            AuthError::MissingToken | AuthError::InvalidToken => {
+                StatusCode::UNAUTHORIZED
+            }
+        }
+        .into_response()
+    }
+}
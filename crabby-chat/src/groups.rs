@@ -0,0 +1,387 @@
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use eyre::{Result, eyre};
+use hashbrown::HashMap;
+use tokio::sync::RwLock;
+use tonic::{
+    Code,
+    transport::{Channel, Endpoint},
+};
+use uuid::Uuid;
+
+pub mod proto {
+    tonic::include_proto!("groups");
+}
+use proto::{
+    BatchListGroupMembersRequest, GetGroupMembershipVersionRequest,
+    IsGroupAdminRequest, group_service_client::GroupServiceClient,
+};
+
+///Resolves the members of a group so the engine knows who a
+/// `Destination::Group` message has to be delivered to.
+#[async_trait]
+pub trait ResolveMembers: Send + Sync + 'static {
+    async fn members(&self, group_id: &Uuid) -> Result<Vec<Uuid>>;
+    async fn is_admin(&self, group_id: &Uuid, user_id: &Uuid) -> Result<bool>;
+    ///Membership version `members` currently answers for, `None` if the
+    /// group has no members. Anything worked out from the members is
+    /// stale once it moves.
+    async fn version(&self, group_id: &Uuid) -> Result<Option<u64>>;
+}
+
+///Static membership table, used in tests.
+#[derive(Default)]
+pub struct InMemoryGroups {
+    groups: HashMap<Uuid, Vec<Uuid>>,
+    admins: HashMap<Uuid, Vec<Uuid>>,
+}
+impl InMemoryGroups {
+    pub fn new(groups: HashMap<Uuid, Vec<Uuid>>) -> Self {
+        Self {
+            groups,
+            admins: HashMap::new(),
+        }
+    }
+    pub fn with_admin(mut self, group_id: Uuid, user_id: Uuid) -> Self {
+        self.admins.entry(group_id).or_default().push(user_id);
+        self
+    }
+}
+#[async_trait]
+impl ResolveMembers for InMemoryGroups {
+    async fn members(&self, group_id: &Uuid) -> Result<Vec<Uuid>> {
+        Ok(self.groups.get(group_id).cloned().unwrap_or_default())
+    }
+    async fn is_admin(&self, group_id: &Uuid, user_id: &Uuid) -> Result<bool> {
+        Ok(self
+            .admins
+            .get(group_id)
+            .is_some_and(|admins| admins.contains(user_id)))
+    }
+    async fn version(&self, group_id: &Uuid) -> Result<Option<u64>> {
+        //The table never changes
+        Ok(self.groups.contains_key(group_id).then_some(0))
+    }
+}
+
+///Members of a group as of membership version `ver`
+#[derive(Debug, Clone)]
+pub struct Members {
+    pub ver: u64,
+    pub users: Vec<Uuid>,
+}
+
+///Where `CachedMembers` reads membership from. Asking for the version is
+/// expected to be much cheaper than fetching the members.
+#[async_trait]
+pub trait MembershipSource: Send + Sync + 'static {
+    ///Current membership version, `None` if the group has no members
+    async fn version(&self, group_id: &Uuid) -> Result<Option<u64>>;
+    async fn fetch(&self, group_id: &Uuid) -> Result<Option<Members>>;
+    async fn is_admin(&self, group_id: &Uuid, user_id: &Uuid) -> Result<bool>;
+}
+
+struct CachedGroup {
+    members: Members,
+    //Admin checks answered at `members.ver`, a role change moves it
+    admins: HashMap<Uuid, bool>,
+    checked_at: Instant,
+}
+
+///Keeps the members and admin checks of every group it has resolved. A
+/// cached group is trusted for `ttl`, then revalidated against its
+/// membership version and only refetched once the version has moved.
+pub struct CachedMembers<S> {
+    source: S,
+    ttl: Duration,
+    cache: RwLock<HashMap<Uuid, CachedGroup>>,
+}
+impl<S: MembershipSource> CachedMembers<S> {
+    pub fn new(source: S, ttl: Duration) -> Self {
+        Self {
+            source,
+            ttl,
+            cache: RwLock::new(HashMap::new()),
+        }
+    }
+
+    //Brings the cached group up to date if it is older than `ttl`, false
+    // if the group has no members
+    async fn refresh(&self, group_id: &Uuid) -> Result<bool> {
+        let cached_ver = match self.cache.read().await.get(group_id) {
+            Some(group) if group.checked_at.elapsed() < self.ttl => {
+                return Ok(true);
+            }
+            Some(group) => Some(group.members.ver),
+            None => None,
+        };
+        if let Some(cached_ver) = cached_ver {
+            match self.source.version(group_id).await? {
+                Some(ver) if ver == cached_ver => {
+                    if let Some(group) =
+                        self.cache.write().await.get_mut(group_id)
+                    {
+                        group.checked_at = Instant::now();
+                    }
+                    return Ok(true);
+                }
+                Some(_) => {}
+                None => {
+                    self.cache.write().await.remove(group_id);
+                    return Ok(false);
+                }
+            }
+        }
+        match self.source.fetch(group_id).await? {
+            Some(members) => {
+                let group = CachedGroup {
+                    members,
+                    admins: HashMap::new(),
+                    checked_at: Instant::now(),
+                };
+                self.cache.write().await.insert(*group_id, group);
+                Ok(true)
+            }
+            None => {
+                self.cache.write().await.remove(group_id);
+                Ok(false)
+            }
+        }
+    }
+}
+#[async_trait]
+impl<S: MembershipSource> ResolveMembers for CachedMembers<S> {
+    async fn members(&self, group_id: &Uuid) -> Result<Vec<Uuid>> {
+        if !self.refresh(group_id).await? {
+            return Ok(Vec::new());
+        }
+        Ok(self
+            .cache
+            .read()
+            .await
+            .get(group_id)
+            .map(|group| group.members.users.clone())
+            .unwrap_or_default())
+    }
+    async fn is_admin(&self, group_id: &Uuid, user_id: &Uuid) -> Result<bool> {
+        if !self.refresh(group_id).await? {
+            return Ok(false);
+        }
+        if let Some(group) = self.cache.read().await.get(group_id) {
+            //Only members hold a role
+            if !group.members.users.contains(user_id) {
+                return Ok(false);
+            }
+            if let Some(admin) = group.admins.get(user_id) {
+                return Ok(*admin);
+            }
+        }
+        let admin = self.source.is_admin(group_id, user_id).await?;
+        if let Some(group) = self.cache.write().await.get_mut(group_id) {
+            group.admins.insert(*user_id, admin);
+        }
+        Ok(admin)
+    }
+    async fn version(&self, group_id: &Uuid) -> Result<Option<u64>> {
+        if !self.refresh(group_id).await? {
+            return Ok(None);
+        }
+        Ok(self
+            .cache
+            .read()
+            .await
+            .get(group_id)
+            .map(|group| group.members.ver))
+    }
+}
+
+///Reads group membership from crabby-group's `GroupService`
+pub struct GroupServiceMembers {
+    client: GroupServiceClient<Channel>,
+}
+impl GroupServiceMembers {
+    ///The connection is lazy so the chat service can start before
+    /// crabby-group is reachable
+    pub fn new(addr: String) -> Result<Self> {
+        let channel = Endpoint::from_shared(addr)?.connect_lazy();
+        Ok(Self {
+            client: GroupServiceClient::new(channel),
+        })
+    }
+}
+#[async_trait]
+impl MembershipSource for GroupServiceMembers {
+    async fn version(&self, group_id: &Uuid) -> Result<Option<u64>> {
+        let response = self
+            .client
+            .clone()
+            .get_group_membership_version(GetGroupMembershipVersionRequest {
+                group_id: group_id.to_string(),
+            })
+            .await;
+        match response {
+            Ok(response) => Ok(Some(response.into_inner().ver)),
+            //The version row only exists once the group had members
+            Err(status) if status.code() == Code::NotFound => Ok(None),
+            Err(status) => Err(eyre!("group service: {status}")),
+        }
+    }
+
+    async fn fetch(&self, group_id: &Uuid) -> Result<Option<Members>> {
+        let mut response = self
+            .client
+            .clone()
+            .batch_list_group_members(BatchListGroupMembersRequest {
+                group_id: vec![group_id.to_string()],
+            })
+            .await
+            .map_err(|status| eyre!("group service: {status}"))?
+            .into_inner()
+            .response;
+        let Some(group) = response.remove(&group_id.to_string()) else {
+            return Ok(None);
+        };
+        let users = group
+            .member
+            .iter()
+            .map(|member| Uuid::parse_str(member))
+            .collect::<Result<_, _>>()?;
+        Ok(Some(Members {
+            ver: group.ver,
+            users,
+        }))
+    }
+
+    async fn is_admin(&self, group_id: &Uuid, user_id: &Uuid) -> Result<bool> {
+        let response = self
+            .client
+            .clone()
+            .is_group_admin(IsGroupAdminRequest {
+                group_id: group_id.to_string(),
+                user_id: user_id.to_string(),
+            })
+            .await
+            .map_err(|status| eyre!("group service: {status}"))?;
+        Ok(response.into_inner().admin)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        Arc, Mutex,
+        atomic::{AtomicUsize, Ordering},
+    };
+
+    use super::*;
+
+    #[derive(Default)]
+    struct CountingSource {
+        members: Mutex<Option<Members>>,
+        admins: Mutex<Vec<Uuid>>,
+        versions: AtomicUsize,
+        fetches: AtomicUsize,
+        admin_checks: AtomicUsize,
+    }
+    impl CountingSource {
+        fn set(&self, ver: u64, users: Vec<Uuid>) {
+            *self.members.lock().unwrap() = Some(Members { ver, users });
+        }
+        fn count(counter: &AtomicUsize) -> usize {
+            counter.load(Ordering::SeqCst)
+        }
+    }
+    #[async_trait]
+    impl MembershipSource for Arc<CountingSource> {
+        async fn version(&self, _group_id: &Uuid) -> Result<Option<u64>> {
+            self.versions.fetch_add(1, Ordering::SeqCst);
+            Ok(self.members.lock().unwrap().as_ref().map(|m| m.ver))
+        }
+        async fn fetch(&self, _group_id: &Uuid) -> Result<Option<Members>> {
+            self.fetches.fetch_add(1, Ordering::SeqCst);
+            Ok(self.members.lock().unwrap().clone())
+        }
+        async fn is_admin(&self, _: &Uuid, user_id: &Uuid) -> Result<bool> {
+            self.admin_checks.fetch_add(1, Ordering::SeqCst);
+            Ok(self.admins.lock().unwrap().contains(user_id))
+        }
+    }
+
+    #[tokio::test]
+    async fn members_are_trusted_until_the_ttl_runs_out() {
+        let group = Uuid::now_v7();
+        let alice = Uuid::now_v7();
+        let source = Arc::new(CountingSource::default());
+        source.set(1, vec![alice]);
+        let cache = CachedMembers::new(source.clone(), Duration::from_secs(60));
+
+        for _ in 0..3 {
+            assert_eq!(cache.members(&group).await.unwrap(), vec![alice]);
+        }
+        assert_eq!(CountingSource::count(&source.fetches), 1);
+        assert_eq!(CountingSource::count(&source.versions), 0);
+    }
+
+    #[tokio::test]
+    async fn members_are_refetched_only_when_the_version_moves() {
+        let group = Uuid::now_v7();
+        let (alice, bob) = (Uuid::now_v7(), Uuid::now_v7());
+        let source = Arc::new(CountingSource::default());
+        source.set(1, vec![alice]);
+        //Revalidated on every lookup
+        let cache = CachedMembers::new(source.clone(), Duration::ZERO);
+
+        assert_eq!(cache.members(&group).await.unwrap(), vec![alice]);
+        assert_eq!(cache.members(&group).await.unwrap(), vec![alice]);
+        assert_eq!(source.fetches.load(Ordering::SeqCst), 1);
+
+        source.set(2, vec![alice, bob]);
+        assert_eq!(cache.members(&group).await.unwrap(), vec![alice, bob]);
+        assert_eq!(source.fetches.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn admin_checks_are_cached_until_the_version_moves() {
+        let group = Uuid::now_v7();
+        let (alice, bob, carol) =
+            (Uuid::now_v7(), Uuid::now_v7(), Uuid::now_v7());
+        let source = Arc::new(CountingSource::default());
+        source.set(1, vec![alice, bob]);
+        source.admins.lock().unwrap().push(alice);
+        let cache = CachedMembers::new(source.clone(), Duration::ZERO);
+
+        assert!(cache.is_admin(&group, &alice).await.unwrap());
+        assert!(cache.is_admin(&group, &alice).await.unwrap());
+        assert!(!cache.is_admin(&group, &bob).await.unwrap());
+        //Not a member, so not asked about
+        assert!(!cache.is_admin(&group, &carol).await.unwrap());
+        assert_eq!(CountingSource::count(&source.admin_checks), 2);
+
+        //Alice steps down, which moves the version
+        source.admins.lock().unwrap().clear();
+        source.set(2, vec![alice, bob]);
+        assert!(!cache.is_admin(&group, &alice).await.unwrap());
+        assert_eq!(CountingSource::count(&source.admin_checks), 3);
+    }
+
+    #[tokio::test]
+    async fn version_is_served_from_the_cache_until_the_ttl_runs_out() {
+        let group = Uuid::now_v7();
+        let alice = Uuid::now_v7();
+        let source = Arc::new(CountingSource::default());
+        source.set(1, vec![alice]);
+        let cache = CachedMembers::new(source.clone(), Duration::from_secs(60));
+
+        assert_eq!(cache.version(&group).await.unwrap(), Some(1));
+        source.set(2, vec![alice]);
+        assert_eq!(cache.version(&group).await.unwrap(), Some(1));
+        assert_eq!(CountingSource::count(&source.fetches), 1);
+        assert_eq!(CountingSource::count(&source.versions), 0);
+
+        let cache = CachedMembers::new(source.clone(), Duration::ZERO);
+        assert_eq!(cache.version(&group).await.unwrap(), Some(2));
+        *source.members.lock().unwrap() = None;
+        assert_eq!(cache.version(&group).await.unwrap(), None);
+    }
+}
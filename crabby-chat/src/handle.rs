@@ -1,5 +1,6 @@
 use crate::error::ChatError;
 
+#[allow(async_fn_in_trait)]
 pub trait Handler<T, R> {
     async fn handle(&mut self, event: T) -> Result<R, ChatError>;
 }
@@ -0,0 +1,186 @@
+use std::{ops::RangeInclusive, time::Duration};
+
+use axum::extract::ws::{Message as WsMessage, close_code};
+use crabby_specs::ws::incoming::CrabbyWsFromClient;
+use futures::{Stream, StreamExt};
+
+use crate::actors::{
+    converter::incoming::{Decode, InboundFrame},
+    incoming::IncomingWebsocketActor,
+};
+
+///Versions of the chat protocol this server speaks
+pub const PROTOCOL_VERSIONS: RangeInclusive<u32> = 1..=1;
+///Optional features a client may ask for in its `Hello`
+pub const FEATURES: &[&str] = &[
+    "mentions",
+    "offline_queue",
+    "reactions",
+    "resume",
+    "threads",
+];
+///How long a new connection has to say `Hello`
+pub const HELLO_TIMEOUT: Duration = Duration::from_secs(10);
+///Close code sent to a client speaking a version this server does not
+pub const UNSUPPORTED_VERSION_CODE: u16 = 4002;
+const MAX_CLIENT_NAME_CHARS: usize = 64;
+
+///What a connection settled on in its handshake
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Negotiated {
+    pub protocol_version: u32,
+    pub client_name: String,
+    ///Features both sides support, sorted
+    pub features: Vec<String>,
+}
+
+///Why a connection was turned away before it was attached
+#[derive(Debug, PartialEq, Eq)]
+pub enum Refusal {
+    UnsupportedVersion(u32),
+    ///Something other than a `Hello` came first
+    ExpectedHello,
+    TimedOut,
+    ///The client went away first
+    Closed,
+}
+impl Refusal {
+    ///Code and reason of the close frame the client is sent, nothing
+    /// once it is gone
+    pub fn close(&self) -> Option<(u16, String)> {
+        match self {
+            Refusal::UnsupportedVersion(version) => Some((
+                UNSUPPORTED_VERSION_CODE,
+                format!(
+                    "protocol version {version} is not supported, use {} to {}",
+                    PROTOCOL_VERSIONS.start(),
+                    PROTOCOL_VERSIONS.end()
+                ),
+            )),
+            Refusal::ExpectedHello => {
+                Some((close_code::POLICY, "expected Hello".to_string()))
+            }
+            Refusal::TimedOut => {
+                Some((close_code::POLICY, "no Hello in time".to_string()))
+            }
+            Refusal::Closed => None,
+        }
+    }
+}
+
+///Waits for the client's `Hello` and settles what the connection speaks.
+/// Pings and pongs may come first, anything else ends the handshake.
+pub async fn negotiate<S, E>(
+    stream: &mut S,
+    timeout: Duration,
+) -> Result<Negotiated, Refusal>
+where
+    S: Stream<Item = Result<WsMessage, E>> + Unpin,
+{
+    tokio::time::timeout(timeout, hello(stream))
+        .await
+        .unwrap_or(Err(Refusal::TimedOut))
+}
+
+async fn hello<S, E>(stream: &mut S) -> Result<Negotiated, Refusal>
+where
+    S: Stream<Item = Result<WsMessage, E>> + Unpin,
+{
+    while let Some(item) = stream.next().await {
+        let Ok(item) = item else {
+            return Err(Refusal::Closed);
+        };
+        match <IncomingWebsocketActor as Decode<WsMessage>>::decode(item) {
+            Ok(InboundFrame::Message(CrabbyWsFromClient::Hello {
+                protocol_version,
+                client_name,
+                capabilities,
+            })) => return accept(protocol_version, client_name, capabilities),
+            Ok(InboundFrame::Ping | InboundFrame::Pong) => (),
+            Ok(InboundFrame::Close) => return Err(Refusal::Closed),
+            Ok(InboundFrame::Message(_)) | Err(_) => {
+                return Err(Refusal::ExpectedHello);
+            }
+        }
+    }
+    Err(Refusal::Closed)
+}
+
+fn accept(
+    protocol_version: u32,
+    client_name: String,
+    capabilities: Vec<String>,
+) -> Result<Negotiated, Refusal> {
+    if !PROTOCOL_VERSIONS.contains(&protocol_version) {
+        return Err(Refusal::UnsupportedVersion(protocol_version));
+    }
+    //Capabilities this server does not know are left out, not refused,
+    // so newer clients can still talk to older servers
+    let mut features: Vec<_> = capabilities
+        .into_iter()
+        .filter(|capability| FEATURES.contains(&capability.as_str()))
+        .collect();
+    features.sort();
+    features.dedup();
+    Ok(Negotiated {
+        protocol_version,
+        client_name: client_name.chars().take(MAX_CLIENT_NAME_CHARS).collect(),
+        features,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::Bytes;
+    use futures::stream;
+
+    fn hello_frame(protocol_version: u32, capabilities: &[&str]) -> WsMessage {
+        let hello = CrabbyWsFromClient::Hello {
+            protocol_version,
+            client_name: "crabby-cli/0.1".to_string(),
+            capabilities: capabilities.iter().map(|c| c.to_string()).collect(),
+        };
+        WsMessage::Binary(Bytes::from(serde_json::to_vec(&hello).unwrap()))
+    }
+
+    async fn negotiate_frames(
+        frames: Vec<WsMessage>,
+    ) -> Result<Negotiated, Refusal> {
+        let mut frames =
+            stream::iter(frames.into_iter().map(Ok::<_, axum::Error>));
+        negotiate(&mut frames, HELLO_TIMEOUT).await
+    }
+
+    #[tokio::test]
+    async fn features_are_the_ones_both_sides_know() {
+        let negotiated = negotiate_frames(vec![
+            WsMessage::Ping(Bytes::new()),
+            hello_frame(1, &["threads", "holograms", "resume", "threads"]),
+        ])
+        .await
+        .unwrap();
+        assert_eq!(negotiated.protocol_version, 1);
+        assert_eq!(negotiated.features, vec!["resume", "threads"]);
+    }
+
+    #[tokio::test]
+    async fn unsupported_versions_are_closed_with_their_own_code() {
+        let refusal = negotiate_frames(vec![hello_frame(7, &[])])
+            .await
+            .unwrap_err();
+        assert_eq!(refusal, Refusal::UnsupportedVersion(7));
+        let (code, _) = refusal.close().unwrap();
+        assert_eq!(code, UNSUPPORTED_VERSION_CODE);
+    }
+
+    #[tokio::test]
+    async fn chat_traffic_before_hello_is_refused() {
+        let ack = WsMessage::Text(r#"{"type":"Ack","up_to":3}"#.into());
+        assert_eq!(
+            negotiate_frames(vec![ack, hello_frame(1, &[])]).await,
+            Err(Refusal::ExpectedHello)
+        );
+        assert_eq!(negotiate_frames(Vec::new()).await, Err(Refusal::Closed));
+    }
+}
@@ -7,6 +7,12 @@ use ferroid::{
     id::SnowflakeTwitterId,
     time::MonotonicClock,
 };
+use jiff::Timestamp;
+
+//Bits below the timestamp in a twitter style snowflake (sequence and
+// machine id)
+const TIMESTAMP_SHIFT: u64 = 22;
+
 #[async_trait]
 pub trait GenerateId: Send + Sync + 'static {
     async fn id(&self) -> u64;
@@ -37,6 +43,14 @@ impl GenerateId
     }
 }
 
+///Recovers the time an id was generated at. Our snowflakes use the UNIX
+/// epoch with millisecond precision, so this is the server's view of when
+/// the message carrying the id was accepted.
+pub fn timestamp_of(id: u64) -> Timestamp {
+    let millis = (id >> TIMESTAMP_SHIFT) as i64;
+    Timestamp::from_millisecond(millis).unwrap_or(Timestamp::UNIX_EPOCH)
+}
+
 #[cfg(test)]
 pub struct NoOpIdGeneratorImpl;
 #[cfg(test)]
@@ -46,3 +60,44 @@ impl GenerateId for NoOpIdGeneratorImpl {
         1u64
     }
 }
+
+///Hands out 1, 2, 3 and so on, for tests that tell messages apart by id
+#[cfg(test)]
+#[derive(Default)]
+pub struct SequentialIdGenerator(std::sync::atomic::AtomicU64);
+#[cfg(test)]
+#[async_trait]
+impl GenerateId for SequentialIdGenerator {
+    async fn id(&self) -> u64 {
+        self.0.fetch_add(1, std::sync::atomic::Ordering::Relaxed) + 1
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SLACK_MILLIS: i64 = 1000;
+
+    #[tokio::test]
+    async fn timestamp_of_matches_generation_time() {
+        let generator = AtomicSnowflakeGenerator::<
+            SnowflakeTwitterId,
+            MonotonicClock,
+        >::new(0, MonotonicClock::default());
+        let before = Timestamp::now().as_millisecond();
+        let id = GenerateId::id(&generator).await;
+        let after = Timestamp::now().as_millisecond();
+        let stamped = timestamp_of(id).as_millisecond();
+        //The clock is advanced by a ticker thread, which can fall behind
+        // the wall clock on a busy machine
+        assert!((before - SLACK_MILLIS..=after).contains(&stamped));
+    }
+
+    #[test]
+    fn timestamp_of_ignores_sequence_and_machine_bits() {
+        let millis = 1_767_225_600_000u64;
+        let id = (millis << TIMESTAMP_SHIFT) | 0x3F_FFFF;
+        assert_eq!(timestamp_of(id).as_millisecond(), millis as i64);
+    }
+}
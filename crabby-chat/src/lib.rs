@@ -0,0 +1,22 @@
+#![allow(dead_code)]
+pub mod actors;
+pub mod auth;
+pub mod cluster;
+pub mod config;
+pub mod error;
+pub mod groups;
+pub mod handle;
+pub mod handshake;
+pub mod id;
+pub mod liveness;
+pub mod mentions;
+pub mod messages;
+pub mod presence;
+pub mod queue;
+pub mod ratelimit;
+pub mod replay;
+pub mod sessions;
+pub mod store;
+pub mod typing;
+pub mod users;
+pub mod validate;
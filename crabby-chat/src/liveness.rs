@@ -0,0 +1,119 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+///Tracks when a connection last showed signs of life. The incoming side
+/// records every frame it reads, pongs included, so the connection can be
+/// judged idle without trusting the TCP socket. The outgoing side records
+/// the pings it sends, which gives the round trip time of each pong.
+#[derive(Debug, Clone)]
+pub struct Liveness {
+    inner: Arc<Mutex<LivenessState>>,
+}
+#[derive(Debug)]
+struct LivenessState {
+    last_seen: Instant,
+    last_pong: Option<Instant>,
+    //Sent time of the ping no pong has answered yet
+    ping_sent_at: Option<Instant>,
+    missed: u32,
+    rtt: Option<Duration>,
+}
+impl Default for Liveness {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+impl Liveness {
+    pub fn new() -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(LivenessState {
+                last_seen: Instant::now(),
+                last_pong: None,
+                ping_sent_at: None,
+                missed: 0,
+                rtt: None,
+            })),
+        }
+    }
+    ///Any frame from the client, data or control
+    pub fn saw_frame(&self) {
+        self.state().last_seen = Instant::now();
+    }
+    pub fn saw_pong(&self) {
+        let mut state = self.state();
+        let now = Instant::now();
+        state.last_seen = now;
+        state.last_pong = Some(now);
+        if let Some(sent) = state.ping_sent_at.take() {
+            state.rtt = Some(now.duration_since(sent));
+        }
+        state.missed = 0;
+    }
+    ///Records a ping about to be sent and returns how many pings in a row
+    /// went unanswered before it
+    pub fn ping_sent(&self) -> u32 {
+        let mut state = self.state();
+        if state.ping_sent_at.is_some() {
+            state.missed += 1;
+        }
+        state.ping_sent_at = Some(Instant::now());
+        state.missed
+    }
+    pub fn idle_for(&self) -> Duration {
+        self.state().last_seen.elapsed()
+    }
+    pub fn last_pong(&self) -> Option<Instant> {
+        self.state().last_pong
+    }
+    ///Round trip time of the last answered ping
+    pub fn rtt(&self) -> Option<Duration> {
+        self.state().rtt
+    }
+    pub fn missed(&self) -> u32 {
+        self.state().missed
+    }
+    fn state(&self) -> std::sync::MutexGuard<'_, LivenessState> {
+        //The state is plain timestamps, a poisoned lock is still usable
+        self.inner.lock().unwrap_or_else(|err| err.into_inner())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pong_counts_as_activity() {
+        let liveness = Liveness::new();
+        assert!(liveness.last_pong().is_none());
+        std::thread::sleep(Duration::from_millis(5));
+        let before = liveness.idle_for();
+        liveness.saw_pong();
+        assert!(liveness.idle_for() < before);
+        assert!(liveness.last_pong().is_some());
+    }
+
+    #[test]
+    fn clones_share_state() {
+        let liveness = Liveness::new();
+        let reader = liveness.clone();
+        liveness.saw_pong();
+        assert!(reader.last_pong().is_some());
+    }
+
+    #[test]
+    fn unanswered_pings_are_counted_until_a_pong() {
+        let liveness = Liveness::new();
+        assert_eq!(liveness.ping_sent(), 0);
+        assert_eq!(liveness.ping_sent(), 1);
+        assert_eq!(liveness.ping_sent(), 2);
+        assert!(liveness.rtt().is_none());
+
+        liveness.saw_pong();
+        assert_eq!(liveness.missed(), 0);
+        assert!(liveness.rtt().is_some());
+        assert_eq!(liveness.ping_sent(), 0);
+    }
+}
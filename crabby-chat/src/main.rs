@@ -1,21 +1,22 @@
 #![allow(dead_code)]
-mod actors;
 mod client;
-mod error;
-mod handle;
-pub mod id;
-pub mod messages;
 use axum::{
-    extract::{ConnectInfo, FromRef, State, WebSocketUpgrade, ws::WebSocket},
+    Json,
+    extract::{
+        ConnectInfo, FromRef, Query, State, WebSocketUpgrade, ws::WebSocket,
+    },
     response::IntoResponse,
     routing::get,
 };
 use crabby_core::shutdown::shutdown_signal;
+use crabby_specs::nats::transport::NatsCoreTransport;
+use crabby_transport::memory::InMemoryTransport;
 use ferroid::{generator::AtomicSnowflakeGenerator, time::MonotonicClock};
-use futures::StreamExt;
-use hashbrown::HashMap;
+use futures::{SinkExt, StreamExt};
 use kameo::actor::{ActorRef, Spawn};
-use std::net::SocketAddr;
+use serde::Deserialize;
+use sqlx::postgres::PgPoolOptions;
+use std::{net::SocketAddr, sync::Arc};
 use tokio::net::TcpListener;
 use tracing::{info, instrument};
 
@@ -23,13 +24,29 @@ use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
 use uuid::{NoContext, Timestamp, Uuid};
 
-use crate::{
+use crabby_chat::{
     actors::{
+        converter::outgoing::{ControlFrame, Encode, ServerToTransport},
         engine::EngineActor,
         incoming::{IncomingMessageActor, IncomingWebsocketActor},
         outgoing::OutgoingWebsocketActor,
     },
+    auth::{AuthServiceKeys, AuthenticatedUser, TokenVerifier, Verifier},
+    cluster::{Cluster, TransportBus},
+    config::{
+        FrameLimits, HeartbeatConfig, MembershipCacheConfig,
+        OfflineQueueConfig, PresenceConfig, QueueConfig, RateLimitConfig,
+    },
+    groups::{CachedMembers, GroupServiceMembers},
+    handshake::{self, HELLO_TIMEOUT},
     id::IdGenerator,
+    liveness::Liveness,
+    messages::internal::{ConnectionDiagnostics, ResumeSession},
+    queue::OutboundQueue,
+    ratelimit::RateLimiter,
+    sessions::{ConnectionStats, SessionRegistry},
+    store::{MessageStore, PgMessageRepo},
+    users::AuthServiceUsers,
 };
 
 #[tokio::main]
@@ -49,68 +66,267 @@ async fn test() {
         )
         .with(tracing_subscriber::fmt::layer().pretty())
         .init();
+    //Every node of a cluster needs its own machine id or message ids
+    // collide
+    let machine_id = std::env::var("MACHINE_ID")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(0);
     let id_gen = IdGenerator::new(AtomicSnowflakeGenerator::new(
-        0,
+        machine_id,
         MonotonicClock::default(),
     ));
-    let engine = EngineActor::new(HashMap::default(), id_gen);
+    let group_addr = std::env::var("GROUP_GRPC_ADDR")
+        .unwrap_or_else(|_| "http://0.0.0.0:8080".to_string());
+    let groups = Arc::new(CachedMembers::new(
+        GroupServiceMembers::new(group_addr).expect("valid GROUP_GRPC_ADDR"),
+        MembershipCacheConfig::from_env().ttl,
+    ));
+    let database_url =
+        std::env::var("DATABASE_URL").expect("DATABASE_URL must be set");
+    let pool = PgPoolOptions::new()
+        .max_connections(10)
+        .connect(&database_url)
+        .await
+        .expect("could not connect to postgres");
+    sqlx::migrate!("./migrations")
+        .run(&pool)
+        .await
+        .expect("could not run migrations");
+    let store = MessageStore::new(PgMessageRepo::new(pool));
+    let node_id =
+        std::env::var("NODE_ID").unwrap_or_else(|_| crate::id().to_string());
+    //Without NATS the node runs on its own
+    let cluster = match std::env::var("NATS_URL") {
+        Ok(url) => Cluster::new(
+            node_id,
+            TransportBus::new(
+                NatsCoreTransport::connect(&url)
+                    .await
+                    .expect("could not connect to nats"),
+            ),
+        ),
+        Err(_) => Cluster::new(
+            node_id,
+            TransportBus::new(InMemoryTransport::default()),
+        ),
+    };
+    let auth_addr = std::env::var("AUTH_GRPC_ADDR")
+        .unwrap_or_else(|_| "http://0.0.0.0:6769".to_string());
+    let service_token = std::env::var("INTERNAL_SERVICE_TOKEN")
+        .expect("INTERNAL_SERVICE_TOKEN is needed");
+    let users = Arc::new(
+        AuthServiceUsers::new(auth_addr.clone(), &service_token)
+            .expect("valid AUTH_GRPC_ADDR and INTERNAL_SERVICE_TOKEN"),
+    );
+    let queue = QueueConfig::from_env();
+    let engine = EngineActor::new(
+        SessionRegistry::default(),
+        id_gen,
+        groups,
+        users,
+        store,
+        cluster,
+    )
+    .with_offline_queue(OfflineQueueConfig::from_env(&queue))
+    .with_presence_heartbeat(PresenceConfig::from_env());
     //spawn Engine
     let engine_ref = EngineActor::spawn(engine);
+    let issuer =
+        std::env::var("ACCESS_ISSUER").expect("ACCESS_ISSUER is needed");
+    let audience =
+        std::env::var("ACCESS_AUDIENCE").expect("ACCESS_AUDIENCE is needed");
+    let keys = AuthServiceKeys::new(auth_addr).expect("valid AUTH_GRPC_ADDR");
+    let verifier = Arc::new(TokenVerifier::new(keys, &issuer, &audience));
+    let channel = ChannelState {
+        inner: engine_ref,
+        heartbeat: HeartbeatConfig::from_env(),
+        queue,
+        limits: RateLimiter::new(RateLimitConfig::from_env()),
+        frames: FrameLimits::from_env(),
+    };
     let state = SharedState {
-        channel: ChannelState { inner: engine_ref },
+        channel: channel.clone(),
+        verifier,
     };
     let listener = TcpListener::bind("0.0.0.0:6969").await.unwrap();
     let router = axum::Router::new()
         .route("/ws", get(websocket))
         .with_state(state);
-    axum::serve(
-        listener,
-        router.into_make_service_with_connect_info::<SocketAddr>(),
+    //Diagnostics list every connected user, they stay off the public port
+    let diagnostics_addr = std::env::var("DIAGNOSTICS_ADDR")
+        .unwrap_or_else(|_| "127.0.0.1:6970".to_string());
+    let diagnostics_listener =
+        TcpListener::bind(&diagnostics_addr).await.unwrap();
+    let diagnostics = axum::Router::new()
+        .route("/diagnostics/connections", get(connections))
+        .with_state(channel);
+    let (served, diagnosed) = tokio::join!(
+        axum::serve(
+            listener,
+            router.into_make_service_with_connect_info::<SocketAddr>(),
+        )
+        .with_graceful_shutdown(shutdown_signal())
+        .into_future(),
+        axum::serve(diagnostics_listener, diagnostics)
+            .with_graceful_shutdown(shutdown_signal())
+            .into_future()
+    );
+    served.unwrap();
+    diagnosed.unwrap();
+}
+
+///Heartbeat figures, round trip time included, of every live session
+async fn connections(
+    State(state): State<ChannelState>,
+) -> Json<Vec<ConnectionStats>> {
+    Json(
+        state
+            .inner
+            .ask(ConnectionDiagnostics)
+            .await
+            .unwrap_or_default(),
     )
-    .with_graceful_shutdown(shutdown_signal())
-    .await
-    .unwrap();
 }
+///Query parameters a client reconnects with to pick up an earlier
+/// session where it left off
+#[derive(Debug, Deserialize)]
+struct ResumeParams {
+    session_id: Option<Uuid>,
+    ///Highest `seq` the client processed on its previous connection
+    last_seq: Option<u64>,
+}
+
+//The upgrade is refused with a 401 unless a valid crabby-auth bearer
+// token is provided
 async fn websocket(
     ws: WebSocketUpgrade,
     ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    AuthenticatedUser(user_id): AuthenticatedUser,
+    Query(resume): Query<ResumeParams>,
     State(state): State<ChannelState>,
 ) -> impl IntoResponse {
-    info!("received connection from {addr}");
-    ws.on_upgrade(move |socket| websocket_handler(socket, addr, state))
+    info!("received connection from {addr} for user {user_id}");
+    //Anything bigger fails the connection before it is buffered
+    ws.max_frame_size(state.frames.max_frame_bytes)
+        .max_message_size(state.frames.max_frame_bytes)
+        .on_upgrade(move |socket| {
+            websocket_handler(socket, addr, state, user_id, resume)
+        })
 }
 
-// TODO: add token extractor for extracting user UUID
 async fn websocket_handler(
     ws: WebSocket,
     _addr: SocketAddr,
     state: ChannelState,
+    id: Uuid,
+    resume: ResumeParams,
 ) {
-    let (sink, stream) = ws.split();
-    //Later we extract will user id using some kind of interceptor
-    let id = crate::id();
-    let outbox = OutgoingWebsocketActor::new(sink, state.inner.clone(), id);
-    OutgoingWebsocketActor::spawn(outbox);
-    let inbox: IncomingWebsocketActor =
-        IncomingMessageActor::new(state.inner.clone(), id);
+    let (mut sink, mut stream) = ws.split();
+    //Nothing is attached before the client said which protocol it speaks
+    let negotiated = match handshake::negotiate(&mut stream, HELLO_TIMEOUT)
+        .await
+    {
+        Ok(negotiated) => negotiated,
+        Err(refusal) => {
+            info!("turned away a connection of user {id}: {refusal:?}");
+            if let Some((code, reason)) = refusal.close() {
+                let close = ControlFrame::Close { code, reason };
+                if let Ok(close) =
+                    <ServerToTransport as Encode<ControlFrame>>::encode(close)
+                {
+                    let _ = sink.send(close).await;
+                }
+            }
+            return;
+        }
+    };
+    info!(
+        "user {id} speaks protocol version {} from {}",
+        negotiated.protocol_version, negotiated.client_name
+    );
+    //Shared by both halves, the incoming side sees the pongs the outgoing
+    // side's pings are answered with
+    let liveness = Liveness::new();
+    let queue = OutboundQueue::new(state.queue);
+    //Falls back to a fresh session when there is nothing left to resume
+    let parked = match resume.session_id {
+        Some(session_id) => state
+            .inner
+            .ask(ResumeSession {
+                user_id: id,
+                session_id,
+                last_seq: resume.last_seq.unwrap_or_default(),
+            })
+            .await
+            .ok()
+            .flatten()
+            .map(|buffer| (session_id, buffer)),
+        None => None,
+    };
+    let outbox = match parked {
+        Some((session_id, buffer)) => OutgoingWebsocketActor::new(
+            sink,
+            state.inner.clone(),
+            id,
+            session_id,
+            queue.clone(),
+            liveness.clone(),
+            state.heartbeat,
+        )
+        .resume(buffer, resume.last_seq.unwrap_or_default())
+        .welcome(negotiated),
+        None => OutgoingWebsocketActor::new(
+            sink,
+            state.inner.clone(),
+            id,
+            crate::id(),
+            queue.clone(),
+            liveness.clone(),
+            state.heartbeat,
+        )
+        .welcome(negotiated),
+    };
+    let outbox_ref = OutgoingWebsocketActor::spawn(outbox);
+    let inbox: IncomingWebsocketActor = IncomingMessageActor::new(
+        state.inner.clone(),
+        id,
+        queue,
+        outbox_ref.clone().recipient(),
+        liveness,
+        state.limits.connection(id),
+        state.frames,
+    );
     let stream = Box::pin(stream.filter_map(|item| async move { item.ok() }));
     let inbox_ref = IncomingWebsocketActor::spawn(inbox);
+    //Either half stopping takes the other one down with it
+    inbox_ref.link(&outbox_ref).await;
     inbox_ref.attach_stream(stream, (), ());
 }
 
 #[derive(Debug, Clone)]
 struct ChannelState {
     inner: ActorRef<EngineActor>,
+    heartbeat: HeartbeatConfig,
+    queue: QueueConfig,
+    limits: RateLimiter,
+    frames: FrameLimits,
 }
-#[derive(Clone, Debug)]
+#[derive(Clone)]
 struct SharedState {
     channel: ChannelState,
+    verifier: Verifier,
 }
 impl FromRef<SharedState> for ChannelState {
     fn from_ref(input: &SharedState) -> Self {
         input.channel.clone()
     }
 }
+impl FromRef<SharedState> for Verifier {
+    fn from_ref(input: &SharedState) -> Self {
+        input.verifier.clone()
+    }
+}
 
 fn id() -> Uuid {
     let ts = Timestamp::now(NoContext);
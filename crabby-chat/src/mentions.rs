@@ -0,0 +1,99 @@
+///Most users a single message can mention, by name and picked by the
+/// client each
+pub const MAX_MENTIONS: usize = 32;
+
+//Longest username crabby-auth accepts at registration
+const MAX_USERNAME_LEN: usize = 20;
+
+///`@group` mentions everyone in the group a message is sent to, no user
+/// can be registered under it since usernames are at least five long
+const GROUP_MENTION: &str = "group";
+
+///Mentions written out in the contents of a message
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Mentions {
+    ///Lowercased and without duplicates, in the order they appear
+    pub usernames: Vec<String>,
+    pub group: bool,
+}
+
+///Finds the `@username`s in `contents`. An `@` only starts a mention at
+/// the start of a word, so email addresses are left alone, and dots or
+/// dashes ending a mention are taken to be punctuation.
+pub fn parse(contents: &str) -> Mentions {
+    let mut found = Mentions::default();
+    let mut prev = None;
+    for (i, c) in contents.char_indices() {
+        let starts = c == '@' && !prev.is_some_and(is_word_char);
+        prev = Some(c);
+        if !starts {
+            continue;
+        }
+        let rest = &contents[i + 1..];
+        let end = rest
+            .find(|c: char| !(is_word_char(c) || matches!(c, '.' | '-')))
+            .unwrap_or(rest.len());
+        let name = rest[..end].trim_end_matches(['.', '-']);
+        if name.is_empty() || name.chars().count() > MAX_USERNAME_LEN {
+            continue;
+        }
+        let name = name.to_lowercase();
+        if name == GROUP_MENTION {
+            found.group = true;
+        } else if !found.usernames.contains(&name)
+            && found.usernames.len() < MAX_MENTIONS
+        {
+            found.usernames.push(name);
+        }
+    }
+    found
+}
+
+fn is_word_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names_are_found_at_word_starts_only() {
+        let found =
+            parse("@Alice_1 and @bob.smith. mail carol@example.com, @ALICE_1");
+        assert_eq!(found.usernames, vec!["alice_1", "bob.smith"]);
+        assert!(!found.group);
+    }
+
+    #[test]
+    fn group_mentions_are_not_usernames() {
+        let found = parse("heads up @group (@dave-)");
+        assert_eq!(found.usernames, vec!["dave"]);
+        assert!(found.group);
+    }
+
+    #[test]
+    fn lone_and_overlong_mentions_are_skipped() {
+        assert_eq!(parse("mail me @"), Mentions::default());
+        assert_eq!(parse("@ alice"), Mentions::default());
+        //One past the longest username there can be
+        let found = parse("@abcdefghijklmnopqrstu and @abcdefghijklmnopqrst");
+        assert_eq!(found.usernames, vec!["abcdefghijklmnopqrst"]);
+    }
+
+    #[test]
+    fn punctuation_ends_a_name() {
+        let found = parse("thanks @alice! @bob, @carol? (@dave) @erin...");
+        assert_eq!(
+            found.usernames,
+            vec!["alice", "bob", "carol", "dave", "erin"]
+        );
+    }
+
+    #[test]
+    fn repeats_are_kept_once() {
+        let found = parse("@alice @Alice @ALICE @group @Group");
+        assert_eq!(found.usernames, vec!["alice"]);
+        assert!(found.group);
+    }
+}
@@ -1,11 +1,87 @@
-use crabby_specs::ws::outgoing::CrabbyWsFromServer;
+use crabby_specs::{
+    nats::channel::{Delivery, PresenceEvent},
+    ws::{common::Destination, incoming::CrabbyWsFromClient},
+};
 use serde::{Deserialize, Serialize};
 
-use kameo::prelude::Recipient;
 use uuid::Uuid;
 
-pub struct UserConnected(pub Uuid, pub Recipient<CrabbyWsFromServer>);
+use crate::{liveness::Liveness, queue::OutboundQueue, replay::ReplayBuffer};
+
+pub struct UserConnected {
+    pub user_id: Uuid,
+    pub session_id: Uuid,
+    pub queue: OutboundQueue,
+    pub liveness: Liveness,
+    ///The connection resumed a parked session and was replayed what it
+    /// missed. The session stays parked until this arrives.
+    pub resumed: bool,
+}
+///A session ended for good, only that session is removed so a newer
+/// connection of the same user stays registered
 #[derive(Serialize, Deserialize)]
-pub struct UserDisconnected(pub Uuid);
+pub struct UserDisconnected {
+    pub user_id: Uuid,
+    pub session_id: Uuid,
+}
 #[derive(Clone)]
 pub struct UserMessage;
+///A decoded client message tagged with the user of the connection it
+/// was read from. Replies meant only for that connection, such as a
+/// history page, go to `reply_to`.
+#[derive(Clone)]
+pub struct ClientMessage {
+    pub user_id: Uuid,
+    pub message: CrabbyWsFromClient,
+    pub reply_to: OutboundQueue,
+}
+///Connection level instructions for the outgoing actor, sent by the
+/// incoming actor and the outbound queue of the same connection
+#[derive(Debug, Clone, Copy)]
+pub enum ConnectionControl {
+    ///The client acknowledged every sequenced frame up to and including
+    /// this one
+    Acked(u64),
+    ///The client closed the connection, the session ends with it
+    Close,
+    ///The client fell too far behind under the `Disconnect` policy
+    Overflowed,
+    ///The client kept going over its rate limit, the session ends
+    RateLimited,
+}
+///A connection went away, its unacknowledged frames are kept so the
+/// session can be resumed
+pub struct ParkSession {
+    pub user_id: Uuid,
+    pub session_id: Uuid,
+    pub buffer: ReplayBuffer,
+}
+///Hands back the parked session's buffer, if it still exists, belongs
+/// to the user and still holds every frame after `last_seq`
+pub struct ResumeSession {
+    pub user_id: Uuid,
+    pub session_id: Uuid,
+    ///Last frame the client saw
+    pub last_seq: u64,
+}
+///Time for the outgoing actor to ping its connection
+pub struct Heartbeat;
+///Asks the engine for the heartbeat figures of every live session
+pub struct ConnectionDiagnostics;
+///A delivery for a locally connected user that came in over the cluster
+pub struct RemoteDelivery {
+    pub user_id: Uuid,
+    pub delivery: Delivery,
+}
+///Presence news from the cluster, this node's own included
+pub struct RemotePresence {
+    pub event: PresenceEvent,
+}
+///Time to tell the other nodes this one is alive and to give up on the
+/// ones that went quiet
+pub struct PresenceTick;
+///Time to check whether a typing indicator was refreshed
+pub struct TypingExpired {
+    pub user_id: Uuid,
+    pub dest: Destination,
+}
@@ -0,0 +1,420 @@
+use std::time::{Duration, Instant};
+
+use crabby_specs::ws::{common::PresenceStatus, outgoing::UserPresence};
+use hashbrown::{HashMap, HashSet};
+use jiff::{SignedDuration, Timestamp};
+use uuid::Uuid;
+
+///Longest `custom_text` kept, in characters
+pub const MAX_CUSTOM_TEXT_LEN: usize = 128;
+///Users no node has held a session of for this long are forgotten, along
+/// with when they were last seen and the status they chose
+pub const FORGET_AFTER: SignedDuration = SignedDuration::from_hours(24);
+///Longest a cached presence audience is used before it is worked out
+/// again, which bounds how long a new conversation started on another
+/// node goes unnoticed
+pub const AUDIENCE_TTL: Duration = Duration::from_secs(60);
+
+#[derive(Debug)]
+struct PresenceEntry {
+    ///What the user chose, only shown while they are connected
+    status: PresenceStatus,
+    custom_text: Option<String>,
+    ///Chat nodes holding at least one session of the user
+    nodes: HashSet<String>,
+    last_seen: Option<Timestamp>,
+}
+impl Default for PresenceEntry {
+    fn default() -> Self {
+        Self {
+            status: PresenceStatus::Online,
+            custom_text: None,
+            nodes: HashSet::new(),
+            last_seen: None,
+        }
+    }
+}
+
+///Presence of every user this node has heard about, its own users and
+/// the ones other nodes announced. A user is online while any node holds
+/// a session of them. Every change returns the presence others should
+/// now see, or `None` when nothing visible changed.
+#[derive(Debug)]
+pub struct PresenceBoard {
+    node_id: String,
+    users: HashMap<Uuid, PresenceEntry>,
+    //When each of the other nodes was last heard from
+    heard: HashMap<String, Instant>,
+}
+impl PresenceBoard {
+    pub fn new(node_id: impl Into<String>) -> Self {
+        Self {
+            node_id: node_id.into(),
+            users: HashMap::new(),
+            heard: HashMap::new(),
+        }
+    }
+    pub fn get(&self, user_id: Uuid) -> UserPresence {
+        match self.users.get(&user_id) {
+            Some(entry) => view(user_id, entry),
+            None => UserPresence {
+                user_id,
+                status: PresenceStatus::Offline,
+                custom_text: None,
+                last_seen: None,
+            },
+        }
+    }
+    ///Whether any node holds a session of the user, whatever status they
+    /// chose
+    pub fn is_connected(&self, user_id: Uuid) -> bool {
+        self.users
+            .get(&user_id)
+            .is_some_and(|entry| !entry.nodes.is_empty())
+    }
+    ///Whether a node other than this one holds a session of the user
+    pub fn is_remote(&self, user_id: Uuid) -> bool {
+        self.users.get(&user_id).is_some_and(|entry| {
+            entry.nodes.iter().any(|node| *node != self.node_id)
+        })
+    }
+    ///Whether another node that was heard from within `max_quiet` holds a
+    /// session of the user. A node that went quiet may be gone already,
+    /// even before it is given up on.
+    pub fn is_held_elsewhere(
+        &self,
+        user_id: Uuid,
+        now: Instant,
+        max_quiet: Duration,
+    ) -> bool {
+        self.users.get(&user_id).is_some_and(|entry| {
+            entry.nodes.iter().any(|node| {
+                *node != self.node_id
+                    && self.heard.get(node).is_some_and(|heard_at| {
+                        now.duration_since(*heard_at) < max_quiet
+                    })
+            })
+        })
+    }
+    ///The user's chosen status and custom text
+    pub fn chosen(&self, user_id: Uuid) -> (PresenceStatus, Option<String>) {
+        self.users
+            .get(&user_id)
+            .map(|entry| (entry.status, entry.custom_text.clone()))
+            .unwrap_or((PresenceStatus::Online, None))
+    }
+    ///The first session of the user on this node connected
+    pub fn connected(&mut self, user_id: Uuid) -> Option<UserPresence> {
+        let node_id = self.node_id.clone();
+        self.update(user_id, |entry| {
+            //Coming back from offline resets an away status
+            if entry.nodes.is_empty() {
+                entry.status = PresenceStatus::Online;
+            }
+            entry.nodes.insert(node_id);
+        })
+    }
+    ///The last session of the user on this node went away
+    pub fn disconnected(&mut self, user_id: Uuid) -> Option<UserPresence> {
+        let node_id = self.node_id.clone();
+        self.update(user_id, |entry| {
+            entry.nodes.remove(&node_id);
+        })
+    }
+    pub fn set(
+        &mut self,
+        user_id: Uuid,
+        status: PresenceStatus,
+        custom_text: Option<String>,
+    ) -> Option<UserPresence> {
+        let custom_text = custom_text.map(|text| {
+            text.chars().take(MAX_CUSTOM_TEXT_LEN).collect::<String>()
+        });
+        self.update(user_id, |entry| {
+            entry.status = status;
+            entry.custom_text = custom_text;
+        })
+    }
+    ///Applies another node's view of the user, the status chosen last
+    /// wins wherever it was chosen
+    pub fn apply(
+        &mut self,
+        origin: &str,
+        user_id: Uuid,
+        connected: bool,
+        status: PresenceStatus,
+        custom_text: Option<String>,
+    ) -> Option<UserPresence> {
+        self.update(user_id, |entry| {
+            if connected {
+                entry.nodes.insert(origin.to_string());
+                entry.status = status;
+                entry.custom_text = custom_text;
+            } else {
+                entry.nodes.remove(origin);
+            }
+        })
+    }
+    ///Notes that another node is alive, returns whether it was unknown
+    /// or already given up on
+    pub fn heard_from(&mut self, origin: &str, now: Instant) -> bool {
+        self.heard.insert(origin.to_string(), now).is_none()
+    }
+    ///Gives up on the nodes not heard from since `stale_before`, and on
+    /// every session they held
+    pub fn expire(&mut self, stale_before: Instant) -> Vec<UserPresence> {
+        let mut gone = HashSet::new();
+        self.heard.retain(|node, heard_at| {
+            let alive = *heard_at >= stale_before;
+            if !alive {
+                gone.insert(node.clone());
+            }
+            alive
+        });
+        if gone.is_empty() {
+            return Vec::new();
+        }
+        let held: Vec<Uuid> = self
+            .users
+            .iter()
+            .filter(|(_, entry)| entry.nodes.iter().any(|n| gone.contains(n)))
+            .map(|(user_id, _)| *user_id)
+            .collect();
+        held.into_iter()
+            .filter_map(|user_id| {
+                self.update(user_id, |entry| {
+                    entry.nodes.retain(|node| !gone.contains(node));
+                })
+            })
+            .collect()
+    }
+    ///Forgets the users no node has held a session of since
+    /// `unseen_since`
+    pub fn forget(&mut self, unseen_since: Timestamp) {
+        self.users.retain(|_, entry| {
+            !entry.nodes.is_empty()
+                || entry.last_seen.is_some_and(|at| at >= unseen_since)
+        });
+    }
+    fn update(
+        &mut self,
+        user_id: Uuid,
+        change: impl FnOnce(&mut PresenceEntry),
+    ) -> Option<UserPresence> {
+        let entry = self.users.entry(user_id).or_default();
+        let before = view(user_id, entry);
+        let was_connected = !entry.nodes.is_empty();
+        change(entry);
+        if was_connected && entry.nodes.is_empty() {
+            entry.last_seen = Some(Timestamp::now());
+        }
+        let after = view(user_id, entry);
+        (before.status != after.status
+            || before.custom_text != after.custom_text)
+            .then_some(after)
+    }
+}
+
+///Users someone shares a direct conversation or a group with, and the
+/// membership version of each of those groups at the time
+#[derive(Debug, Clone, Default)]
+pub struct Audience {
+    pub users: HashSet<Uuid>,
+    pub groups: Vec<(Uuid, Option<u64>)>,
+}
+
+///The presence audience of every user it was worked out for lately.
+/// Working it out takes a store query and a member lookup per group, so
+/// it is kept until a new message may have changed it, the version of
+/// one of its groups moves, or it gets older than `AUDIENCE_TTL`.
+#[derive(Debug, Default)]
+pub struct AudienceCache {
+    audiences: HashMap<Uuid, (Audience, Instant)>,
+}
+impl AudienceCache {
+    ///The user's audience unless it is older than `AUDIENCE_TTL`, the
+    /// caller still has to check its group versions
+    pub fn get(&self, user_id: Uuid, now: Instant) -> Option<&Audience> {
+        self.audiences
+            .get(&user_id)
+            .filter(|(_, at)| now.duration_since(*at) < AUDIENCE_TTL)
+            .map(|(audience, _)| audience)
+    }
+    pub fn insert(&mut self, user_id: Uuid, audience: Audience, now: Instant) {
+        self.audiences.insert(user_id, (audience, now));
+    }
+    pub fn invalidate(&mut self, user_id: Uuid) {
+        self.audiences.remove(&user_id);
+    }
+    ///Drops every audience older than `AUDIENCE_TTL`
+    pub fn expire(&mut self, now: Instant) {
+        self.audiences
+            .retain(|_, (_, at)| now.duration_since(*at) < AUDIENCE_TTL);
+    }
+}
+
+fn view(user_id: Uuid, entry: &PresenceEntry) -> UserPresence {
+    if entry.nodes.is_empty() || entry.status == PresenceStatus::Offline {
+        //Appearing offline hides when the user was last seen
+        let last_seen = entry
+            .nodes
+            .is_empty()
+            .then_some(entry.last_seen)
+            .flatten()
+            .map(|at| at.to_string());
+        return UserPresence {
+            user_id,
+            status: PresenceStatus::Offline,
+            custom_text: None,
+            last_seen,
+        };
+    }
+    UserPresence {
+        user_id,
+        status: entry.status,
+        custom_text: entry.custom_text.clone(),
+        last_seen: None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn online_while_any_node_holds_a_session() {
+        let user = Uuid::from_u128(1);
+        let mut board = PresenceBoard::new("a");
+
+        let online = board.connected(user).unwrap();
+        assert_eq!(online.status, PresenceStatus::Online);
+        assert!(
+            board
+                .apply("b", user, true, PresenceStatus::Online, None)
+                .is_none()
+        );
+
+        assert!(board.disconnected(user).is_none());
+        let offline = board
+            .apply("b", user, false, PresenceStatus::Online, None)
+            .unwrap();
+        assert_eq!(offline.status, PresenceStatus::Offline);
+        assert!(offline.last_seen.is_some());
+    }
+
+    #[test]
+    fn remote_only_while_another_node_holds_a_session() {
+        let user = Uuid::from_u128(1);
+        let mut board = PresenceBoard::new("a");
+
+        board.connected(user);
+        assert!(!board.is_remote(user));
+        board.apply("b", user, true, PresenceStatus::Online, None);
+        assert!(board.is_remote(user));
+        board.apply("b", user, false, PresenceStatus::Online, None);
+        assert!(!board.is_remote(user));
+        assert!(board.is_connected(user));
+    }
+
+    #[test]
+    fn sessions_of_a_node_that_went_quiet_are_dropped() {
+        let (alice, bob) = (Uuid::from_u128(1), Uuid::from_u128(2));
+        let mut board = PresenceBoard::new("a");
+        let start = Instant::now();
+
+        assert!(board.heard_from("b", start));
+        board.apply("b", alice, true, PresenceStatus::Online, None);
+        board.connected(bob);
+        board.apply("b", bob, true, PresenceStatus::Online, None);
+        assert!(board.expire(start).is_empty());
+
+        let later = start + Duration::from_secs(30);
+        let changes = board.expire(later);
+        assert_eq!(changes.len(), 1);
+        assert_eq!(changes[0].user_id, alice);
+        assert_eq!(changes[0].status, PresenceStatus::Offline);
+        assert!(!board.is_connected(alice));
+        //This node still holds bob
+        assert!(board.is_connected(bob));
+        assert!(!board.is_remote(bob));
+        //Hearing from it again starts over
+        assert!(board.heard_from("b", later));
+        assert!(!board.heard_from("b", later));
+    }
+
+    #[test]
+    fn sessions_on_a_quiet_node_do_not_count() {
+        let user = Uuid::from_u128(1);
+        let mut board = PresenceBoard::new("a");
+        let start = Instant::now();
+        let max_quiet = Duration::from_secs(30);
+
+        board.connected(user);
+        assert!(!board.is_held_elsewhere(user, start, max_quiet));
+        board.heard_from("b", start);
+        board.apply("b", user, true, PresenceStatus::Online, None);
+        assert!(board.is_held_elsewhere(user, start, max_quiet));
+        //Not given up on yet, but no longer trusted either
+        assert!(!board.is_held_elsewhere(user, start + max_quiet, max_quiet));
+        assert!(board.is_remote(user));
+    }
+
+    #[test]
+    fn users_held_nowhere_are_forgotten_after_a_while() {
+        let (alice, bob) = (Uuid::from_u128(1), Uuid::from_u128(2));
+        let mut board = PresenceBoard::new("a");
+        board.connected(alice);
+        board.connected(bob);
+        board.disconnected(bob);
+
+        board.forget(Timestamp::now() - FORGET_AFTER);
+        assert!(board.get(bob).last_seen.is_some());
+        board.forget(Timestamp::now() + SignedDuration::from_secs(1));
+        assert!(board.get(bob).last_seen.is_none());
+        assert!(board.is_connected(alice));
+    }
+
+    #[test]
+    fn audiences_are_kept_until_they_get_old() {
+        let (alice, bob) = (Uuid::from_u128(1), Uuid::from_u128(2));
+        let mut cache = AudienceCache::default();
+        let start = Instant::now();
+        let audience = Audience {
+            users: HashSet::from([bob]),
+            groups: Vec::new(),
+        };
+
+        cache.insert(alice, audience, start);
+        assert!(cache.get(alice, start + AUDIENCE_TTL / 2).is_some());
+        assert!(cache.get(alice, start + AUDIENCE_TTL).is_none());
+        cache.invalidate(alice);
+        assert!(cache.get(alice, start).is_none());
+
+        cache.insert(alice, Audience::default(), start);
+        cache.expire(start + AUDIENCE_TTL);
+        assert!(cache.get(alice, start).is_none());
+    }
+
+    #[test]
+    fn chosen_status_shows_only_while_connected() {
+        let user = Uuid::from_u128(1);
+        let mut board = PresenceBoard::new("a");
+
+        assert!(
+            board
+                .set(user, PresenceStatus::Away, Some("lunch".to_string()))
+                .is_none()
+        );
+        let online = board.connected(user).unwrap();
+        //Connecting after being offline starts out online again
+        assert_eq!(online.status, PresenceStatus::Online);
+        assert_eq!(online.custom_text.as_deref(), Some("lunch"));
+
+        let away = board.set(user, PresenceStatus::Away, None).unwrap();
+        assert_eq!(away.status, PresenceStatus::Away);
+
+        let hidden = board.set(user, PresenceStatus::Offline, None).unwrap();
+        assert_eq!(hidden.status, PresenceStatus::Offline);
+        assert!(hidden.last_seen.is_none());
+    }
+}
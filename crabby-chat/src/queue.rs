@@ -0,0 +1,321 @@
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex, MutexGuard},
+};
+
+use crabby_specs::ws::{common::Destination, outgoing::CrabbyWsFromServer};
+use tokio::sync::Notify;
+use uuid::Uuid;
+
+use crate::config::{QueueConfig, SlowConsumerPolicy};
+
+///Bounded queue in front of a connection's outgoing actor. Pushing never
+/// waits, so the engine is never held up by a single slow client; what
+/// happens once the queue is full is up to the `SlowConsumerPolicy`.
+#[derive(Debug, Clone)]
+pub struct OutboundQueue {
+    shared: Arc<Shared>,
+}
+#[derive(Debug)]
+struct Shared {
+    state: Mutex<QueueState>,
+    notify: Notify,
+    config: QueueConfig,
+}
+#[derive(Debug, Default)]
+struct QueueState {
+    items: VecDeque<CrabbyWsFromServer>,
+    dropped: u64,
+    //Set under `Disconnect` once the client fell too far behind
+    overflowed: bool,
+    closed: bool,
+}
+impl OutboundQueue {
+    pub fn new(config: QueueConfig) -> Self {
+        Self {
+            shared: Arc::new(Shared {
+                state: Mutex::new(QueueState::default()),
+                notify: Notify::new(),
+                config,
+            }),
+        }
+    }
+    pub fn push(&self, msg: CrabbyWsFromServer) {
+        let mut state = self.state();
+        if state.closed {
+            return;
+        }
+        //Anything after the overflow is lost, the connection is on its way
+        // out and `dropped` tells it so
+        if state.overflowed {
+            state.dropped += 1;
+            return;
+        }
+        if state.items.len() >= self.shared.config.capacity {
+            match self.shared.config.policy {
+                SlowConsumerPolicy::DropOldest => {
+                    state.items.pop_front();
+                    state.dropped += 1;
+                }
+                SlowConsumerPolicy::Coalesce => {
+                    //An event only the latest state of matters replaces
+                    // the one it supersedes. Failing that, chat messages
+                    // can be fetched again from history, the replies a
+                    // client is waiting on cannot.
+                    let superseded = Supersedes::of(&msg).and_then(|key| {
+                        state.items.iter().position(|queued| {
+                            Supersedes::of(queued) == Some(key)
+                        })
+                    });
+                    let victim = match superseded {
+                        Some(victim) => victim,
+                        None => {
+                            state.dropped += 1;
+                            state
+                                .items
+                                .iter()
+                                .position(|queued| {
+                                    matches!(
+                                        queued,
+                                        CrabbyWsFromServer::ChatMessage { .. }
+                                    )
+                                })
+                                .unwrap_or(0)
+                        }
+                    };
+                    state.items.remove(victim);
+                }
+                //Filling up already overflowed it, see below
+                SlowConsumerPolicy::Disconnect => (),
+            }
+        }
+        state.items.push_back(msg);
+        //Nothing queued is dropped, the backlog is parked with the session
+        // when the connection is closed
+        if self.shared.config.policy == SlowConsumerPolicy::Disconnect
+            && state.items.len() >= self.shared.config.capacity
+        {
+            state.overflowed = true;
+        }
+        drop(state);
+        self.shared.notify.notify_one();
+    }
+    ///Waits for the next message, `None` once the queue is closed or has
+    /// overflowed
+    pub async fn pop(&self) -> Option<CrabbyWsFromServer> {
+        loop {
+            let notified = self.shared.notify.notified();
+            {
+                let mut state = self.state();
+                if state.overflowed {
+                    return None;
+                }
+                if let Some(msg) = state.items.pop_front() {
+                    return Some(msg);
+                }
+                if state.closed {
+                    return None;
+                }
+            }
+            notified.await;
+        }
+    }
+    ///Stops accepting messages and hands back whatever was still queued
+    pub fn close(&self) -> Vec<CrabbyWsFromServer> {
+        let mut state = self.state();
+        state.closed = true;
+        let rest = state.items.drain(..).collect();
+        drop(state);
+        self.shared.notify.notify_one();
+        rest
+    }
+    pub fn depth(&self) -> usize {
+        self.state().items.len()
+    }
+    ///Messages thrown away because the queue was full
+    pub fn dropped(&self) -> u64 {
+        self.state().dropped
+    }
+    pub fn overflowed(&self) -> bool {
+        self.state().overflowed
+    }
+    fn state(&self) -> MutexGuard<'_, QueueState> {
+        self.shared
+            .state
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+    }
+}
+
+///What a queued event is the latest state of. A newer event with the
+/// same key tells the client everything the older one did.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Supersedes<'a> {
+    Typing(Uuid, &'a Destination),
+    Presence(Uuid),
+    ReactionCount(u64, &'a str),
+    ReadCursor(Uuid, &'a Destination),
+}
+impl<'a> Supersedes<'a> {
+    fn of(msg: &'a CrabbyWsFromServer) -> Option<Self> {
+        match msg {
+            CrabbyWsFromServer::UserTyping { user_id, dest, .. } => {
+                Some(Self::Typing(*user_id, dest))
+            }
+            CrabbyWsFromServer::PresenceChanged { user_id, .. } => {
+                Some(Self::Presence(*user_id))
+            }
+            CrabbyWsFromServer::ReactionChanged {
+                message_id, emoji, ..
+            } => Some(Self::ReactionCount(*message_id, emoji)),
+            CrabbyWsFromServer::ReadReceipt { user_id, dest, .. } => {
+                Some(Self::ReadCursor(*user_id, dest))
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crabby_specs::ws::common::TypingState;
+
+    use super::*;
+
+    fn queue(policy: SlowConsumerPolicy) -> OutboundQueue {
+        OutboundQueue::new(QueueConfig {
+            capacity: 2,
+            policy,
+        })
+    }
+
+    fn message(id: u64) -> CrabbyWsFromServer {
+        CrabbyWsFromServer::ChatMessage {
+            message_id: id,
+            user_id: Uuid::nil(),
+            dest: Destination::Individual { id: Uuid::nil() },
+            timestamp: String::new(),
+            contents: String::new(),
+            client_sent_at: None,
+            reply_to: None,
+            thread_root: None,
+            mentions: Vec::new(),
+            mentions_group: false,
+        }
+    }
+
+    fn ack(id: u64) -> CrabbyWsFromServer {
+        CrabbyWsFromServer::SendAck {
+            client_msg_id: format!("c{id}"),
+            message_id: id,
+            timestamp: String::new(),
+        }
+    }
+
+    fn typing(user_id: Uuid, state: TypingState) -> CrabbyWsFromServer {
+        CrabbyWsFromServer::UserTyping {
+            user_id,
+            dest: Destination::Group { id: Uuid::nil() },
+            state,
+        }
+    }
+
+    fn reaction(
+        message_id: u64,
+        emoji: &str,
+        count: u64,
+    ) -> CrabbyWsFromServer {
+        CrabbyWsFromServer::ReactionChanged {
+            message_id,
+            dest: Destination::Group { id: Uuid::nil() },
+            user_id: Uuid::nil(),
+            emoji: emoji.to_string(),
+            added: true,
+            count,
+        }
+    }
+
+    async fn pops(queue: &OutboundQueue, expected: CrabbyWsFromServer) {
+        let popped = queue.pop().await.expect("queue should not be empty");
+        assert_eq!(
+            serde_json::to_value(popped).unwrap(),
+            serde_json::to_value(expected).unwrap()
+        );
+    }
+
+    fn id_of(msg: &CrabbyWsFromServer) -> u64 {
+        match msg {
+            CrabbyWsFromServer::ChatMessage { message_id, .. }
+            | CrabbyWsFromServer::SendAck { message_id, .. } => *message_id,
+            other => panic!("unexpected message {other:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn drop_oldest_keeps_the_newest() {
+        let queue = queue(SlowConsumerPolicy::DropOldest);
+        for id in 1..=3 {
+            queue.push(message(id));
+        }
+        assert_eq!(queue.depth(), 2);
+        assert_eq!(queue.dropped(), 1);
+        assert_eq!(id_of(&queue.pop().await.unwrap()), 2);
+        assert_eq!(id_of(&queue.pop().await.unwrap()), 3);
+    }
+
+    #[tokio::test]
+    async fn coalesce_keeps_replies() {
+        let queue = queue(SlowConsumerPolicy::Coalesce);
+        queue.push(ack(1));
+        queue.push(message(2));
+        queue.push(message(3));
+        assert_eq!(queue.dropped(), 1);
+        assert_eq!(id_of(&queue.pop().await.unwrap()), 1);
+        assert_eq!(id_of(&queue.pop().await.unwrap()), 3);
+    }
+
+    #[tokio::test]
+    async fn coalesce_replaces_superseded_events() {
+        let queue = queue(SlowConsumerPolicy::Coalesce);
+        let (alice, bob) = (Uuid::from_u128(1), Uuid::from_u128(2));
+        queue.push(typing(alice, TypingState::Started));
+        queue.push(typing(bob, TypingState::Started));
+        queue.push(typing(alice, TypingState::Stopped));
+        //Nothing was lost, alice's latest state moved to the back
+        assert_eq!(queue.dropped(), 0);
+        pops(&queue, typing(bob, TypingState::Started)).await;
+        pops(&queue, typing(alice, TypingState::Stopped)).await;
+
+        queue.push(reaction(7, "🦀", 1));
+        queue.push(reaction(7, "👍", 1));
+        queue.push(reaction(7, "🦀", 2));
+        assert_eq!(queue.dropped(), 0);
+        pops(&queue, reaction(7, "👍", 1)).await;
+        pops(&queue, reaction(7, "🦀", 2)).await;
+
+        //Something with nothing to supersede still makes room
+        queue.push(typing(alice, TypingState::Started));
+        queue.push(reaction(7, "🦀", 3));
+        queue.push(typing(bob, TypingState::Started));
+        assert_eq!(queue.dropped(), 1);
+        assert_eq!(queue.depth(), 2);
+    }
+
+    #[tokio::test]
+    async fn disconnect_keeps_the_backlog_for_parking() {
+        let queue = queue(SlowConsumerPolicy::Disconnect);
+        queue.push(message(1));
+        assert!(!queue.overflowed());
+        for id in 2..=4 {
+            queue.push(message(id));
+        }
+        assert!(queue.overflowed());
+        //The queue never grows past its capacity, what did not fit is
+        // counted
+        assert_eq!(queue.depth(), 2);
+        assert_eq!(queue.dropped(), 2);
+        assert!(queue.pop().await.is_none());
+        let rest: Vec<_> = queue.close().iter().map(id_of).collect();
+        assert_eq!(rest, vec![1, 2]);
+    }
+}
@@ -0,0 +1,237 @@
+use std::{
+    sync::{Arc, Mutex, MutexGuard},
+    time::{Duration, Instant},
+};
+
+use hashbrown::HashMap;
+use uuid::Uuid;
+
+use crate::config::{RateLimit, RateLimitConfig};
+
+///Tokens come back at `rate` per second, up to `capacity`
+#[derive(Debug)]
+struct TokenBucket {
+    capacity: f64,
+    rate: f64,
+    tokens: f64,
+    updated_at: Instant,
+}
+impl TokenBucket {
+    fn new(per_sec: u32, now: Instant) -> Self {
+        let capacity = f64::from(per_sec.max(1));
+        Self {
+            capacity,
+            rate: capacity,
+            tokens: capacity,
+            updated_at: now,
+        }
+    }
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated_at);
+        self.tokens = (self.tokens + elapsed.as_secs_f64() * self.rate)
+            .min(self.capacity);
+        self.updated_at = now;
+    }
+    //Anything bigger than the bucket only needs a full one, or it would
+    // never get through
+    fn cost(&self, amount: usize) -> f64 {
+        (amount as f64).min(self.capacity)
+    }
+    ///How long until `amount` tokens are there, zero if they already are
+    fn wait(&mut self, amount: usize, now: Instant) -> Duration {
+        self.refill(now);
+        let missing = self.cost(amount) - self.tokens;
+        if missing <= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(missing / self.rate)
+        }
+    }
+    fn take(&mut self, amount: usize) {
+        self.tokens -= self.cost(amount);
+    }
+    fn is_full(&mut self, now: Instant) -> bool {
+        self.refill(now);
+        self.tokens >= self.capacity
+    }
+}
+
+///A message bucket and a byte bucket, every frame is charged to both
+#[derive(Debug)]
+struct Buckets {
+    messages: TokenBucket,
+    bytes: TokenBucket,
+}
+impl Buckets {
+    fn new(limit: RateLimit, now: Instant) -> Self {
+        Self {
+            messages: TokenBucket::new(limit.messages_per_sec, now),
+            bytes: TokenBucket::new(limit.bytes_per_sec, now),
+        }
+    }
+    fn wait(&mut self, len: usize, now: Instant) -> Duration {
+        self.messages.wait(1, now).max(self.bytes.wait(len, now))
+    }
+    fn take(&mut self, len: usize) {
+        self.messages.take(1);
+        self.bytes.take(len);
+    }
+    fn is_full(&mut self, now: Instant) -> bool {
+        self.messages.is_full(now) && self.bytes.is_full(now)
+    }
+}
+
+///What to do with a frame read from a client
+#[derive(Debug, PartialEq, Eq)]
+pub enum Verdict {
+    Allowed,
+    ///Over the limit, the frame is dropped and the client may try again
+    /// after this long
+    Limited(Duration),
+    ///Over the limit too many times in a row, the connection is closed
+    Disconnect,
+}
+
+///Hands out the rate limits of new connections. The buckets of a user
+/// are shared by all of the user's connections to this node and outlive
+/// them until they have refilled, so reconnecting does not reset them.
+#[derive(Debug, Clone)]
+pub struct RateLimiter {
+    config: RateLimitConfig,
+    users: Arc<Mutex<HashMap<Uuid, Arc<Mutex<Buckets>>>>>,
+}
+impl RateLimiter {
+    pub fn new(config: RateLimitConfig) -> Self {
+        Self {
+            config,
+            users: Arc::default(),
+        }
+    }
+    pub fn connection(&self, user_id: Uuid) -> ConnectionLimiter {
+        let now = Instant::now();
+        let mut users = lock(&self.users);
+        //Nothing is lost by forgetting a full bucket nobody holds
+        users.retain(|_, buckets| {
+            Arc::strong_count(buckets) > 1 || !lock(buckets).is_full(now)
+        });
+        let user = users
+            .entry(user_id)
+            .or_insert_with(|| {
+                Arc::new(Mutex::new(Buckets::new(self.config.user, now)))
+            })
+            .clone();
+        ConnectionLimiter {
+            own: Buckets::new(self.config.connection, now),
+            user,
+            max_strikes: self.config.max_strikes,
+            strikes: 0,
+        }
+    }
+}
+
+///Rate limits of one connection, owned by its incoming actor
+#[derive(Debug)]
+pub struct ConnectionLimiter {
+    own: Buckets,
+    user: Arc<Mutex<Buckets>>,
+    max_strikes: u32,
+    //Frames over the limit since the last one that was allowed
+    strikes: u32,
+}
+impl ConnectionLimiter {
+    ///Charges a data frame of `len` bytes to the connection and the user.
+    /// Frames over the limit are not charged.
+    pub fn check(&mut self, len: usize, now: Instant) -> Verdict {
+        let mut user = lock(&self.user);
+        let wait = self.own.wait(len, now).max(user.wait(len, now));
+        if wait.is_zero() {
+            self.own.take(len);
+            user.take(len);
+            self.strikes = 0;
+            return Verdict::Allowed;
+        }
+        self.strikes += 1;
+        if self.strikes >= self.max_strikes {
+            Verdict::Disconnect
+        } else {
+            Verdict::Limited(wait)
+        }
+    }
+}
+
+//Buckets are plain numbers, a poisoned lock is still usable
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|err| err.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limiter(max_strikes: u32) -> RateLimiter {
+        RateLimiter::new(RateLimitConfig {
+            connection: RateLimit {
+                messages_per_sec: 2,
+                bytes_per_sec: 100,
+            },
+            user: RateLimit {
+                messages_per_sec: 3,
+                bytes_per_sec: 1000,
+            },
+            max_strikes,
+        })
+    }
+
+    #[test]
+    fn bursts_are_limited_until_tokens_come_back() {
+        let mut connection = limiter(10).connection(Uuid::from_u128(1));
+        let now = Instant::now();
+        assert_eq!(connection.check(10, now), Verdict::Allowed);
+        assert_eq!(connection.check(10, now), Verdict::Allowed);
+        assert_eq!(
+            connection.check(10, now),
+            Verdict::Limited(Duration::from_millis(500))
+        );
+        let later = now + Duration::from_millis(500);
+        assert_eq!(connection.check(10, later), Verdict::Allowed);
+    }
+
+    #[test]
+    fn bytes_are_limited_on_their_own() {
+        let mut connection = limiter(10).connection(Uuid::from_u128(1));
+        let now = Instant::now();
+        assert_eq!(connection.check(50, now), Verdict::Allowed);
+        assert_eq!(
+            connection.check(100, now),
+            Verdict::Limited(Duration::from_millis(500))
+        );
+    }
+
+    #[test]
+    fn connections_of_a_user_share_its_buckets() {
+        let limiter = limiter(10);
+        let user = Uuid::from_u128(1);
+        let mut phone = limiter.connection(user);
+        let mut desktop = limiter.connection(user);
+        let now = Instant::now();
+        assert_eq!(phone.check(1, now), Verdict::Allowed);
+        assert_eq!(phone.check(1, now), Verdict::Allowed);
+        assert_eq!(desktop.check(1, now), Verdict::Allowed);
+        assert!(matches!(desktop.check(1, now), Verdict::Limited(_)));
+
+        //Someone else is not held up by it
+        let mut other = limiter.connection(Uuid::from_u128(2));
+        assert_eq!(other.check(1, now), Verdict::Allowed);
+    }
+
+    #[test]
+    fn repeat_offenders_are_disconnected() {
+        let mut connection = limiter(3).connection(Uuid::from_u128(1));
+        let now = Instant::now();
+        connection.check(1, now);
+        connection.check(1, now);
+        assert!(matches!(connection.check(1, now), Verdict::Limited(_)));
+        assert!(matches!(connection.check(1, now), Verdict::Limited(_)));
+        assert_eq!(connection.check(1, now), Verdict::Disconnect);
+    }
+}
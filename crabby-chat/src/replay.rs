@@ -0,0 +1,158 @@
+use std::collections::VecDeque;
+
+use crabby_specs::ws::outgoing::{CrabbyWsFromServer, ServerFrame};
+use tracing::warn;
+
+///Most frames a session keeps waiting for an ack before it starts
+/// forgetting the oldest ones. A session that forgot frames its client
+/// never acknowledged can no longer be resumed.
+pub const REPLAY_CAPACITY: usize = 1024;
+
+///Per-session record of sequenced frames the client has not acknowledged
+/// yet. It outlives the connection it was created for so that a client
+/// resuming the session gets everything it missed.
+#[derive(Debug, Clone)]
+pub struct ReplayBuffer {
+    next_seq: u64,
+    unacked: VecDeque<ServerFrame>,
+    capacity: usize,
+    //Highest seq dropped without an ack to make room, 0 if none was
+    forgotten_up_to: u64,
+}
+impl Default for ReplayBuffer {
+    fn default() -> Self {
+        Self::new(REPLAY_CAPACITY)
+    }
+}
+impl ReplayBuffer {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            next_seq: 1,
+            unacked: VecDeque::new(),
+            capacity,
+            forgotten_up_to: 0,
+        }
+    }
+    ///Assigns the next sequence number to `message` and keeps it until
+    /// it is acknowledged
+    pub fn push(&mut self, message: CrabbyWsFromServer) -> ServerFrame {
+        let frame = ServerFrame {
+            seq: Some(self.next_seq),
+            message,
+        };
+        self.next_seq += 1;
+        if self.unacked.len() == self.capacity
+            && let Some(dropped) = self.unacked.pop_front()
+        {
+            warn!("replay buffer full, dropping frame {:?}", dropped.seq);
+            self.forgotten_up_to = dropped.seq.unwrap_or_default();
+        }
+        self.unacked.push_back(frame.clone());
+        frame
+    }
+    ///Sequence number the next frame gets
+    pub fn next_seq(&self) -> u64 {
+        self.next_seq
+    }
+    ///Forgets every frame up to and including `up_to`
+    pub fn ack(&mut self, up_to: u64) {
+        while self
+            .unacked
+            .front()
+            .is_some_and(|frame| frame.seq.is_some_and(|seq| seq <= up_to))
+        {
+            self.unacked.pop_front();
+        }
+    }
+    ///Whether every frame after `last_seq` is still held, so a client
+    /// that saw up to `last_seq` misses nothing by resuming
+    pub fn covers(&self, last_seq: u64) -> bool {
+        last_seq >= self.forgotten_up_to
+    }
+    ///Frames the client has not acknowledged yet, oldest first
+    pub fn pending(&self) -> impl Iterator<Item = &ServerFrame> {
+        self.unacked.iter()
+    }
+    pub fn len(&self) -> usize {
+        self.unacked.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.unacked.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crabby_specs::ws::common::Destination;
+    use uuid::Uuid;
+
+    use super::*;
+
+    fn message(id: u64) -> CrabbyWsFromServer {
+        CrabbyWsFromServer::ChatMessage {
+            message_id: id,
+            user_id: Uuid::nil(),
+            dest: Destination::Individual { id: Uuid::nil() },
+            timestamp: String::new(),
+            contents: String::new(),
+            client_sent_at: None,
+            reply_to: None,
+            thread_root: None,
+            mentions: Vec::new(),
+            mentions_group: false,
+        }
+    }
+
+    fn seqs(buffer: &ReplayBuffer) -> Vec<u64> {
+        buffer.pending().filter_map(|frame| frame.seq).collect()
+    }
+
+    #[test]
+    fn frames_are_numbered_from_one() {
+        let mut buffer = ReplayBuffer::default();
+        assert_eq!(buffer.push(message(10)).seq, Some(1));
+        assert_eq!(buffer.push(message(11)).seq, Some(2));
+        assert_eq!(seqs(&buffer), vec![1, 2]);
+    }
+
+    #[test]
+    fn ack_drops_everything_up_to_and_including() {
+        let mut buffer = ReplayBuffer::default();
+        for id in 0..4 {
+            buffer.push(message(id));
+        }
+        buffer.ack(2);
+        assert_eq!(seqs(&buffer), vec![3, 4]);
+        //Stale acks are harmless
+        buffer.ack(1);
+        assert_eq!(seqs(&buffer), vec![3, 4]);
+        buffer.ack(10);
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn full_buffer_forgets_oldest_frame() {
+        let mut buffer = ReplayBuffer::new(2);
+        for id in 0..3 {
+            buffer.push(message(id));
+        }
+        assert_eq!(seqs(&buffer), vec![2, 3]);
+    }
+
+    #[test]
+    fn forgotten_frames_are_not_covered() {
+        let mut buffer = ReplayBuffer::new(2);
+        assert!(buffer.covers(0));
+        for id in 0..4 {
+            buffer.push(message(id));
+        }
+        //Frames 1 and 2 were dropped unacknowledged
+        assert!(!buffer.covers(0));
+        assert!(!buffer.covers(1));
+        assert!(buffer.covers(2));
+        //Room made by acks forgets nothing
+        buffer.ack(3);
+        buffer.push(message(4));
+        assert!(buffer.covers(2));
+    }
+}
@@ -0,0 +1,104 @@
+use hashbrown::HashMap;
+use serde::Serialize;
+use uuid::Uuid;
+
+use crate::{liveness::Liveness, queue::OutboundQueue};
+
+pub struct Session {
+    pub queue: OutboundQueue,
+    pub liveness: Liveness,
+}
+
+///Heartbeat figures of a single live session
+#[derive(Debug, Clone, Serialize)]
+pub struct ConnectionStats {
+    pub user_id: Uuid,
+    pub session_id: Uuid,
+    ///Round trip time of the last answered ping
+    pub rtt_ms: Option<u64>,
+    ///Time since the client last sent anything
+    pub idle_ms: u64,
+    pub missed_heartbeats: u32,
+    ///Messages waiting in the outbound queue
+    pub queue_depth: usize,
+    ///Messages the outbound queue threw away because it was full
+    pub dropped: u64,
+}
+
+///Live sessions keyed by user and then session, every device a user has
+/// connected is a session of its own.
+#[derive(Default)]
+pub struct SessionRegistry {
+    users: HashMap<Uuid, HashMap<Uuid, Session>>,
+}
+impl SessionRegistry {
+    pub fn insert(
+        &mut self,
+        user_id: Uuid,
+        session_id: Uuid,
+        session: Session,
+    ) {
+        self.users
+            .entry(user_id)
+            .or_default()
+            .insert(session_id, session);
+    }
+    ///Removes a single session, returns whether it was registered. The
+    /// user's other sessions are left alone.
+    pub fn remove(&mut self, user_id: Uuid, session_id: Uuid) -> bool {
+        let Some(sessions) = self.users.get_mut(&user_id) else {
+            return false;
+        };
+        let removed = sessions.remove(&session_id).is_some();
+        if sessions.is_empty() {
+            self.users.remove(&user_id);
+        }
+        removed
+    }
+    ///Every live session of the user
+    pub fn sessions(
+        &self,
+        user_id: &Uuid,
+    ) -> impl Iterator<Item = &OutboundQueue> {
+        self.users
+            .get(user_id)
+            .into_iter()
+            .flat_map(|sessions| sessions.values())
+            .map(|session| &session.queue)
+    }
+    pub fn is_online(&self, user_id: &Uuid) -> bool {
+        self.users.contains_key(user_id)
+    }
+    ///Users with at least one live session
+    pub fn users(&self) -> impl Iterator<Item = &Uuid> {
+        self.users.keys()
+    }
+    ///Number of live sessions across all users
+    pub fn len(&self) -> usize {
+        self.users.values().map(HashMap::len).sum()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.users.is_empty()
+    }
+    pub fn stats(&self) -> Vec<ConnectionStats> {
+        self.users
+            .iter()
+            .flat_map(|(user_id, sessions)| {
+                sessions
+                    .iter()
+                    .map(|(session_id, session)| ConnectionStats {
+                        user_id: *user_id,
+                        session_id: *session_id,
+                        rtt_ms: session
+                            .liveness
+                            .rtt()
+                            .map(|rtt| rtt.as_millis() as u64),
+                        idle_ms: session.liveness.idle_for().as_millis() as u64,
+                        missed_heartbeats: session.liveness.missed(),
+                        queue_depth: session.queue.depth(),
+                        dropped: session.queue.dropped(),
+                    })
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,1432 @@
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use async_trait::async_trait;
+use crabby_specs::ws::{
+    common::Destination,
+    outgoing::{
+        CrabbyWsFromServer, HistoricalMessage, MessageRevision,
+        ReactionSummary, ThreadSummary, UnreadCount,
+    },
+};
+use eyre::Result;
+use hashbrown::{HashMap, HashSet};
+use sqlx::{PgPool, query, query_as, query_scalar};
+use tokio::sync::RwLock;
+use uuid::Uuid;
+
+use crate::{config::OfflineQueueConfig, id::timestamp_of};
+
+///Upper bound on the number of messages a single `FetchHistory` returns
+pub const MAX_HISTORY_PAGE: u32 = 100;
+
+///A chat message as it is persisted, before the server timestamp is
+/// derived from its id
+#[derive(Debug, Clone)]
+pub struct StoredMessage {
+    pub message_id: u64,
+    pub sender_id: Uuid,
+    ///Idempotency key of the sender, absent on messages stored before
+    /// clients provided one
+    pub client_msg_id: Option<String>,
+    pub dest: Destination,
+    pub contents: String,
+    pub client_sent_at: Option<String>,
+    ///Id handed out for the latest edit, the time of the edit is derived
+    /// from it like the send time is from `message_id`
+    pub edited_id: Option<u64>,
+    ///Set once the message was deleted, its contents are gone by then
+    pub deleted_id: Option<u64>,
+    pub reply_to: Option<u64>,
+    ///Message that started the thread this one was posted in
+    pub thread_root: Option<u64>,
+    ///Users in the conversation who were mentioned
+    pub mentions: Vec<Uuid>,
+    ///Whether the whole group was mentioned
+    pub mentions_group: bool,
+}
+impl StoredMessage {
+    pub fn into_historical(
+        self,
+        reactions: Vec<ReactionSummary>,
+        thread: Option<ThreadSummary>,
+    ) -> HistoricalMessage {
+        HistoricalMessage {
+            message_id: self.message_id,
+            user_id: self.sender_id,
+            dest: self.dest,
+            timestamp: timestamp_of(self.message_id).to_string(),
+            contents: self.contents,
+            client_sent_at: self.client_sent_at,
+            edited_at: self.edited_id.map(|id| timestamp_of(id).to_string()),
+            deleted_at: self.deleted_id.map(|id| timestamp_of(id).to_string()),
+            reactions,
+            reply_to: self.reply_to,
+            thread_root: self.thread_root,
+            thread,
+            mentions: self.mentions,
+            mentions_group: self.mentions_group,
+        }
+    }
+}
+impl From<StoredMessage> for CrabbyWsFromServer {
+    fn from(value: StoredMessage) -> Self {
+        CrabbyWsFromServer::ChatMessage {
+            message_id: value.message_id,
+            user_id: value.sender_id,
+            dest: value.dest,
+            timestamp: timestamp_of(value.message_id).to_string(),
+            contents: value.contents,
+            client_sent_at: value.client_sent_at,
+            reply_to: value.reply_to,
+            thread_root: value.thread_root,
+            mentions: value.mentions,
+            mentions_group: value.mentions_group,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SaveOutcome {
+    Stored,
+    ///The sender already has a message with the same `client_msg_id`,
+    /// nothing was written
+    Duplicate {
+        message_id: u64,
+    },
+}
+
+///Persists chat messages and serves them back a page at a time.
+#[async_trait]
+pub trait MessageRepo: Send + Sync + 'static {
+    async fn save(&self, message: &StoredMessage) -> Result<SaveOutcome>;
+    ///Returns at most `limit` messages of the conversation `viewer` has
+    /// with `dest` whose id is below `before`, newest first. For direct
+    /// messages both directions of the conversation are returned. Replies
+    /// in threads are left out.
+    async fn history(
+        &self,
+        viewer: Uuid,
+        dest: &Destination,
+        before: u64,
+        limit: u32,
+    ) -> Result<Vec<StoredMessage>>;
+    ///Returns at most `limit` replies in the thread started by `root`
+    /// whose id is below `before`, newest first
+    async fn thread(
+        &self,
+        root: u64,
+        before: u64,
+        limit: u32,
+    ) -> Result<Vec<StoredMessage>>;
+    ///Replies to each of the messages that started a thread
+    async fn threads(
+        &self,
+        roots: &[u64],
+    ) -> Result<HashMap<u64, ThreadSummary>>;
+    ///The sender of `root` and everyone who replied to it
+    async fn thread_participants(&self, root: u64) -> Result<Vec<Uuid>>;
+    ///Every conversation the user has taken part in: the other side of
+    /// each direct conversation and the groups they have written to
+    async fn conversations(&self, user: Uuid) -> Result<Vec<Destination>>;
+    ///Moves the user's read cursor for `dest` forward to `up_to`, returns
+    /// whether it moved
+    async fn mark_read(
+        &self,
+        user: Uuid,
+        dest: &Destination,
+        up_to: u64,
+    ) -> Result<bool>;
+    ///Unread state of every conversation the user has written in or
+    /// marked read
+    async fn unread(&self, user: Uuid) -> Result<Vec<UnreadCount>>;
+    async fn find(&self, message_id: u64) -> Result<Option<StoredMessage>>;
+    ///Replaces the contents of a message that is not deleted and keeps
+    /// the version it replaced. Returns every earlier version, oldest
+    /// first, or `None` if there is no such message left to edit.
+    async fn edit(
+        &self,
+        message_id: u64,
+        contents: &str,
+        edited_id: u64,
+    ) -> Result<Option<Vec<MessageRevision>>>;
+    ///Turns a message into a tombstone, dropping its contents, earlier
+    /// versions and reactions. Returns whether there was a message left to
+    /// delete.
+    async fn delete(&self, message_id: u64, deleted_id: u64) -> Result<bool>;
+    ///Adds the user's reaction, returning how many users now react to the
+    /// message with `emoji`, or `None` if they already did
+    async fn react(
+        &self,
+        message_id: u64,
+        user: Uuid,
+        emoji: &str,
+    ) -> Result<Option<u64>>;
+    ///Takes the user's reaction back, returning how many users still
+    /// react with `emoji`, or `None` if they did not
+    async fn unreact(
+        &self,
+        message_id: u64,
+        user: Uuid,
+        emoji: &str,
+    ) -> Result<Option<u64>>;
+    ///Reactions to each of the messages that has any, as `viewer` sees
+    /// them
+    async fn reactions(
+        &self,
+        viewer: Uuid,
+        message_ids: &[u64],
+    ) -> Result<HashMap<u64, Vec<ReactionSummary>>>;
+    ///Queues a message for users who are not connected anywhere, dropping
+    /// what goes beyond the limits of each of them
+    async fn enqueue(
+        &self,
+        users: &[Uuid],
+        message_id: u64,
+        limits: OfflineQueueConfig,
+    ) -> Result<()>;
+    ///Takes everything queued for the user that is younger than
+    /// `max_age`, oldest first. Messages deleted in the meantime are left
+    /// out.
+    async fn drain(
+        &self,
+        user: Uuid,
+        max_age: Duration,
+    ) -> Result<Vec<StoredMessage>>;
+}
+
+pub struct MessageStore {
+    repo: Arc<dyn MessageRepo>,
+}
+impl MessageStore {
+    pub fn new(r: impl MessageRepo) -> Self {
+        Self { repo: Arc::new(r) }
+    }
+}
+#[async_trait]
+impl MessageRepo for MessageStore {
+    async fn save(&self, message: &StoredMessage) -> Result<SaveOutcome> {
+        self.repo.save(message).await
+    }
+    async fn history(
+        &self,
+        viewer: Uuid,
+        dest: &Destination,
+        before: u64,
+        limit: u32,
+    ) -> Result<Vec<StoredMessage>> {
+        self.repo.history(viewer, dest, before, limit).await
+    }
+    async fn thread(
+        &self,
+        root: u64,
+        before: u64,
+        limit: u32,
+    ) -> Result<Vec<StoredMessage>> {
+        self.repo.thread(root, before, limit).await
+    }
+    async fn threads(
+        &self,
+        roots: &[u64],
+    ) -> Result<HashMap<u64, ThreadSummary>> {
+        self.repo.threads(roots).await
+    }
+    async fn thread_participants(&self, root: u64) -> Result<Vec<Uuid>> {
+        self.repo.thread_participants(root).await
+    }
+    async fn conversations(&self, user: Uuid) -> Result<Vec<Destination>> {
+        self.repo.conversations(user).await
+    }
+    async fn mark_read(
+        &self,
+        user: Uuid,
+        dest: &Destination,
+        up_to: u64,
+    ) -> Result<bool> {
+        self.repo.mark_read(user, dest, up_to).await
+    }
+    async fn unread(&self, user: Uuid) -> Result<Vec<UnreadCount>> {
+        self.repo.unread(user).await
+    }
+    async fn find(&self, message_id: u64) -> Result<Option<StoredMessage>> {
+        self.repo.find(message_id).await
+    }
+    async fn edit(
+        &self,
+        message_id: u64,
+        contents: &str,
+        edited_id: u64,
+    ) -> Result<Option<Vec<MessageRevision>>> {
+        self.repo.edit(message_id, contents, edited_id).await
+    }
+    async fn delete(&self, message_id: u64, deleted_id: u64) -> Result<bool> {
+        self.repo.delete(message_id, deleted_id).await
+    }
+    async fn react(
+        &self,
+        message_id: u64,
+        user: Uuid,
+        emoji: &str,
+    ) -> Result<Option<u64>> {
+        self.repo.react(message_id, user, emoji).await
+    }
+    async fn unreact(
+        &self,
+        message_id: u64,
+        user: Uuid,
+        emoji: &str,
+    ) -> Result<Option<u64>> {
+        self.repo.unreact(message_id, user, emoji).await
+    }
+    async fn reactions(
+        &self,
+        viewer: Uuid,
+        message_ids: &[u64],
+    ) -> Result<HashMap<u64, Vec<ReactionSummary>>> {
+        self.repo.reactions(viewer, message_ids).await
+    }
+    async fn enqueue(
+        &self,
+        users: &[Uuid],
+        message_id: u64,
+        limits: OfflineQueueConfig,
+    ) -> Result<()> {
+        self.repo.enqueue(users, message_id, limits).await
+    }
+    async fn drain(
+        &self,
+        user: Uuid,
+        max_age: Duration,
+    ) -> Result<Vec<StoredMessage>> {
+        self.repo.drain(user, max_age).await
+    }
+}
+
+#[derive(sqlx::Type, Debug, PartialEq)]
+#[sqlx(type_name = "destination_type", rename_all = "lowercase")]
+enum DestinationType {
+    Individual,
+    Group,
+}
+
+struct MessageRow {
+    message_id: i64,
+    sender_id: Uuid,
+    client_msg_id: Option<String>,
+    group: bool,
+    dest_id: Uuid,
+    contents: String,
+    client_sent_at: Option<String>,
+    edited_id: Option<i64>,
+    deleted_id: Option<i64>,
+    reply_to: Option<i64>,
+    thread_root: Option<i64>,
+    mentions: Vec<Uuid>,
+    mentions_group: bool,
+}
+impl MessageRow {
+    fn into_stored(self) -> StoredMessage {
+        let dest = if self.group {
+            Destination::Group { id: self.dest_id }
+        } else {
+            Destination::Individual { id: self.dest_id }
+        };
+        StoredMessage {
+            message_id: self.message_id as u64,
+            sender_id: self.sender_id,
+            client_msg_id: self.client_msg_id,
+            dest,
+            contents: self.contents,
+            client_sent_at: self.client_sent_at,
+            edited_id: self.edited_id.map(|id| id as u64),
+            deleted_id: self.deleted_id.map(|id| id as u64),
+            reply_to: self.reply_to.map(|id| id as u64),
+            thread_root: self.thread_root.map(|id| id as u64),
+            mentions: self.mentions,
+            mentions_group: self.mentions_group,
+        }
+    }
+}
+
+fn dest_parts(dest: &Destination) -> (DestinationType, Uuid) {
+    match *dest {
+        Destination::Individual { id } => (DestinationType::Individual, id),
+        Destination::Group { id } => (DestinationType::Group, id),
+    }
+}
+//Snowflakes keep the sign bit clear so they always fit in a BIGINT
+fn to_db_id(id: u64) -> i64 {
+    id.min(i64::MAX as u64) as i64
+}
+
+pub struct PgMessageRepo {
+    conn: PgPool,
+}
+impl PgMessageRepo {
+    pub fn new(conn: PgPool) -> Self {
+        Self { conn }
+    }
+    async fn reaction_count(
+        &self,
+        message_id: u64,
+        emoji: &str,
+    ) -> Result<u64> {
+        let count = query_scalar!(
+            "SELECT COUNT(*) AS \"count!\" FROM message_reaction WHERE \
+             message_id = $1 AND emoji = $2",
+            to_db_id(message_id),
+            emoji
+        )
+        .fetch_one(&self.conn)
+        .await?;
+        Ok(count as u64)
+    }
+}
+#[async_trait]
+impl MessageRepo for PgMessageRepo {
+    async fn save(&self, message: &StoredMessage) -> Result<SaveOutcome> {
+        let (dest_type, dest_id) = dest_parts(&message.dest);
+        let inserted = query!(
+            "INSERT INTO chat_message(message_id, sender_id, client_msg_id, \
+             dest_type, dest_id, contents, client_sent_at, reply_to, \
+             thread_root, mentions, mentions_group) VALUES ($1, $2, $3, $4, \
+             $5, $6, $7, $8, $9, $10, $11) ON CONFLICT (sender_id, \
+             client_msg_id) WHERE client_msg_id IS NOT NULL DO NOTHING",
+            to_db_id(message.message_id),
+            message.sender_id,
+            message.client_msg_id,
+            dest_type as DestinationType,
+            dest_id,
+            message.contents,
+            message.client_sent_at,
+            message.reply_to.map(to_db_id),
+            message.thread_root.map(to_db_id),
+            &message.mentions,
+            message.mentions_group
+        )
+        .execute(&self.conn)
+        .await?
+        .rows_affected();
+        if inserted == 1 {
+            return Ok(SaveOutcome::Stored);
+        }
+        let original = query!(
+            "SELECT message_id FROM chat_message WHERE sender_id = $1 AND \
+             client_msg_id = $2",
+            message.sender_id,
+            message.client_msg_id
+        )
+        .fetch_one(&self.conn)
+        .await?;
+        Ok(SaveOutcome::Duplicate {
+            message_id: original.message_id as u64,
+        })
+    }
+
+    async fn history(
+        &self,
+        viewer: Uuid,
+        dest: &Destination,
+        before: u64,
+        limit: u32,
+    ) -> Result<Vec<StoredMessage>> {
+        let rows =
+            match dest {
+                Destination::Group { id } => query_as!(
+                    MessageRow,
+                    "SELECT message_id, sender_id, client_msg_id, dest_type = \
+                     'group' AS \"group!\", dest_id, contents, client_sent_at, \
+                     edited_id, deleted_id, reply_to, thread_root, mentions, \
+                     mentions_group FROM chat_message WHERE dest_type = \
+                     'group' AND dest_id = $1 AND thread_root IS NULL AND \
+                     message_id < $2 ORDER BY message_id DESC LIMIT $3",
+                    id,
+                    to_db_id(before),
+                    i64::from(limit)
+                )
+                .fetch_all(&self.conn)
+                .await?,
+                Destination::Individual { id } => query_as!(
+                    MessageRow,
+                    "SELECT message_id, sender_id, client_msg_id, dest_type = \
+                     'group' AS \"group!\", dest_id, contents, client_sent_at, \
+                     edited_id, deleted_id, reply_to, thread_root, mentions, \
+                     mentions_group FROM chat_message WHERE dest_type = \
+                     'individual' AND ((sender_id = $1 AND dest_id = $2) OR \
+                     (sender_id = $2 AND dest_id = $1)) AND thread_root IS \
+                     NULL AND message_id < $3 ORDER BY message_id DESC LIMIT \
+                     $4",
+                    viewer,
+                    id,
+                    to_db_id(before),
+                    i64::from(limit)
+                )
+                .fetch_all(&self.conn)
+                .await?,
+            };
+        Ok(rows.into_iter().map(MessageRow::into_stored).collect())
+    }
+
+    async fn thread(
+        &self,
+        root: u64,
+        before: u64,
+        limit: u32,
+    ) -> Result<Vec<StoredMessage>> {
+        let rows = query_as!(
+            MessageRow,
+            "SELECT message_id, sender_id, client_msg_id, dest_type = 'group' \
+             AS \"group!\", dest_id, contents, client_sent_at, edited_id, \
+             deleted_id, reply_to, thread_root, mentions, mentions_group FROM \
+             chat_message WHERE thread_root = $1 AND message_id < $2 ORDER BY \
+             message_id DESC LIMIT $3",
+            to_db_id(root),
+            to_db_id(before),
+            i64::from(limit)
+        )
+        .fetch_all(&self.conn)
+        .await?;
+        Ok(rows.into_iter().map(MessageRow::into_stored).collect())
+    }
+
+    async fn threads(
+        &self,
+        roots: &[u64],
+    ) -> Result<HashMap<u64, ThreadSummary>> {
+        let roots: Vec<i64> = roots.iter().copied().map(to_db_id).collect();
+        let rows = query!(
+            "SELECT DISTINCT ON (thread_root) thread_root AS \"root!\", \
+             message_id, sender_id, COUNT(*) OVER (PARTITION BY \
+             thread_root) AS \"replies!\" FROM chat_message WHERE \
+             thread_root = ANY($1::bigint[]) ORDER BY thread_root, \
+             message_id DESC",
+            &roots as &[i64]
+        )
+        .fetch_all(&self.conn)
+        .await?;
+        Ok(rows
+            .into_iter()
+            .map(|row| {
+                let summary = ThreadSummary {
+                    reply_count: row.replies as u64,
+                    last_reply_id: row.message_id as u64,
+                    last_reply_by: row.sender_id,
+                    last_reply_at: timestamp_of(row.message_id as u64)
+                        .to_string(),
+                };
+                (row.root as u64, summary)
+            })
+            .collect())
+    }
+
+    async fn thread_participants(&self, root: u64) -> Result<Vec<Uuid>> {
+        let users = query_scalar!(
+            "SELECT DISTINCT sender_id FROM chat_message WHERE message_id = \
+             $1 OR thread_root = $1",
+            to_db_id(root)
+        )
+        .fetch_all(&self.conn)
+        .await?;
+        Ok(users)
+    }
+
+    async fn conversations(&self, user: Uuid) -> Result<Vec<Destination>> {
+        let rows = query!(
+            "SELECT DISTINCT dest_type = 'group' AS \"group!\", CASE WHEN \
+             dest_type = 'individual' AND dest_id = $1 THEN sender_id ELSE \
+             dest_id END AS \"peer!\" FROM chat_message WHERE sender_id = $1 \
+             OR (dest_type = 'individual' AND dest_id = $1)",
+            user
+        )
+        .fetch_all(&self.conn)
+        .await?;
+        Ok(rows
+            .into_iter()
+            .map(|row| {
+                if row.group {
+                    Destination::Group { id: row.peer }
+                } else {
+                    Destination::Individual { id: row.peer }
+                }
+            })
+            .collect())
+    }
+
+    async fn mark_read(
+        &self,
+        user: Uuid,
+        dest: &Destination,
+        up_to: u64,
+    ) -> Result<bool> {
+        let (dest_type, dest_id) = dest_parts(dest);
+        let moved = query!(
+            "INSERT INTO read_cursor(user_id, dest_type, dest_id, up_to) \
+             VALUES ($1, $2, $3, $4) ON CONFLICT (user_id, dest_type, \
+             dest_id) DO UPDATE SET up_to = EXCLUDED.up_to WHERE \
+             read_cursor.up_to < EXCLUDED.up_to",
+            user,
+            dest_type as DestinationType,
+            dest_id,
+            to_db_id(up_to)
+        )
+        .execute(&self.conn)
+        .await?
+        .rows_affected();
+        Ok(moved == 1)
+    }
+
+    async fn unread(&self, user: Uuid) -> Result<Vec<UnreadCount>> {
+        let rows = query!(
+            "WITH conversation AS (SELECT dest_type, CASE WHEN dest_type = \
+             'individual' AND dest_id = $1 THEN sender_id ELSE dest_id END \
+             AS peer FROM chat_message WHERE sender_id = $1 OR (dest_type = \
+             'individual' AND dest_id = $1) UNION SELECT dest_type, dest_id \
+             FROM read_cursor WHERE user_id = $1) SELECT c.dest_type = \
+             'group' AS \"group!\", c.peer AS \"peer!\", r.up_to AS \
+             \"read_up_to?\", (SELECT COUNT(*) FROM chat_message m WHERE \
+             m.dest_type = c.dest_type AND m.message_id > COALESCE(r.up_to, \
+             0) AND m.sender_id <> $1 AND ((c.dest_type = 'group' AND \
+             m.dest_id = c.peer) OR (c.dest_type = 'individual' AND \
+             m.sender_id = c.peer AND m.dest_id = $1))) AS \"unread!\" FROM \
+             conversation c LEFT JOIN read_cursor r ON r.user_id = $1 AND \
+             r.dest_type = c.dest_type AND r.dest_id = c.peer",
+            user
+        )
+        .fetch_all(&self.conn)
+        .await?;
+        Ok(rows
+            .into_iter()
+            .map(|row| UnreadCount {
+                dest: if row.group {
+                    Destination::Group { id: row.peer }
+                } else {
+                    Destination::Individual { id: row.peer }
+                },
+                unread: row.unread as u64,
+                read_up_to: row.read_up_to.map(|up_to| up_to as u64),
+            })
+            .collect())
+    }
+
+    async fn find(&self, message_id: u64) -> Result<Option<StoredMessage>> {
+        let row = query_as!(
+            MessageRow,
+            "SELECT message_id, sender_id, client_msg_id, dest_type = 'group' \
+             AS \"group!\", dest_id, contents, client_sent_at, edited_id, \
+             deleted_id, reply_to, thread_root, mentions, mentions_group \
+             FROM chat_message WHERE message_id = $1",
+            to_db_id(message_id)
+        )
+        .fetch_optional(&self.conn)
+        .await?;
+        Ok(row.map(MessageRow::into_stored))
+    }
+
+    async fn edit(
+        &self,
+        message_id: u64,
+        contents: &str,
+        edited_id: u64,
+    ) -> Result<Option<Vec<MessageRevision>>> {
+        let mut tx = self.conn.begin().await?;
+        //The row stays locked until the edit is committed, so concurrent
+        // edits from other nodes line up behind each other
+        let kept = query!(
+            "INSERT INTO chat_message_revision(message_id, written_id, \
+             contents) SELECT message_id, COALESCE(edited_id, message_id), \
+             contents FROM chat_message WHERE message_id = $1 AND deleted_id \
+             IS NULL FOR UPDATE",
+            to_db_id(message_id)
+        )
+        .execute(tx.as_mut())
+        .await?
+        .rows_affected();
+        if kept == 0 {
+            return Ok(None);
+        }
+        query!(
+            "UPDATE chat_message SET contents = $2, edited_id = $3 WHERE \
+             message_id = $1",
+            to_db_id(message_id),
+            contents,
+            to_db_id(edited_id)
+        )
+        .execute(tx.as_mut())
+        .await?;
+        let revisions = query!(
+            "SELECT written_id, contents FROM chat_message_revision WHERE \
+             message_id = $1 ORDER BY written_id",
+            to_db_id(message_id)
+        )
+        .fetch_all(tx.as_mut())
+        .await?;
+        tx.commit().await?;
+        Ok(Some(
+            revisions
+                .into_iter()
+                .map(|row| MessageRevision {
+                    contents: row.contents,
+                    written_at: timestamp_of(row.written_id as u64).to_string(),
+                })
+                .collect(),
+        ))
+    }
+
+    async fn delete(&self, message_id: u64, deleted_id: u64) -> Result<bool> {
+        let mut tx = self.conn.begin().await?;
+        let deleted = query!(
+            "UPDATE chat_message SET contents = '', deleted_id = $2 WHERE \
+             message_id = $1 AND deleted_id IS NULL",
+            to_db_id(message_id),
+            to_db_id(deleted_id)
+        )
+        .execute(tx.as_mut())
+        .await?
+        .rows_affected();
+        if deleted == 0 {
+            return Ok(false);
+        }
+        query!(
+            "DELETE FROM chat_message_revision WHERE message_id = $1",
+            to_db_id(message_id)
+        )
+        .execute(tx.as_mut())
+        .await?;
+        query!(
+            "DELETE FROM message_reaction WHERE message_id = $1",
+            to_db_id(message_id)
+        )
+        .execute(tx.as_mut())
+        .await?;
+        tx.commit().await?;
+        Ok(true)
+    }
+
+    async fn react(
+        &self,
+        message_id: u64,
+        user: Uuid,
+        emoji: &str,
+    ) -> Result<Option<u64>> {
+        let added = query!(
+            "INSERT INTO message_reaction(message_id, user_id, emoji) VALUES \
+             ($1, $2, $3) ON CONFLICT DO NOTHING",
+            to_db_id(message_id),
+            user,
+            emoji
+        )
+        .execute(&self.conn)
+        .await?
+        .rows_affected();
+        if added == 0 {
+            return Ok(None);
+        }
+        Ok(Some(self.reaction_count(message_id, emoji).await?))
+    }
+
+    async fn unreact(
+        &self,
+        message_id: u64,
+        user: Uuid,
+        emoji: &str,
+    ) -> Result<Option<u64>> {
+        let removed = query!(
+            "DELETE FROM message_reaction WHERE message_id = $1 AND user_id \
+             = $2 AND emoji = $3",
+            to_db_id(message_id),
+            user,
+            emoji
+        )
+        .execute(&self.conn)
+        .await?
+        .rows_affected();
+        if removed == 0 {
+            return Ok(None);
+        }
+        Ok(Some(self.reaction_count(message_id, emoji).await?))
+    }
+
+    async fn reactions(
+        &self,
+        viewer: Uuid,
+        message_ids: &[u64],
+    ) -> Result<HashMap<u64, Vec<ReactionSummary>>> {
+        let ids: Vec<i64> = message_ids.iter().copied().map(to_db_id).collect();
+        let rows = query!(
+            "SELECT message_id, emoji, COUNT(*) AS \"count!\", BOOL_OR(user_id \
+             = $1) AS \"reacted!\" FROM message_reaction WHERE message_id = \
+             ANY($2::bigint[]) GROUP BY message_id, emoji ORDER BY \
+             MIN(reacted_at)",
+            viewer,
+            &ids as &[i64]
+        )
+        .fetch_all(&self.conn)
+        .await?;
+        let mut reactions: HashMap<u64, Vec<ReactionSummary>> = HashMap::new();
+        for row in rows {
+            reactions.entry(row.message_id as u64).or_default().push(
+                ReactionSummary {
+                    emoji: row.emoji,
+                    count: row.count as u64,
+                    reacted: row.reacted,
+                },
+            );
+        }
+        Ok(reactions)
+    }
+
+    async fn enqueue(
+        &self,
+        users: &[Uuid],
+        message_id: u64,
+        limits: OfflineQueueConfig,
+    ) -> Result<()> {
+        let mut tx = self.conn.begin().await?;
+        query!(
+            "INSERT INTO offline_message(user_id, message_id) SELECT \
+             user_id, $2 FROM UNNEST($1::uuid[]) AS user_id ON CONFLICT DO \
+             NOTHING",
+            users,
+            to_db_id(message_id)
+        )
+        .execute(tx.as_mut())
+        .await?;
+        //Only the queues that just grew are trimmed, the rest shrink when
+        // they next grow or are drained
+        query!(
+            "DELETE FROM offline_message o USING (SELECT user_id, message_id, \
+             ROW_NUMBER() OVER (PARTITION BY user_id ORDER BY message_id \
+             DESC) AS position FROM offline_message WHERE user_id = \
+             ANY($1::uuid[])) q WHERE o.user_id = q.user_id AND o.message_id \
+             = q.message_id AND (q.position > $2 OR o.queued_at < now() - \
+             make_interval(secs => $3))",
+            users,
+            limits.max_messages as i64,
+            limits.max_age.as_secs_f64()
+        )
+        .execute(tx.as_mut())
+        .await?;
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn drain(
+        &self,
+        user: Uuid,
+        max_age: Duration,
+    ) -> Result<Vec<StoredMessage>> {
+        let rows = query_as!(
+            MessageRow,
+            "WITH drained AS (DELETE FROM offline_message WHERE user_id = $1 \
+             RETURNING message_id, queued_at) SELECT m.message_id, \
+             m.sender_id, m.client_msg_id, m.dest_type = 'group' AS \
+             \"group!\", m.dest_id, m.contents, m.client_sent_at, \
+             m.edited_id, m.deleted_id, m.reply_to, m.thread_root, \
+             m.mentions, m.mentions_group FROM drained d JOIN chat_message m \
+             ON m.message_id = d.message_id WHERE m.deleted_id IS NULL AND \
+             d.queued_at > now() - make_interval(secs => $2) ORDER BY \
+             m.message_id",
+            user,
+            max_age.as_secs_f64()
+        )
+        .fetch_all(&self.conn)
+        .await?;
+        Ok(rows.into_iter().map(MessageRow::into_stored).collect())
+    }
+}
+
+///Keeps messages in memory, used in tests and when running the engine
+/// without a database.
+#[derive(Default)]
+pub struct InMemoryMessageRepo {
+    messages: RwLock<Vec<StoredMessage>>,
+    cursors: RwLock<HashMap<(Uuid, Destination), u64>>,
+    revisions: RwLock<HashMap<u64, Vec<MessageRevision>>>,
+    ///Message, user and emoji of every reaction, oldest first
+    reactions: RwLock<Vec<(u64, Uuid, String)>>,
+    ///User and message of every queued message, with when it was queued
+    offline: RwLock<Vec<(Uuid, u64, Instant)>>,
+}
+#[async_trait]
+impl MessageRepo for InMemoryMessageRepo {
+    async fn save(&self, message: &StoredMessage) -> Result<SaveOutcome> {
+        let mut messages = self.messages.write().await;
+        if message.client_msg_id.is_some()
+            && let Some(original) = messages.iter().find(|m| {
+                m.sender_id == message.sender_id
+                    && m.client_msg_id == message.client_msg_id
+            })
+        {
+            return Ok(SaveOutcome::Duplicate {
+                message_id: original.message_id,
+            });
+        }
+        messages.push(message.clone());
+        Ok(SaveOutcome::Stored)
+    }
+
+    async fn history(
+        &self,
+        viewer: Uuid,
+        dest: &Destination,
+        before: u64,
+        limit: u32,
+    ) -> Result<Vec<StoredMessage>> {
+        let messages = self.messages.read().await;
+        let mut page: Vec<_> = messages
+            .iter()
+            .filter(|m| m.message_id < before && m.thread_root.is_none())
+            .filter(|m| match (dest, &m.dest) {
+                (Destination::Group { id }, Destination::Group { id: to }) => {
+                    id == to
+                }
+                (
+                    Destination::Individual { id },
+                    Destination::Individual { id: to },
+                ) => {
+                    (m.sender_id == viewer && to == id)
+                        || (m.sender_id == *id && *to == viewer)
+                }
+                _ => false,
+            })
+            .cloned()
+            .collect();
+        page.sort_by_key(|m| std::cmp::Reverse(m.message_id));
+        page.truncate(limit as usize);
+        Ok(page)
+    }
+
+    async fn thread(
+        &self,
+        root: u64,
+        before: u64,
+        limit: u32,
+    ) -> Result<Vec<StoredMessage>> {
+        let messages = self.messages.read().await;
+        let mut page: Vec<_> = messages
+            .iter()
+            .filter(|m| m.thread_root == Some(root) && m.message_id < before)
+            .cloned()
+            .collect();
+        page.sort_by_key(|m| std::cmp::Reverse(m.message_id));
+        page.truncate(limit as usize);
+        Ok(page)
+    }
+
+    async fn threads(
+        &self,
+        roots: &[u64],
+    ) -> Result<HashMap<u64, ThreadSummary>> {
+        let messages = self.messages.read().await;
+        let mut threads: HashMap<u64, ThreadSummary> = HashMap::new();
+        for m in messages.iter() {
+            let Some(root) = m.thread_root.filter(|root| roots.contains(root))
+            else {
+                continue;
+            };
+            let summary = threads.entry(root).or_insert(ThreadSummary {
+                reply_count: 0,
+                last_reply_id: 0,
+                last_reply_by: m.sender_id,
+                last_reply_at: String::new(),
+            });
+            summary.reply_count += 1;
+            if m.message_id >= summary.last_reply_id {
+                summary.last_reply_id = m.message_id;
+                summary.last_reply_by = m.sender_id;
+                summary.last_reply_at = timestamp_of(m.message_id).to_string();
+            }
+        }
+        Ok(threads)
+    }
+
+    async fn thread_participants(&self, root: u64) -> Result<Vec<Uuid>> {
+        let messages = self.messages.read().await;
+        let users: HashSet<_> = messages
+            .iter()
+            .filter(|m| m.message_id == root || m.thread_root == Some(root))
+            .map(|m| m.sender_id)
+            .collect();
+        Ok(users.into_iter().collect())
+    }
+
+    async fn conversations(&self, user: Uuid) -> Result<Vec<Destination>> {
+        let messages = self.messages.read().await;
+        let mut groups = HashSet::new();
+        let mut peers = HashSet::new();
+        for m in messages.iter() {
+            match m.dest {
+                Destination::Group { id } if m.sender_id == user => {
+                    groups.insert(id);
+                }
+                Destination::Individual { id } if m.sender_id == user => {
+                    peers.insert(id);
+                }
+                Destination::Individual { id } if id == user => {
+                    peers.insert(m.sender_id);
+                }
+                _ => {}
+            }
+        }
+        Ok(groups
+            .into_iter()
+            .map(|id| Destination::Group { id })
+            .chain(peers.into_iter().map(|id| Destination::Individual { id }))
+            .collect())
+    }
+
+    async fn mark_read(
+        &self,
+        user: Uuid,
+        dest: &Destination,
+        up_to: u64,
+    ) -> Result<bool> {
+        let mut cursors = self.cursors.write().await;
+        let cursor = cursors.entry((user, dest.clone())).or_default();
+        if *cursor >= up_to {
+            return Ok(false);
+        }
+        *cursor = up_to;
+        Ok(true)
+    }
+
+    async fn unread(&self, user: Uuid) -> Result<Vec<UnreadCount>> {
+        let mut dests: HashSet<_> =
+            self.conversations(user).await?.into_iter().collect();
+        let cursors = self.cursors.read().await;
+        dests.extend(
+            cursors
+                .keys()
+                .filter(|(reader, _)| *reader == user)
+                .map(|(_, dest)| dest.clone()),
+        );
+        let messages = self.messages.read().await;
+        Ok(dests
+            .into_iter()
+            .map(|dest| {
+                let read_up_to = cursors.get(&(user, dest.clone())).copied();
+                let unread = messages
+                    .iter()
+                    .filter(|m| m.message_id > read_up_to.unwrap_or(0))
+                    .filter(|m| m.sender_id != user)
+                    .filter(|m| match (&dest, &m.dest) {
+                        (
+                            Destination::Group { id },
+                            Destination::Group { id: to },
+                        ) => id == to,
+                        (
+                            Destination::Individual { id },
+                            Destination::Individual { id: to },
+                        ) => m.sender_id == *id && *to == user,
+                        _ => false,
+                    })
+                    .count() as u64;
+                UnreadCount {
+                    dest,
+                    unread,
+                    read_up_to,
+                }
+            })
+            .collect())
+    }
+
+    async fn find(&self, message_id: u64) -> Result<Option<StoredMessage>> {
+        let messages = self.messages.read().await;
+        Ok(messages
+            .iter()
+            .find(|m| m.message_id == message_id)
+            .cloned())
+    }
+
+    async fn edit(
+        &self,
+        message_id: u64,
+        contents: &str,
+        edited_id: u64,
+    ) -> Result<Option<Vec<MessageRevision>>> {
+        let mut messages = self.messages.write().await;
+        let Some(message) = messages
+            .iter_mut()
+            .find(|m| m.message_id == message_id && m.deleted_id.is_none())
+        else {
+            return Ok(None);
+        };
+        let written_id = message.edited_id.unwrap_or(message.message_id);
+        let replaced =
+            std::mem::replace(&mut message.contents, contents.to_string());
+        message.edited_id = Some(edited_id);
+        let mut revisions = self.revisions.write().await;
+        let kept = revisions.entry(message_id).or_default();
+        kept.push(MessageRevision {
+            contents: replaced,
+            written_at: timestamp_of(written_id).to_string(),
+        });
+        Ok(Some(kept.clone()))
+    }
+
+    async fn delete(&self, message_id: u64, deleted_id: u64) -> Result<bool> {
+        let mut messages = self.messages.write().await;
+        let Some(message) = messages
+            .iter_mut()
+            .find(|m| m.message_id == message_id && m.deleted_id.is_none())
+        else {
+            return Ok(false);
+        };
+        message.contents.clear();
+        message.deleted_id = Some(deleted_id);
+        self.revisions.write().await.remove(&message_id);
+        self.reactions
+            .write()
+            .await
+            .retain(|(reacted_to, _, _)| *reacted_to != message_id);
+        Ok(true)
+    }
+
+    async fn react(
+        &self,
+        message_id: u64,
+        user: Uuid,
+        emoji: &str,
+    ) -> Result<Option<u64>> {
+        let mut reactions = self.reactions.write().await;
+        let reaction = (message_id, user, emoji.to_string());
+        if reactions.contains(&reaction) {
+            return Ok(None);
+        }
+        reactions.push(reaction);
+        Ok(Some(count_of(&reactions, message_id, emoji)))
+    }
+
+    async fn unreact(
+        &self,
+        message_id: u64,
+        user: Uuid,
+        emoji: &str,
+    ) -> Result<Option<u64>> {
+        let mut reactions = self.reactions.write().await;
+        let before = reactions.len();
+        reactions.retain(|(reacted_to, by, with)| {
+            (*reacted_to, *by, with.as_str()) != (message_id, user, emoji)
+        });
+        if reactions.len() == before {
+            return Ok(None);
+        }
+        Ok(Some(count_of(&reactions, message_id, emoji)))
+    }
+
+    async fn reactions(
+        &self,
+        viewer: Uuid,
+        message_ids: &[u64],
+    ) -> Result<HashMap<u64, Vec<ReactionSummary>>> {
+        let reactions = self.reactions.read().await;
+        let mut summaries: HashMap<u64, Vec<ReactionSummary>> = HashMap::new();
+        for (message_id, user, emoji) in reactions.iter() {
+            if !message_ids.contains(message_id) {
+                continue;
+            }
+            let summary = summaries.entry(*message_id).or_default();
+            match summary.iter_mut().find(|s| s.emoji == *emoji) {
+                Some(existing) => {
+                    existing.count += 1;
+                    existing.reacted |= *user == viewer;
+                }
+                None => summary.push(ReactionSummary {
+                    emoji: emoji.clone(),
+                    count: 1,
+                    reacted: *user == viewer,
+                }),
+            }
+        }
+        Ok(summaries)
+    }
+
+    async fn enqueue(
+        &self,
+        users: &[Uuid],
+        message_id: u64,
+        limits: OfflineQueueConfig,
+    ) -> Result<()> {
+        let mut offline = self.offline.write().await;
+        for user in users {
+            if !offline
+                .iter()
+                .any(|(u, m, _)| (u, *m) == (user, message_id))
+            {
+                offline.push((*user, message_id, Instant::now()));
+            }
+        }
+        offline
+            .retain(|(_, _, queued_at)| queued_at.elapsed() < limits.max_age);
+        for user in users {
+            let mut queued: Vec<u64> = offline
+                .iter()
+                .filter(|(u, _, _)| u == user)
+                .map(|(_, m, _)| *m)
+                .collect();
+            if queued.len() <= limits.max_messages {
+                continue;
+            }
+            queued.sort_unstable();
+            let dropped = &queued[..queued.len() - limits.max_messages];
+            offline.retain(|(u, m, _)| u != user || !dropped.contains(m));
+        }
+        Ok(())
+    }
+
+    async fn drain(
+        &self,
+        user: Uuid,
+        max_age: Duration,
+    ) -> Result<Vec<StoredMessage>> {
+        let mut offline = self.offline.write().await;
+        let mut queued = Vec::new();
+        offline.retain(|(u, message_id, queued_at)| {
+            if *u == user && queued_at.elapsed() < max_age {
+                queued.push(*message_id);
+            }
+            *u != user
+        });
+        queued.sort_unstable();
+        let messages = self.messages.read().await;
+        Ok(queued
+            .into_iter()
+            .filter_map(|id| messages.iter().find(|m| m.message_id == id))
+            .filter(|m| m.deleted_id.is_none())
+            .cloned()
+            .collect())
+    }
+}
+
+fn count_of(
+    reactions: &[(u64, Uuid, String)],
+    message_id: u64,
+    emoji: &str,
+) -> u64 {
+    reactions
+        .iter()
+        .filter(|(reacted_to, _, with)| {
+            *reacted_to == message_id && with == emoji
+        })
+        .count() as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn direct(id: u64, from: Uuid, to: Uuid) -> StoredMessage {
+        StoredMessage {
+            message_id: id,
+            sender_id: from,
+            client_msg_id: Some(format!("c{id}")),
+            dest: Destination::Individual { id: to },
+            contents: format!("message {id}"),
+            client_sent_at: None,
+            edited_id: None,
+            deleted_id: None,
+            reply_to: None,
+            thread_root: None,
+            mentions: Vec::new(),
+            mentions_group: false,
+        }
+    }
+
+    #[tokio::test]
+    async fn direct_history_contains_both_sides_newest_first() {
+        let repo = InMemoryMessageRepo::default();
+        let (alice, bob, carol) =
+            (Uuid::from_u128(1), Uuid::from_u128(2), Uuid::from_u128(3));
+        repo.save(&direct(1, alice, bob)).await.unwrap();
+        repo.save(&direct(2, bob, alice)).await.unwrap();
+        repo.save(&direct(3, alice, carol)).await.unwrap();
+
+        let page = repo
+            .history(alice, &Destination::Individual { id: bob }, u64::MAX, 10)
+            .await
+            .unwrap();
+        let ids: Vec<_> = page.iter().map(|m| m.message_id).collect();
+        assert_eq!(ids, vec![2, 1]);
+    }
+
+    #[tokio::test]
+    async fn history_pages_with_before_and_limit() {
+        let repo = InMemoryMessageRepo::default();
+        let (alice, group) = (Uuid::from_u128(1), Uuid::from_u128(100));
+        for id in 1..=5 {
+            repo.save(&StoredMessage {
+                message_id: id,
+                sender_id: alice,
+                client_msg_id: None,
+                dest: Destination::Group { id: group },
+                contents: String::new(),
+                client_sent_at: None,
+                edited_id: None,
+                deleted_id: None,
+                reply_to: None,
+                thread_root: None,
+                mentions: Vec::new(),
+                mentions_group: false,
+            })
+            .await
+            .unwrap();
+        }
+
+        let page = repo
+            .history(alice, &Destination::Group { id: group }, 4, 2)
+            .await
+            .unwrap();
+        let ids: Vec<_> = page.iter().map(|m| m.message_id).collect();
+        assert_eq!(ids, vec![3, 2]);
+    }
+
+    #[tokio::test]
+    async fn same_client_msg_id_is_a_duplicate_per_sender() {
+        let repo = InMemoryMessageRepo::default();
+        let (alice, bob) = (Uuid::from_u128(1), Uuid::from_u128(2));
+        let mut first = direct(1, alice, bob);
+        first.client_msg_id = Some("same".to_string());
+        let mut retry = direct(2, alice, bob);
+        retry.client_msg_id = Some("same".to_string());
+        let mut other = direct(3, bob, alice);
+        other.client_msg_id = Some("same".to_string());
+
+        assert_eq!(repo.save(&first).await.unwrap(), SaveOutcome::Stored);
+        assert_eq!(
+            repo.save(&retry).await.unwrap(),
+            SaveOutcome::Duplicate { message_id: 1 }
+        );
+        assert_eq!(repo.save(&other).await.unwrap(), SaveOutcome::Stored);
+    }
+
+    #[tokio::test]
+    async fn unread_counts_start_after_the_read_cursor() {
+        let repo = InMemoryMessageRepo::default();
+        let (alice, bob) = (Uuid::from_u128(1), Uuid::from_u128(2));
+        for id in 1..=3 {
+            repo.save(&direct(id, bob, alice)).await.unwrap();
+        }
+        repo.save(&direct(4, alice, bob)).await.unwrap();
+        let dest = Destination::Individual { id: bob };
+
+        assert!(repo.mark_read(alice, &dest, 2).await.unwrap());
+        assert!(!repo.mark_read(alice, &dest, 1).await.unwrap());
+
+        let counts = repo.unread(alice).await.unwrap();
+        assert_eq!(
+            counts,
+            vec![UnreadCount {
+                dest,
+                unread: 1,
+                read_up_to: Some(2),
+            }]
+        );
+    }
+
+    #[tokio::test]
+    async fn edits_keep_earlier_versions_until_deleted() {
+        let repo = InMemoryMessageRepo::default();
+        let (alice, bob) = (Uuid::from_u128(1), Uuid::from_u128(2));
+        repo.save(&direct(1, alice, bob)).await.unwrap();
+
+        let revisions = repo.edit(1, "second", 2).await.unwrap().unwrap();
+        assert_eq!(revisions.len(), 1);
+        let revisions = repo.edit(1, "third", 3).await.unwrap().unwrap();
+        let contents: Vec<_> =
+            revisions.iter().map(|r| r.contents.as_str()).collect();
+        assert_eq!(contents, vec!["message 1", "second"]);
+        assert_eq!(revisions[1].written_at, timestamp_of(2).to_string());
+
+        assert!(repo.delete(1, 4).await.unwrap());
+        assert!(!repo.delete(1, 5).await.unwrap());
+        assert!(repo.edit(1, "fourth", 6).await.unwrap().is_none());
+        let tombstone = repo.find(1).await.unwrap().unwrap();
+        assert!(tombstone.contents.is_empty());
+        assert_eq!(tombstone.edited_id, Some(3));
+        assert_eq!(tombstone.deleted_id, Some(4));
+    }
+
+    #[tokio::test]
+    async fn reactions_are_counted_per_emoji() {
+        let repo = InMemoryMessageRepo::default();
+        let (alice, bob) = (Uuid::from_u128(1), Uuid::from_u128(2));
+        repo.save(&direct(1, alice, bob)).await.unwrap();
+
+        assert_eq!(repo.react(1, alice, "👍").await.unwrap(), Some(1));
+        assert_eq!(repo.react(1, alice, "👍").await.unwrap(), None);
+        assert_eq!(repo.react(1, bob, "👍").await.unwrap(), Some(2));
+        assert_eq!(repo.react(1, bob, "🎉").await.unwrap(), Some(1));
+        assert_eq!(repo.unreact(1, bob, "👍").await.unwrap(), Some(1));
+        assert_eq!(repo.unreact(1, bob, "👍").await.unwrap(), None);
+
+        let reactions = repo.reactions(bob, &[1, 2]).await.unwrap();
+        assert_eq!(
+            reactions[&1],
+            vec![
+                ReactionSummary {
+                    emoji: "👍".to_string(),
+                    count: 1,
+                    reacted: false,
+                },
+                ReactionSummary {
+                    emoji: "🎉".to_string(),
+                    count: 1,
+                    reacted: true,
+                },
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn replies_stay_out_of_history_and_sum_up_per_thread() {
+        let repo = InMemoryMessageRepo::default();
+        let (alice, bob) = (Uuid::from_u128(1), Uuid::from_u128(2));
+        repo.save(&direct(1, alice, bob)).await.unwrap();
+        for (id, from, to) in [(2, bob, alice), (3, alice, bob)] {
+            let mut reply = direct(id, from, to);
+            reply.reply_to = Some(1);
+            reply.thread_root = Some(1);
+            repo.save(&reply).await.unwrap();
+        }
+
+        let dest = Destination::Individual { id: bob };
+        let page = repo.history(alice, &dest, u64::MAX, 10).await.unwrap();
+        assert_eq!(page.len(), 1);
+        let replies = repo.thread(1, u64::MAX, 10).await.unwrap();
+        let ids: Vec<_> = replies.iter().map(|m| m.message_id).collect();
+        assert_eq!(ids, vec![3, 2]);
+
+        let threads = repo.threads(&[1]).await.unwrap();
+        assert_eq!(threads[&1].reply_count, 2);
+        assert_eq!(threads[&1].last_reply_id, 3);
+        assert_eq!(threads[&1].last_reply_by, alice);
+        let mut participants = repo.thread_participants(1).await.unwrap();
+        participants.sort();
+        assert_eq!(participants, vec![alice, bob]);
+    }
+
+    #[tokio::test]
+    async fn offline_queue_keeps_the_newest_messages() {
+        let repo = InMemoryMessageRepo::default();
+        let (alice, bob) = (Uuid::from_u128(1), Uuid::from_u128(2));
+        let limits = OfflineQueueConfig {
+            max_messages: 2,
+            ..OfflineQueueConfig::default()
+        };
+        for id in 1..=4 {
+            repo.save(&direct(id, alice, bob)).await.unwrap();
+            repo.enqueue(&[bob], id, limits).await.unwrap();
+        }
+        repo.delete(4, 5).await.unwrap();
+
+        let drained = repo.drain(bob, limits.max_age).await.unwrap();
+        let ids: Vec<_> = drained.iter().map(|m| m.message_id).collect();
+        assert_eq!(ids, vec![3]);
+        assert!(repo.drain(bob, limits.max_age).await.unwrap().is_empty());
+
+        repo.enqueue(&[bob], 1, limits).await.unwrap();
+        assert!(repo.drain(bob, Duration::ZERO).await.unwrap().is_empty());
+    }
+}
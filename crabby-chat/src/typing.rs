@@ -0,0 +1,131 @@
+use std::time::{Duration, Instant};
+
+use crabby_specs::ws::common::Destination;
+use hashbrown::HashMap;
+use uuid::Uuid;
+
+///Least time between two `started` events of a user in one conversation
+/// that are passed on, anything in between only keeps the indicator alive
+pub const TYPING_INTERVAL: Duration = Duration::from_secs(3);
+
+///How long an indicator lasts without being refreshed
+pub const TYPING_TIMEOUT: Duration = Duration::from_secs(8);
+
+struct Indicator {
+    forwarded_at: Instant,
+    refreshed_at: Instant,
+}
+
+///What became of an indicator once its timeout came up
+#[derive(Debug, PartialEq, Eq)]
+pub enum Expiry {
+    ///Not refreshed in time, it is gone and a `stopped` is due
+    Expired,
+    ///Refreshed since, check again after this long
+    Remaining(Duration),
+    ///Already stopped by the client
+    Gone,
+}
+
+///Typing indicators currently showing, one per user and conversation.
+/// Nothing in here outlives the process, typing is never stored.
+#[derive(Default)]
+pub struct TypingTracker {
+    indicators: HashMap<(Uuid, Destination), Indicator>,
+}
+impl TypingTracker {
+    pub fn is_showing(&self, user_id: Uuid, dest: &Destination) -> bool {
+        self.indicators.contains_key(&(user_id, dest.clone()))
+    }
+    ///Records a `started` and tells whether it should be passed on
+    pub fn started(
+        &mut self,
+        user_id: Uuid,
+        dest: Destination,
+        now: Instant,
+    ) -> bool {
+        match self.indicators.get_mut(&(user_id, dest.clone())) {
+            Some(indicator) => {
+                indicator.refreshed_at = now;
+                if now.duration_since(indicator.forwarded_at) < TYPING_INTERVAL
+                {
+                    return false;
+                }
+                indicator.forwarded_at = now;
+                true
+            }
+            None => {
+                self.indicators.insert(
+                    (user_id, dest),
+                    Indicator {
+                        forwarded_at: now,
+                        refreshed_at: now,
+                    },
+                );
+                true
+            }
+        }
+    }
+    ///Records a `stopped`, which is only passed on if an indicator was
+    /// showing
+    pub fn stopped(&mut self, user_id: Uuid, dest: Destination) -> bool {
+        self.indicators.remove(&(user_id, dest)).is_some()
+    }
+    pub fn expire(
+        &mut self,
+        user_id: Uuid,
+        dest: Destination,
+        now: Instant,
+    ) -> Expiry {
+        let key = (user_id, dest);
+        let Some(indicator) = self.indicators.get(&key) else {
+            return Expiry::Gone;
+        };
+        let idle = now.duration_since(indicator.refreshed_at);
+        if idle < TYPING_TIMEOUT {
+            return Expiry::Remaining(TYPING_TIMEOUT - idle);
+        }
+        self.indicators.remove(&key);
+        Expiry::Expired
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn started_is_rate_limited_but_keeps_the_indicator_alive() {
+        let (user, dest) =
+            (Uuid::from_u128(1), Destination::Group { id: Uuid::nil() });
+        let mut typing = TypingTracker::default();
+        let start = Instant::now();
+
+        assert!(typing.started(user, dest.clone(), start));
+        let soon = start + Duration::from_secs(1);
+        assert!(!typing.started(user, dest.clone(), soon));
+        assert!(typing.started(user, dest.clone(), start + TYPING_INTERVAL));
+
+        assert_eq!(
+            typing.expire(user, dest.clone(), start + TYPING_TIMEOUT),
+            Expiry::Remaining(TYPING_INTERVAL)
+        );
+        let later = start + TYPING_INTERVAL + TYPING_TIMEOUT;
+        assert_eq!(typing.expire(user, dest.clone(), later), Expiry::Expired);
+        assert!(!typing.stopped(user, dest));
+    }
+
+    #[test]
+    fn stopped_is_passed_on_once() {
+        let (user, dest) = (
+            Uuid::from_u128(1),
+            Destination::Individual { id: Uuid::nil() },
+        );
+        let mut typing = TypingTracker::default();
+
+        assert!(typing.started(user, dest.clone(), Instant::now()));
+        assert!(typing.stopped(user, dest.clone()));
+        assert!(!typing.stopped(user, dest.clone()));
+        assert_eq!(typing.expire(user, dest, Instant::now()), Expiry::Gone);
+    }
+}
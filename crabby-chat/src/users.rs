@@ -0,0 +1,87 @@
+use async_trait::async_trait;
+use eyre::{Result, eyre};
+use hashbrown::HashMap;
+use tonic::{
+    Request,
+    metadata::{Ascii, MetadataValue},
+    transport::{Channel, Endpoint},
+};
+use uuid::Uuid;
+
+use crate::auth::proto::{
+    LookupUsersRequest, authenticate_client::AuthenticateClient,
+};
+
+//Same entry crabby-auth checks internal-only calls for
+const SERVICE_TOKEN_HEADER: &str = "x-service-token";
+
+///Resolves usernames to the ids of the users behind them, so `@username`
+/// mentions can be turned into users.
+#[async_trait]
+pub trait ResolveUsers: Send + Sync + 'static {
+    ///Ids of the users with one of `usernames`, unknown names are skipped.
+    /// Names are matched case-insensitively.
+    async fn by_username(&self, usernames: &[String]) -> Result<Vec<Uuid>>;
+}
+
+///Static username table, used in tests.
+#[derive(Default)]
+pub struct InMemoryUsers {
+    users: HashMap<String, Uuid>,
+}
+impl InMemoryUsers {
+    pub fn with_user(mut self, username: &str, user_id: Uuid) -> Self {
+        self.users.insert(username.to_lowercase(), user_id);
+        self
+    }
+}
+#[async_trait]
+impl ResolveUsers for InMemoryUsers {
+    async fn by_username(&self, usernames: &[String]) -> Result<Vec<Uuid>> {
+        Ok(usernames
+            .iter()
+            .filter_map(|name| self.users.get(&name.to_lowercase()).copied())
+            .collect())
+    }
+}
+
+///Looks users up in crabby-auth's `LookupUsers`, which only answers
+/// callers presenting the shared `INTERNAL_SERVICE_TOKEN`
+pub struct AuthServiceUsers {
+    client: AuthenticateClient<Channel>,
+    service_token: MetadataValue<Ascii>,
+}
+impl AuthServiceUsers {
+    ///The connection is lazy so the chat service can start before
+    /// crabby-auth is reachable
+    pub fn new(addr: String, service_token: &str) -> Result<Self> {
+        let channel = Endpoint::from_shared(addr)?.connect_lazy();
+        Ok(Self {
+            client: AuthenticateClient::new(channel),
+            service_token: service_token.parse()?,
+        })
+    }
+}
+#[async_trait]
+impl ResolveUsers for AuthServiceUsers {
+    async fn by_username(&self, usernames: &[String]) -> Result<Vec<Uuid>> {
+        let mut request = Request::new(LookupUsersRequest {
+            usernames: usernames.to_vec(),
+        });
+        request
+            .metadata_mut()
+            .insert(SERVICE_TOKEN_HEADER, self.service_token.clone());
+        let users = self
+            .client
+            .clone()
+            .lookup_users(request)
+            .await
+            .map_err(|status| eyre!("auth service: {status}"))?
+            .into_inner()
+            .users;
+        users
+            .iter()
+            .map(|user| Ok(Uuid::parse_str(&user.user_id)?))
+            .collect()
+    }
+}
@@ -0,0 +1,181 @@
+use crabby_specs::ws::{
+    incoming::CrabbyWsFromClient,
+    outgoing::{CrabbyWsFromServer, ErrorCode},
+};
+use jiff::Timestamp;
+use unicode_normalization::UnicodeNormalization;
+
+use crate::config::FrameLimits;
+
+///A frame refused before it reached the engine
+#[derive(Debug, PartialEq, Eq)]
+pub struct Rejection {
+    pub code: ErrorCode,
+    pub reason: String,
+}
+impl Rejection {
+    pub fn new(code: ErrorCode, reason: impl Into<String>) -> Self {
+        Self {
+            code,
+            reason: reason.into(),
+        }
+    }
+    ///The `Error` sent back, pointing at `cause` if the frame could be
+    /// decoded
+    pub fn into_event(
+        self,
+        cause: Option<&CrabbyWsFromClient>,
+    ) -> CrabbyWsFromServer {
+        CrabbyWsFromServer::error(
+            self.code,
+            self.reason,
+            cause.map(CrabbyWsFromClient::correlation),
+        )
+    }
+}
+
+///Checks a decoded client message. The contents of new and edited
+/// messages are normalised to NFC.
+pub fn message(
+    msg: &mut CrabbyWsFromClient,
+    limits: &FrameLimits,
+) -> Result<(), Rejection> {
+    match msg {
+        CrabbyWsFromClient::UserMessage {
+            contents,
+            client_sent_at,
+            ..
+        } => {
+            if let Some(sent_at) = client_sent_at
+                && sent_at.parse::<Timestamp>().is_err()
+            {
+                return Err(Rejection::new(
+                    ErrorCode::InvalidTimestamp,
+                    "client_sent_at is not an RFC 3339 time",
+                ));
+            }
+            *contents = normalise(contents, limits)?;
+        }
+        CrabbyWsFromClient::EditMessage { contents, .. } => {
+            *contents = normalise(contents, limits)?;
+        }
+        _ => (),
+    }
+    Ok(())
+}
+
+fn normalise(raw: &str, limits: &FrameLimits) -> Result<String, Rejection> {
+    //Tab and newline are how people lay out what they write, anything
+    // else could mess with the terminal or layout of whoever reads it
+    if let Some(control) = raw
+        .chars()
+        .find(|c| c.is_control() && !matches!(c, '\t' | '\n'))
+    {
+        return Err(Rejection::new(
+            ErrorCode::ControlCharacters,
+            format!("contents hold U+{:04X}", u32::from(control)),
+        ));
+    }
+    let normalised: String = raw.nfc().collect();
+    if normalised.trim().is_empty() {
+        return Err(Rejection::new(
+            ErrorCode::EmptyMessage,
+            "contents are blank",
+        ));
+    }
+    if normalised.len() > limits.max_contents_bytes {
+        return Err(Rejection::new(
+            ErrorCode::MessageTooLarge,
+            format!(
+                "contents are {} bytes, at most {} are accepted",
+                normalised.len(),
+                limits.max_contents_bytes
+            ),
+        ));
+    }
+    Ok(normalised)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crabby_specs::ws::common::Destination;
+    use uuid::Uuid;
+
+    fn user_message(contents: &str) -> CrabbyWsFromClient {
+        CrabbyWsFromClient::UserMessage {
+            client_msg_id: "c1".to_string(),
+            dest: Destination::Individual { id: Uuid::nil() },
+            contents: contents.to_string(),
+            client_sent_at: None,
+            reply_to: None,
+            thread_root: None,
+            mentions: Vec::new(),
+        }
+    }
+
+    fn check(
+        mut msg: CrabbyWsFromClient,
+        limits: &FrameLimits,
+    ) -> Result<CrabbyWsFromClient, Rejection> {
+        message(&mut msg, limits).map(|()| msg)
+    }
+
+    fn code(msg: CrabbyWsFromClient) -> ErrorCode {
+        check(msg, &FrameLimits::default()).unwrap_err().code
+    }
+
+    #[test]
+    fn contents_are_normalised_to_nfc() {
+        //"é" written as "e" and a combining acute accent
+        let checked =
+            check(user_message("cafe\u{301}"), &FrameLimits::default());
+        match checked.unwrap() {
+            CrabbyWsFromClient::UserMessage { contents, .. } => {
+                assert_eq!(contents, "caf\u{e9}")
+            }
+            other => panic!("Expected UserMessage, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn blank_and_control_characters_are_refused() {
+        assert_eq!(code(user_message("")), ErrorCode::EmptyMessage);
+        assert_eq!(code(user_message(" \n\t")), ErrorCode::EmptyMessage);
+        assert_eq!(code(user_message("hi\u{7}")), ErrorCode::ControlCharacters);
+        assert_eq!(
+            code(CrabbyWsFromClient::EditMessage {
+                message_id: 1,
+                contents: "\u{1b}[2J".to_string(),
+            }),
+            ErrorCode::ControlCharacters
+        );
+        assert!(
+            check(user_message("two\nlines"), &FrameLimits::default()).is_ok()
+        );
+    }
+
+    #[test]
+    fn size_is_counted_after_normalising() {
+        let limits = FrameLimits {
+            max_frame_bytes: 1024,
+            max_contents_bytes: 3,
+        };
+        //Four bytes as sent, three once composed
+        assert!(check(user_message("ae\u{301}"), &limits).is_ok());
+        assert_eq!(
+            check(user_message("abcd"), &limits).unwrap_err().code,
+            ErrorCode::MessageTooLarge
+        );
+    }
+
+    #[test]
+    fn client_sent_at_must_be_a_time() {
+        let mut msg = user_message("hi");
+        if let CrabbyWsFromClient::UserMessage { client_sent_at, .. } = &mut msg
+        {
+            *client_sent_at = Some("yesterday".to_string());
+        }
+        assert_eq!(code(msg), ErrorCode::InvalidTimestamp);
+    }
+}
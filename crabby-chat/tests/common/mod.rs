@@ -0,0 +1,73 @@
+use std::time::Duration;
+
+use sqlx::{AssertSqlSafe, Connection, PgConnection, PgPool, SqlSafeStr};
+use url::Url;
+use uuid::Uuid;
+
+///A temporary database with the chat migrations applied, one per test so
+/// tests never see each other's rows and can run in parallel
+pub struct TestDb {
+    pub pool: PgPool,
+    admin_url: String,
+    db_name: String,
+}
+
+impl TestDb {
+    ///Creates the database on the server `DATABASE_URL` points to, the
+    /// role has to be allowed to `CREATE DATABASE`
+    pub async fn new() -> eyre::Result<Self> {
+        let base = std::env::var("DATABASE_URL")
+            .or_else(|_| std::env::var("TEST_DATABASE_URL"))
+            .map_err(|_| {
+                eyre::eyre!(
+                    "Set DATABASE_URL (or TEST_DATABASE_URL) for the \
+                     integration tests"
+                )
+            })?;
+
+        let mut url = Url::parse(&base)?;
+        url.set_path("/postgres");
+        let admin_url = url.to_string();
+
+        let db_name = format!("crabby_chat_test_{}", Uuid::now_v7().simple());
+
+        let mut admin = PgConnection::connect(&admin_url).await?;
+        let create = AssertSqlSafe(format!("CREATE DATABASE {db_name}"));
+        sqlx::query(create.into_sql_str())
+            .execute(&mut admin)
+            .await?;
+
+        let mut test_url = Url::parse(&base)?;
+        test_url.set_path(&format!("/{db_name}"));
+        let test_url = test_url.to_string();
+
+        //The new database may take a moment to accept connections
+        let pool = loop {
+            match PgPool::connect(&test_url).await {
+                Ok(pool) => break pool,
+                Err(_) => tokio::time::sleep(Duration::from_millis(150)).await,
+            }
+        };
+
+        sqlx::migrate!("./migrations").run(&pool).await?;
+
+        Ok(Self {
+            pool,
+            admin_url,
+            db_name,
+        })
+    }
+
+    pub async fn teardown(self) -> eyre::Result<()> {
+        self.pool.close().await;
+
+        let mut admin = PgConnection::connect(&self.admin_url).await?;
+        //FORCE ends whatever connections are still open
+        let drop = AssertSqlSafe(format!(
+            "DROP DATABASE IF EXISTS {} WITH (FORCE)",
+            self.db_name
+        ));
+        let _ = sqlx::query(drop.into_sql_str()).execute(&mut admin).await;
+        Ok(())
+    }
+}
@@ -0,0 +1,348 @@
+mod common;
+
+use std::time::Duration;
+
+use crabby_chat::{
+    config::OfflineQueueConfig,
+    store::{MessageRepo, PgMessageRepo, SaveOutcome, StoredMessage},
+};
+use crabby_specs::ws::common::Destination;
+use uuid::Uuid;
+
+fn message(
+    message_id: u64,
+    sender_id: Uuid,
+    dest: Destination,
+    contents: &str,
+) -> StoredMessage {
+    StoredMessage {
+        message_id,
+        sender_id,
+        client_msg_id: Some(format!("c{message_id}")),
+        dest,
+        contents: contents.to_string(),
+        client_sent_at: None,
+        edited_id: None,
+        deleted_id: None,
+        reply_to: None,
+        thread_root: None,
+        mentions: Vec::new(),
+        mentions_group: false,
+    }
+}
+
+fn reply(
+    message_id: u64,
+    sender_id: Uuid,
+    dest: Destination,
+    root: u64,
+) -> StoredMessage {
+    StoredMessage {
+        reply_to: Some(root),
+        thread_root: Some(root),
+        ..message(message_id, sender_id, dest, "reply")
+    }
+}
+
+fn ids(messages: &[StoredMessage]) -> Vec<u64> {
+    messages.iter().map(|m| m.message_id).collect()
+}
+
+#[tokio::test]
+async fn save_round_trips_and_acks_a_resend_with_the_original_id()
+-> eyre::Result<()> {
+    let db = common::TestDb::new().await?;
+    let repo = PgMessageRepo::new(db.pool.clone());
+    let (alice, bob) = (Uuid::from_u128(1), Uuid::from_u128(2));
+
+    let mut sent =
+        message(10, alice, Destination::Individual { id: bob }, "hi");
+    sent.client_sent_at = Some("2026-01-01T00:00:00Z".to_string());
+    sent.mentions = vec![bob];
+    assert_eq!(repo.save(&sent).await?, SaveOutcome::Stored);
+
+    let found = repo.find(10).await?.expect("stored message");
+    assert_eq!(found.sender_id, alice);
+    assert_eq!(found.dest, Destination::Individual { id: bob });
+    assert_eq!(found.contents, "hi");
+    assert_eq!(found.client_msg_id.as_deref(), Some("c10"));
+    assert_eq!(
+        found.client_sent_at.as_deref(),
+        Some("2026-01-01T00:00:00Z")
+    );
+    assert_eq!(found.mentions, vec![bob]);
+    assert!(!found.mentions_group);
+
+    //Same client_msg_id under a new id is the retransmit of the first
+    let resent = StoredMessage {
+        message_id: 11,
+        ..sent.clone()
+    };
+    assert_eq!(
+        repo.save(&resent).await?,
+        SaveOutcome::Duplicate { message_id: 10 }
+    );
+    assert!(repo.find(11).await?.is_none());
+
+    //Another sender may use the same key
+    let other = StoredMessage {
+        message_id: 12,
+        sender_id: bob,
+        ..sent
+    };
+    assert_eq!(repo.save(&other).await?, SaveOutcome::Stored);
+
+    db.teardown().await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn direct_history_holds_both_directions_newest_first() -> eyre::Result<()>
+{
+    let db = common::TestDb::new().await?;
+    let repo = PgMessageRepo::new(db.pool.clone());
+    let (alice, bob, carol) =
+        (Uuid::from_u128(1), Uuid::from_u128(2), Uuid::from_u128(3));
+
+    repo.save(&message(1, alice, Destination::Individual { id: bob }, "a"))
+        .await?;
+    repo.save(&message(2, bob, Destination::Individual { id: alice }, "b"))
+        .await?;
+    repo.save(&message(
+        3,
+        carol,
+        Destination::Individual { id: alice },
+        "c",
+    ))
+    .await?;
+    repo.save(&message(4, alice, Destination::Individual { id: bob }, "d"))
+        .await?;
+    repo.save(&reply(5, bob, Destination::Individual { id: alice }, 4))
+        .await?;
+
+    let dest = Destination::Individual { id: bob };
+    let page = repo.history(alice, &dest, u64::MAX, 10).await?;
+    assert_eq!(ids(&page), vec![4, 2, 1]);
+    //Bob sees the same conversation from his side
+    let theirs = repo
+        .history(bob, &Destination::Individual { id: alice }, u64::MAX, 10)
+        .await?;
+    assert_eq!(ids(&theirs), vec![4, 2, 1]);
+
+    let first = repo.history(alice, &dest, u64::MAX, 2).await?;
+    assert_eq!(ids(&first), vec![4, 2]);
+    let second = repo.history(alice, &dest, 2, 2).await?;
+    assert_eq!(ids(&second), vec![1]);
+
+    db.teardown().await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn group_history_only_holds_that_group() -> eyre::Result<()> {
+    let db = common::TestDb::new().await?;
+    let repo = PgMessageRepo::new(db.pool.clone());
+    let (alice, bob) = (Uuid::from_u128(1), Uuid::from_u128(2));
+    let (group, other) = (Uuid::from_u128(100), Uuid::from_u128(101));
+
+    repo.save(&message(1, alice, Destination::Group { id: group }, "a"))
+        .await?;
+    repo.save(&message(2, bob, Destination::Group { id: other }, "b"))
+        .await?;
+    repo.save(&message(3, bob, Destination::Group { id: group }, "c"))
+        .await?;
+
+    let page = repo
+        .history(alice, &Destination::Group { id: group }, u64::MAX, 10)
+        .await?;
+    assert_eq!(ids(&page), vec![3, 1]);
+
+    db.teardown().await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn threads_are_paged_and_summarised() -> eyre::Result<()> {
+    let db = common::TestDb::new().await?;
+    let repo = PgMessageRepo::new(db.pool.clone());
+    let (alice, bob, carol) =
+        (Uuid::from_u128(1), Uuid::from_u128(2), Uuid::from_u128(3));
+    let dest = Destination::Group {
+        id: Uuid::from_u128(100),
+    };
+
+    repo.save(&message(1, alice, dest.clone(), "root")).await?;
+    repo.save(&message(2, alice, dest.clone(), "quiet")).await?;
+    repo.save(&reply(3, bob, dest.clone(), 1)).await?;
+    repo.save(&reply(4, carol, dest.clone(), 1)).await?;
+    repo.save(&reply(5, bob, dest.clone(), 1)).await?;
+
+    assert_eq!(ids(&repo.thread(1, u64::MAX, 10).await?), vec![5, 4, 3]);
+    assert_eq!(ids(&repo.thread(1, 5, 1).await?), vec![4]);
+
+    let summaries = repo.threads(&[1, 2]).await?;
+    assert_eq!(summaries.len(), 1);
+    let summary = &summaries[&1];
+    assert_eq!(summary.reply_count, 3);
+    assert_eq!(summary.last_reply_id, 5);
+    assert_eq!(summary.last_reply_by, bob);
+
+    let mut participants = repo.thread_participants(1).await?;
+    participants.sort();
+    assert_eq!(participants, vec![alice, bob, carol]);
+
+    db.teardown().await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn edits_keep_revisions_until_the_message_is_deleted() -> eyre::Result<()>
+{
+    let db = common::TestDb::new().await?;
+    let repo = PgMessageRepo::new(db.pool.clone());
+    let (alice, bob) = (Uuid::from_u128(1), Uuid::from_u128(2));
+    let dest = Destination::Individual { id: bob };
+
+    repo.save(&message(1, alice, dest, "first")).await?;
+    let revisions = repo.edit(1, "second", 2).await?.expect("edited");
+    assert_eq!(revisions.len(), 1);
+    assert_eq!(revisions[0].contents, "first");
+    let revisions = repo.edit(1, "third", 3).await?.expect("edited");
+    let contents: Vec<_> =
+        revisions.iter().map(|r| r.contents.as_str()).collect();
+    assert_eq!(contents, vec!["first", "second"]);
+    let edited = repo.find(1).await?.expect("stored message");
+    assert_eq!(edited.contents, "third");
+    assert_eq!(edited.edited_id, Some(3));
+
+    repo.react(1, bob, "👍").await?;
+    assert!(repo.delete(1, 4).await?);
+    let tombstone = repo.find(1).await?.expect("tombstone");
+    assert_eq!(tombstone.contents, "");
+    assert_eq!(tombstone.deleted_id, Some(4));
+    assert!(repo.reactions(bob, &[1]).await?.is_empty());
+
+    //Nothing is left to change once it is gone
+    assert!(repo.edit(1, "again", 5).await?.is_none());
+    assert!(!repo.delete(1, 6).await?);
+    assert!(repo.edit(99, "missing", 7).await?.is_none());
+
+    db.teardown().await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn reactions_are_counted_per_emoji() -> eyre::Result<()> {
+    let db = common::TestDb::new().await?;
+    let repo = PgMessageRepo::new(db.pool.clone());
+    let (alice, bob) = (Uuid::from_u128(1), Uuid::from_u128(2));
+    let dest = Destination::Individual { id: bob };
+
+    repo.save(&message(1, alice, dest, "hi")).await?;
+    assert_eq!(repo.react(1, alice, "👍").await?, Some(1));
+    assert_eq!(repo.react(1, bob, "👍").await?, Some(2));
+    assert_eq!(repo.react(1, bob, "👍").await?, None);
+    assert_eq!(repo.react(1, bob, "🎉").await?, Some(1));
+
+    let reactions = repo.reactions(alice, &[1]).await?;
+    let summary: Vec<_> = reactions[&1]
+        .iter()
+        .map(|r| (r.emoji.as_str(), r.count, r.reacted))
+        .collect();
+    assert_eq!(summary, vec![("👍", 2, true), ("🎉", 1, false)]);
+
+    assert_eq!(repo.unreact(1, bob, "👍").await?, Some(1));
+    assert_eq!(repo.unreact(1, bob, "👍").await?, None);
+
+    db.teardown().await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn read_cursors_only_move_forward_and_drive_unread_counts()
+-> eyre::Result<()> {
+    let db = common::TestDb::new().await?;
+    let repo = PgMessageRepo::new(db.pool.clone());
+    let (alice, bob) = (Uuid::from_u128(1), Uuid::from_u128(2));
+    let group = Uuid::from_u128(100);
+
+    repo.save(&message(1, alice, Destination::Individual { id: bob }, "a"))
+        .await?;
+    repo.save(&message(2, bob, Destination::Individual { id: alice }, "b"))
+        .await?;
+    repo.save(&message(3, bob, Destination::Individual { id: alice }, "c"))
+        .await?;
+    repo.save(&message(4, bob, Destination::Group { id: group }, "d"))
+        .await?;
+
+    let direct = Destination::Individual { id: bob };
+    assert!(repo.mark_read(alice, &direct, 2).await?);
+    assert!(!repo.mark_read(alice, &direct, 1).await?);
+    assert!(!repo.mark_read(alice, &direct, 2).await?);
+    //Marking a group read makes it one of alice's conversations
+    assert!(
+        repo.mark_read(alice, &Destination::Group { id: group }, 0)
+            .await?
+    );
+
+    let mut unread: Vec<_> = repo
+        .unread(alice)
+        .await?
+        .into_iter()
+        .map(|u| (u.dest, u.unread, u.read_up_to))
+        .collect();
+    unread.sort_by_key(|(dest, ..)| matches!(dest, Destination::Group { .. }));
+    assert_eq!(
+        unread,
+        vec![
+            (direct, 1, Some(2)),
+            (Destination::Group { id: group }, 1, Some(0)),
+        ]
+    );
+
+    let mut conversations = repo.conversations(bob).await?;
+    conversations.sort_by_key(|dest| matches!(dest, Destination::Group { .. }));
+    assert_eq!(
+        conversations,
+        vec![
+            Destination::Individual { id: alice },
+            Destination::Group { id: group },
+        ]
+    );
+
+    db.teardown().await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn offline_queue_is_trimmed_and_drained_oldest_first() -> eyre::Result<()>
+{
+    let db = common::TestDb::new().await?;
+    let repo = PgMessageRepo::new(db.pool.clone());
+    let (alice, bob, carol) =
+        (Uuid::from_u128(1), Uuid::from_u128(2), Uuid::from_u128(3));
+    let dest = Destination::Group {
+        id: Uuid::from_u128(100),
+    };
+    let limits = OfflineQueueConfig {
+        max_messages: 2,
+        max_age: Duration::from_secs(60),
+    };
+
+    for id in 1..=3 {
+        repo.save(&message(id, alice, dest.clone(), "hi")).await?;
+        repo.enqueue(&[bob, carol], id, limits).await?;
+    }
+    //Queuing the same message again changes nothing
+    repo.enqueue(&[bob], 3, limits).await?;
+    repo.delete(2, 4).await?;
+
+    //Only the newest two were kept and the deleted one is skipped
+    assert_eq!(ids(&repo.drain(bob, limits.max_age).await?), vec![3]);
+    assert!(repo.drain(bob, limits.max_age).await?.is_empty());
+    //Anything queued for longer than max_age is dropped on drain
+    assert!(repo.drain(carol, Duration::ZERO).await?.is_empty());
+
+    db.teardown().await?;
+    Ok(())
+}
@@ -4,6 +4,7 @@ use pasetors::token::TrustedToken;
 /*This trait will eventually be used by any service that will require token based authentication
 I am adding the trait bound for KeyRetrieval because in order to verify the validity of the token,
 a key is always required regardless of it being a public key or a local decryption key*/
+#[allow(async_fn_in_trait)]
 pub trait VerifyToken<K>
 where
     Self::Storage: KeyRetrieval<K>,
@@ -11,6 +12,7 @@ where
     type Storage;
     async fn verify(&self, token: String) -> Result<TrustedToken>;
 }
+#[allow(async_fn_in_trait)]
 pub trait KeyRetrieval<K> {
     async fn get_key(&self, kid: &str) -> Result<K>;
 }
@@ -22,9 +22,9 @@ pub struct StorageState {
 #[sqlx(transparent)]
 pub struct GroupId(pub Uuid);
 
-impl ToString for GroupId {
-    fn to_string(&self) -> String {
-        self.0.to_string()
+impl std::fmt::Display for GroupId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.0.fmt(f)
     }
 }
 
@@ -33,9 +33,9 @@ impl ToString for GroupId {
 #[sqlx(transparent)]
 pub struct MemberId(pub Uuid);
 
-impl ToString for MemberId {
-    fn to_string(&self) -> String {
-        self.0.to_string()
+impl std::fmt::Display for MemberId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.0.fmt(f)
     }
 }
 
@@ -1,6 +1,6 @@
 use std::{env, fs, path::PathBuf};
 
-use utoipa::openapi::{self, Contact};
+use utoipa::openapi::Contact;
 
 fn main() {
     let (_, mut openapi) = crabby_group::api::router().split_for_parts();
@@ -1,4 +1,6 @@
-use sqlx::{PgPool, Postgres, Transaction, query, types::Uuid};
+use sqlx::{
+    PgExecutor, PgPool, Postgres, Transaction, query, types::Uuid,
+};
 use tonic::async_trait;
 
 use crate::{
@@ -85,20 +87,7 @@ impl DatabaseRepo for PgRepo {
         let mut tx = self.conn.begin().await?;
 
         // Actor must be an admin to add users
-        let actor_role = query!(
-            "SELECT role as \"role: Role\" FROM group_membership WHERE \
-             group_id = $1 AND user_id = $2",
-            group_id.0,
-            payload.actor_id.0
-        )
-        .fetch_optional(tx.as_mut())
-        .await?;
-
-        let is_admin = actor_role
-            .and_then(|r| r.role)
-            .map(|role| role == Role::Admin)
-            .unwrap_or(false);
-        if !is_admin {
+        if !is_admin(tx.as_mut(), group_id.0, payload.actor_id.0).await? {
             return Err(GroupError::Forbidden);
         }
 
@@ -112,10 +101,10 @@ impl DatabaseRepo for PgRepo {
         .execute(tx.as_mut())
         .await
         .map_err(|e| {
-            if let sqlx::Error::Database(ref db_err) = e {
-                if db_err.kind() == sqlx::error::ErrorKind::UniqueViolation {
-                    return GroupError::AlreadyMember;
-                }
+            if let sqlx::Error::Database(ref db_err) = e
+                && db_err.kind() == sqlx::error::ErrorKind::UniqueViolation
+            {
+                return GroupError::AlreadyMember;
             }
             GroupError::Database(e)
         })?;
@@ -142,23 +131,11 @@ impl DatabaseRepo for PgRepo {
     ) -> Result<bool, GroupError> {
         let mut tx = self.conn.begin().await?;
 
-        // Check actor's role in the group
-        let actor_role = query!(
-            "SELECT role as \"role: Role\" FROM group_membership WHERE \
-             group_id = $1 AND user_id = $2",
-            params.group_id.0,
-            payload.actor_id.0
-        )
-        .fetch_optional(tx.as_mut())
-        .await?;
-
         let is_self_remove = params.member_id.0 == payload.actor_id.0;
-        let is_admin = actor_role
-            .and_then(|r| r.role)
-            .map(|role| role == Role::Admin)
-            .unwrap_or(false);
-
-        if !is_self_remove && !is_admin {
+        if !is_self_remove
+            && !is_admin(tx.as_mut(), params.group_id.0, payload.actor_id.0)
+                .await?
+        {
             return Err(GroupError::Forbidden);
         }
 
@@ -227,3 +204,25 @@ impl PgRepo {
         Ok(())
     }
 }
+
+/// Returns `true` if `user_id` is an admin of `group_id`, `false` if they
+/// are a plain member or not in the group at all.
+pub(crate) async fn is_admin<'c>(
+    conn: impl PgExecutor<'c>,
+    group_id: Uuid,
+    user_id: Uuid,
+) -> Result<bool, sqlx::Error> {
+    let role = query!(
+        "SELECT role as \"role: Role\" FROM group_membership WHERE \
+         group_id = $1 AND user_id = $2",
+        group_id,
+        user_id
+    )
+    .fetch_optional(conn)
+    .await?;
+
+    Ok(role
+        .and_then(|r| r.role)
+        .map(|role| role == Role::Admin)
+        .unwrap_or(false))
+}
@@ -3,6 +3,8 @@ use std::collections::HashMap;
 use sqlx::{PgPool, types::Uuid};
 use tonic::{Request, Response, Status};
 
+use crate::database::repo;
+
 pub mod proto {
     tonic::include_proto!("groups");
 }
@@ -10,7 +12,8 @@ pub mod proto {
 use proto::{
     BatchListGroupMembersRequest, BatchListGroupMembersResponse, CheckMembershipRequest,
     CheckMembershipResponse, GetGroupMembershipVersionRequest, GetGroupMembershipVersionResponse,
-    GroupMembers, ListGroupMembersRequest, ListGroupMembersResponse,
+    GroupMembers, IsGroupAdminRequest, IsGroupAdminResponse, ListGroupMembersRequest,
+    ListGroupMembersResponse,
     group_service_server::{GroupService, GroupServiceServer},
 };
 
@@ -154,4 +157,20 @@ impl GroupService for GroupServiceImpl {
             ver: ver as u64,
         }))
     }
+
+    /// Returns `true` if `user_id` is an admin of `group_id`.
+    async fn is_group_admin(
+        &self,
+        request: Request<IsGroupAdminRequest>,
+    ) -> Result<Response<IsGroupAdminResponse>, Status> {
+        let req = request.into_inner();
+        let group_id = parse_uuid(&req.group_id)?;
+        let user_id = parse_uuid(&req.user_id)?;
+
+        let admin = repo::is_admin(&self.pool, group_id, user_id)
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?;
+
+        Ok(Response::new(IsGroupAdminResponse { admin }))
+    }
 }
@@ -4,7 +4,7 @@ use crabby_group::{
     api::StorageState, database::repo::PgRepo, grpc::GroupServiceImpl,
 };
 use sqlx::postgres::PgPoolOptions;
-use tonic::{service::Routes, transport::Server};
+use tonic::service::Routes;
 
 #[tokio::main]
 async fn main() -> eyre::Result<()> {
@@ -1,13 +1,23 @@
 use crabby_transport::{channel::Channel, codec::JsonCodec};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
 
-use crate::ws::outgoing::CrabbyWsFromServer;
+use crate::ws::{common::PresenceStatus, outgoing::CrabbyWsFromServer};
+
+///A server message for a user, tagged with the chat node that published
+/// it so that node can skip its own deliveries
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Delivery {
+    pub origin: String,
+    pub message: CrabbyWsFromServer,
+}
 
 pub struct UserMessageDelivery {
     user_id: String,
 }
 
 impl Channel for UserMessageDelivery {
-    type Message = CrabbyWsFromServer;
+    type Message = Delivery;
     type Codec = JsonCodec;
 
     fn channel_name() -> &'static str {
@@ -27,6 +37,54 @@ impl UserMessageDelivery {
     }
 }
 
+///Presence news shared by every crabby-chat node
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type")]
+pub enum PresenceEvent {
+    ///The publishing node's view of a user changed
+    Update {
+        origin: String,
+        user_id: Uuid,
+        ///Whether `origin` holds a session of the user
+        connected: bool,
+        status: PresenceStatus,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        custom_text: Option<String>,
+    },
+    ///A node started, every other node should announce the users it
+    /// holds sessions of
+    Sync { origin: String },
+    ///Sent by every node on a fixed interval. Nodes that go quiet are
+    /// taken to be gone along with every session they held.
+    Heartbeat { origin: String },
+}
+impl PresenceEvent {
+    pub fn origin(&self) -> &str {
+        match self {
+            PresenceEvent::Update { origin, .. }
+            | PresenceEvent::Sync { origin }
+            | PresenceEvent::Heartbeat { origin } => origin,
+        }
+    }
+}
+
+///Single subject every node publishes and subscribes to, presence is
+/// needed wherever a user who might be interested is connected
+pub struct PresenceUpdates;
+
+impl Channel for PresenceUpdates {
+    type Message = PresenceEvent;
+    type Codec = JsonCodec;
+
+    fn channel_name() -> &'static str {
+        "crabby-presence"
+    }
+
+    fn subject(&self) -> String {
+        "presence.updates".to_string()
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use crabby_transport::{channel::Channel, codec::Codec};
@@ -65,9 +123,16 @@ mod tests {
         let msg = CrabbyWsFromServer::ChatMessage {
             message_id: 42,
             user_id: Uuid::nil(),
-            dest: crate::ws::common::Destination::Individual { id: Uuid::nil() },
+            dest: crate::ws::common::Destination::Individual {
+                id: Uuid::nil(),
+            },
             timestamp: "2026-01-01T00:00:00Z".to_string(),
             contents: "hello".to_string(),
+            client_sent_at: None,
+            reply_to: None,
+            thread_root: None,
+            mentions: Vec::new(),
+            mentions_group: false,
         };
 
         let encoded = JsonCodec::encode(&msg).expect("encode failed");
@@ -83,6 +148,54 @@ mod tests {
                 assert_eq!(message_id, 42);
                 assert_eq!(contents, "hello");
             }
+            other => panic!("expected ChatMessage, got {other:?}"),
         }
     }
+
+    #[test]
+    fn delivery_roundtrip_keeps_origin() {
+        let delivery = Delivery {
+            origin: "node-a".to_string(),
+            message: CrabbyWsFromServer::SendAck {
+                client_msg_id: "c1".to_string(),
+                message_id: 7,
+                timestamp: String::new(),
+            },
+        };
+
+        let encoded = JsonCodec::encode(&delivery).expect("encode failed");
+        let decoded: Delivery =
+            JsonCodec::decode(&encoded).expect("decode failed");
+
+        assert_eq!(decoded.origin, "node-a");
+        assert!(matches!(
+            decoded.message,
+            CrabbyWsFromServer::SendAck { message_id: 7, .. }
+        ));
+    }
+
+    #[test]
+    fn presence_event_roundtrip_keeps_origin() {
+        let event = PresenceEvent::Update {
+            origin: "node-a".to_string(),
+            user_id: Uuid::nil(),
+            connected: true,
+            status: PresenceStatus::Away,
+            custom_text: Some("lunch".to_string()),
+        };
+
+        let encoded = JsonCodec::encode(&event).expect("encode failed");
+        let decoded: PresenceEvent =
+            JsonCodec::decode(&encoded).expect("decode failed");
+
+        assert_eq!(decoded.origin(), "node-a");
+        assert!(matches!(
+            decoded,
+            PresenceEvent::Update {
+                status: PresenceStatus::Away,
+                connected: true,
+                ..
+            }
+        ));
+    }
 }
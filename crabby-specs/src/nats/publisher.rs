@@ -1,16 +1,12 @@
-use std::sync::Arc;
-
 use async_nats::Client;
 use async_trait::async_trait;
 use crabby_transport::{
     channel::Channel,
-    codec::{Codec, MsgpackCodec},
+    codec::Codec,
     publisher::Publisher,
 };
 use eyre::{Ok, Result};
 
-use crate::ws::outgoing::CrabbyWsFromServer;
-
 pub struct NatsCorePublisher {
     inner: Client,
     subject: String,
@@ -9,19 +9,22 @@ use crabby_transport::{
 use eyre::Result;
 use futures_util::{Stream, StreamExt};
 
-use crate::nats::{channel::UserMessageDelivery, error::NatsAdapterError};
+use crate::nats::error::NatsAdapterError;
 
 pub struct NatsCoreSubscriber {
     inner: async_nats::Client,
 }
 #[async_trait]
-impl Subscriber<UserMessageDelivery> for NatsCoreSubscriber {
+impl<C> Subscriber<C> for NatsCoreSubscriber
+where
+    C: Channel + Send + Sync + Unpin + 'static,
+{
     type Stream = ChannelStream<
-        UserMessageDelivery,
+        C,
         //This Bytes object represents the Payload
         Pin<Box<dyn Stream<Item = Result<Bytes>> + Send>>,
     >;
-    type Message = <UserMessageDelivery as Channel>::Message;
+    type Message = C::Message;
     async fn subscribe(
         &self,
         topic: impl Channel + Send + 'static,
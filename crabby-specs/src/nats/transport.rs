@@ -5,13 +5,21 @@ use eyre::Ok;
 use crate::nats::{
     publisher::NatsCorePublisher, subscriber::NatsCoreSubscriber,
 };
-//TODO:When instantiating the transport, check for transport specific
-// URL in the env
 // TODO: Take in channel as argument to subscriber and publisher
 // methods
 pub struct NatsCoreTransport {
     inner: Client,
 }
+impl NatsCoreTransport {
+    pub fn new(client: Client) -> Self {
+        Self { inner: client }
+    }
+    ///Connects to the NATS server at `url`, for instance the one found in
+    /// `NATS_URL`
+    pub async fn connect(url: &str) -> eyre::Result<Self> {
+        Ok(Self::new(async_nats::connect(url).await?))
+    }
+}
 
 impl<C> Transport<C> for NatsCoreTransport
 where
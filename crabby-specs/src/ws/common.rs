@@ -2,10 +2,33 @@ use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
-#[derive(Debug, Clone, JsonSchema, Serialize, Deserialize)]
+#[derive(
+    Debug, Clone, PartialEq, Eq, Hash, JsonSchema, Serialize, Deserialize,
+)]
 #[serde(tag = "type")]
 #[schemars(inline)]
 pub enum Destination {
     Individual { id: Uuid },
     Group { id: Uuid },
 }
+
+///Presence of a user as other users see it
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, JsonSchema, Serialize, Deserialize,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum PresenceStatus {
+    Online,
+    Away,
+    /// No connected session, or the user chose to appear offline
+    Offline,
+}
+
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, JsonSchema, Serialize, Deserialize,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum TypingState {
+    Started,
+    Stopped,
+}
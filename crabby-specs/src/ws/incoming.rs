@@ -1,19 +1,280 @@
 use ::serde::{Deserialize, Serialize};
 use asyncapi_rust::{ToAsyncApiMessage, schemars::JsonSchema};
+
 use uuid::Uuid;
 
-use crate::ws::common::Destination;
+use crate::ws::{
+    common::{Destination, PresenceStatus, TypingState},
+    outgoing::Correlation,
+};
 
+//The sender of a message is always the authenticated user of the
+// connection it arrives on, so nothing in here identifies the sender
 #[derive(
     Debug, Clone, JsonSchema, ToAsyncApiMessage, Serialize, Deserialize,
 )]
 #[serde(tag = "type")]
 pub enum CrabbyWsFromClient {
+    #[asyncapi(description = "Opens the connection, before anything else")]
+    Hello {
+        /// Version of the chat protocol the client speaks
+        protocol_version: u32,
+        /// Name and version of the client, only used for logging
+        client_name: String,
+        /// Optional features the client understands
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        capabilities: Vec<String>,
+    },
     #[asyncapi(description = "User sent chat message")]
     UserMessage {
-        user_id: Uuid,
+        /// Idempotency key chosen by the client, unique per sender.
+        /// Resending a message with the same key never delivers it twice.
+        client_msg_id: String,
         dest: Destination,
-        timestamp: String,
+        /// Stored in Unicode NFC. May not be blank or hold control
+        /// characters other than tab and newline.
         contents: String,
+        /// Advisory RFC 3339 time at which the client claims to have sent
+        /// the message. It is passed along untouched and never used for
+        /// ordering.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        client_sent_at: Option<String>,
+        /// Message of the same conversation this one answers
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        reply_to: Option<u64>,
+        /// Message that started the thread this one is posted in. Left out
+        /// when answering with `reply_to`, the thread of that message is
+        /// used, or a new one is started under it.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        thread_root: Option<u64>,
+        /// Users picked by the client, on top of the `@username`s found in
+        /// `contents`. `@group` mentions everyone in a group.
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        mentions: Vec<Uuid>,
+    },
+    #[asyncapi(description = "Request a page of stored messages")]
+    FetchHistory {
+        dest: Destination,
+        /// Only messages with a lower `message_id` are returned, leave it
+        /// out to start from the newest message
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        before: Option<u64>,
+        /// Maximum number of messages wanted, the server caps this
+        limit: u32,
+    },
+    #[asyncapi(description = "Request a page of the replies in a thread")]
+    FetchThread {
+        thread_root: u64,
+        /// Same paging as `FetchHistory`
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        before: Option<u64>,
+        limit: u32,
+    },
+    #[asyncapi(description = "Acknowledge sequenced server frames")]
+    Ack {
+        /// Highest `seq` the client has processed, every frame up to and
+        /// including it is acknowledged
+        up_to: u64,
+    },
+    #[asyncapi(description = "Choose the presence other users see")]
+    SetPresence {
+        /// Applies while the user has a connected session, choosing
+        /// `offline` makes them appear offline
+        status: PresenceStatus,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        custom_text: Option<String>,
     },
+    #[asyncapi(description = "Look up the presence of users")]
+    QueryPresence {
+        /// Later changes to the presence of these users are sent as
+        /// `PresenceChanged` for as long as the user stays connected.
+        /// Users the asking user shares no direct conversation or group
+        /// with are left out.
+        user_ids: Vec<Uuid>,
+    },
+    #[asyncapi(description = "The user started or stopped typing")]
+    Typing {
+        dest: Destination,
+        /// Keep sending `started` every few seconds while typing, an
+        /// indicator that is not refreshed expires on its own
+        state: TypingState,
+    },
+    #[asyncapi(description = "Mark a conversation read")]
+    MarkRead {
+        dest: Destination,
+        /// Newest message the user has read, the read cursor never moves
+        /// back
+        up_to_message_id: u64,
+    },
+    #[asyncapi(description = "Replace the contents of a sent message")]
+    EditMessage {
+        /// Only the sender of the message may edit it, or an admin of the
+        /// group it was sent to
+        message_id: u64,
+        /// Held to the same rules as the contents of a `UserMessage`
+        contents: String,
+    },
+    #[asyncapi(description = "Delete a sent message")]
+    DeleteMessage {
+        /// Same rules as `EditMessage`, a deleted message stays behind as
+        /// a tombstone
+        message_id: u64,
+    },
+    #[asyncapi(description = "React to a message")]
+    React {
+        message_id: u64,
+        /// A user reacts with each emoji at most once per message
+        emoji: String,
+    },
+    #[asyncapi(description = "Take back a reaction")]
+    Unreact { message_id: u64, emoji: String },
+}
+impl CrabbyWsFromClient {
+    ///The `type` the message is tagged with
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Self::Hello { .. } => "Hello",
+            Self::UserMessage { .. } => "UserMessage",
+            Self::FetchHistory { .. } => "FetchHistory",
+            Self::FetchThread { .. } => "FetchThread",
+            Self::Ack { .. } => "Ack",
+            Self::SetPresence { .. } => "SetPresence",
+            Self::QueryPresence { .. } => "QueryPresence",
+            Self::Typing { .. } => "Typing",
+            Self::MarkRead { .. } => "MarkRead",
+            Self::EditMessage { .. } => "EditMessage",
+            Self::DeleteMessage { .. } => "DeleteMessage",
+            Self::React { .. } => "React",
+            Self::Unreact { .. } => "Unreact",
+        }
+    }
+    ///Points an `Error` back at this message
+    pub fn correlation(&self) -> Correlation {
+        let id = match self {
+            Self::UserMessage { client_msg_id, .. } => {
+                Some(client_msg_id.clone())
+            }
+            Self::EditMessage { message_id, .. }
+            | Self::DeleteMessage { message_id }
+            | Self::React { message_id, .. }
+            | Self::Unreact { message_id, .. } => Some(message_id.to_string()),
+            _ => None,
+        };
+        Correlation {
+            request: self.kind().to_string(),
+            id,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+
+    use serde_json::Value;
+
+    use super::*;
+
+    ///One of every client message
+    fn samples() -> Vec<CrabbyWsFromClient> {
+        let dest = Destination::Individual { id: Uuid::nil() };
+        vec![
+            CrabbyWsFromClient::Hello {
+                protocol_version: 1,
+                client_name: "test".to_string(),
+                capabilities: Vec::new(),
+            },
+            CrabbyWsFromClient::UserMessage {
+                client_msg_id: "c1".to_string(),
+                dest: dest.clone(),
+                contents: "hello".to_string(),
+                client_sent_at: None,
+                reply_to: None,
+                thread_root: None,
+                mentions: Vec::new(),
+            },
+            CrabbyWsFromClient::FetchHistory {
+                dest: dest.clone(),
+                before: None,
+                limit: 10,
+            },
+            CrabbyWsFromClient::FetchThread {
+                thread_root: 1,
+                before: None,
+                limit: 10,
+            },
+            CrabbyWsFromClient::Ack { up_to: 1 },
+            CrabbyWsFromClient::SetPresence {
+                status: PresenceStatus::Away,
+                custom_text: None,
+            },
+            CrabbyWsFromClient::QueryPresence {
+                user_ids: vec![Uuid::nil()],
+            },
+            CrabbyWsFromClient::Typing {
+                dest: dest.clone(),
+                state: TypingState::Started,
+            },
+            CrabbyWsFromClient::MarkRead {
+                dest,
+                up_to_message_id: 1,
+            },
+            CrabbyWsFromClient::EditMessage {
+                message_id: 1,
+                contents: "edited".to_string(),
+            },
+            CrabbyWsFromClient::DeleteMessage { message_id: 1 },
+            CrabbyWsFromClient::React {
+                message_id: 1,
+                emoji: "🦀".to_string(),
+            },
+            CrabbyWsFromClient::Unreact {
+                message_id: 1,
+                emoji: "🦀".to_string(),
+            },
+        ]
+    }
+
+    #[test]
+    fn kind_is_the_serialized_type() {
+        for msg in samples() {
+            let value = serde_json::to_value(&msg).unwrap();
+            assert_eq!(value["type"], msg.kind());
+            let back: CrabbyWsFromClient =
+                serde_json::from_value(value).unwrap();
+            assert_eq!(back.kind(), msg.kind());
+        }
+    }
+
+    #[test]
+    fn samples_cover_every_type() {
+        //A new message has to be added to the samples to be checked
+        let schema =
+            serde_json::to_value(schemars::schema_for!(CrabbyWsFromClient))
+                .unwrap();
+        let types: BTreeSet<String> = schema["oneOf"]
+            .as_array()
+            .expect("client messages are a tagged enum")
+            .iter()
+            .filter_map(|variant| match &variant["properties"]["type"] {
+                Value::Object(tag) => tag
+                    .get("const")
+                    .or_else(|| tag.get("enum").and_then(|e| e.get(0)))
+                    .and_then(Value::as_str)
+                    .map(str::to_string),
+                _ => None,
+            })
+            .collect();
+        let sampled: BTreeSet<String> =
+            samples().iter().map(|msg| msg.kind().to_string()).collect();
+        assert_eq!(types, sampled);
+    }
+
+    #[test]
+    fn correlation_carries_the_message_id() {
+        let correlation =
+            CrabbyWsFromClient::DeleteMessage { message_id: 7 }.correlation();
+        assert_eq!(correlation.request, "DeleteMessage");
+        assert_eq!(correlation.id.as_deref(), Some("7"));
+    }
 }
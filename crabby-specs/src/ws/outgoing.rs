@@ -2,7 +2,7 @@ use ::serde::{Deserialize, Serialize};
 use asyncapi_rust::{ToAsyncApiMessage, schemars::JsonSchema};
 use uuid::Uuid;
 
-use crate::ws::common::Destination;
+use crate::ws::common::{Destination, PresenceStatus, TypingState};
 
 //Any other type of websocket message that I will be sending back to
 // the client will be defined inside of this enum
@@ -14,9 +14,374 @@ pub enum CrabbyWsFromServer {
     #[asyncapi(description = "Server sent chat message")]
     ChatMessage {
         message_id: u64,
+        /// Authenticated user that sent the message
         user_id: Uuid,
         dest: Destination,
+        /// RFC 3339 time the server accepted the message, derived from
+        /// `message_id`
         timestamp: String,
         contents: String,
+        /// Advisory send time reported by the sending client
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        client_sent_at: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        reply_to: Option<u64>,
+        /// Set on replies in a thread, they are left out of `History`
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        thread_root: Option<u64>,
+        /// Users mentioned in the message
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        mentions: Vec<Uuid>,
+        /// Whether the whole group was mentioned with `@group`
+        #[serde(default)]
+        mentions_group: bool,
     },
+    #[asyncapi(description = "A page of stored messages")]
+    History {
+        dest: Destination,
+        /// Oldest message first
+        messages: Vec<HistoricalMessage>,
+        /// `before` value for the next older page, absent once the start
+        /// of the conversation has been reached
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        next_before: Option<u64>,
+    },
+    #[asyncapi(description = "A page of the replies in a thread")]
+    Thread {
+        thread_root: u64,
+        /// Oldest reply first, empty if the thread is not visible to the
+        /// user
+        messages: Vec<HistoricalMessage>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        next_before: Option<u64>,
+    },
+    #[asyncapi(description = "Someone replied in a thread you are in")]
+    ThreadUpdated {
+        dest: Destination,
+        thread_root: u64,
+        thread: ThreadSummary,
+    },
+    #[asyncapi(description = "Someone mentioned you in a message")]
+    Mentioned {
+        message_id: u64,
+        dest: Destination,
+        /// User who wrote the message
+        user_id: Uuid,
+        /// Whether the whole group was mentioned rather than you by name
+        group_wide: bool,
+    },
+    #[asyncapi(description = "A sent message was accepted")]
+    SendAck {
+        client_msg_id: String,
+        /// Id the message was stored under, the same one recipients see
+        message_id: u64,
+        timestamp: String,
+    },
+    #[asyncapi(description = "Something the client sent failed")]
+    Error {
+        code: ErrorCode,
+        /// Readable detail for logs, match on `code` instead
+        reason: String,
+        /// Whether sending the same message again later may succeed
+        retryable: bool,
+        /// The client message that failed, left out when the frame could
+        /// not be decoded
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        correlation: Option<Correlation>,
+        /// Milliseconds to wait before sending again, set with
+        /// `rate_limited`
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        retry_after_ms: Option<u64>,
+    },
+    #[asyncapi(description = "Answer to `Hello`, chat traffic follows")]
+    Welcome {
+        /// Same session as the `SessionStarted` that follows
+        session_id: Uuid,
+        server_version: String,
+        /// The `capabilities` of the `Hello` that this server supports
+        negotiated_features: Vec<String>,
+        /// Milliseconds between two pings from the server
+        heartbeat_interval: u64,
+    },
+    #[asyncapi(description = "Connection attached to a session")]
+    SessionStarted {
+        /// Pass back as `session_id` when reconnecting to resume
+        session_id: Uuid,
+        /// Whether unacknowledged frames of an earlier connection are
+        /// being replayed
+        resumed: bool,
+    },
+    #[asyncapi(description = "Queued messages were sent, live ones follow")]
+    CatchUpComplete {
+        /// Messages that were queued while the user was offline and went
+        /// out ahead of this marker
+        queued: u64,
+    },
+    #[asyncapi(description = "A user's presence changed")]
+    PresenceChanged {
+        user_id: Uuid,
+        status: PresenceStatus,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        custom_text: Option<String>,
+        /// RFC 3339 time the user went offline
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        last_seen: Option<String>,
+    },
+    #[asyncapi(description = "Presence of the users asked for")]
+    Presence { users: Vec<UserPresence> },
+    #[asyncapi(description = "Someone in a conversation is typing")]
+    UserTyping {
+        user_id: Uuid,
+        dest: Destination,
+        /// `stopped` is also sent once an indicator expires
+        state: TypingState,
+    },
+    #[asyncapi(description = "Someone read a conversation")]
+    ReadReceipt {
+        /// User who read it
+        user_id: Uuid,
+        dest: Destination,
+        up_to_message_id: u64,
+    },
+    #[asyncapi(description = "Unread messages per conversation")]
+    UnreadCounts {
+        /// Sent once a connection is attached, for every conversation the
+        /// user has written in or marked read
+        conversations: Vec<UnreadCount>,
+    },
+    #[asyncapi(description = "A message was edited")]
+    MessageEdited {
+        message_id: u64,
+        dest: Destination,
+        /// User who edited it, the sender or an admin of the group
+        user_id: Uuid,
+        contents: String,
+        /// RFC 3339 time of this edit
+        edited_at: String,
+        /// Earlier versions of the message, oldest first
+        revisions: Vec<MessageRevision>,
+    },
+    #[asyncapi(description = "A message was deleted")]
+    MessageDeleted {
+        message_id: u64,
+        dest: Destination,
+        /// User who deleted it, the sender or an admin of the group
+        user_id: Uuid,
+        /// RFC 3339 time of the delete
+        deleted_at: String,
+    },
+    #[asyncapi(description = "Reactions to a message changed")]
+    ReactionChanged {
+        message_id: u64,
+        dest: Destination,
+        /// User who reacted or took their reaction back
+        user_id: Uuid,
+        emoji: String,
+        /// Whether the reaction was added or taken back
+        added: bool,
+        /// Users now reacting to the message with `emoji`
+        count: u64,
+    },
+}
+
+///Envelope every server message is written in. Frames carrying a `seq`
+/// are kept by the server until the client acknowledges them and are
+/// replayed when the session is resumed, so a client may see the same
+/// `seq` more than once.
+#[derive(Debug, Clone, JsonSchema, Serialize, Deserialize)]
+pub struct ServerFrame {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seq: Option<u64>,
+    #[serde(flatten)]
+    pub message: CrabbyWsFromServer,
+}
+
+///What went wrong in an `Error`. Codes are never renamed or reused, new
+/// ones may be added.
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, JsonSchema, Serialize, Deserialize,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    /// `client_msg_id` is empty or longer than 64 bytes
+    InvalidClientMsgId,
+    /// The user is not a member of the destination group
+    NotAMember,
+    /// Something the server relies on is down, it is safe to retry
+    Unavailable,
+    /// There is no message with this id
+    NotFound,
+    /// The message was deleted, it can no longer be changed
+    Deleted,
+    /// Only the sender or an admin of the group may change the message,
+    /// and only those in the conversation may react to it
+    Forbidden,
+    /// `emoji` is empty or longer than 32 bytes
+    InvalidEmoji,
+    /// `reply_to` or `thread_root` is not a message of the conversation,
+    /// or the two are not in the same thread
+    InvalidThread,
+    /// Sent too fast, the frame was dropped. Wait `retry_after_ms` before
+    /// sending again
+    RateLimited,
+    /// The frame is not a client message
+    Malformed,
+    /// The frame is larger than the server accepts
+    FrameTooLarge,
+    /// `contents` is larger than the server accepts once normalised
+    MessageTooLarge,
+    /// `contents` is empty or only whitespace
+    EmptyMessage,
+    /// `contents` holds control characters other than tab and newline
+    ControlCharacters,
+    /// `client_sent_at` is not an RFC 3339 time
+    InvalidTimestamp,
+    /// The server failed to handle the message, it is safe to retry
+    Internal,
+    /// `Hello` was sent again after the handshake
+    UnexpectedHello,
+}
+impl ErrorCode {
+    pub fn is_retryable(self) -> bool {
+        matches!(self, Self::Unavailable | Self::Internal | Self::RateLimited)
+    }
+}
+
+///Which client message an `Error` is about
+#[derive(Debug, Clone, PartialEq, Eq, JsonSchema, Serialize, Deserialize)]
+#[schemars(inline)]
+pub struct Correlation {
+    /// `type` of the client message
+    pub request: String,
+    /// `client_msg_id` of a `UserMessage`, or the `message_id` an edit,
+    /// delete or reaction was about
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+}
+
+///A stored chat message as returned inside `History`
+#[derive(Debug, Clone, JsonSchema, Serialize, Deserialize)]
+#[schemars(inline)]
+pub struct HistoricalMessage {
+    pub message_id: u64,
+    pub user_id: Uuid,
+    pub dest: Destination,
+    pub timestamp: String,
+    /// Empty once the message was deleted
+    pub contents: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client_sent_at: Option<String>,
+    /// RFC 3339 time of the latest edit
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub edited_at: Option<String>,
+    /// RFC 3339 time of the delete, present only on tombstones
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub deleted_at: Option<String>,
+    /// In the order each emoji was first used
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub reactions: Vec<ReactionSummary>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reply_to: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub thread_root: Option<u64>,
+    /// Present on messages that started a thread
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub thread: Option<ThreadSummary>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub mentions: Vec<Uuid>,
+    #[serde(default)]
+    pub mentions_group: bool,
+}
+
+///Replies to a message that started a thread
+#[derive(Debug, Clone, PartialEq, Eq, JsonSchema, Serialize, Deserialize)]
+#[schemars(inline)]
+pub struct ThreadSummary {
+    pub reply_count: u64,
+    pub last_reply_id: u64,
+    /// User who wrote the latest reply
+    pub last_reply_by: Uuid,
+    /// RFC 3339 time of the latest reply
+    pub last_reply_at: String,
+}
+
+///How many users reacted to a message with one emoji
+#[derive(Debug, Clone, PartialEq, Eq, JsonSchema, Serialize, Deserialize)]
+#[schemars(inline)]
+pub struct ReactionSummary {
+    pub emoji: String,
+    pub count: u64,
+    /// Whether the user who fetched the history is one of them
+    pub reacted: bool,
+}
+
+///An earlier version of an edited message
+#[derive(Debug, Clone, PartialEq, Eq, JsonSchema, Serialize, Deserialize)]
+#[schemars(inline)]
+pub struct MessageRevision {
+    pub contents: String,
+    /// RFC 3339 time this version was written
+    pub written_at: String,
+}
+
+///Unread state of one conversation as returned inside `UnreadCounts`
+#[derive(Debug, Clone, PartialEq, Eq, JsonSchema, Serialize, Deserialize)]
+#[schemars(inline)]
+pub struct UnreadCount {
+    pub dest: Destination,
+    /// Messages from others newer than the read cursor
+    pub unread: u64,
+    /// Read cursor, absent if the conversation was never marked read
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub read_up_to: Option<u64>,
+}
+
+///Presence of one user as returned inside `Presence`
+#[derive(Debug, Clone, PartialEq, Eq, JsonSchema, Serialize, Deserialize)]
+#[schemars(inline)]
+pub struct UserPresence {
+    pub user_id: Uuid,
+    pub status: PresenceStatus,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub custom_text: Option<String>,
+    /// RFC 3339 time the last session of an offline user went away, if
+    /// this server has seen it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_seen: Option<String>,
+}
+impl CrabbyWsFromServer {
+    ///An `Error`, retryable whenever its code is
+    pub fn error(
+        code: ErrorCode,
+        reason: impl Into<String>,
+        correlation: Option<Correlation>,
+    ) -> Self {
+        CrabbyWsFromServer::Error {
+            code,
+            reason: reason.into(),
+            retryable: code.is_retryable(),
+            correlation,
+            retry_after_ms: None,
+        }
+    }
+    ///A `rate_limited` error for a frame that was dropped undecoded
+    pub fn rate_limited(retry_after_ms: u64) -> Self {
+        CrabbyWsFromServer::Error {
+            code: ErrorCode::RateLimited,
+            reason: "sent too fast".to_string(),
+            retryable: true,
+            correlation: None,
+            retry_after_ms: Some(retry_after_ms),
+        }
+    }
+}
+impl From<UserPresence> for CrabbyWsFromServer {
+    fn from(value: UserPresence) -> Self {
+        CrabbyWsFromServer::PresenceChanged {
+            user_id: value.user_id,
+            status: value.status,
+            custom_text: value.custom_text,
+            last_seen: value.last_seen,
+        }
+    }
 }
@@ -1,4 +1,4 @@
-use bytes::{Bytes, BytesMut};
+use bytes::Bytes;
 use eyre::{Ok, Result};
 use serde::{Serialize, de::DeserializeOwned};
 
@@ -1,5 +1,6 @@
 pub mod channel;
 pub mod codec;
+pub mod memory;
 pub mod publisher;
 pub mod subscriber;
 pub mod transport;
@@ -0,0 +1,165 @@
+use std::{
+    collections::HashMap,
+    pin::Pin,
+    sync::{Arc, Mutex, MutexGuard},
+};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use eyre::Result;
+use futures_channel::mpsc::{UnboundedSender, unbounded};
+use futures_util::{Stream, StreamExt};
+
+use crate::{
+    channel::Channel,
+    codec::Codec,
+    publisher::Publisher,
+    subscriber::{ChannelStream, Subscriber},
+    transport::Transport,
+};
+
+type Subscriptions = HashMap<String, Vec<UnboundedSender<Bytes>>>;
+
+///Transport that never leaves the process. Every clone shares the same
+/// subjects, so publishers and subscribers created from clones of one
+/// `InMemoryTransport` reach each other the way they would over a broker.
+///Meant for tests and for running a single node without one.
+#[derive(Clone, Default)]
+pub struct InMemoryTransport {
+    subjects: Arc<Mutex<Subscriptions>>,
+}
+impl InMemoryTransport {
+    fn subjects(&self) -> MutexGuard<'_, Subscriptions> {
+        self.subjects.lock().unwrap_or_else(|err| err.into_inner())
+    }
+}
+
+impl<C> Transport<C> for InMemoryTransport
+where
+    C: Channel + Send + Sync + 'static,
+{
+    type Publisher = InMemoryPublisher;
+
+    type Subscriber = InMemorySubscriber;
+
+    fn subscriber(&self) -> Result<Self::Subscriber> {
+        Ok(InMemorySubscriber {
+            transport: self.clone(),
+        })
+    }
+
+    fn publisher(&self, channel: &C) -> Result<Self::Publisher> {
+        Ok(InMemoryPublisher {
+            transport: self.clone(),
+            subject: channel.subject(),
+        })
+    }
+}
+
+pub struct InMemoryPublisher {
+    transport: InMemoryTransport,
+    subject: String,
+}
+#[async_trait]
+impl<C> Publisher<C> for InMemoryPublisher
+where
+    C: Channel + Send + Sync + 'static,
+    C::Message: Send + Sync,
+{
+    async fn publish(&self, message: C::Message) -> Result<()> {
+        let encoded = C::Codec::encode(&message)?;
+        let mut subjects = self.transport.subjects();
+        if let Some(subscribers) = subjects.get_mut(&self.subject) {
+            //Dropped subscriptions are pruned on the next publish
+            subscribers.retain(|tx| tx.unbounded_send(encoded.clone()).is_ok());
+            if subscribers.is_empty() {
+                subjects.remove(&self.subject);
+            }
+        }
+        Ok(())
+    }
+}
+
+pub struct InMemorySubscriber {
+    transport: InMemoryTransport,
+}
+#[async_trait]
+impl<C> Subscriber<C> for InMemorySubscriber
+where
+    C: Channel + Send + Sync + Unpin + 'static,
+{
+    type Stream =
+        ChannelStream<C, Pin<Box<dyn Stream<Item = Result<Bytes>> + Send>>>;
+    type Message = C::Message;
+
+    async fn subscribe(
+        &self,
+        topic: impl Channel + Send + 'static,
+    ) -> Result<Self::Stream> {
+        let (tx, rx) = unbounded();
+        self.transport
+            .subjects()
+            .entry(topic.subject())
+            .or_default()
+            .push(tx);
+        Ok(ChannelStream::new(Box::pin(rx.map(Ok))))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codec::JsonCodec;
+
+    struct Greetings {
+        name: String,
+    }
+    impl Channel for Greetings {
+        type Message = String;
+        type Codec = JsonCodec;
+
+        fn channel_name() -> &'static str {
+            "greetings"
+        }
+
+        fn subject(&self) -> String {
+            format!("greetings.{}", self.name)
+        }
+    }
+
+    fn greetings(name: &str) -> Greetings {
+        Greetings {
+            name: name.to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn clones_share_subjects() {
+        let transport = InMemoryTransport::default();
+        let subscriber =
+            <InMemoryTransport as Transport<Greetings>>::subscriber(
+                &transport.clone(),
+            )
+            .unwrap();
+        let mut alice =
+            Subscriber::<Greetings>::subscribe(&subscriber, greetings("alice"))
+                .await
+                .unwrap();
+        let mut bob =
+            Subscriber::<Greetings>::subscribe(&subscriber, greetings("bob"))
+                .await
+                .unwrap();
+
+        let publisher = transport.publisher(&greetings("alice")).unwrap();
+        Publisher::<Greetings>::publish(&publisher, "hi".to_string())
+            .await
+            .unwrap();
+
+        assert_eq!(alice.next().await.unwrap().unwrap(), "hi");
+        drop(publisher);
+        drop(transport);
+        drop(subscriber);
+        //Nothing was published for bob and every sender is gone
+        assert!(bob.next().await.is_none());
+    }
+}
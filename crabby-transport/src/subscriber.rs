@@ -57,21 +57,18 @@ where
     type Item = Result<C::Message>;
 
     fn poll_next(
-        mut self: std::pin::Pin<&mut Self>,
+        self: std::pin::Pin<&mut Self>,
         cx: &mut std::task::Context<'_>,
     ) -> std::task::Poll<Option<Self::Item>> {
         let channel_stream = self.get_mut();
         match Pin::new(&mut channel_stream.stream).poll_next(cx) {
+            //A payload that does not decode is reported, it does not end
+            // the stream
             std::task::Poll::Ready(Some(Ok(encoded))) => {
-                let decoded = C::Codec::decode(encoded.as_ref());
-                if let Ok(msg) = decoded {
-                    Poll::Ready(Some(Ok(msg)))
-                } else {
-                    Poll::Ready(None)
-                }
+                Poll::Ready(Some(C::Codec::decode(encoded.as_ref())))
             }
-            std::task::Poll::Ready(None) => todo!(),
-            std::task::Poll::Pending => todo!(),
+            std::task::Poll::Ready(None) => Poll::Ready(None),
+            std::task::Poll::Pending => Poll::Pending,
             Poll::Ready(Some(Err(e))) => Poll::Ready(Some(Err(e))),
         }
     }
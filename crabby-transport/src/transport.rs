@@ -1,6 +1,6 @@
 use eyre::Result;
 
-use crate::{channel::Channel, subscriber};
+use crate::channel::Channel;
 
 pub trait Transport<C: Channel + Send + Sync + 'static>:
     Send + Sync + 'static